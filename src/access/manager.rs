@@ -0,0 +1,419 @@
+use log::{info, warn};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{self, Instant},
+};
+
+use super::{Door, Event, EventType, Permission};
+use crate::{
+    serial::devices::max::{self, Led, SerialMax, Sounder},
+    users::UserStore,
+};
+
+// Time for which the reader shows red and sounds after refusing a card.
+const DENIED_INDICATION: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Indication {
+    Released,
+    Denied,
+}
+
+struct DoorState {
+    door: Door,
+    reader: Option<Arc<SerialMax>>,
+    // Shown at the reader until the given time.
+    indication: Option<(Indication, Instant)>,
+    // None until the reader first reports the door.
+    open: Option<bool>,
+    // When the door, opened after being released, is reported held open.
+    held_at: Option<Instant>,
+    // Whether the door was forced or held open and hasn't since closed.
+    alarm: bool,
+}
+
+impl DoorState {
+    fn is_released(&self) -> bool {
+        matches!(self.indication, Some((Indication::Released, _)))
+    }
+
+    /// Sets the reader's LED, sounder and lock relay to reflect the state of the door.
+    fn apply(&self) {
+        let Some(reader) = &self.reader else {
+            return;
+        };
+
+        let (led, sounder, relay) = match self.indication {
+            Some((Indication::Released, _)) => (Led::Green, Sounder::Off, true),
+            Some((Indication::Denied, _)) => (Led::Red, Sounder::On, false),
+            None => (Led::Off, Sounder::Off, false),
+        };
+
+        reader.mutate_state(|state| {
+            state.led = led;
+            state.sounder = if self.alarm {
+                Sounder::Intermittent
+            } else {
+                sounder
+            };
+            state.relay = relay;
+        });
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let indication = self.indication.map(|(_, until)| until);
+
+        match (indication, self.held_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// AccessManager controls doors fitted with MAX readers, releasing them for users whose fobs are
+/// permitted through at the time presented, and reporting doors forced or held open.
+pub struct AccessManager {
+    // By door number.
+    doors: BTreeMap<u8, DoorState>,
+    permissions: Vec<Permission>,
+    users: Arc<UserStore>,
+
+    input_tx: mpsc::UnboundedSender<(u8, max::Event)>,
+    input_rx: mpsc::UnboundedReceiver<(u8, max::Event)>,
+    event_ch: broadcast::Sender<Event>,
+}
+
+impl AccessManager {
+    pub fn new(
+        doors: Vec<Door>,
+        permissions: Vec<Permission>,
+        users: Arc<UserStore>,
+    ) -> AccessManager {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+
+        AccessManager {
+            doors: doors
+                .into_iter()
+                .map(|door| {
+                    (
+                        door.number,
+                        DoorState {
+                            door,
+                            reader: None,
+                            indication: None,
+                            open: None,
+                            held_at: None,
+                            alarm: false,
+                        },
+                    )
+                })
+                .collect(),
+            permissions,
+            users,
+            input_tx,
+            input_rx,
+            event_ch: broadcast::Sender::new(16),
+        }
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_ch.subscribe()
+    }
+
+    /// Takes control of the door whose reader is at the given bus address, forwarding events
+    /// from the reader into this manager.
+    pub fn attach_reader(&mut self, address: u8, reader: Arc<SerialMax>) {
+        let Some(state) = self
+            .doors
+            .values_mut()
+            .find(|state| state.door.reader == address)
+        else {
+            warn!("No door is controlled by the MAX at {:02X}", address);
+            return;
+        };
+
+        let door = state.door.number;
+        let mut reader_events = reader.subscribe_events();
+        state.reader = Some(reader);
+        let input_tx = self.input_tx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match reader_events.recv().await {
+                    Ok(event) => {
+                        if input_tx.send((door, event)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("AccessManager lagged {} events from MAX {:02X}", n, address);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub async fn run(&mut self) {
+        loop {
+            let deadline = self
+                .doors
+                .values()
+                .filter_map(DoorState::next_deadline)
+                .min();
+
+            tokio::select! {
+                Some((door, event)) = self.input_rx.recv() => self.process(door, event),
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    self.expire();
+                }
+            }
+        }
+    }
+
+    fn send(&self, event: EventType) {
+        let _ = self.event_ch.send(Event(event));
+    }
+
+    fn process(&mut self, door: u8, max::Event(event): max::Event) {
+        let now = Instant::now();
+
+        match event {
+            max::EventType::CardRead(id) => self.card_read(door, id, now),
+            max::EventType::Door { open } => {
+                let Some(state) = self.doors.get_mut(&door) else {
+                    return;
+                };
+                // The door found open when the reader first reports isn't taken as forced.
+                if state
+                    .open
+                    .replace(open)
+                    .is_none_or(|previous| previous == open)
+                {
+                    return;
+                }
+
+                let event = if open && state.is_released() {
+                    state.held_at = Some(now + state.door.held_open);
+                    None
+                } else if open {
+                    warn!("Door {} ({}) forced open", door, state.door.name);
+                    state.alarm = true;
+                    Some(EventType::ForcedOpen { door })
+                } else {
+                    state.held_at = None;
+                    std::mem::take(&mut state.alarm).then_some(EventType::Secured { door })
+                };
+
+                state.apply();
+                if let Some(event) = event {
+                    self.send(event);
+                }
+            }
+            // The enclosure tamper is raised by the alarm core, see AlarmManager::attach_max.
+            max::EventType::Tamper(_) => {}
+        }
+    }
+
+    fn card_read(&mut self, door: u8, id: u32, now: Instant) {
+        let at = chrono::Local::now().naive_local();
+        let Some(state) = self.doors.get_mut(&door) else {
+            return;
+        };
+
+        // A fob that must be given with a PIN is refused, as the reader has no keypad.
+        let user = self
+            .users
+            .authenticate_fob(id, None, at)
+            .filter(|user| {
+                self.permissions
+                    .iter()
+                    .any(|permission| permission.allows(user.number, door, at))
+            })
+            .map(|user| user.number);
+
+        let event = match user {
+            Some(user) => {
+                info!(
+                    "Door {} ({}) released for user {}",
+                    door, state.door.name, user
+                );
+                state.indication = Some((Indication::Released, now + state.door.release));
+                EventType::Granted { door, user }
+            }
+            None => {
+                let user = self
+                    .users
+                    .users()
+                    .iter()
+                    .find(|user| user.fob == Some(id))
+                    .map(|user| user.number);
+                info!("Door {} ({}) refused card {}", door, state.door.name, id);
+                state.indication = Some((Indication::Denied, now + DENIED_INDICATION));
+                EventType::Denied { door, user }
+            }
+        };
+
+        state.apply();
+        self.send(event);
+    }
+
+    /// Ends indications and raises held open doors whose time has come.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let mut events = Vec::new();
+
+        for state in self.doors.values_mut() {
+            if state.indication.is_some_and(|(_, until)| until <= now) {
+                state.indication = None;
+            }
+            if state.held_at.is_some_and(|at| at <= now) {
+                warn!("Door {} ({}) held open", state.door.number, state.door.name);
+                state.held_at = None;
+                state.alarm = true;
+                events.push(EventType::HeldOpen {
+                    door: state.door.number,
+                });
+            }
+
+            state.apply();
+        }
+
+        for event in events {
+            self.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        areas::Areas,
+        users::{AccessLevel, PinHash, User, Validity},
+    };
+
+    fn manager() -> (AccessManager, Arc<SerialMax>) {
+        let users = UserStore::new(
+            [(1, Some(1001)), (2, Some(2002)), (3, None)]
+                .into_iter()
+                .map(|(number, fob)| User {
+                    number,
+                    name: format!("USER {}", number),
+                    pin: PinHash::with_rounds("1234", 1_000),
+                    fob,
+                    fob_with_pin: false,
+                    level: AccessLevel::ACCESS_ONLY,
+                    areas: Areas::ALL,
+                    validity: Validity::default(),
+                })
+                .collect(),
+        )
+        .unwrap();
+
+        let mut manager = AccessManager::new(
+            vec![Door {
+                number: 1,
+                name: "FRONT DOOR".to_string(),
+                reader: 0x60,
+                release: Duration::from_secs(5),
+                held_open: Duration::from_secs(30),
+            }],
+            vec![Permission {
+                user: 1,
+                doors: vec![1],
+                schedule: None,
+            }],
+            Arc::new(users),
+        );
+        let reader = Arc::new(SerialMax::new());
+        manager.attach_reader(0x60, reader.clone());
+
+        (manager, reader)
+    }
+
+    fn door(open: bool) -> max::Event {
+        max::Event(max::EventType::Door { open })
+    }
+
+    #[tokio::test]
+    async fn test_grants_permitted_user() {
+        time::pause();
+
+        let (mut manager, reader) = manager();
+        let mut events = manager.subscribe_events();
+
+        manager.process(1, max::Event(max::EventType::CardRead(1001)));
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::Granted { door: 1, user: 1 }
+        );
+        assert!(reader.state().relay);
+        assert_eq!(reader.state().led, Led::Green);
+
+        time::advance(Duration::from_secs(5)).await;
+        manager.expire();
+        assert!(!reader.state().relay);
+        assert_eq!(reader.state().led, Led::Off);
+
+        // A user without permission, and an unknown card.
+        manager.process(1, max::Event(max::EventType::CardRead(2002)));
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::Denied {
+                door: 1,
+                user: Some(2)
+            }
+        );
+        assert!(!reader.state().relay);
+        assert_eq!(reader.state().led, Led::Red);
+        assert_eq!(reader.state().sounder, Sounder::On);
+
+        manager.process(1, max::Event(max::EventType::CardRead(9999)));
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::Denied {
+                door: 1,
+                user: None
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forced_and_held_open() {
+        time::pause();
+
+        let (mut manager, reader) = manager();
+        let mut events = manager.subscribe_events();
+
+        manager.process(1, door(false));
+        manager.process(1, door(true));
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::ForcedOpen { door: 1 }
+        );
+        assert_eq!(reader.state().sounder, Sounder::Intermittent);
+        manager.process(1, door(false));
+        assert_eq!(events.try_recv().unwrap().0, EventType::Secured { door: 1 });
+        assert_eq!(reader.state().sounder, Sounder::Off);
+
+        manager.process(1, max::Event(max::EventType::CardRead(1001)));
+        events.try_recv().unwrap();
+        manager.process(1, door(true));
+        assert!(events.try_recv().is_err());
+
+        time::advance(Duration::from_secs(30)).await;
+        manager.expire();
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::HeldOpen { door: 1 }
+        );
+        assert_eq!(reader.state().sounder, Sounder::Intermittent);
+
+        manager.process(1, door(false));
+        assert_eq!(events.try_recv().unwrap().0, EventType::Secured { door: 1 });
+    }
+}
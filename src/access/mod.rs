@@ -0,0 +1,155 @@
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDateTime, NaiveTime, Weekday};
+use serde::Deserialize;
+use std::time::Duration;
+
+pub mod manager;
+
+// Time for which the lock is released after access is granted, unless configured otherwise.
+pub const RELEASE_TIME: Duration = Duration::from_secs(5);
+// Time a door may stand open after access is granted before it's reported held open.
+pub const HELD_OPEN_TIME: Duration = Duration::from_secs(30);
+
+/// TimeWindow is a period of local time recurring on the given days of the week. A window whose
+/// end is before its start runs past midnight into the following day.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    pub days: Vec<Weekday>,
+    pub from: NaiveTime,
+    pub until: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let time = at.time();
+
+        if self.from <= self.until {
+            self.days.contains(&at.weekday()) && time >= self.from && time < self.until
+        } else {
+            let yesterday = (at - ChronoDuration::days(1)).weekday();
+
+            (self.days.contains(&at.weekday()) && time >= self.from)
+                || (self.days.contains(&yesterday) && time < self.until)
+        }
+    }
+}
+
+/// Schedule is a named set of time windows during which access is permitted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    pub name: String,
+    pub windows: Vec<TimeWindow>,
+}
+
+impl Schedule {
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        self.windows.iter().any(|window| window.contains(at))
+    }
+}
+
+/// Door is a door controlled by a MAX reader, which releases its lock.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Door {
+    pub number: u8,
+    pub name: String,
+    // Bus address of the reader at the door.
+    pub reader: u8,
+    // Time for which the lock is released after access is granted.
+    pub release: Duration,
+    // Time the door may stand open after access is granted before it's reported held open.
+    pub held_open: Duration,
+}
+
+/// Permission allows a user through a set of doors, at any time or only within a schedule.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permission {
+    pub user: u16,
+    pub doors: Vec<u8>,
+    pub schedule: Option<Schedule>,
+}
+
+impl Permission {
+    pub fn allows(&self, user: u16, door: u8, at: NaiveDateTime) -> bool {
+        self.user == user
+            && self.doors.contains(&door)
+            && self
+                .schedule
+                .as_ref()
+                .is_none_or(|schedule| schedule.contains(at))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventType {
+    // The door was released for the user presenting their card.
+    Granted { door: u8, user: u16 },
+    // A card was refused, belonging to the given user if known.
+    Denied { door: u8, user: Option<u16> },
+    // The door opened without being released.
+    ForcedOpen { door: u8 },
+    // The door stood open for longer than allowed after being released.
+    HeldOpen { door: u8 },
+    // The door closed after being forced or held open.
+    Secured { door: u8 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        // 2 October 2023 was a Monday.
+        NaiveDate::from_ymd_opt(2023, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_time_windows() {
+        let office = TimeWindow {
+            days: vec![Weekday::Mon, Weekday::Tue],
+            from: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+            until: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+        };
+        assert!(office.contains(at(2, 8)));
+        assert!(!office.contains(at(2, 18)));
+        assert!(!office.contains(at(4, 12)));
+
+        let night = TimeWindow {
+            days: vec![Weekday::Mon],
+            from: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            until: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        };
+        assert!(night.contains(at(2, 23)));
+        assert!(night.contains(at(3, 5)));
+        assert!(!night.contains(at(3, 23)));
+        assert!(!night.contains(at(2, 5)));
+    }
+
+    #[test]
+    fn test_permission_allows() {
+        let permission = Permission {
+            user: 1,
+            doors: vec![1, 2],
+            schedule: Some(Schedule {
+                name: "OFFICE".to_string(),
+                windows: vec![TimeWindow {
+                    days: vec![Weekday::Mon],
+                    from: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+                    until: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+                }],
+            }),
+        };
+
+        assert!(permission.allows(1, 2, at(2, 12)));
+        assert!(!permission.allows(1, 3, at(2, 12)));
+        assert!(!permission.allows(2, 1, at(2, 12)));
+        assert!(!permission.allows(1, 1, at(2, 20)));
+    }
+}
@@ -0,0 +1,1216 @@
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    time::{self, Instant},
+};
+
+use super::{
+    AlarmHandle, AreaStates, Cause, Command, Event, EventType, Fault, FaultKind, Module, SetMode,
+    SystemState, Timers,
+};
+use crate::{
+    areas::{Area, Areas, AREA_COUNT},
+    channel::recv_event,
+    serial::{
+        devices::{
+            max::{self, SerialMax},
+            psu::{self, SerialPsu},
+            rio::{self, SerialRio},
+        },
+        manager::{self as serial, DeviceStatus},
+    },
+    zones::{self, Zone, ZoneState, ZoneType},
+};
+
+#[derive(Clone, Copy, Default)]
+struct AreaTimers {
+    // When the current exit or entry timer expires.
+    deadline: Option<Instant>,
+    // Set when a Final zone opens during the exit procedure, so that its closure can terminate
+    // the procedure early.
+    final_opened: bool,
+}
+
+/// AlarmManager is the core alarm state machine. It consumes commands from users and zone state
+/// changes, and moves each area independently between the unset, setting, set and alarm states.
+pub struct AlarmManager {
+    timers: Timers,
+    zones: HashMap<u16, Zone>,
+    zone_states: HashMap<u16, ZoneState>,
+
+    states: AreaStates,
+    area_timers: [AreaTimers; AREA_COUNT],
+    // Zones omitted by users, ignored until their areas are next unset.
+    omitted: BTreeSet<u16>,
+    // Areas with chime switched on.
+    chime: Areas,
+
+    // Commands, with the number of the user issuing them if known.
+    commands: mpsc::UnboundedReceiver<(Command, Option<u16>)>,
+    zone_events: broadcast::Receiver<zones::Event>,
+    state_tx: watch::Sender<AreaStates>,
+    omitted_tx: watch::Sender<BTreeSet<u16>>,
+    chime_tx: watch::Sender<Areas>,
+    event_ch: broadcast::Sender<Event>,
+
+    // Modules supervised, and the status changes of devices on the bus, once attached.
+    modules: HashMap<u8, Module>,
+    device_events: Option<broadcast::Receiver<serial::Event>>,
+    // Conditions raised and cleared by smart PSUs, by the address of the PSU.
+    conditions_tx: mpsc::UnboundedSender<(u8, FaultKind, bool)>,
+    conditions: mpsc::UnboundedReceiver<(u8, FaultKind, bool)>,
+    // Enclosure tamper switches of modules opening and closing, by the address of the module.
+    tampers_tx: mpsc::UnboundedSender<(u8, bool)>,
+    tampers: mpsc::UnboundedReceiver<(u8, bool)>,
+    // Conditions yet to persist for their delay, and when they're due to be raised as faults.
+    pending_faults: Vec<(u8, FaultKind, Instant)>,
+    faults: Vec<Fault>,
+    faults_tx: watch::Sender<Vec<Fault>>,
+}
+
+impl AlarmManager {
+    pub fn new(
+        timers: Timers,
+        zones: Vec<Zone>,
+        zone_events: broadcast::Receiver<zones::Event>,
+    ) -> (AlarmManager, AlarmHandle) {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (state_tx, state_rx) = watch::channel(AreaStates::default());
+        let (omitted_tx, omitted_rx) = watch::channel(BTreeSet::new());
+        let (chime_tx, chime_rx) = watch::channel(Areas::NONE);
+        let (conditions_tx, conditions) = mpsc::unbounded_channel();
+        let (tampers_tx, tampers) = mpsc::unbounded_channel();
+        let (faults_tx, faults_rx) = watch::channel(Vec::new());
+        let event_ch = broadcast::Sender::new(32);
+
+        (
+            AlarmManager {
+                timers,
+                zones: zones.into_iter().map(|zone| (zone.number, zone)).collect(),
+                zone_states: HashMap::new(),
+                states: AreaStates::default(),
+                area_timers: [AreaTimers::default(); AREA_COUNT],
+                omitted: BTreeSet::new(),
+                chime: Areas::NONE,
+                commands,
+                zone_events,
+                state_tx,
+                omitted_tx,
+                chime_tx,
+                event_ch: event_ch.clone(),
+                modules: HashMap::new(),
+                device_events: None,
+                conditions_tx,
+                conditions,
+                tampers_tx,
+                tampers,
+                pending_faults: Vec::new(),
+                faults: Vec::new(),
+                faults_tx,
+            },
+            AlarmHandle {
+                commands: commands_tx,
+                state: state_rx,
+                omitted: omitted_rx,
+                chime: chime_rx,
+                faults: faults_rx,
+                event_ch,
+            },
+        )
+    }
+
+    /// Supervises modules on the bus, given the events of the SerialManager polling them, raising
+    /// a fault when one goes missing or its communications fail.
+    pub fn supervise(
+        &mut self,
+        modules: Vec<Module>,
+        device_events: broadcast::Receiver<serial::Event>,
+    ) {
+        self.modules = modules
+            .into_iter()
+            .map(|module| (module.address, module))
+            .collect();
+        self.device_events = Some(device_events);
+    }
+
+    /// Switches chime on in the given areas, as configured at startup.
+    pub fn set_chime(&mut self, areas: Areas) {
+        self.chime = areas;
+        let _ = self.chime_tx.send(areas);
+    }
+
+    /// Raises faults for the conditions reported by the smart PSU at the given address, once
+    /// they've persisted for the delays given by the timers.
+    pub fn attach_psu(&mut self, address: u8, psu: &SerialPsu) {
+        let mut psu_events = psu.subscribe_events();
+        let conditions_tx = self.conditions_tx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match psu_events.recv().await {
+                    Ok(psu::Event(psu::EventType::Condition { condition, active })) => {
+                        if conditions_tx
+                            .send((address, condition.into(), active))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("AlarmManager lagged {} events from PSU {:02X}", n, address);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Raises a tamper fault while the enclosure of the RIO at the given address is open.
+    pub fn attach_rio(&mut self, address: u8, rio: &SerialRio) {
+        self.forward_tamper(address, rio.subscribe_events(), |event| match event {
+            rio::Event(rio::EventType::Tamper(open)) => Some(open),
+            _ => None,
+        });
+    }
+
+    /// Raises a tamper fault while the enclosure of the MAX reader at the given address is open.
+    pub fn attach_max(&mut self, address: u8, max: &SerialMax) {
+        self.forward_tamper(address, max.subscribe_events(), |event| match event {
+            max::Event(max::EventType::Tamper(open)) => Some(open),
+            _ => None,
+        });
+    }
+
+    /// Forwards the tamper switch of the module at the given address, as picked out of its
+    /// events by `tamper`.
+    fn forward_tamper<T: Clone + Send + 'static>(
+        &self,
+        address: u8,
+        mut events: broadcast::Receiver<T>,
+        tamper: fn(T) -> Option<bool>,
+    ) {
+        let tampers_tx = self.tampers_tx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let Some(open) = tamper(event) else {
+                            continue;
+                        };
+                        if tampers_tx.send((address, open)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!(
+                            "AlarmManager lagged {} events from module {:02X}",
+                            n, address
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub async fn run(&mut self) {
+        loop {
+            let deadline = self
+                .area_timers
+                .iter()
+                .filter_map(|timers| timers.deadline)
+                .chain(self.pending_faults.iter().map(|&(_, _, at)| at))
+                .min();
+
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some((command, user)) => self.process_command(command, user),
+                    None => break,
+                },
+                event = self.zone_events.recv() => match event {
+                    Ok(zones::Event(zones::EventType::StateChanged { zone, state, .. })) => {
+                        self.process_zone_state(zone, state);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("AlarmManager lagged {} zone events", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                event = recv_event(&mut self.device_events) => match event {
+                    Ok(serial::Event(serial::EventType::StatusChanged { address, status, .. })) => {
+                        self.process_device_status(address, status);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("AlarmManager lagged {} device events", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => self.device_events = None,
+                },
+                Some((address, kind, active)) = self.conditions.recv() => {
+                    self.process_condition(address, kind, active);
+                }
+                Some((address, open)) = self.tampers.recv() => {
+                    if open {
+                        self.raise_fault(address, FaultKind::Tamper);
+                    } else {
+                        self.restore_fault(address, FaultKind::Tamper);
+                    }
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    let now = Instant::now();
+
+                    for area in Areas::ALL.iter() {
+                        let timers = &mut self.area_timers[area.index()];
+
+                        if timers.deadline.is_some_and(|deadline| deadline <= now) {
+                            timers.deadline = None;
+                            self.timer_expired(area);
+                        }
+                    }
+
+                    let (due, pending) = std::mem::take(&mut self.pending_faults)
+                        .into_iter()
+                        .partition(|&(_, _, at)| at <= now);
+                    self.pending_faults = pending;
+                    for (address, kind, _) in due {
+                        self.raise_fault(address, kind);
+                    }
+                }
+            }
+        }
+    }
+
+    fn transition(&mut self, area: Area, state: SystemState, cause: Cause) {
+        let previous = self.states.get(area);
+        if state == previous {
+            return;
+        }
+
+        info!(
+            "Area {} state changed from {} to {} ({:?})",
+            area, previous, state, cause
+        );
+
+        self.states.set(area, state);
+        self.area_timers[area.index()].deadline = match state {
+            SystemState::ExitTiming(_) => Some(Instant::now() + self.timers.exit),
+            SystemState::EntryTiming(_) => Some(Instant::now() + self.timers.entry),
+            _ => None,
+        };
+
+        let _ = self.state_tx.send(self.states);
+
+        // Omits last for a single set, so lapse once the zone's areas are unset again.
+        if state == SystemState::Unset
+            && (previous.is_set() || previous == SystemState::AlarmResetRequired)
+            && !matches!(previous, SystemState::ExitTiming(_))
+        {
+            let (zones, states) = (&self.zones, &self.states);
+            let before = self.omitted.len();
+
+            self.omitted.retain(|number| {
+                zones.get(number).is_some_and(|zone| {
+                    zone.areas
+                        .iter()
+                        .any(|area| states.get(area) != SystemState::Unset)
+                })
+            });
+
+            if self.omitted.len() != before {
+                let _ = self.omitted_tx.send(self.omitted.clone());
+            }
+        }
+        let _ = self.event_ch.send(Event(EventType::StateChanged {
+            area,
+            state,
+            previous,
+            cause,
+        }));
+    }
+
+    fn process_command(&mut self, command: Command, user: Option<u16>) {
+        let areas = match command {
+            Command::Set { areas, .. } | Command::Unset { areas } | Command::Reset { areas } => {
+                areas
+            }
+            Command::Omit { zone, omit } => {
+                self.omit(zone, omit);
+                return;
+            }
+            Command::Chime { areas, chime } => {
+                self.switch_chime(areas, chime);
+                return;
+            }
+            Command::Tamper { areas } => {
+                for area in areas.iter() {
+                    self.transition(area, SystemState::Alarm, Cause::Tamper);
+                }
+                return;
+            }
+            Command::AcknowledgeFaults => {
+                self.acknowledge_faults(user.map_or(Cause::Command, Cause::User));
+                return;
+            }
+        };
+
+        for area in areas.iter() {
+            let next = match (command, self.states.get(area)) {
+                (Command::Set { mode, .. }, SystemState::Unset) => {
+                    self.area_timers[area.index()].final_opened = false;
+                    Some(SystemState::ExitTiming(mode))
+                }
+                (Command::Unset { .. }, state) if state.is_set() => Some(SystemState::Unset),
+                (Command::Unset { .. }, SystemState::Alarm) => {
+                    Some(SystemState::AlarmResetRequired)
+                }
+                (Command::Reset { .. }, SystemState::AlarmResetRequired) => {
+                    Some(SystemState::Unset)
+                }
+                (_, state) => {
+                    debug!(
+                        "Ignoring command {:?} for area {} in state {}",
+                        command, area, state
+                    );
+                    None
+                }
+            };
+
+            if let Some(next) = next {
+                self.transition(area, next, user.map_or(Cause::Command, Cause::User));
+            }
+        }
+    }
+
+    /// Latches a fault when a supervised module goes missing or its communications fail, and
+    /// marks the fault restored once the module recovers.
+    fn process_device_status(&mut self, address: u8, status: DeviceStatus) {
+        if !self.modules.contains_key(&address) {
+            return;
+        }
+
+        match status {
+            DeviceStatus::Offline => self.raise_fault(address, FaultKind::Missing),
+            DeviceStatus::OnlineCorruptReplies => self.raise_fault(address, FaultKind::Comms),
+            DeviceStatus::OnlineOK => {
+                self.restore_fault(address, FaultKind::Missing);
+                self.restore_fault(address, FaultKind::Comms);
+            }
+            DeviceStatus::Unknown => {}
+        }
+    }
+
+    /// Latches a fault for a condition reported by a smart PSU once it has persisted for its
+    /// delay, and marks the fault restored once the condition clears.
+    fn process_condition(&mut self, address: u8, kind: FaultKind, active: bool) {
+        let pending = self
+            .pending_faults
+            .iter()
+            .position(|&(a, k, _)| a == address && k == kind);
+
+        match (active, pending) {
+            // The condition cleared before it was due to be raised.
+            (false, Some(i)) => {
+                self.pending_faults.remove(i);
+            }
+            (false, None) => self.restore_fault(address, kind),
+            (true, Some(_)) => {}
+            (true, None) => {
+                let delay = self.timers.fault_delay(kind);
+
+                if delay.is_zero() {
+                    self.raise_fault(address, kind);
+                } else {
+                    debug!(
+                        "Module {:02X} fault {} pending for {:?}",
+                        address, kind, delay
+                    );
+                    self.pending_faults
+                        .push((address, kind, Instant::now() + delay));
+                }
+            }
+        }
+    }
+
+    /// Latches a fault of a module, raising a tamper alarm in its set areas if so configured and
+    /// the fault suggests tampering.
+    fn raise_fault(&mut self, address: u8, kind: FaultKind) {
+        match self
+            .faults
+            .iter_mut()
+            .find(|fault| fault.address == address && fault.kind == kind)
+        {
+            Some(fault) if !fault.restored => return,
+            Some(fault) => fault.restored = false,
+            None => self.faults.push(Fault {
+                address,
+                kind,
+                restored: false,
+            }),
+        }
+
+        warn!("Module {:02X} fault: {}", address, kind);
+        let _ = self.faults_tx.send(self.faults.clone());
+        let _ = self
+            .event_ch
+            .send(Event(EventType::FaultRaised { address, kind }));
+
+        let Some(module) = self.modules.get(&address).copied() else {
+            return;
+        };
+        if module.tamper && kind.is_tamper() {
+            for area in module.areas.iter() {
+                if self.states.get(area).is_set() {
+                    self.transition(area, SystemState::Alarm, Cause::Module(address));
+                }
+            }
+        }
+    }
+
+    fn restore_fault(&mut self, address: u8, kind: FaultKind) {
+        let Some(fault) = self
+            .faults
+            .iter_mut()
+            .find(|fault| fault.address == address && fault.kind == kind && !fault.restored)
+        else {
+            return;
+        };
+
+        fault.restored = true;
+        info!("Module {:02X} fault {} restored", address, kind);
+
+        let _ = self.faults_tx.send(self.faults.clone());
+        let _ = self
+            .event_ch
+            .send(Event(EventType::FaultRestored { address, kind }));
+    }
+
+    /// Clears the faults of modules that have recovered. Those still faulty remain latched.
+    fn acknowledge_faults(&mut self, cause: Cause) {
+        let mut addresses: Vec<u8> = self
+            .faults
+            .iter()
+            .filter(|fault| fault.restored)
+            .map(|fault| fault.address)
+            .collect();
+        if addresses.is_empty() {
+            return;
+        }
+        addresses.sort();
+        addresses.dedup();
+
+        self.faults.retain(|fault| !fault.restored);
+        info!(
+            "Module faults {:02X?} acknowledged ({:?})",
+            addresses, cause
+        );
+
+        let _ = self.faults_tx.send(self.faults.clone());
+        let _ = self
+            .event_ch
+            .send(Event(EventType::FaultsAcknowledged { addresses, cause }));
+    }
+
+    /// Switches the chime on or off in the given areas.
+    fn switch_chime(&mut self, areas: Areas, chime: bool) {
+        let before = self.chime;
+        for area in areas.iter() {
+            if chime {
+                self.chime.insert(area);
+            } else {
+                self.chime.remove(area);
+            }
+        }
+
+        if self.chime != before {
+            info!("Chime in areas {}: {}", areas, chime);
+            let _ = self.chime_tx.send(self.chime);
+        }
+    }
+
+    /// Omits or restores a zone, provided it may be omitted and all of its areas are unset.
+    fn omit(&mut self, number: u16, omit: bool) {
+        let Some(zone) = self.zones.get(&number) else {
+            return;
+        };
+
+        if !zone.zone_type.can_omit()
+            || zone
+                .areas
+                .iter()
+                .any(|area| self.states.get(area) != SystemState::Unset)
+        {
+            debug!("Ignoring request to omit zone {}", number);
+            return;
+        }
+
+        let changed = if omit {
+            self.omitted.insert(number)
+        } else {
+            self.omitted.remove(&number)
+        };
+
+        if changed {
+            info!("Zone {} omitted: {}", number, omit);
+            let _ = self.omitted_tx.send(self.omitted.clone());
+        }
+    }
+
+    /// Returns whether the zone generates an alarm when opened. A zone shared between areas is
+    /// only armed once every one of its areas has armed it.
+    fn is_armed(&self, zone: &Zone) -> bool {
+        !self.omitted.contains(&zone.number)
+            && !zone.areas.is_empty()
+            && zone.areas.iter().all(|area| match self.states.get(area) {
+                SystemState::Set | SystemState::EntryTiming(SetMode::Full) => true,
+                SystemState::PartSet | SystemState::EntryTiming(SetMode::Part) => zone.part_set,
+                _ => false,
+            })
+    }
+
+    fn process_zone_state(&mut self, number: u16, state: ZoneState) {
+        let Some(zone) = self.zones.get(&number).cloned() else {
+            return;
+        };
+        self.zone_states.insert(number, state);
+
+        let armed = self.is_armed(&zone);
+        let cause = if state == ZoneState::Tamper {
+            Cause::ZoneTamper(number)
+        } else {
+            Cause::Zone(number)
+        };
+
+        for area in zone.areas.iter() {
+            if let Some(next) = self.zone_transition(area, &zone, state, armed) {
+                self.transition(area, next, cause);
+            }
+        }
+    }
+
+    /// Determines the next state of `area` in response to the zone changing to `state`.
+    fn zone_transition(
+        &mut self,
+        area: Area,
+        zone: &Zone,
+        state: ZoneState,
+        armed: bool,
+    ) -> Option<SystemState> {
+        let area_state = self.states.get(area);
+        let area_timers = &mut self.area_timers[area.index()];
+
+        if state == ZoneState::Tamper {
+            return Some(SystemState::Alarm);
+        } else if state == ZoneState::Closed {
+            return match (zone.zone_type, area_state) {
+                (ZoneType::Final, SystemState::ExitTiming(mode)) if area_timers.final_opened => {
+                    Some(Self::set_state(mode))
+                }
+                _ => None,
+            };
+        }
+
+        match (zone.zone_type, area_state) {
+            (ZoneType::TwentyFourHour | ZoneType::Fire | ZoneType::Pa | ZoneType::Tamper, _) => {
+                Some(SystemState::Alarm)
+            }
+            (ZoneType::Log, _) => None,
+            (ZoneType::Keyswitch, SystemState::Unset) => {
+                area_timers.final_opened = false;
+                Some(SystemState::ExitTiming(SetMode::Full))
+            }
+            (ZoneType::Keyswitch, state) if state.is_set() => Some(SystemState::Unset),
+            (ZoneType::Keyswitch, _) => None,
+            (ZoneType::Final, SystemState::ExitTiming(_)) => {
+                area_timers.final_opened = true;
+                None
+            }
+            (_, _) if !armed => None,
+            (ZoneType::Final | ZoneType::Entry, SystemState::Set) => {
+                Some(SystemState::EntryTiming(SetMode::Full))
+            }
+            (ZoneType::Final | ZoneType::Entry, SystemState::PartSet) => {
+                Some(SystemState::EntryTiming(SetMode::Part))
+            }
+            (ZoneType::Final | ZoneType::Entry | ZoneType::Exit, SystemState::EntryTiming(_)) => {
+                None
+            }
+            (ZoneType::Final | ZoneType::Entry | ZoneType::Exit | ZoneType::Intruder, _) => {
+                Some(SystemState::Alarm)
+            }
+        }
+    }
+
+    fn timer_expired(&mut self, area: Area) {
+        match self.states.get(area) {
+            SystemState::ExitTiming(mode) => {
+                let set_state = Self::set_state(mode);
+                let open_zones: Vec<u16> = {
+                    let mut zones: Vec<u16> = self
+                        .zones
+                        .values()
+                        .filter(|zone| {
+                            zone.areas.contains(area)
+                                && matches!(
+                                    zone.zone_type,
+                                    ZoneType::Final
+                                        | ZoneType::Exit
+                                        | ZoneType::Entry
+                                        | ZoneType::Intruder
+                                )
+                                && (mode == SetMode::Full || zone.part_set)
+                        })
+                        .filter(|zone| {
+                            !self.omitted.contains(&zone.number)
+                                && self
+                                    .zone_states
+                                    .get(&zone.number)
+                                    .is_some_and(|&state| state != ZoneState::Closed)
+                        })
+                        .map(|zone| zone.number)
+                        .collect();
+                    zones.sort();
+
+                    zones
+                };
+
+                if open_zones.is_empty() {
+                    self.transition(area, set_state, Cause::Timer);
+                } else {
+                    warn!(
+                        "Area {} failed to set with zones {:?} open",
+                        area, open_zones
+                    );
+                    let _ = self.event_ch.send(Event(EventType::SetFailed {
+                        area,
+                        zones: open_zones,
+                    }));
+                    self.transition(area, SystemState::Unset, Cause::Timer);
+                }
+            }
+            SystemState::EntryTiming(_) => self.transition(area, SystemState::Alarm, Cause::Timer),
+            _ => {}
+        }
+    }
+
+    fn set_state(mode: SetMode) -> SystemState {
+        match mode {
+            SetMode::Full => SystemState::Set,
+            SetMode::Part => SystemState::PartSet,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        alarm::Module,
+        serial::{devices::keypad::Beeper, SerialDevice, SerialMessage},
+        zones::{EolScheme, ZoneInput},
+    };
+
+    const FRONT_DOOR: u16 = 1001;
+    const LOUNGE: u16 = 1002;
+    const BEDROOM: u16 = 1003;
+    const GARAGE: u16 = 1004;
+    const HALLWAY: u16 = 1005;
+
+    fn area(letter: char) -> Area {
+        Area::try_from(letter).unwrap()
+    }
+
+    fn areas(letters: &str) -> Areas {
+        letters.parse().unwrap()
+    }
+
+    fn zone(number: u16, zone_type: ZoneType, part_set: bool, zone_areas: &str) -> Zone {
+        Zone {
+            number,
+            name: format!("ZONE {}", number),
+            zone_type,
+            scheme: EolScheme::OneKOneK,
+            input: ZoneInput {
+                device: 0x20,
+                input: (number % 10) as u8,
+            },
+            part_set,
+            chime: false,
+            areas: areas(zone_areas),
+        }
+    }
+
+    fn instantiate_alarm_manager() -> (broadcast::Sender<zones::Event>, AlarmHandle) {
+        let zone_tx = broadcast::Sender::new(10);
+
+        let (mut manager, handle) = AlarmManager::new(
+            Timers {
+                exit: Duration::from_secs(10),
+                entry: Duration::from_secs(20),
+                ..Default::default()
+            },
+            vec![
+                zone(FRONT_DOOR, ZoneType::Final, true, "A"),
+                zone(LOUNGE, ZoneType::Intruder, true, "A"),
+                zone(BEDROOM, ZoneType::Intruder, false, "A"),
+                zone(GARAGE, ZoneType::Intruder, false, "B"),
+                zone(HALLWAY, ZoneType::Intruder, false, "AB"),
+            ],
+            zone_tx.subscribe(),
+        );
+
+        tokio::spawn(async move { manager.run().await });
+
+        (zone_tx, handle)
+    }
+
+    fn send_zone(tx: &broadcast::Sender<zones::Event>, zone: u16, state: ZoneState) {
+        tx.send(zones::Event(zones::EventType::StateChanged {
+            zone,
+            zone_type: ZoneType::Intruder,
+            state,
+            previous: None,
+        }))
+        .unwrap();
+    }
+
+    async fn wait_for_state(handle: &AlarmHandle, area: Area, state: SystemState) {
+        let mut rx = handle.subscribe_state();
+        rx.wait_for(|states| states.get(area) == state)
+            .await
+            .unwrap();
+    }
+
+    fn set(letters: &str, mode: SetMode) -> Command {
+        Command::Set {
+            areas: areas(letters),
+            mode,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exit_timer_sets_system() {
+        time::pause();
+
+        let (_zone_tx, handle) = instantiate_alarm_manager();
+        let start = Instant::now();
+
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::ExitTiming(SetMode::Full)).await;
+        assert!(matches!(
+            handle.states().beeper(areas("A")),
+            Beeper::Intermittent { .. }
+        ));
+
+        wait_for_state(&handle, area('A'), SystemState::Set).await;
+        assert_eq!((Instant::now() - start).as_secs(), 10);
+        assert_eq!(handle.states().beeper(areas("A")), Beeper::Off);
+        assert_eq!(handle.state(area('B')), SystemState::Unset);
+    }
+
+    #[tokio::test]
+    async fn test_final_zone_terminates_exit() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+        let start = Instant::now();
+
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::ExitTiming(SetMode::Full)).await;
+
+        time::advance(Duration::from_secs(2)).await;
+        send_zone(&zone_tx, FRONT_DOOR, ZoneState::Open);
+        send_zone(&zone_tx, FRONT_DOOR, ZoneState::Closed);
+
+        wait_for_state(&handle, area('A'), SystemState::Set).await;
+        assert_eq!((Instant::now() - start).as_secs(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_set_fails_with_open_zone() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+        let mut events = handle.subscribe_events();
+
+        send_zone(&zone_tx, LOUNGE, ZoneState::Open);
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::ExitTiming(SetMode::Full)).await;
+        wait_for_state(&handle, area('A'), SystemState::Unset).await;
+
+        loop {
+            if let EventType::SetFailed { area: a, zones } = events.recv().await.unwrap().0 {
+                assert_eq!(a, area('A'));
+                assert_eq!(zones, vec![LOUNGE]);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_entry_timer_expires_into_alarm() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::Set).await;
+
+        let start = Instant::now();
+        send_zone(&zone_tx, FRONT_DOOR, ZoneState::Open);
+        wait_for_state(&handle, area('A'), SystemState::EntryTiming(SetMode::Full)).await;
+        assert_eq!(
+            handle.states().beeper(areas("A")),
+            Beeper::new_intermittent(Duration::from_millis(200), Duration::from_millis(200))
+        );
+
+        wait_for_state(&handle, area('A'), SystemState::Alarm).await;
+        assert_eq!((Instant::now() - start).as_secs(), 20);
+        assert_eq!(handle.states().beeper(areas("AB")), Beeper::On);
+        assert_eq!(handle.states().beeper(areas("B")), Beeper::Off);
+
+        handle.send(Command::Unset { areas: areas("A") });
+        wait_for_state(&handle, area('A'), SystemState::AlarmResetRequired).await;
+        assert_eq!(handle.states().beeper(areas("A")), Beeper::Off);
+
+        handle.send(Command::Reset { areas: areas("A") });
+        wait_for_state(&handle, area('A'), SystemState::Unset).await;
+    }
+
+    #[tokio::test]
+    async fn test_unset_during_entry() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::Set).await;
+
+        send_zone(&zone_tx, FRONT_DOOR, ZoneState::Open);
+        wait_for_state(&handle, area('A'), SystemState::EntryTiming(SetMode::Full)).await;
+
+        time::advance(Duration::from_secs(5)).await;
+        handle.send(Command::Unset { areas: areas("A") });
+        wait_for_state(&handle, area('A'), SystemState::Unset).await;
+    }
+
+    #[tokio::test]
+    async fn test_part_set_ignores_unarmed_zones() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+
+        handle.send(set("A", SetMode::Part));
+        wait_for_state(&handle, area('A'), SystemState::PartSet).await;
+
+        let mut events = handle.subscribe_events();
+        send_zone(&zone_tx, BEDROOM, ZoneState::Open);
+        send_zone(&zone_tx, LOUNGE, ZoneState::Open);
+
+        wait_for_state(&handle, area('A'), SystemState::Alarm).await;
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                state: SystemState::Alarm,
+                cause: Cause::Zone(LOUNGE),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_zone_tamper_alarms_while_unset() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+
+        let mut events = handle.subscribe_events();
+        send_zone(&zone_tx, LOUNGE, ZoneState::Tamper);
+
+        wait_for_state(&handle, area('A'), SystemState::Alarm).await;
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                cause: Cause::ZoneTamper(LOUNGE),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_areas_alarm_independently() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+
+        handle.send(set("B", SetMode::Full));
+        wait_for_state(&handle, area('B'), SystemState::Set).await;
+
+        // The hallway is shared with area A, which remains unset, so it is not armed.
+        let mut events = handle.subscribe_events();
+        send_zone(&zone_tx, HALLWAY, ZoneState::Open);
+        send_zone(&zone_tx, LOUNGE, ZoneState::Open);
+        send_zone(&zone_tx, GARAGE, ZoneState::Open);
+
+        wait_for_state(&handle, area('B'), SystemState::Alarm).await;
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                cause: Cause::Zone(GARAGE),
+                ..
+            }
+        ));
+        assert_eq!(handle.state(area('A')), SystemState::Unset);
+
+        handle.send(Command::Unset { areas: areas("AB") });
+        wait_for_state(&handle, area('B'), SystemState::AlarmResetRequired).await;
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                area: a,
+                state: SystemState::AlarmResetRequired,
+                cause: Cause::Command,
+                ..
+            } if a == area('B')
+        ));
+    }
+
+    fn send_status(tx: &broadcast::Sender<serial::Event>, address: u8, status: DeviceStatus) {
+        tx.send(serial::Event(serial::EventType::StatusChanged {
+            address,
+            status,
+            previous: DeviceStatus::Unknown,
+        }))
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_module_fault_latches_until_acknowledged() {
+        time::pause();
+
+        let (zone_tx, zone_rx) = broadcast::channel(10);
+        let (device_tx, device_rx) = broadcast::channel(10);
+        let (mut manager, handle) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        manager.supervise(
+            vec![Module {
+                address: 0x20,
+                areas: areas("A"),
+                tamper: true,
+            }],
+            device_rx,
+        );
+        tokio::spawn(async move {
+            let _zone_tx = zone_tx;
+            manager.run().await
+        });
+
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::Set).await;
+
+        let mut events = handle.subscribe_events();
+        let mut faults = handle.subscribe_faults();
+        send_status(&device_tx, 0x20, DeviceStatus::Offline);
+        wait_for_state(&handle, area('A'), SystemState::Alarm).await;
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::FaultRaised {
+                address: 0x20,
+                kind: FaultKind::Missing
+            }
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                cause: Cause::Module(0x20),
+                ..
+            }
+        ));
+
+        // The fault can't be acknowledged while the module is still missing.
+        handle.send_as(1, Command::AcknowledgeFaults);
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(handle.faults()[0].kind, FaultKind::Missing);
+
+        send_status(&device_tx, 0x20, DeviceStatus::OnlineOK);
+        faults
+            .wait_for(|faults| faults.first().is_some_and(|fault| fault.restored))
+            .await
+            .unwrap();
+
+        handle.send_as(1, Command::AcknowledgeFaults);
+        faults.wait_for(|faults| faults.is_empty()).await.unwrap();
+    }
+
+    fn psu_status(psu: &SerialPsu, flags: u8) {
+        psu.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: psu::ReplyCommand::Status.into(),
+            additional_data: Some(vec![flags, 0x05, 0x52, 0x05, 0x5B]),
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_psu_conditions_raised_after_delay() {
+        time::pause();
+
+        let (zone_tx, zone_rx) = broadcast::channel(10);
+        let (mut manager, handle) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        let psu = SerialPsu::new();
+        psu.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: psu::ReplyCommand::Initialised.into(),
+            additional_data: Some(vec![0x0A, 0x01, 0x05]),
+        }));
+        manager.attach_psu(0x70, &psu);
+        tokio::spawn(async move {
+            let _zone_tx = zone_tx;
+            manager.run().await
+        });
+        let mut faults = handle.subscribe_faults();
+
+        // A mains failure shorter than the delay goes unreported.
+        psu_status(&psu, 0x00);
+        psu_status(&psu, 0x01);
+        time::sleep(Duration::from_secs(29 * 60)).await;
+        psu_status(&psu, 0x00);
+        time::sleep(Duration::from_secs(5 * 60)).await;
+        assert!(handle.faults().is_empty());
+
+        // Low battery has no delay by default.
+        psu_status(&psu, 0x01);
+        psu_status(&psu, 0x03);
+        faults.wait_for(|faults| faults.len() == 1).await.unwrap();
+        assert_eq!(handle.faults()[0].kind, FaultKind::BatteryLow);
+
+        time::sleep(Duration::from_secs(31 * 60)).await;
+        let fault = handle.faults()[1];
+        assert_eq!((fault.address, fault.kind), (0x70, FaultKind::MainsFail));
+
+        // Only the restored mains fault is cleared on acknowledgement.
+        psu_status(&psu, 0x02);
+        faults.wait_for(|faults| faults[1].restored).await.unwrap();
+        handle.send_as(1, Command::AcknowledgeFaults);
+        faults.wait_for(|faults| faults.len() == 1).await.unwrap();
+        assert_eq!(handle.faults()[0].kind, FaultKind::BatteryLow);
+    }
+
+    #[tokio::test]
+    async fn test_rio_tamper_raises_alarm() {
+        time::pause();
+
+        let (zone_tx, zone_rx) = broadcast::channel(10);
+        let (_device_tx, device_rx) = broadcast::channel(10);
+        let (mut manager, handle) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        manager.supervise(
+            vec![Module {
+                address: 0x20,
+                areas: areas("A"),
+                tamper: true,
+            }],
+            device_rx,
+        );
+        let rio = SerialRio::new();
+        rio.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: rio::ReplyCommand::Initialised.into(),
+            additional_data: Some(vec![0x01, 0x01, 0x05]),
+        }));
+        manager.attach_rio(0x20, &rio);
+        tokio::spawn(async move {
+            let _zone_tx = zone_tx;
+            manager.run().await
+        });
+        let mut state_rx = handle.subscribe_state();
+
+        handle.send(set("A", SetMode::Full));
+        time::sleep(Duration::from_secs(60)).await;
+        assert!(handle.state(Area::A).is_set());
+
+        // Readings of every zone, then the flags byte with the enclosure tamper open.
+        rio.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: rio::ReplyCommand::ZoneStatus.into(),
+            additional_data: Some(vec![0x0A, 0x0A, 0x0A, 0x0A, 0x0A, 0x0A, 0x0A, 0x0A, 0x40]),
+        }));
+        state_rx
+            .wait_for(|states| states.get(Area::A) == SystemState::Alarm)
+            .await
+            .unwrap();
+        let fault = handle.faults()[0];
+        assert_eq!((fault.address, fault.kind), (0x20, FaultKind::Tamper));
+    }
+
+    #[tokio::test]
+    async fn test_max_tamper_raises_fault() {
+        time::pause();
+
+        let (zone_tx, zone_rx) = broadcast::channel(10);
+        let (mut manager, handle) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        let max = SerialMax::new();
+        max.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: max::ReplyCommand::Initialised.into(),
+            additional_data: Some(vec![0x09, 0x03, 0x12]),
+        }));
+        manager.attach_max(0x60, &max);
+        tokio::spawn(async move {
+            let _zone_tx = zone_tx;
+            manager.run().await
+        });
+        let mut faults = handle.subscribe_faults();
+
+        let status = |flags| {
+            max.receive_update(Ok(SerialMessage {
+                recipient_address: 0x11,
+                command: max::ReplyCommand::Status.into(),
+                additional_data: Some(vec![flags]),
+            }))
+        };
+        status(max::TAMPER_FLAG);
+        faults.wait_for(|faults| faults.len() == 1).await.unwrap();
+        assert_eq!(handle.faults()[0].kind, FaultKind::Tamper);
+
+        status(0x00);
+        faults.wait_for(|faults| faults[0].restored).await.unwrap();
+        assert_eq!(handle.state(Area::A), SystemState::Unset);
+    }
+
+    #[tokio::test]
+    async fn test_omitted_zone_ignored_until_unset() {
+        time::pause();
+
+        let (zone_tx, handle) = instantiate_alarm_manager();
+        let mut omitted_rx = handle.omitted.clone();
+
+        handle.send(Command::Omit {
+            zone: LOUNGE,
+            omit: true,
+        });
+        omitted_rx.changed().await.unwrap();
+        assert!(handle.omitted().contains(&LOUNGE));
+
+        // An omitted zone may be left open when setting.
+        send_zone(&zone_tx, LOUNGE, ZoneState::Open);
+        handle.send(set("A", SetMode::Full));
+        wait_for_state(&handle, area('A'), SystemState::Set).await;
+
+        // Zones can't be omitted or restored while their areas are set.
+        handle.send(Command::Omit {
+            zone: LOUNGE,
+            omit: false,
+        });
+        send_zone(&zone_tx, LOUNGE, ZoneState::Closed);
+        send_zone(&zone_tx, LOUNGE, ZoneState::Open);
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(handle.state(area('A')), SystemState::Set);
+
+        handle.send(Command::Unset { areas: areas("A") });
+        omitted_rx.changed().await.unwrap();
+        assert!(handle.omitted().is_empty());
+    }
+}
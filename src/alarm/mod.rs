@@ -0,0 +1,350 @@
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, time::Duration};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::{
+    areas::{Area, Areas, AREA_COUNT},
+    serial::devices::{keypad::Beeper, psu},
+};
+
+pub mod manager;
+
+/// SetMode distinguishes between setting every zone and setting only those zones configured to
+/// be part of the part set.
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SetMode {
+    #[display(fmt = "FULL")]
+    Full,
+    #[display(fmt = "PART")]
+    Part,
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum SystemState {
+    #[display(fmt = "UNSET")]
+    Unset,
+    #[display(fmt = "EXIT TIMING")]
+    ExitTiming(SetMode),
+    #[display(fmt = "FULL SET")]
+    Set,
+    #[display(fmt = "PART SET")]
+    PartSet,
+    #[display(fmt = "ENTRY TIMING")]
+    EntryTiming(SetMode),
+    #[display(fmt = "ALARM")]
+    Alarm,
+    #[display(fmt = "RESET REQUIRED")]
+    AlarmResetRequired,
+}
+
+impl SystemState {
+    /// Returns the keypad beeper pattern to sound while in this state.
+    pub fn beeper(&self) -> Beeper {
+        match self {
+            SystemState::ExitTiming(_) => {
+                Beeper::new_intermittent(Duration::from_millis(500), Duration::from_millis(500))
+            }
+            SystemState::EntryTiming(_) => {
+                Beeper::new_intermittent(Duration::from_millis(200), Duration::from_millis(200))
+            }
+            SystemState::Alarm => Beeper::On,
+            _ => Beeper::Off,
+        }
+    }
+
+    /// Orders states by the urgency of their beeper pattern, so that a keypad serving several
+    /// areas sounds the most urgent one.
+    fn beeper_priority(&self) -> u8 {
+        match self {
+            SystemState::Alarm => 3,
+            SystemState::EntryTiming(_) => 2,
+            SystemState::ExitTiming(_) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Returns whether the state has any zones armed or is in the process of arming them.
+    pub fn is_set(&self) -> bool {
+        matches!(
+            self,
+            SystemState::ExitTiming(_)
+                | SystemState::Set
+                | SystemState::PartSet
+                | SystemState::EntryTiming(_)
+        )
+    }
+}
+
+/// AreaStates holds the system state of every area.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AreaStates([SystemState; AREA_COUNT]);
+
+impl Default for AreaStates {
+    fn default() -> Self {
+        AreaStates([SystemState::Unset; AREA_COUNT])
+    }
+}
+
+impl AreaStates {
+    pub fn get(&self, area: Area) -> SystemState {
+        self.0[area.index()]
+    }
+
+    fn set(&mut self, area: Area, state: SystemState) {
+        self.0[area.index()] = state;
+    }
+
+    /// Returns the subset of `areas` which are in a state satisfying the predicate.
+    pub fn filter<P>(&self, areas: Areas, predicate: P) -> Areas
+    where
+        P: Fn(SystemState) -> bool,
+    {
+        areas
+            .iter()
+            .filter(|&area| predicate(self.get(area)))
+            .collect()
+    }
+
+    /// Returns the beeper pattern for a keypad serving the given areas.
+    pub fn beeper(&self, areas: Areas) -> Beeper {
+        areas
+            .iter()
+            .map(|area| self.get(area))
+            .max_by_key(|state| state.beeper_priority())
+            .map_or(Beeper::Off, |state| state.beeper())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timers {
+    // Time allowed to leave the premises after setting.
+    pub exit: Duration,
+    // Time allowed to unset after opening an entry route zone.
+    pub entry: Duration,
+    // Times for which each condition reported by a smart PSU must persist before a fault is
+    // raised, so that brief interruptions go unreported.
+    pub mains_fail: Duration,
+    pub battery_low: Duration,
+    pub fuse_blown: Duration,
+    pub psu_tamper: Duration,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            exit: Duration::from_secs(30),
+            entry: Duration::from_secs(30),
+            mains_fail: Duration::from_secs(30 * 60),
+            battery_low: Duration::ZERO,
+            fuse_blown: Duration::ZERO,
+            psu_tamper: Duration::ZERO,
+        }
+    }
+}
+
+impl Timers {
+    /// Returns the time for which a condition reported by a PSU must persist before it's raised as
+    /// a fault. The tamper switches of other modules raise their faults immediately.
+    fn fault_delay(&self, kind: FaultKind) -> Duration {
+        match kind {
+            FaultKind::MainsFail => self.mains_fail,
+            FaultKind::BatteryLow => self.battery_low,
+            FaultKind::FuseBlown => self.fuse_blown,
+            FaultKind::Tamper => self.psu_tamper,
+            FaultKind::Missing | FaultKind::Comms => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Set { areas: Areas, mode: SetMode },
+    Unset { areas: Areas },
+    // Returns areas to unset after an alarm has been acknowledged.
+    Reset { areas: Areas },
+    // Omits a zone from, or restores it to, the next set of its areas.
+    Omit { zone: u16, omit: bool },
+    // Switches the chime of zones in the areas on or off.
+    Chime { areas: Areas, chime: bool },
+    // Raises a tamper alarm, e.g. when a keypad is locked out after repeated invalid codes.
+    Tamper { areas: Areas },
+    // Clears the module faults whose conditions have since restored.
+    AcknowledgeFaults,
+}
+
+/// Cause records what triggered a change in system state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cause {
+    Command,
+    // A command issued by the given user number.
+    User(u16),
+    Tamper,
+    Zone(u16),
+    // The wiring of the zone was cut or shorted, whatever its type.
+    ZoneTamper(u16),
+    Timer,
+    // A fault of the module at the given address on the bus.
+    Module(u8),
+}
+
+/// FaultKind is the fault raised when a module on the bus stops working, or a smart PSU reports a
+/// problem with the power supply.
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FaultKind {
+    // The module stopped replying.
+    #[display(fmt = "MISSING")]
+    Missing,
+    // The module replies, but its replies are corrupt.
+    #[display(fmt = "COMMS")]
+    Comms,
+    #[display(fmt = "AC FAIL")]
+    MainsFail,
+    #[display(fmt = "LOW BAT")]
+    BatteryLow,
+    // The PSU's output fuse blew.
+    #[display(fmt = "FUSE")]
+    FuseBlown,
+    // The enclosure of a PSU, RIO or door reader was opened.
+    #[display(fmt = "TAMPER")]
+    Tamper,
+}
+
+impl FaultKind {
+    /// Returns whether the fault suggests the module is being tampered with, so raises a tamper
+    /// alarm where the module is configured to.
+    fn is_tamper(&self) -> bool {
+        matches!(
+            self,
+            FaultKind::Missing | FaultKind::Comms | FaultKind::Tamper
+        )
+    }
+}
+
+impl From<psu::Condition> for FaultKind {
+    fn from(value: psu::Condition) -> Self {
+        match value {
+            psu::Condition::MainsFail => FaultKind::MainsFail,
+            psu::Condition::BatteryLow => FaultKind::BatteryLow,
+            psu::Condition::FuseBlown => FaultKind::FuseBlown,
+            psu::Condition::Tamper => FaultKind::Tamper,
+        }
+    }
+}
+
+/// Fault is a fault of a module, which latches until acknowledged by a user. A module may have
+/// faults of several kinds at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    pub address: u8,
+    pub kind: FaultKind,
+    // Whether the module has since recovered, or the condition cleared, so that the fault may be
+    // acknowledged.
+    pub restored: bool,
+}
+
+/// Module is a device on the bus supervised by the alarm core.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Module {
+    pub address: u8,
+    // Areas the module serves, which go into alarm if it faults while they are set and `tamper`
+    // is configured.
+    pub areas: Areas,
+    pub tamper: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum EventType {
+    StateChanged {
+        area: Area,
+        state: SystemState,
+        previous: SystemState,
+        cause: Cause,
+    },
+    // The exit procedure completed with zones still open, so the area returned to unset.
+    SetFailed {
+        area: Area,
+        zones: Vec<u16>,
+    },
+    FaultRaised {
+        address: u8,
+        kind: FaultKind,
+    },
+    FaultRestored {
+        address: u8,
+        kind: FaultKind,
+    },
+    // Restored faults of the given modules were cleared.
+    FaultsAcknowledged {
+        addresses: Vec<u8>,
+        cause: Cause,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+/// AlarmHandle is used to command and observe an AlarmManager from other tasks.
+#[derive(Clone)]
+pub struct AlarmHandle {
+    commands: mpsc::UnboundedSender<(Command, Option<u16>)>,
+    state: watch::Receiver<AreaStates>,
+    omitted: watch::Receiver<BTreeSet<u16>>,
+    chime: watch::Receiver<Areas>,
+    faults: watch::Receiver<Vec<Fault>>,
+    event_ch: broadcast::Sender<Event>,
+}
+
+impl AlarmHandle {
+    pub fn send(&self, command: Command) {
+        // The manager only goes away on shutdown, at which point commands are moot.
+        let _ = self.commands.send((command, None));
+    }
+
+    /// Sends a command on behalf of a user, so that resulting state changes are attributed to
+    /// them.
+    pub fn send_as(&self, user: u16, command: Command) {
+        let _ = self.commands.send((command, Some(user)));
+    }
+
+    pub fn state(&self, area: Area) -> SystemState {
+        self.state.borrow().get(area)
+    }
+
+    pub fn states(&self) -> AreaStates {
+        *self.state.borrow()
+    }
+
+    /// Returns the zones omitted from the next set.
+    pub fn omitted(&self) -> BTreeSet<u16> {
+        self.omitted.borrow().clone()
+    }
+
+    /// Returns the areas in which chime zones sound at keypads while unset.
+    pub fn chime(&self) -> Areas {
+        *self.chime.borrow()
+    }
+
+    pub fn subscribe_chime(&self) -> watch::Receiver<Areas> {
+        self.chime.clone()
+    }
+
+    /// Returns the latched module faults, in the order raised.
+    pub fn faults(&self) -> Vec<Fault> {
+        self.faults.borrow().clone()
+    }
+
+    pub fn subscribe_faults(&self) -> watch::Receiver<Vec<Fault>> {
+        self.faults.clone()
+    }
+
+    pub fn subscribe_state(&self) -> watch::Receiver<AreaStates> {
+        self.state.clone()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_ch.subscribe()
+    }
+}
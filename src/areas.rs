@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+/// AREA_COUNT is the number of independently settable areas, known as groups A to H on Galaxy
+/// panels.
+pub const AREA_COUNT: usize = 8;
+
+/// Area is a single partition of the system, identified by its Galaxy group letter.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "char", into = "char")]
+pub struct Area(u8);
+
+#[derive(Clone, Debug, Error, PartialEq)]
+#[error("invalid area {0:?}, expected a letter from A to H")]
+pub struct InvalidAreaError(pub char);
+
+impl Area {
+    pub const A: Area = Area(0);
+
+    /// Returns the area for a zero-based index, or None if out of range.
+    pub fn from_index(index: usize) -> Option<Area> {
+        (index < AREA_COUNT).then_some(Area(index as u8))
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn letter(&self) -> char {
+        (b'A' + self.0) as char
+    }
+}
+
+impl TryFrom<char> for Area {
+    type Error = InvalidAreaError;
+
+    fn try_from(value: char) -> Result<Self, Self::Error> {
+        match value.to_ascii_uppercase() {
+            c @ 'A'..='H' => Ok(Area(c as u8 - b'A')),
+            _ => Err(InvalidAreaError(value)),
+        }
+    }
+}
+
+impl From<Area> for char {
+    fn from(area: Area) -> char {
+        area.letter()
+    }
+}
+
+impl fmt::Display for Area {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.letter())
+    }
+}
+
+/// Areas is a set of areas, stored as a bit mask with bit 0 representing area A.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct Areas(u8);
+
+impl Areas {
+    pub const NONE: Areas = Areas(0);
+    pub const ALL: Areas = Areas(0xFF);
+
+    pub fn single(area: Area) -> Areas {
+        Areas(1 << area.0)
+    }
+
+    pub fn contains(&self, area: Area) -> bool {
+        self.0 & (1 << area.0) != 0
+    }
+
+    pub fn insert(&mut self, area: Area) {
+        self.0 |= 1 << area.0;
+    }
+
+    pub fn remove(&mut self, area: Area) {
+        self.0 &= !(1 << area.0);
+    }
+
+    pub fn toggle(&mut self, area: Area) {
+        self.0 ^= 1 << area.0;
+    }
+
+    pub fn intersection(&self, other: Areas) -> Areas {
+        Areas(self.0 & other.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Area> + '_ {
+        (0..AREA_COUNT as u8)
+            .map(Area)
+            .filter(|&area| self.contains(area))
+    }
+
+    /// Renders the set for the keypad, with the letter of each member area in its position and
+    /// `absent` in place of areas that are not members, e.g. "AB-D----".
+    pub fn to_keypad_string(&self, absent: char) -> String {
+        (0..AREA_COUNT as u8)
+            .map(Area)
+            .map(|area| {
+                if self.contains(area) {
+                    area.letter()
+                } else {
+                    absent
+                }
+            })
+            .collect()
+    }
+}
+
+impl FromIterator<Area> for Areas {
+    fn from_iter<T: IntoIterator<Item = Area>>(iter: T) -> Self {
+        let mut areas = Areas::NONE;
+        for area in iter {
+            areas.insert(area);
+        }
+
+        areas
+    }
+}
+
+impl std::str::FromStr for Areas {
+    type Err = InvalidAreaError;
+
+    /// Parses a string of area letters, e.g. "ABD".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.chars().map(Area::try_from).collect()
+    }
+}
+
+impl TryFrom<String> for Areas {
+    type Error = InvalidAreaError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Areas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for area in self.iter() {
+            write!(f, "{}", area)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let areas: Areas = "abD".parse().unwrap();
+
+        assert_eq!(areas.len(), 3);
+        assert_eq!(areas.to_string(), "ABD");
+        assert_eq!(areas.to_keypad_string('-'), "AB-D----");
+        assert_eq!("AZ".parse::<Areas>(), Err(InvalidAreaError('Z')));
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let mut areas: Areas = "AC".parse().unwrap();
+        areas.toggle(Area::try_from('C').unwrap());
+        areas.toggle(Area::try_from('B').unwrap());
+
+        assert_eq!(areas.to_string(), "AB");
+        assert_eq!(areas.intersection("BH".parse().unwrap()).to_string(), "B");
+        assert!(Areas::NONE.is_empty());
+        assert_eq!(Areas::ALL.len(), AREA_COUNT);
+    }
+}
@@ -0,0 +1,11 @@
+use tokio::sync::broadcast;
+
+/// Receives the next event from an optional source, or waits forever if there's none.
+pub(crate) async fn recv_event<T: Clone>(
+    events: &mut Option<broadcast::Receiver<T>>,
+) -> Result<T, broadcast::error::RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,1187 @@
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+use toml::Spanned;
+
+use crate::{
+    access::{Door, Permission, Schedule, TimeWindow, HELD_OPEN_TIME, RELEASE_TIME},
+    alarm::Timers,
+    areas::{Area, Areas},
+    keypad::manager::Options,
+    reporting::{contact_id::Account, dc09::Key, ip::Protocol, Delivery},
+    serial::{
+        devices::DeviceKind,
+        galaxy::{bus::PANEL_ADDRESS, Direction, HalfDuplex, Timing},
+        manager::POLL_INTERVAL,
+    },
+    users::{AccessLevel, PinHash, User, UserStore, UserStoreError, Validity},
+    zones::{EolScheme, Zone, ZoneInput, ZoneType},
+};
+
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+const DEFAULT_BANNER: &str = "GALAXY";
+const DEFAULT_EVENT_LOG_DIRECTORY: &str = "events";
+// Galaxy panels typically retain the most recent 1000 events.
+const DEFAULT_EVENT_LOG_CAPACITY: usize = 1000;
+const DEFAULT_EVENT_LOG_KEY_FILE: &str = "event_log.key";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("unable to read configuration: {0}")]
+    Io(#[from] io::Error),
+    // The configuration is syntactically or semantically invalid at the given line and column,
+    // both numbered from 1.
+    #[error("line {line}, column {column}: {message}")]
+    Invalid {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    fn at(source: &str, span: Range<usize>, message: impl Into<String>) -> ConfigError {
+        let before = &source[..span.start.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        ConfigError::Invalid {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+}
+
+/// DeviceType identifies the kind of module attached to the bus at an address.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceType {
+    Keypad,
+    Rio,
+    // The prox reader integrated in a CP-038 keypad.
+    Prox,
+    // A standalone MAX or MAX3 reader controlling a door.
+    Max,
+    // A smart PSU reporting the state of its mains supply, battery and output.
+    Psu,
+}
+
+impl From<DeviceType> for DeviceKind {
+    fn from(value: DeviceType) -> Self {
+        match value {
+            DeviceType::Keypad => DeviceKind::Keypad,
+            DeviceType::Rio => DeviceKind::Rio,
+            DeviceType::Prox => DeviceKind::Prox,
+            DeviceType::Max => DeviceKind::Max,
+            DeviceType::Psu => DeviceKind::Psu,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    pub address: u8,
+    pub device_type: DeviceType,
+    // Areas served by a keypad, or the keypad of a prox reader. Other devices take every area.
+    pub areas: Areas,
+    // Address of the keypad in which a prox reader is integrated.
+    pub keypad: Option<u8>,
+    // Whether the module going missing or failing to communicate, or its enclosure being opened,
+    // raises a tamper alarm in any of its areas that are set, as well as a fault.
+    pub tamper_on_fault: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SerialConfig {
+    pub port: String,
+    pub baud_rate: u32,
+    // File to which all traffic on the bus is captured, for replaying later.
+    pub capture: Option<PathBuf>,
+    pub half_duplex: HalfDuplex,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventLogConfig {
+    pub directory: PathBuf,
+    // Minimum number of the most recent events retained.
+    pub capacity: usize,
+    // Key with which the log is hashed, generated if missing.
+    pub key_file: PathBuf,
+}
+
+/// ReportingConfig describes how alarms are reported to a monitoring station by SIA DC-09.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportingConfig {
+    pub account: Account,
+    pub protocol: Protocol,
+    pub receiver: SocketAddr,
+    // Receiver and line numbers at the monitoring station, in hex.
+    pub receiver_number: Option<String>,
+    pub line_number: String,
+    // Key with which messages are encrypted, if at all.
+    pub key: Option<Key>,
+    pub delivery: Delivery,
+    // Time without traffic after which a heartbeat is sent, if at all.
+    pub heartbeat: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub serial: SerialConfig,
+    pub bus: Timing,
+    // Longest time between polls of each device on the bus, however idle.
+    pub poll_interval: Duration,
+    // How often the bus is scanned for modules that aren't configured, if at all.
+    pub discovery_interval: Option<Duration>,
+    pub keypad: Options,
+    pub timers: Timers,
+    pub devices: Vec<Device>,
+    // Names of the areas in use.
+    pub areas: BTreeMap<Area, String>,
+    // Areas with chime switched on at startup, until switched from a keypad.
+    pub chime: Areas,
+    pub zones: Vec<Zone>,
+    pub users: UserStore,
+    // Doors controlled by MAX readers, and the users permitted through them.
+    pub doors: Vec<Door>,
+    pub permissions: Vec<Permission>,
+    pub event_log: EventLogConfig,
+    // Address on which metrics are served over HTTP for Prometheus, if at all.
+    pub metrics: Option<SocketAddr>,
+    pub reporting: Option<ReportingConfig>,
+    // Hex encoded SHA-256 digest of the configuration source, identifying this configuration.
+    pub digest: String,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Config, ConfigError> {
+        let raw: RawConfig = toml::from_str(source).map_err(|e| {
+            ConfigError::at(source, e.span().unwrap_or(0..0), e.message().to_string())
+        })?;
+
+        let mut config = raw.validate(source)?;
+        config.digest = Sha256::digest(source.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    banner: Option<Spanned<String>>,
+    #[serde(default)]
+    chime: Option<Spanned<Areas>>,
+    serial: RawSerial,
+    #[serde(default)]
+    timers: RawTimers,
+    #[serde(default)]
+    areas: Option<BTreeMap<Area, String>>,
+    #[serde(default)]
+    devices: Vec<Spanned<RawDevice>>,
+    #[serde(default)]
+    zones: Vec<Spanned<RawZone>>,
+    #[serde(default)]
+    users: Vec<Spanned<RawUser>>,
+    #[serde(default)]
+    doors: Vec<Spanned<RawDoor>>,
+    #[serde(default)]
+    schedules: Vec<Spanned<RawSchedule>>,
+    #[serde(default)]
+    permissions: Vec<Spanned<RawPermission>>,
+    #[serde(default)]
+    event_log: RawEventLog,
+    #[serde(default)]
+    metrics: Option<RawMetrics>,
+    #[serde(default)]
+    reporting: Option<RawReporting>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSerial {
+    port: String,
+    #[serde(default = "default_baud_rate")]
+    baud_rate: u32,
+    #[serde(default)]
+    capture: Option<PathBuf>,
+    #[serde(default)]
+    direction: Direction,
+    #[serde(default)]
+    pre_transmit_delay_ms: u64,
+    #[serde(default)]
+    post_transmit_delay_ms: u64,
+    #[serde(default)]
+    echo: bool,
+}
+
+fn default_baud_rate() -> u32 {
+    DEFAULT_BAUD_RATE
+}
+
+// Timers are given in whole milliseconds for bus timing, and whole seconds otherwise.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTimers {
+    bus_timeout_ms: Option<u64>,
+    interpacket_gap_ms: Option<u64>,
+    intercharacter_timeout_ms: Option<u64>,
+    poll_interval_ms: Option<u64>,
+    discovery_interval_s: Option<u64>,
+    backlight_timeout_s: Option<u64>,
+    exit_s: Option<u64>,
+    entry_s: Option<u64>,
+    mains_fail_delay_s: Option<u64>,
+    battery_low_delay_s: Option<u64>,
+    fuse_delay_s: Option<u64>,
+    psu_tamper_delay_s: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEventLog {
+    directory: Option<PathBuf>,
+    capacity: Option<Spanned<usize>>,
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMetrics {
+    listen: SocketAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReporting {
+    account: Spanned<String>,
+    receiver: SocketAddr,
+    #[serde(default)]
+    protocol: Protocol,
+    receiver_number: Option<Spanned<String>>,
+    line_number: Option<Spanned<String>>,
+    key: Option<Spanned<String>>,
+    attempts: Option<Spanned<u32>>,
+    retry_delay_s: Option<u64>,
+    ack_timeout_s: Option<u64>,
+    heartbeat_s: Option<u64>,
+}
+
+impl RawReporting {
+    fn validate(self, source: &str) -> Result<ReportingConfig, ConfigError> {
+        // Receiver and line numbers are 1 to 6 hex digits.
+        let check_number = |number: Spanned<String>| {
+            let valid = (1..=6).contains(&number.get_ref().len())
+                && number.get_ref().chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                return Err(ConfigError::at(
+                    source,
+                    number.span(),
+                    format!(
+                        "invalid number {:?}, expected 1 to 6 hex digits",
+                        number.get_ref()
+                    ),
+                ));
+            }
+            Ok(number.into_inner().to_ascii_uppercase())
+        };
+
+        let defaults = Delivery::default();
+        let delivery = Delivery {
+            attempts: match self.attempts {
+                Some(attempts) if *attempts.get_ref() == 0 => {
+                    return Err(ConfigError::at(
+                        source,
+                        attempts.span(),
+                        "reports need at least 1 attempt",
+                    ))
+                }
+                Some(attempts) => *attempts.get_ref(),
+                None => defaults.attempts,
+            },
+            retry_delay: self
+                .retry_delay_s
+                .map_or(defaults.retry_delay, Duration::from_secs),
+            ack_timeout: self
+                .ack_timeout_s
+                .map_or(defaults.ack_timeout, Duration::from_secs),
+        };
+
+        Ok(ReportingConfig {
+            account: self
+                .account
+                .get_ref()
+                .parse()
+                .map_err(|e| ConfigError::at(source, self.account.span(), format!("{}", e)))?,
+            protocol: self.protocol,
+            receiver: self.receiver,
+            receiver_number: self.receiver_number.map(check_number).transpose()?,
+            line_number: match self.line_number {
+                Some(number) => check_number(number)?,
+                None => "0".to_string(),
+            },
+            key: match self.key {
+                Some(key) => Some(
+                    key.get_ref()
+                        .parse()
+                        .map_err(|e| ConfigError::at(source, key.span(), format!("{}", e)))?,
+                ),
+                None => None,
+            },
+            delivery,
+            heartbeat: self.heartbeat_s.filter(|&s| s > 0).map(Duration::from_secs),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDevice {
+    address: Spanned<u8>,
+    #[serde(rename = "type")]
+    device_type: DeviceType,
+    areas: Option<Spanned<Areas>>,
+    keypad: Option<Spanned<u8>>,
+    #[serde(default)]
+    tamper_on_fault: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawZone {
+    device: Spanned<u8>,
+    input: Spanned<u8>,
+    name: Spanned<String>,
+    #[serde(rename = "type")]
+    zone_type: ZoneType,
+    #[serde(default)]
+    scheme: EolScheme,
+    #[serde(default = "default_part_set")]
+    part_set: bool,
+    #[serde(default)]
+    chime: bool,
+    areas: Option<Spanned<Areas>>,
+}
+
+fn default_part_set() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawUser {
+    number: u16,
+    name: Spanned<String>,
+    pin: PinHash,
+    fob: Option<u32>,
+    #[serde(default)]
+    fob_with_pin: bool,
+    level: AccessLevel,
+    areas: Option<Spanned<Areas>>,
+    valid_from: Option<NaiveDateTime>,
+    valid_until: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDoor {
+    number: Spanned<u8>,
+    name: Spanned<String>,
+    reader: Spanned<u8>,
+    release_s: Option<u64>,
+    held_open_s: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSchedule {
+    name: Spanned<String>,
+    windows: Vec<TimeWindow>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPermission {
+    user: Spanned<u16>,
+    doors: Spanned<Vec<u8>>,
+    // Name of the schedule limiting when the doors may be used, or at any time if omitted.
+    schedule: Option<Spanned<String>>,
+}
+
+// Names shown on the keypad are limited to a single line of the display.
+fn check_display_text(source: &str, text: &Spanned<String>) -> Result<(), ConfigError> {
+    if text.get_ref().chars().count() > 16 {
+        return Err(ConfigError::at(
+            source,
+            text.span(),
+            format!("{:?} is longer than 16 characters", text.get_ref()),
+        ));
+    }
+
+    Ok(())
+}
+
+impl RawConfig {
+    fn validate(self, source: &str) -> Result<Config, ConfigError> {
+        let areas = self
+            .areas
+            .unwrap_or_else(|| BTreeMap::from([(Area::A, "AREA A".to_string())]));
+        let defined_areas: Areas = areas.keys().copied().collect();
+
+        // Resolves an optional set of areas, defaulting to every defined area and rejecting any
+        // which are not defined.
+        let check_areas = |spanned: &Option<Spanned<Areas>>| match spanned {
+            None => Ok(defined_areas),
+            Some(spanned) => {
+                let undefined: Areas = spanned
+                    .get_ref()
+                    .iter()
+                    .filter(|&area| !defined_areas.contains(area))
+                    .collect();
+
+                if undefined.is_empty() {
+                    Ok(*spanned.get_ref())
+                } else {
+                    Err(ConfigError::at(
+                        source,
+                        spanned.span(),
+                        format!("area {} is not defined in [areas]", undefined),
+                    ))
+                }
+            }
+        };
+
+        let mut devices: Vec<Device> = Vec::with_capacity(self.devices.len());
+        for spanned in &self.devices {
+            let raw = spanned.get_ref();
+            let address = *raw.address.get_ref();
+
+            if devices.iter().any(|device| device.address == address) {
+                return Err(ConfigError::at(
+                    source,
+                    raw.address.span(),
+                    format!("device address {:#04x} is used more than once", address),
+                ));
+            }
+
+            // Each kind of module answers within its own range of addresses, which also keeps the
+            // zone numbers of RIOs distinct.
+            let kind = DeviceKind::from(raw.device_type);
+            if address == PANEL_ADDRESS {
+                return Err(ConfigError::at(
+                    source,
+                    raw.address.span(),
+                    format!("device address {:#04x} is the panel's own", address),
+                ));
+            }
+            if !kind.addresses().contains(&address) {
+                return Err(ConfigError::at(
+                    source,
+                    raw.address.span(),
+                    format!(
+                        "device address {:#04x} is outside the {} range {:#04x} to {:#04x}",
+                        address,
+                        kind,
+                        kind.addresses().start(),
+                        kind.addresses().end()
+                    ),
+                ));
+            }
+
+            if raw.device_type != DeviceType::Keypad {
+                if let Some(areas) = &raw.areas {
+                    return Err(ConfigError::at(
+                        source,
+                        areas.span(),
+                        "areas may only be assigned to keypads",
+                    ));
+                }
+            }
+
+            let keypad = match (&raw.keypad, raw.device_type) {
+                (None, DeviceType::Prox) => {
+                    return Err(ConfigError::at(
+                        source,
+                        spanned.span(),
+                        "a prox reader must name the keypad it's part of",
+                    ))
+                }
+                (None, _) => None,
+                (Some(keypad), DeviceType::Prox) => Some(keypad),
+                (Some(keypad), _) => {
+                    return Err(ConfigError::at(
+                        source,
+                        keypad.span(),
+                        "a keypad may only be named by a prox reader",
+                    ))
+                }
+            };
+
+            devices.push(Device {
+                address,
+                device_type: raw.device_type,
+                areas: check_areas(&raw.areas)?,
+                keypad: keypad.map(|keypad| *keypad.get_ref()),
+                tamper_on_fault: raw.tamper_on_fault,
+            });
+        }
+
+        // A prox reader takes the areas of its keypad, which may be defined after it.
+        for (i, spanned) in self.devices.iter().enumerate() {
+            let Some(keypad) = &spanned.get_ref().keypad else {
+                continue;
+            };
+            let address = *keypad.get_ref();
+
+            let Some(areas) = devices
+                .iter()
+                .find(|d| d.address == address && d.device_type == DeviceType::Keypad)
+                .map(|d| d.areas)
+            else {
+                return Err(ConfigError::at(
+                    source,
+                    keypad.span(),
+                    format!("device {:#04x} is not a configured keypad", address),
+                ));
+            };
+            if devices[..i].iter().any(|d| d.keypad == Some(address)) {
+                return Err(ConfigError::at(
+                    source,
+                    keypad.span(),
+                    format!("keypad {:#04x} already has a prox reader", address),
+                ));
+            }
+
+            devices[i].areas = areas;
+        }
+
+        let mut zones: Vec<Zone> = Vec::with_capacity(self.zones.len());
+        for spanned in &self.zones {
+            let raw = spanned.get_ref();
+            let device = *raw.device.get_ref();
+
+            if !devices
+                .iter()
+                .any(|d| d.address == device && d.device_type == DeviceType::Rio)
+            {
+                return Err(ConfigError::at(
+                    source,
+                    raw.device.span(),
+                    format!("device {:#04x} is not a configured RIO", device),
+                ));
+            }
+
+            let input = *raw.input.get_ref();
+            if !(1..=8).contains(&input) {
+                return Err(ConfigError::at(
+                    source,
+                    raw.input.span(),
+                    format!("zone input {} is out of range 1 to 8", input),
+                ));
+            }
+
+            let input = ZoneInput { device, input };
+            let number = input.galaxy_zone_number();
+            if zones.iter().any(|zone| zone.number == number) {
+                return Err(ConfigError::at(
+                    source,
+                    spanned.span(),
+                    format!("zone {} is defined more than once", number),
+                ));
+            }
+
+            check_display_text(source, &raw.name)?;
+
+            zones.push(Zone {
+                number,
+                name: raw.name.get_ref().clone(),
+                zone_type: raw.zone_type,
+                scheme: raw.scheme,
+                input,
+                part_set: raw.part_set,
+                chime: raw.chime,
+                areas: check_areas(&raw.areas)?,
+            });
+        }
+
+        let mut users = Vec::with_capacity(self.users.len());
+        for spanned in &self.users {
+            let raw = spanned.get_ref();
+
+            check_display_text(source, &raw.name)?;
+
+            users.push(User {
+                number: raw.number,
+                name: raw.name.get_ref().clone(),
+                pin: raw.pin.clone(),
+                fob: raw.fob,
+                fob_with_pin: raw.fob_with_pin,
+                level: raw.level,
+                areas: check_areas(&raw.areas)?,
+                validity: Validity {
+                    from: raw.valid_from,
+                    until: raw.valid_until,
+                },
+            });
+        }
+
+        let users = UserStore::new(users).map_err(|e| match e {
+            UserStoreError::DuplicateUser(number) => {
+                let span = self
+                    .users
+                    .iter()
+                    .filter(|user| user.get_ref().number == number)
+                    .nth(1)
+                    .map_or(0..0, |user| user.span());
+
+                ConfigError::at(source, span, e.to_string())
+            }
+            UserStoreError::DuplicateFob { second, .. } => {
+                let span = self
+                    .users
+                    .iter()
+                    .find(|user| user.get_ref().number == second)
+                    .map_or(0..0, |user| user.span());
+
+                ConfigError::at(source, span, e.to_string())
+            }
+        })?;
+
+        let mut doors: Vec<Door> = Vec::with_capacity(self.doors.len());
+        for spanned in &self.doors {
+            let raw = spanned.get_ref();
+            let number = *raw.number.get_ref();
+            let reader = *raw.reader.get_ref();
+
+            if doors.iter().any(|door| door.number == number) {
+                return Err(ConfigError::at(
+                    source,
+                    raw.number.span(),
+                    format!("door {} is defined more than once", number),
+                ));
+            }
+            if !devices
+                .iter()
+                .any(|d| d.address == reader && d.device_type == DeviceType::Max)
+            {
+                return Err(ConfigError::at(
+                    source,
+                    raw.reader.span(),
+                    format!("device {:#04x} is not a configured MAX", reader),
+                ));
+            }
+            if doors.iter().any(|door| door.reader == reader) {
+                return Err(ConfigError::at(
+                    source,
+                    raw.reader.span(),
+                    format!("MAX {:#04x} already controls a door", reader),
+                ));
+            }
+
+            check_display_text(source, &raw.name)?;
+
+            doors.push(Door {
+                number,
+                name: raw.name.get_ref().clone(),
+                reader,
+                release: raw.release_s.map_or(RELEASE_TIME, Duration::from_secs),
+                held_open: raw.held_open_s.map_or(HELD_OPEN_TIME, Duration::from_secs),
+            });
+        }
+
+        let mut schedules: Vec<Schedule> = Vec::with_capacity(self.schedules.len());
+        for spanned in &self.schedules {
+            let raw = spanned.get_ref();
+
+            if schedules
+                .iter()
+                .any(|schedule| schedule.name == *raw.name.get_ref())
+            {
+                return Err(ConfigError::at(
+                    source,
+                    raw.name.span(),
+                    format!(
+                        "schedule {:?} is defined more than once",
+                        raw.name.get_ref()
+                    ),
+                ));
+            }
+
+            schedules.push(Schedule {
+                name: raw.name.get_ref().clone(),
+                windows: raw.windows.clone(),
+            });
+        }
+
+        let mut permissions = Vec::with_capacity(self.permissions.len());
+        for spanned in &self.permissions {
+            let raw = spanned.get_ref();
+
+            if users.get(*raw.user.get_ref()).is_none() {
+                return Err(ConfigError::at(
+                    source,
+                    raw.user.span(),
+                    format!("user {} is not defined", raw.user.get_ref()),
+                ));
+            }
+            if let Some(door) = raw
+                .doors
+                .get_ref()
+                .iter()
+                .find(|&&number| !doors.iter().any(|door| door.number == number))
+            {
+                return Err(ConfigError::at(
+                    source,
+                    raw.doors.span(),
+                    format!("door {} is not defined", door),
+                ));
+            }
+
+            let schedule = match &raw.schedule {
+                None => None,
+                Some(name) => Some(
+                    schedules
+                        .iter()
+                        .find(|schedule| schedule.name == *name.get_ref())
+                        .cloned()
+                        .ok_or_else(|| {
+                            ConfigError::at(
+                                source,
+                                name.span(),
+                                format!("schedule {:?} is not defined", name.get_ref()),
+                            )
+                        })?,
+                ),
+            };
+
+            permissions.push(Permission {
+                user: *raw.user.get_ref(),
+                doors: raw.doors.get_ref().clone(),
+                schedule,
+            });
+        }
+
+        let mut keypad = Options::default();
+        if let Some(banner) = &self.banner {
+            check_display_text(source, banner)?;
+            keypad.banner = banner.get_ref().clone();
+        } else {
+            keypad.banner = DEFAULT_BANNER.to_string();
+        }
+
+        let mut bus = Timing::default();
+        let mut poll_interval = POLL_INTERVAL;
+        let mut timers = Timers::default();
+        let t = &self.timers;
+
+        if let Some(ms) = t.bus_timeout_ms {
+            bus.bus_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = t.interpacket_gap_ms {
+            bus.interpacket_gap = Duration::from_millis(ms);
+        }
+        if let Some(ms) = t.intercharacter_timeout_ms {
+            bus.intercharacter_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = t.poll_interval_ms {
+            poll_interval = Duration::from_millis(ms);
+        }
+        if let Some(s) = t.backlight_timeout_s {
+            keypad.backlight_timeout = Duration::from_secs(s);
+        }
+        if let Some(s) = t.exit_s {
+            timers.exit = Duration::from_secs(s);
+        }
+        if let Some(s) = t.entry_s {
+            timers.entry = Duration::from_secs(s);
+        }
+        if let Some(s) = t.mains_fail_delay_s {
+            timers.mains_fail = Duration::from_secs(s);
+        }
+        if let Some(s) = t.battery_low_delay_s {
+            timers.battery_low = Duration::from_secs(s);
+        }
+        if let Some(s) = t.fuse_delay_s {
+            timers.fuse_blown = Duration::from_secs(s);
+        }
+        if let Some(s) = t.psu_tamper_delay_s {
+            timers.psu_tamper = Duration::from_secs(s);
+        }
+
+        let event_log = EventLogConfig {
+            directory: self
+                .event_log
+                .directory
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_EVENT_LOG_DIRECTORY)),
+            capacity: match self.event_log.capacity {
+                Some(capacity) if *capacity.get_ref() == 0 => {
+                    return Err(ConfigError::at(
+                        source,
+                        capacity.span(),
+                        "event log capacity must be at least 1",
+                    ))
+                }
+                Some(capacity) => *capacity.get_ref(),
+                None => DEFAULT_EVENT_LOG_CAPACITY,
+            },
+            key_file: self
+                .event_log
+                .key_file
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_EVENT_LOG_KEY_FILE)),
+        };
+
+        Ok(Config {
+            serial: SerialConfig {
+                port: self.serial.port,
+                baud_rate: self.serial.baud_rate,
+                capture: self.serial.capture,
+                half_duplex: HalfDuplex {
+                    direction: self.serial.direction,
+                    pre_transmit_delay: Duration::from_millis(self.serial.pre_transmit_delay_ms),
+                    post_transmit_delay: Duration::from_millis(self.serial.post_transmit_delay_ms),
+                    echo: self.serial.echo,
+                },
+            },
+            bus,
+            poll_interval,
+            // Scanning every 0s would leave no time for anything else.
+            discovery_interval: t
+                .discovery_interval_s
+                .filter(|&s| s > 0)
+                .map(Duration::from_secs),
+            keypad,
+            timers,
+            devices,
+            areas,
+            chime: match &self.chime {
+                Some(_) => check_areas(&self.chime)?,
+                None => Areas::NONE,
+            },
+            zones,
+            users,
+            doors,
+            permissions,
+            event_log,
+            metrics: self.metrics.map(|metrics| metrics.listen),
+            reporting: self
+                .reporting
+                .map(|reporting| reporting.validate(source))
+                .transpose()?,
+            digest: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+banner = "TIGER SECURITY"
+
+[serial]
+port = "/dev/ttyUSB0"
+
+[timers]
+bus_timeout_ms = 60
+exit_s = 45
+
+[areas]
+A = "HOUSE"
+B = "GARAGE"
+
+[[devices]]
+address = 0x10
+type = "keypad"
+areas = "A"
+
+[[devices]]
+address = 0x20
+type = "rio"
+
+[[zones]]
+device = 0x20
+input = 1
+name = "FRONT DOOR"
+type = "final"
+areas = "A"
+
+[[zones]]
+device = 0x20
+input = 2
+name = "GARAGE"
+type = "intruder"
+scheme = "4k7/2k2"
+part_set = false
+areas = "B"
+
+[[users]]
+number = 1
+name = "MANAGER"
+pin = "pbkdf2-sha256$1000$00000000000000000000000000000000$585f57603dc756173a0ec7cb8998fabd1030e9d4ac6527327b685c1d4ff22a5c"
+level = 3
+valid_until = "2030-01-01T00:00:00"
+"#;
+
+    fn error_location(source: &str) -> (usize, String) {
+        match Config::parse(source).unwrap_err() {
+            ConfigError::Invalid { line, message, .. } => (line, message),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_parse_example() {
+        let config = Config::parse(EXAMPLE).unwrap();
+
+        assert_eq!(config.serial.port, "/dev/ttyUSB0");
+        assert_eq!(config.serial.baud_rate, DEFAULT_BAUD_RATE);
+        assert_eq!(config.keypad.banner, "TIGER SECURITY");
+        assert_eq!(config.bus.bus_timeout, Duration::from_millis(60));
+        assert_eq!(
+            config.bus.interpacket_gap,
+            Timing::default().interpacket_gap
+        );
+        assert_eq!(config.timers.exit, Duration::from_secs(45));
+        assert_eq!(config.timers.mains_fail, Duration::from_secs(30 * 60));
+        assert_eq!(config.timers.battery_low, Duration::ZERO);
+        let config =
+            Config::parse(&EXAMPLE.replace("exit_s = 45", "mains_fail_delay_s = 600")).unwrap();
+        assert_eq!(config.timers.mains_fail, Duration::from_secs(600));
+        assert_eq!(config.discovery_interval, None);
+        assert_eq!(config.metrics, None);
+        assert_eq!(config.reporting, None);
+
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(config.devices[0].areas, "A".parse().unwrap());
+
+        assert_eq!(config.zones[0].number, 1001);
+        assert_eq!(config.zones[1].zone_type, ZoneType::Intruder);
+        assert_eq!(config.zones[1].scheme, EolScheme::FourK7TwoK2);
+        assert!(!config.zones[1].part_set);
+
+        let user = config.users.get(1).unwrap();
+        assert_eq!(user.level, AccessLevel::MANAGER);
+        assert_eq!(user.areas, "AB".parse().unwrap());
+        assert!(user.validity.until.is_some());
+        assert!(user.pin.verify("1234"));
+    }
+
+    #[test]
+    fn test_parse_shipped_example() {
+        let config = Config::parse(include_str!("../galaxy.example.toml")).unwrap();
+        assert_eq!(config.zones.len(), 3);
+        assert_eq!(config.chime, "A".parse().unwrap());
+        assert!(config.zones[0].chime);
+        assert!(config.users.users()[0].pin.verify("1234"));
+        assert_eq!(config.discovery_interval, Some(Duration::from_secs(60)));
+        assert!(!config.devices[0].tamper_on_fault);
+        assert!(config.devices[1].tamper_on_fault);
+        assert_eq!(config.metrics, Some("127.0.0.1:9464".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_chime() {
+        assert_eq!(Config::parse(EXAMPLE).unwrap().chime, Areas::NONE);
+
+        let config = Config::parse(
+            &EXAMPLE
+                .replace(
+                    "banner = \"TIGER SECURITY\"",
+                    "banner = \"TIGER SECURITY\"\nchime = \"B\"",
+                )
+                .replace("part_set = false", "part_set = false\nchime = true"),
+        )
+        .unwrap();
+        assert_eq!(config.chime, "B".parse().unwrap());
+        assert!(!config.zones[0].chime);
+        assert!(config.zones[1].chime);
+
+        let (line, message) = error_location(&EXAMPLE.replace(
+            "banner = \"TIGER SECURITY\"",
+            "banner = \"TIGER SECURITY\"\nchime = \"AC\"",
+        ));
+        assert_eq!(line, 3);
+        assert!(message.contains("area C"), "{}", message);
+    }
+
+    #[test]
+    fn test_parse_half_duplex() {
+        let config = Config::parse(&EXAMPLE.replace(
+            "port = \"/dev/ttyUSB0\"",
+            "port = \"/dev/ttyUSB0\"\ndirection = \"rts\"\npost_transmit_delay_ms = 2",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            config.serial.half_duplex,
+            HalfDuplex {
+                direction: Direction::Rts,
+                post_transmit_delay: Duration::from_millis(2),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_prox() {
+        let config = Config::parse(&format!(
+            "{}\n[[devices]]\naddress = 0x50\ntype = \"prox\"\nkeypad = 0x10\n\n\
+             [[users]]\nnumber = 2\nname = \"FOB\"\npin = \"{}\"\nfob = 1234567\n\
+             fob_with_pin = true\nlevel = 2\n",
+            EXAMPLE,
+            PinHash::new("5678")
+        ))
+        .unwrap();
+
+        let prox = &config.devices[2];
+        assert_eq!(prox.device_type, DeviceType::Prox);
+        assert_eq!(prox.keypad, Some(0x10));
+        assert_eq!(prox.areas, "A".parse().unwrap());
+
+        let user = config.users.get(2).unwrap();
+        assert_eq!(user.fob, Some(1234567));
+        assert!(user.fob_with_pin);
+        assert_eq!(config.users.get(1).unwrap().fob, None);
+
+        let (_, message) = error_location(&format!(
+            "{}\n[[devices]]\naddress = 0x50\ntype = \"prox\"\nkeypad = 0x20\n",
+            EXAMPLE
+        ));
+        assert!(message.contains("not a configured keypad"), "{}", message);
+    }
+
+    #[test]
+    fn test_parse_access() {
+        let access = r#"
+[[devices]]
+address = 0x60
+type = "max"
+
+[[doors]]
+number = 1
+name = "OFFICE"
+reader = 0x60
+held_open_s = 60
+
+[[schedules]]
+name = "WORKING HOURS"
+windows = [{ days = ["mon", "tue", "wed", "thu", "fri"], from = "08:00", until = "18:00" }]
+
+[[permissions]]
+user = 1
+doors = [1]
+schedule = "WORKING HOURS"
+"#;
+        let config = Config::parse(&format!("{}{}", EXAMPLE, access)).unwrap();
+
+        assert_eq!(config.devices[2].device_type, DeviceType::Max);
+        assert_eq!(config.doors[0].reader, 0x60);
+        assert_eq!(config.doors[0].release, RELEASE_TIME);
+        assert_eq!(config.doors[0].held_open, Duration::from_secs(60));
+
+        let schedule = config.permissions[0].schedule.as_ref().unwrap();
+        assert_eq!(schedule.windows[0].days.len(), 5);
+        assert_eq!(
+            schedule.windows[0].until,
+            chrono::NaiveTime::from_hms_opt(18, 0, 0).unwrap()
+        );
+
+        let (_, message) = error_location(&format!(
+            "{}{}",
+            EXAMPLE,
+            access.replace("schedule = \"WORKING HOURS\"", "schedule = \"NIGHTS\"")
+        ));
+        assert!(message.contains("not defined"), "{}", message);
+
+        let (_, message) = error_location(&format!(
+            "{}{}",
+            EXAMPLE,
+            access.replace("reader = 0x60", "reader = 0x20")
+        ));
+        assert!(message.contains("not a configured MAX"), "{}", message);
+    }
+
+    #[test]
+    fn test_parse_reporting() {
+        let config = Config::parse(&format!(
+            "{}\n[reporting]\naccount = \"12B4\"\nreceiver = \"192.0.2.1:5001\"\n\
+             protocol = \"udp\"\nline_number = \"2\"\nkey = \"{}\"\nheartbeat_s = 90\n",
+            EXAMPLE,
+            "0F".repeat(16)
+        ))
+        .unwrap();
+
+        let reporting = config.reporting.unwrap();
+        assert_eq!(reporting.account, "12B4".parse().unwrap());
+        assert_eq!(reporting.protocol, Protocol::Udp);
+        assert_eq!(reporting.receiver, "192.0.2.1:5001".parse().unwrap());
+        assert_eq!(reporting.receiver_number, None);
+        assert_eq!(reporting.line_number, "2");
+        assert!(reporting.key.is_some());
+        assert_eq!(reporting.delivery, Delivery::default());
+        assert_eq!(reporting.heartbeat, Some(Duration::from_secs(90)));
+
+        let (line, message) = error_location(&format!(
+            "{}\n[reporting]\naccount = \"12A4\"\nreceiver = \"192.0.2.1:5001\"\n",
+            EXAMPLE
+        ));
+        assert_eq!(line, 48);
+        assert!(message.contains("12A4"), "{}", message);
+    }
+
+    #[test]
+    fn test_syntax_errors_report_line() {
+        let (line, message) = error_location(&EXAMPLE.replace("input = 2", "input = 2000"));
+        assert_eq!(line, 33);
+        assert!(message.contains("u8"), "{}", message);
+
+        let (line, _) = error_location(&EXAMPLE.replace("\"intruder\"", "\"burglar\""));
+        assert_eq!(line, 35);
+    }
+
+    #[test]
+    fn test_validation_errors_report_line() {
+        let (line, message) = error_location(
+            &EXAMPLE.replace("device = 0x20\ninput = 2", "device = 0x21\ninput = 2"),
+        );
+        assert_eq!(line, 32);
+        assert!(message.contains("0x21"), "{}", message);
+
+        let (line, message) = error_location(&EXAMPLE.replace("areas = \"B\"", "areas = \"C\""));
+        assert_eq!(line, 38);
+        assert!(message.contains("area C"), "{}", message);
+
+        let (line, _) = error_location(&EXAMPLE.replace("address = 0x20", "address = 0x10"));
+        assert_eq!(line, 21);
+
+        let (line, message) = error_location(&EXAMPLE.replace("input = 2", "input = 1"));
+        assert_eq!(line, 31);
+        assert!(message.contains("more than once"), "{}", message);
+
+        let (line, message) = error_location(&EXAMPLE.replace("address = 0x20", "address = 0x30"));
+        assert_eq!(line, 21);
+        assert!(message.contains("outside the RIO range"), "{}", message);
+
+        let (line, message) = error_location(&EXAMPLE.replace("address = 0x10", "address = 0x11"));
+        assert_eq!(line, 16);
+        assert!(message.contains("panel"), "{}", message);
+    }
+}
@@ -0,0 +1,242 @@
+use chrono::{DateTime, Utc};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{
+    alarm::{FaultKind, SetMode},
+    areas::Area,
+    serial::{devices::DeviceKind, manager::DeviceStatus},
+};
+
+pub mod recorder;
+pub mod store;
+
+pub use store::{load_key, EventLog, EventLogError};
+
+/// EventClass groups events for querying the log.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum EventClass {
+    #[display(fmt = "SET/UNSET")]
+    SetUnset,
+    #[display(fmt = "ALARM")]
+    Alarm,
+    #[display(fmt = "TAMPER")]
+    Tamper,
+    #[display(fmt = "DEVICE")]
+    Device,
+    #[display(fmt = "CODE")]
+    Code,
+    #[display(fmt = "CONFIG")]
+    Config,
+    #[display(fmt = "ACCESS")]
+    Access,
+    #[display(fmt = "TEST")]
+    Test,
+}
+
+/// EventKind is an auditable event, attributed to the user, zone or device involved.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    // The exit procedure started.
+    Setting {
+        area: Area,
+        mode: SetMode,
+        user: Option<u16>,
+    },
+    Set {
+        area: Area,
+        mode: SetMode,
+    },
+    SetFailed {
+        area: Area,
+        zones: Vec<u16>,
+    },
+    Unset {
+        area: Area,
+        user: Option<u16>,
+    },
+    Alarm {
+        area: Area,
+        zone: Option<u16>,
+    },
+    Reset {
+        area: Area,
+        user: Option<u16>,
+    },
+    ZoneTamper {
+        zone: u16,
+    },
+    KeypadLockout {
+        keypad: u8,
+    },
+    DeviceStatus {
+        address: u8,
+        status: DeviceStatus,
+        previous: DeviceStatus,
+    },
+    // A supervised module went missing or its communications failed, or a PSU reported a problem
+    // with the power supply.
+    ModuleFault {
+        address: u8,
+        kind: FaultKind,
+    },
+    // Faults of modules that had recovered were cleared.
+    FaultsAcknowledged {
+        user: Option<u16>,
+    },
+    // A module not in the configuration answered a discovery scan.
+    DeviceDiscovered {
+        address: u8,
+        kind: DeviceKind,
+        firmware: Vec<u8>,
+    },
+    CodeRejected {
+        keypad: u8,
+        attempts: u32,
+    },
+    AccessGranted {
+        door: u8,
+        user: u16,
+    },
+    // A card was refused at a door, belonging to the given user if known.
+    AccessDenied {
+        door: u8,
+        user: Option<u16>,
+    },
+    DoorForced {
+        door: u8,
+    },
+    DoorHeld {
+        door: u8,
+    },
+    // A user finished a walk test, having opened the tested zones but not the untested ones.
+    WalkTest {
+        user: u16,
+        tested: Vec<u16>,
+        untested: Vec<u16>,
+    },
+    // The configuration loaded at startup differs from that previously in use, identified by
+    // the SHA-256 digest of the configuration file.
+    ConfigChanged {
+        digest: String,
+    },
+}
+
+impl EventKind {
+    pub fn class(&self) -> EventClass {
+        match self {
+            EventKind::Setting { .. }
+            | EventKind::Set { .. }
+            | EventKind::SetFailed { .. }
+            | EventKind::Unset { .. } => EventClass::SetUnset,
+            EventKind::Alarm { .. } | EventKind::Reset { .. } => EventClass::Alarm,
+            EventKind::ZoneTamper { .. } | EventKind::KeypadLockout { .. } => EventClass::Tamper,
+            EventKind::DeviceStatus { .. }
+            | EventKind::ModuleFault { .. }
+            | EventKind::FaultsAcknowledged { .. }
+            | EventKind::DeviceDiscovered { .. } => EventClass::Device,
+            EventKind::CodeRejected { .. } => EventClass::Code,
+            EventKind::ConfigChanged { .. } => EventClass::Config,
+            EventKind::AccessGranted { .. } | EventKind::AccessDenied { .. } => EventClass::Access,
+            EventKind::DoorForced { .. } | EventKind::DoorHeld { .. } => EventClass::Tamper,
+            EventKind::WalkTest { .. } => EventClass::Test,
+        }
+    }
+
+    /// Returns the user, zone or device the event is attributed to, abbreviated for the keypad.
+    pub fn attribution(&self) -> Option<String> {
+        match self {
+            EventKind::Setting { user, .. }
+            | EventKind::Unset { user, .. }
+            | EventKind::Reset { user, .. } => user.map(|user| format!("U{:03}", user)),
+            EventKind::Alarm { zone, .. } => zone.map(|zone| zone.to_string()),
+            EventKind::ZoneTamper { zone } => Some(zone.to_string()),
+            EventKind::KeypadLockout { keypad } | EventKind::CodeRejected { keypad, .. } => {
+                Some(format!("KP{:02X}", keypad))
+            }
+            EventKind::DeviceStatus { address, .. }
+            | EventKind::ModuleFault { address, .. }
+            | EventKind::DeviceDiscovered { address, .. } => Some(format!("{:02X}", address)),
+            EventKind::FaultsAcknowledged { user } => user.map(|user| format!("U{:03}", user)),
+            EventKind::AccessGranted { user, .. } | EventKind::WalkTest { user, .. } => {
+                Some(format!("U{:03}", user))
+            }
+            EventKind::AccessDenied { user, .. } => user.map(|user| format!("U{:03}", user)),
+            EventKind::DoorForced { door } | EventKind::DoorHeld { door } => {
+                Some(format!("DR{}", door))
+            }
+            EventKind::Set { .. }
+            | EventKind::SetFailed { .. }
+            | EventKind::ConfigChanged { .. } => None,
+        }
+    }
+}
+
+/// Describes the event in at most 16 characters, to fit a line of the keypad display.
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventKind::Setting { area, mode, .. } => write!(f, "{} SETTING {}", mode, area),
+            EventKind::Set { area, mode } => write!(f, "{} SET {}", mode, area),
+            EventKind::SetFailed { area, .. } => write!(f, "SET FAIL {}", area),
+            EventKind::Unset { area, .. } => write!(f, "UNSET {}", area),
+            EventKind::Alarm { area, .. } => write!(f, "ALARM {}", area),
+            EventKind::Reset { area, .. } => write!(f, "RESET {}", area),
+            EventKind::ZoneTamper { .. } => write!(f, "ZONE TAMPER"),
+            EventKind::KeypadLockout { .. } => write!(f, "KEYPAD LOCKOUT"),
+            EventKind::DeviceStatus { status, .. } => match status {
+                DeviceStatus::Offline => write!(f, "MODULE OFFLINE"),
+                DeviceStatus::OnlineOK => write!(f, "MODULE ONLINE"),
+                DeviceStatus::OnlineCorruptReplies => write!(f, "MODULE COMMS"),
+                DeviceStatus::Unknown => write!(f, "MODULE UNKNOWN"),
+            },
+            EventKind::ModuleFault { kind, .. } => write!(f, "FAULT {}", kind),
+            EventKind::FaultsAcknowledged { .. } => write!(f, "FAULTS RESET"),
+            EventKind::DeviceDiscovered { .. } => write!(f, "NEW MODULE"),
+            EventKind::CodeRejected { .. } => write!(f, "INVALID CODE"),
+            EventKind::ConfigChanged { .. } => write!(f, "CONFIG CHANGED"),
+            EventKind::AccessGranted { door, .. } => write!(f, "ACCESS DOOR {}", door),
+            EventKind::AccessDenied { door, .. } => write!(f, "DENIED DOOR {}", door),
+            EventKind::DoorForced { door } => write!(f, "FORCED DOOR {}", door),
+            EventKind::DoorHeld { door } => write!(f, "HELD DOOR {}", door),
+            EventKind::WalkTest {
+                tested, untested, ..
+            } => write!(
+                f,
+                "WALK TEST {}/{}",
+                tested.len(),
+                tested.len() + untested.len()
+            ),
+        }
+    }
+}
+
+/// Entry is an event as recorded in the log.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    // Position in the log, counting from 1 and contiguous across segments.
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    pub event: EventKind,
+}
+
+/// Filter selects entries from the log. Unset bounds match any time, and an empty set of classes
+/// matches every class.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    // Inclusive lower bound.
+    pub from: Option<DateTime<Utc>>,
+    // Exclusive upper bound.
+    pub until: Option<DateTime<Utc>>,
+    pub classes: Vec<EventClass>,
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.from.is_none_or(|from| entry.time >= from)
+            && self.until.is_none_or(|until| entry.time < until)
+            && (self.classes.is_empty() || self.classes.contains(&entry.event.class()))
+    }
+}
@@ -0,0 +1,274 @@
+use log::{error, warn};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+use super::{EventKind, EventLog};
+use crate::{
+    access::{self, manager::AccessManager},
+    alarm::{self, AlarmHandle, Cause, SetMode, SystemState},
+    keypad::{self, manager::KeypadManager},
+    serial::{
+        galaxy::Exchange,
+        manager::{self as serial, SerialManager},
+    },
+    zones::{self, manager::ZoneManager, ZoneState},
+};
+
+/// Recorder writes events from the rest of the system into the event log.
+pub struct Recorder {
+    log: Arc<Mutex<EventLog>>,
+    tx: mpsc::UnboundedSender<EventKind>,
+    rx: mpsc::UnboundedReceiver<EventKind>,
+}
+
+impl Recorder {
+    pub fn new(log: EventLog) -> Recorder {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        Recorder {
+            log: Arc::new(Mutex::new(log)),
+            tx,
+            rx,
+        }
+    }
+
+    /// Returns the log, e.g. for querying.
+    pub fn log(&self) -> Arc<Mutex<EventLog>> {
+        self.log.clone()
+    }
+
+    /// Returns a sender through which events can be recorded directly.
+    pub fn sender(&self) -> mpsc::UnboundedSender<EventKind> {
+        self.tx.clone()
+    }
+
+    pub fn attach_alarm(&self, alarm: &AlarmHandle) {
+        self.forward(alarm.subscribe_events(), "alarm", alarm_event);
+    }
+
+    pub fn attach_zones(&self, zones: &ZoneManager) {
+        self.forward(zones.subscribe_events(), "zones", |event| match event {
+            zones::Event(zones::EventType::StateChanged {
+                zone,
+                state: ZoneState::Tamper,
+                ..
+            }) => Some(EventKind::ZoneTamper { zone }),
+            _ => None,
+        });
+    }
+
+    /// Records code failures and lockouts at the keypad with the given bus address.
+    pub fn attach_keypad(&self, address: u8, keypad: &KeypadManager) {
+        self.forward(
+            keypad.subscribe_events(),
+            "keypad",
+            move |keypad::Event(event)| match event {
+                keypad::EventType::CodeRejected { attempts } => Some(EventKind::CodeRejected {
+                    keypad: address,
+                    attempts,
+                }),
+                keypad::EventType::LockedOut => Some(EventKind::KeypadLockout { keypad: address }),
+                keypad::EventType::CodeAccepted { .. } => None,
+            },
+        );
+    }
+
+    /// Records access granted and denied at doors, and doors forced or held open.
+    pub fn attach_access(&self, access: &AccessManager) {
+        self.forward(
+            access.subscribe_events(),
+            "access",
+            |access::Event(event)| match event {
+                access::EventType::Granted { door, user } => {
+                    Some(EventKind::AccessGranted { door, user })
+                }
+                access::EventType::Denied { door, user } => {
+                    Some(EventKind::AccessDenied { door, user })
+                }
+                access::EventType::ForcedOpen { door } => Some(EventKind::DoorForced { door }),
+                access::EventType::HeldOpen { door } => Some(EventKind::DoorHeld { door }),
+                access::EventType::Secured { .. } => None,
+            },
+        );
+    }
+
+    pub fn attach_serial<B: Exchange>(&self, serial: &SerialManager<B>) {
+        self.forward(
+            serial.subscribe_events(),
+            "serial",
+            |serial::Event(event)| match event {
+                serial::EventType::StatusChanged {
+                    address,
+                    status,
+                    previous,
+                } => Some(EventKind::DeviceStatus {
+                    address,
+                    status,
+                    previous,
+                }),
+                serial::EventType::Discovered {
+                    address,
+                    kind,
+                    firmware,
+                } => Some(EventKind::DeviceDiscovered {
+                    address,
+                    kind,
+                    firmware,
+                }),
+            },
+        );
+    }
+
+    fn forward<E, F>(&self, mut events: broadcast::Receiver<E>, source: &'static str, convert: F)
+    where
+        E: Clone + Send + 'static,
+        F: Fn(E) -> Option<EventKind> + Send + 'static,
+    {
+        let tx = self.tx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if let Some(kind) = convert(event) {
+                            if tx.send(kind).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Recorder lagged {} events from {}", n, source);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub async fn run(&mut self) {
+        while let Some(event) = self.rx.recv().await {
+            if let Err(e) = self.log.lock().unwrap().append(event) {
+                error!("Unable to write to event log: {}", e);
+            }
+        }
+    }
+}
+
+fn alarm_event(event: alarm::Event) -> Option<EventKind> {
+    match event.0 {
+        alarm::EventType::StateChanged {
+            area,
+            state,
+            previous,
+            cause,
+        } => {
+            let user = match cause {
+                Cause::User(user) => Some(user),
+                _ => None,
+            };
+
+            match (state, previous) {
+                (SystemState::ExitTiming(mode), _) => Some(EventKind::Setting { area, mode, user }),
+                (SystemState::Set, _) => Some(EventKind::Set {
+                    area,
+                    mode: SetMode::Full,
+                }),
+                (SystemState::PartSet, _) => Some(EventKind::Set {
+                    area,
+                    mode: SetMode::Part,
+                }),
+                (SystemState::Alarm, _) => Some(EventKind::Alarm {
+                    area,
+                    zone: match cause {
+                        Cause::Zone(zone) | Cause::ZoneTamper(zone) => Some(zone),
+                        _ => None,
+                    },
+                }),
+                (SystemState::Unset, SystemState::AlarmResetRequired) => {
+                    Some(EventKind::Reset { area, user })
+                }
+                // A failed exit procedure is recorded by the SetFailed event.
+                (SystemState::Unset, _) if cause == Cause::Timer => None,
+                (SystemState::Unset | SystemState::AlarmResetRequired, _) => {
+                    Some(EventKind::Unset { area, user })
+                }
+                (SystemState::EntryTiming(_), _) => None,
+            }
+        }
+        alarm::EventType::SetFailed { area, zones } => Some(EventKind::SetFailed { area, zones }),
+        alarm::EventType::FaultRaised { address, kind } => {
+            Some(EventKind::ModuleFault { address, kind })
+        }
+        // The module coming back online is recorded from the bus.
+        alarm::EventType::FaultRestored { .. } => None,
+        alarm::EventType::FaultsAcknowledged { cause, .. } => Some(EventKind::FaultsAcknowledged {
+            user: match cause {
+                Cause::User(user) => Some(user),
+                _ => None,
+            },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        alarm::{manager::AlarmManager, Command, Timers},
+        areas::{Area, Areas},
+        eventlog::{EventClass, Filter},
+    };
+
+    #[tokio::test]
+    async fn test_records_alarm_events_with_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recorder = Recorder::new(EventLog::open(dir.path(), 100, &[0; 32]).unwrap());
+
+        let (zone_tx, zone_rx) = broadcast::channel(1);
+        let (mut alarm_manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        recorder.attach_alarm(&alarm);
+
+        let log = recorder.log();
+        tokio::spawn(async move { recorder.run().await });
+        tokio::spawn(async move {
+            let _zone_tx = zone_tx;
+            alarm_manager.run().await
+        });
+
+        alarm.send_as(
+            7,
+            Command::Set {
+                areas: Areas::single(Area::A),
+                mode: SetMode::Part,
+            },
+        );
+
+        let entries = loop {
+            time::sleep(Duration::from_millis(10)).await;
+
+            let entries = log
+                .lock()
+                .unwrap()
+                .query(&Filter {
+                    classes: vec![EventClass::SetUnset],
+                    ..Default::default()
+                })
+                .unwrap();
+            if !entries.is_empty() {
+                break entries;
+            }
+        };
+
+        assert_eq!(
+            entries[0].event,
+            EventKind::Setting {
+                area: Area::A,
+                mode: SetMode::Part,
+                user: Some(7)
+            }
+        );
+    }
+}
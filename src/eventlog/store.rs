@@ -0,0 +1,753 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use super::{Entry, EventKind, Filter};
+
+/// SEGMENTS is the number of full segments retained, so that at least the configured capacity of
+/// entries is always available. One further segment is retained while it fills.
+const SEGMENTS: usize = 4;
+
+const SEGMENT_SUFFIX: &str = ".log";
+
+const CHECKPOINT: &str = "checkpoint";
+
+const KEY_LEN: usize = 32;
+
+type Hash = [u8; 32];
+
+// The previous hash recorded by the first ever entry.
+const GENESIS: Hash = [0; 32];
+
+#[derive(Debug, Error)]
+pub enum EventLogError {
+    #[error("event log I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("unable to encode event: {0}")]
+    Encode(#[from] serde_json::Error),
+    #[error("event log key {} is not {KEY_LEN} bytes long", .0.display())]
+    Key(PathBuf),
+    // The log does not match its hash chain or checkpoint, so has been edited, reordered or had
+    // entries removed.
+    #[error("event log segment {segment} line {line}: {reason}")]
+    Tampered {
+        segment: String,
+        line: usize,
+        reason: String,
+    },
+}
+
+/// Record is the on-disk form of an entry, linked to its predecessor by hash.
+#[derive(Deserialize, Serialize)]
+struct Record {
+    prev: String,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+/// Checkpoint is the on-disk record of both ends of the chain, kept apart from the segments so
+/// that entries lost from either end are detected.
+#[derive(Deserialize, Serialize)]
+struct Checkpoint {
+    // The oldest retained entry and the hash it follows, which is that of the last entry of the
+    // most recently pruned segment.
+    first_seq: u64,
+    first_prev: String,
+    // The newest entry, or one less than first_seq while there are none.
+    last_seq: u64,
+    last_hash: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Hash> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+
+    let mut out = [0u8; 32];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(out)
+}
+
+/// Reads the key with which the log is hashed from `path`, generating a random one readable only
+/// by its owner if there is none yet.
+pub fn load_key(path: impl AsRef<Path>) -> Result<Vec<u8>, EventLogError> {
+    let path = path.as_ref();
+    match fs::read(path) {
+        Ok(key) if key.len() == KEY_LEN => Ok(key),
+        Ok(_) => Err(EventLogError::Key(path.to_path_buf())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut key = vec![0; KEY_LEN];
+            rand::thread_rng().fill_bytes(&mut key);
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?;
+            file.write_all(&key)?;
+            file.sync_all()?;
+
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// EventLog is a bounded, append-only and tamper-evident store of events on disk.
+///
+/// The log is split into segment files, named after the sequence number of their first entry,
+/// holding one entry per line as `<hash> <record>`. Each record carries the hash of the entry
+/// before it and each hash is an HMAC of its whole record, so editing, reordering or removing an
+/// entry breaks the chain, and without the key the chain cannot be rebuilt. Once the log is full
+/// the oldest segment is deleted as a whole.
+///
+/// A checkpoint file, also keyed, holds the first and last links of the chain, so truncating the
+/// newest entries or deleting the oldest segments out of turn is detected too. The log can still
+/// be rolled back as a whole to an earlier copy of itself and its checkpoint; keeping the key out
+/// of reach of whoever can write the log is what stops anything worse.
+pub struct EventLog {
+    dir: PathBuf,
+    key: Vec<u8>,
+    segment_len: usize,
+    // First sequence number of each segment present and the hash its first entry follows, oldest
+    // first.
+    segments: VecDeque<(u64, Hash)>,
+    // The segment being appended to and the number of entries it holds.
+    current: Option<(File, usize)>,
+    next_seq: u64,
+    last_hash: Hash,
+}
+
+impl EventLog {
+    /// Opens the log in `dir` hashed with `key`, creating it if necessary, and verifies its
+    /// existing contents. At least `capacity` of the most recent entries are retained.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        capacity: usize,
+        key: &[u8],
+    ) -> Result<EventLog, EventLogError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                name.to_str()?.strip_suffix(SEGMENT_SUFFIX)?.parse().ok()
+            })
+            .collect();
+        segments.sort();
+
+        let mut log = EventLog {
+            dir,
+            key: key.to_vec(),
+            segment_len: capacity.div_ceil(SEGMENTS).max(1),
+            // The anchors are filled in as the segments are read.
+            segments: segments.into_iter().map(|seq| (seq, GENESIS)).collect(),
+            current: None,
+            next_seq: 1,
+            last_hash: GENESIS,
+        };
+
+        match log.read_checkpoint()? {
+            // Segments left behind by a prune that was interrupted once the checkpoint had moved
+            // past them.
+            Some(checkpoint) => {
+                while let Some(&(first_seq, _)) = log.segments.front() {
+                    if first_seq >= checkpoint.first_seq {
+                        break;
+                    }
+                    fs::remove_file(log.segment_path(first_seq))?;
+                    log.segments.pop_front();
+                }
+            }
+            None if log.segments.is_empty() => log.write_checkpoint()?,
+            None => {}
+        }
+
+        if let Some(&(first_seq, _)) = log.segments.back() {
+            log.truncate_torn_entry(first_seq)?;
+        }
+
+        log.read(|_| {})?;
+
+        if let Some(&(first_seq, _)) = log.segments.back() {
+            let last_len = (log.next_seq - first_seq) as usize;
+            if last_len < log.segment_len {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(log.segment_path(first_seq))?;
+                log.current = Some((file, last_len));
+            }
+        }
+
+        Ok(log)
+    }
+
+    /// Discards the last line of the segment if it was left unfinished by an append that was
+    /// interrupted, e.g. by a power cut. Such an entry was never checkpointed, so if the line was
+    /// in fact cut short on purpose the checkpoint still shows the entry missing.
+    fn truncate_torn_entry(&self, first_seq: u64) -> Result<(), EventLogError> {
+        let path = self.segment_path(first_seq);
+        let contents = fs::read(&path)?;
+
+        if contents.last().is_some_and(|&b| b != b'\n') {
+            let end = contents
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1);
+            warn!(
+                "Discarding event log entry torn by an interrupted append to {}",
+                path.display()
+            );
+
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(end as u64)?;
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn segment_path(&self, first_seq: u64) -> PathBuf {
+        self.dir
+            .join(format!("{:020}{}", first_seq, SEGMENT_SUFFIX))
+    }
+
+    fn mac(&self, body: &str) -> Hash {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key");
+        mac.update(body.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    fn read_checkpoint(&self) -> Result<Option<Checkpoint>, EventLogError> {
+        let line = match fs::read_to_string(self.dir.join(CHECKPOINT)) {
+            Ok(line) => line,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let tampered = |reason: &str| EventLogError::Tampered {
+            segment: CHECKPOINT.to_string(),
+            line: 1,
+            reason: reason.to_string(),
+        };
+
+        let (hash, body) = line
+            .trim_end()
+            .split_once(' ')
+            .ok_or_else(|| tampered("malformed checkpoint"))?;
+        let hash = from_hex(hash).ok_or_else(|| tampered("malformed hash"))?;
+
+        if self.mac(body) != hash {
+            return Err(tampered("hash does not match checkpoint"));
+        }
+
+        Ok(Some(
+            serde_json::from_str(body).map_err(|e| tampered(&e.to_string()))?,
+        ))
+    }
+
+    /// Records the current ends of the chain. The file is replaced whole, so a crash leaves
+    /// either the old checkpoint or the new one.
+    fn write_checkpoint(&self) -> Result<(), EventLogError> {
+        let (first_seq, first_prev) = self
+            .segments
+            .front()
+            .copied()
+            .unwrap_or((self.next_seq, self.last_hash));
+        let body = serde_json::to_string(&Checkpoint {
+            first_seq,
+            first_prev: to_hex(&first_prev),
+            last_seq: self.next_seq - 1,
+            last_hash: to_hex(&self.last_hash),
+        })?;
+
+        let path = self.dir.join(CHECKPOINT);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{} {}", to_hex(&self.mac(&body)), body)?;
+        file.sync_data()?;
+        fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Reads and verifies every entry, passing each to `visit`. The position of the end of the
+    /// chain is updated as it is read.
+    fn read<F>(&mut self, mut visit: F) -> Result<(), EventLogError>
+    where
+        F: FnMut(Entry),
+    {
+        let checkpoint_tampered = |reason: &str| EventLogError::Tampered {
+            segment: CHECKPOINT.to_string(),
+            line: 1,
+            reason: reason.to_string(),
+        };
+        let checkpoint = self
+            .read_checkpoint()?
+            .ok_or_else(|| checkpoint_tampered("checkpoint missing"))?;
+        let (Some(first_prev), Some(last_hash)) = (
+            from_hex(&checkpoint.first_prev),
+            from_hex(&checkpoint.last_hash),
+        ) else {
+            return Err(checkpoint_tampered("malformed hash"));
+        };
+
+        let mut prev = first_prev;
+        let mut next_seq = checkpoint.first_seq;
+        let mut anchors = Vec::with_capacity(self.segments.len());
+
+        for &(first_seq, _) in &self.segments {
+            let path = self.segment_path(first_seq);
+            let segment = path.file_name().unwrap().to_string_lossy().to_string();
+            let tampered = |line: usize, reason: &str| EventLogError::Tampered {
+                segment: segment.clone(),
+                line,
+                reason: reason.to_string(),
+            };
+
+            if first_seq != next_seq {
+                return Err(tampered(
+                    0,
+                    "segment does not follow on from its predecessor",
+                ));
+            }
+            anchors.push(prev);
+
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(tampered(0, "segment missing"))
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            for (i, line) in BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                let line_number = i + 1;
+
+                let (hash, body) = line
+                    .split_once(' ')
+                    .ok_or_else(|| tampered(line_number, "malformed entry"))?;
+                let hash = from_hex(hash).ok_or_else(|| tampered(line_number, "malformed hash"))?;
+
+                if self.mac(body) != hash {
+                    return Err(tampered(line_number, "hash does not match entry"));
+                }
+
+                let record: Record = serde_json::from_str(body)
+                    .map_err(|e| tampered(line_number, &e.to_string()))?;
+
+                if record.entry.seq != next_seq {
+                    return Err(tampered(line_number, "entry out of sequence"));
+                }
+
+                // The oldest retained entry is anchored by the checkpoint.
+                if from_hex(&record.prev) != Some(prev) {
+                    return Err(tampered(
+                        line_number,
+                        "entry does not follow its predecessor",
+                    ));
+                }
+
+                if next_seq == checkpoint.last_seq && hash != last_hash {
+                    return Err(tampered(line_number, "entry does not match the checkpoint"));
+                }
+
+                prev = hash;
+                next_seq += 1;
+                visit(record.entry);
+            }
+        }
+
+        // Entries after the checkpoint are those appended just before a crash.
+        if next_seq <= checkpoint.last_seq {
+            return Err(checkpoint_tampered(
+                "entries missing from the end of the log",
+            ));
+        }
+
+        for (segment, anchor) in self.segments.iter_mut().zip(anchors) {
+            segment.1 = anchor;
+        }
+        self.next_seq = next_seq;
+        self.last_hash = prev;
+
+        Ok(())
+    }
+
+    /// Appends an event to the log, timestamped now.
+    pub fn append(&mut self, event: EventKind) -> Result<Entry, EventLogError> {
+        self.append_at(Utc::now(), event)
+    }
+
+    pub(crate) fn append_at(
+        &mut self,
+        time: DateTime<Utc>,
+        event: EventKind,
+    ) -> Result<Entry, EventLogError> {
+        if self.current.is_none() {
+            self.start_segment()?;
+        }
+
+        let record = Record {
+            prev: to_hex(&self.last_hash),
+            entry: Entry {
+                seq: self.next_seq,
+                time,
+                event,
+            },
+        };
+        let body = serde_json::to_string(&record)?;
+        let hash = self.mac(&body);
+
+        let (file, len) = self.current.as_mut().unwrap();
+        writeln!(file, "{} {}", to_hex(&hash), body)?;
+        file.sync_data()?;
+
+        *len += 1;
+        if *len >= self.segment_len {
+            self.current = None;
+        }
+
+        self.next_seq += 1;
+        self.last_hash = hash;
+        self.write_checkpoint()?;
+
+        Ok(record.entry)
+    }
+
+    fn start_segment(&mut self) -> Result<(), EventLogError> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(self.segment_path(self.next_seq))?;
+
+        self.segments.push_back((self.next_seq, self.last_hash));
+        self.current = Some((file, 0));
+
+        let mut pruned = Vec::new();
+        while self.segments.len() > SEGMENTS + 1 {
+            pruned.push(self.segments.pop_front().unwrap().0);
+        }
+
+        // The checkpoint moves on before the segments go, so that they are never missed.
+        if !pruned.is_empty() {
+            self.write_checkpoint()?;
+            for first_seq in pruned {
+                fs::remove_file(self.segment_path(first_seq))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entries matching the filter, oldest first, having verified the whole log.
+    pub fn query(&mut self, filter: &Filter) -> Result<Vec<Entry>, EventLogError> {
+        let mut entries = Vec::new();
+        self.read(|entry| {
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        })?;
+
+        Ok(entries)
+    }
+
+    /// Checks that the log on disk is intact.
+    pub fn verify(&mut self) -> Result<(), EventLogError> {
+        self.read(|_| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::{areas::Area, eventlog::EventClass};
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 9, 1, 12, minute, 0).unwrap()
+    }
+
+    fn rejected(attempts: u32) -> EventKind {
+        EventKind::CodeRejected {
+            keypad: 0x10,
+            attempts,
+        }
+    }
+
+    const KEY: &[u8] = &[7; KEY_LEN];
+
+    fn segment_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_append_query_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let mut log = EventLog::open(dir.path(), 100, KEY).unwrap();
+            log.append_at(at(0), rejected(1)).unwrap();
+            log.append_at(
+                at(1),
+                EventKind::Unset {
+                    area: Area::A,
+                    user: Some(1),
+                },
+            )
+            .unwrap();
+        }
+
+        let mut log = EventLog::open(dir.path(), 100, KEY).unwrap();
+        let entry = log.append_at(at(2), rejected(2)).unwrap();
+        assert_eq!(entry.seq, 3);
+
+        let codes = log
+            .query(&Filter {
+                classes: vec![EventClass::Code],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            codes.iter().map(|entry| entry.seq).collect::<Vec<_>>(),
+            [1, 3]
+        );
+
+        let recent = log
+            .query(&Filter {
+                from: Some(at(1)),
+                until: Some(at(2)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].event.class(), EventClass::SetUnset);
+    }
+
+    #[test]
+    fn test_bounded_by_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+
+        for attempt in 1..=50 {
+            log.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        let entries = log.query(&Filter::default()).unwrap();
+        assert!(entries.len() >= 8 && entries.len() <= 10);
+        assert_eq!(entries.last().unwrap().seq, 50);
+        assert_eq!(segment_files(dir.path()).len(), SEGMENTS + 1);
+
+        // Reopening continues the chain from the retained segments.
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        assert_eq!(log.append_at(at(0), rejected(51)).unwrap().seq, 51);
+        log.verify().unwrap();
+    }
+
+    #[test]
+    fn test_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path(), 100, KEY).unwrap();
+        for attempt in 1..=3 {
+            log.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        let path = segment_files(dir.path()).remove(0);
+        let original = fs::read_to_string(&path).unwrap();
+
+        // Editing an entry.
+        fs::write(
+            &path,
+            original.replacen("\"attempts\":2", "\"attempts\":9", 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            log.verify(),
+            Err(EventLogError::Tampered { line: 2, .. })
+        ));
+
+        // Removing an entry.
+        let lines: Vec<&str> = original.lines().collect();
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(matches!(
+            log.verify(),
+            Err(EventLogError::Tampered { line: 2, .. })
+        ));
+
+        // Removing the first entry, which is anchored by the checkpoint.
+        fs::write(&path, format!("{}\n{}\n", lines[1], lines[2])).unwrap();
+        assert!(matches!(
+            log.verify(),
+            Err(EventLogError::Tampered { line: 1, .. })
+        ));
+
+        fs::write(&path, original).unwrap();
+        log.verify().unwrap();
+    }
+
+    #[test]
+    fn test_detects_rehashed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path(), 100, KEY).unwrap();
+        for attempt in 1..=3 {
+            log.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        // The same entries hashed without the log's key.
+        let forged_dir = tempfile::tempdir().unwrap();
+        let mut forged = EventLog::open(forged_dir.path(), 100, &[8; KEY_LEN]).unwrap();
+        for attempt in [1, 9, 3] {
+            forged.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        fs::copy(
+            segment_files(forged_dir.path()).remove(0),
+            segment_files(dir.path()).remove(0),
+        )
+        .unwrap();
+        assert!(matches!(
+            log.verify(),
+            Err(EventLogError::Tampered { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_detects_loss_at_either_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        for attempt in 1..=20 {
+            log.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        let files = segment_files(dir.path());
+        let newest = fs::read_to_string(files.last().unwrap()).unwrap();
+        let oldest = fs::read_to_string(&files[0]).unwrap();
+
+        // Truncating the newest entry.
+        let lines: Vec<&str> = newest.lines().collect();
+        fs::write(files.last().unwrap(), format!("{}\n", lines[0])).unwrap();
+        assert!(matches!(
+            log.verify(),
+            Err(EventLogError::Tampered { ref segment, .. }) if segment == CHECKPOINT
+        ));
+        fs::write(files.last().unwrap(), newest).unwrap();
+
+        // Removing the oldest segment, whether the log is open or reopened.
+        fs::remove_file(&files[0]).unwrap();
+        assert!(matches!(
+            log.verify(),
+            Err(EventLogError::Tampered { line: 0, .. })
+        ));
+        assert!(matches!(
+            EventLog::open(dir.path(), 8, KEY),
+            Err(EventLogError::Tampered { line: 0, .. })
+        ));
+        fs::write(&files[0], oldest).unwrap();
+
+        // Removing the checkpoint.
+        let checkpoint = dir.path().join(CHECKPOINT);
+        let saved = fs::read_to_string(&checkpoint).unwrap();
+        fs::remove_file(&checkpoint).unwrap();
+        assert!(EventLog::open(dir.path(), 8, KEY).is_err());
+        fs::write(&checkpoint, saved).unwrap();
+
+        log.verify().unwrap();
+    }
+
+    #[test]
+    fn test_reopens_after_crash_before_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        for attempt in 1..=5 {
+            log.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        // The entry reached the disk but the checkpoint did not move on.
+        let checkpoint = dir.path().join(CHECKPOINT);
+        let saved = fs::read_to_string(&checkpoint).unwrap();
+        log.append_at(at(0), rejected(6)).unwrap();
+        fs::write(&checkpoint, saved).unwrap();
+
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        assert_eq!(log.append_at(at(0), rejected(7)).unwrap().seq, 7);
+        log.verify().unwrap();
+    }
+
+    #[test]
+    fn test_reopens_after_torn_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        for attempt in 1..=5 {
+            log.append_at(at(0), rejected(attempt)).unwrap();
+        }
+
+        // Power was lost part way through writing the next entry.
+        let path = segment_files(dir.path()).pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"0123abcd {\"prev\":\"01").unwrap();
+        drop(file);
+
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        assert_eq!(log.append_at(at(0), rejected(6)).unwrap().seq, 6);
+        log.verify().unwrap();
+
+        // Likewise for the first entry of a new segment.
+        fs::write(dir.path().join(format!("{:020}.log", 7)), b"01").unwrap();
+        let mut log = EventLog::open(dir.path(), 8, KEY).unwrap();
+        assert_eq!(log.append_at(at(0), rejected(7)).unwrap().seq, 7);
+        log.verify().unwrap();
+
+        // An entry that was checkpointed can't be passed off as torn.
+        let path = segment_files(dir.path()).pop().unwrap();
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 1]).unwrap();
+        assert!(matches!(
+            EventLog::open(dir.path(), 8, KEY),
+            Err(EventLogError::Tampered { ref segment, .. }) if segment == CHECKPOINT
+        ));
+    }
+
+    #[test]
+    fn test_load_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        let key = load_key(&path).unwrap();
+        assert_eq!(key.len(), KEY_LEN);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(load_key(&path).unwrap(), key);
+
+        fs::write(&path, b"short").unwrap();
+        assert!(matches!(load_key(&path), Err(EventLogError::Key(_))));
+    }
+}
@@ -4,33 +4,204 @@ use std::{
     time::Duration,
 };
 
-use log::debug;
-use tokio::time::Interval;
+use log::{debug, warn};
+use tokio::{
+    runtime,
+    sync::broadcast,
+    task,
+    time::{self, Instant, Interval},
+};
+
+use crate::{
+    alarm::{self, AlarmHandle, SetMode, SystemState},
+    areas::{Area, Areas},
+    channel::recv_event,
+    serial::devices::{
+        keypad::{Backlight, Beeper, Event, EventType, SerialKeypad},
+        prox::{self, SerialProx},
+    },
+    users::{AccessLevel, User, UserStore},
+    zones::{self, ZoneState},
+};
+
+use super::menu::{Context, Menu, Navigator, Outcome, Services};
+
+const SYSTEM_OWNER: &str = "TIGER SECURITY";
+const BACKLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Consecutive invalid codes after which the keypad is locked out and a tamper raised.
+const MAX_CODE_ATTEMPTS: u32 = 6;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(90);
+// How often the menu is redrawn, so that options showing live readings stay current.
+const MENU_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Length of the beep acknowledging a zone opened during the walk test.
+const CHIRP_DURATION: Duration = Duration::from_millis(200);
+// Length of the chime sounded when a chime zone opens while unset, three pulses of 200ms.
+const CHIME_DURATION: Duration = Duration::from_millis(1200);
+
+/// Action is a change of system state requested by a user at the keypad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Set(SetMode),
+    Unset,
+    Reset,
+}
+
+impl Action {
+    fn command(&self, areas: Areas) -> alarm::Command {
+        match *self {
+            Action::Set(mode) => alarm::Command::Set { areas, mode },
+            Action::Unset => alarm::Command::Unset { areas },
+            Action::Reset => alarm::Command::Reset { areas },
+        }
+    }
 
-use crate::serial::devices::keypad::{Backlight, Event, EventType, SerialKeypad};
+    fn title(&self) -> &'static str {
+        match self {
+            Action::Set(SetMode::Full) => "SET GROUPS",
+            Action::Set(SetMode::Part) => "PART SET GROUPS",
+            Action::Unset => "UNSET GROUPS",
+            Action::Reset => "RESET GROUPS",
+        }
+    }
 
-const SYSTEM_OWNER: &'static str = "TIGER SECURITY";
+    fn permitted(&self, level: AccessLevel) -> bool {
+        match self {
+            Action::Set(_) => level.can_set(),
+            Action::Unset | Action::Reset => level.can_unset(),
+        }
+    }
+}
 
 #[derive(Clone, Copy, PartialEq)]
 enum DisplayMode {
     Idle,
     CodeEntry,
+    // The user may act on more than one area, and is choosing which with the number keys.
+    AreaSelection {
+        user: u16,
+        action: Action,
+        permitted: Areas,
+        selected: Areas,
+    },
     Menu,
+    // Too many invalid codes were entered, so keys are ignored until the lockout expires.
+    LockedOut {
+        until: Instant,
+    },
+}
+
+/// Options configures the presentation of a keypad.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    // Shown on the top line of the idle display.
+    pub banner: String,
+    // Time after returning to idle that the backlight is switched off.
+    pub backlight_timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            banner: SYSTEM_OWNER.to_string(),
+            backlight_timeout: BACKLIGHT_TIMEOUT,
+        }
+    }
 }
 
 pub struct KeypadManager {
     keypad: Arc<SerialKeypad>,
+    // Prox reader integrated in the keypad, at which fobs are presented.
+    prox: Option<Arc<SerialProx>>,
+    // Zone state changes, passed to the menu for the walk test and sounding the chime.
+    zone_events: Option<broadcast::Receiver<zones::Event>>,
+    // Areas served by this keypad.
+    areas: Areas,
+    alarm: AlarmHandle,
+    users: Arc<UserStore>,
+    options: Options,
+    menu: Arc<Menu>,
+    services: Services,
 
     state: Arc<Mutex<DisplayMode>>,
     accumulator: Arc<Mutex<Option<String>>>,
+    // Fob presented during code entry, which is authenticated along with any code.
+    fob: Mutex<Option<u32>>,
+    // Consecutive invalid codes entered since the last valid code or lockout.
+    failed_attempts: Mutex<u32>,
+    // The user in the menu and their position within it.
+    navigator: Mutex<Option<(u16, Navigator)>>,
+    // Sound overriding the beeper, chirping or chiming, and when it stops.
+    sounding: Option<(Beeper, Instant)>,
+
+    event_ch: broadcast::Sender<super::Event>,
 }
 
 impl KeypadManager {
-    pub fn new(keypad: Arc<SerialKeypad>) -> KeypadManager {
+    pub fn new(
+        keypad: Arc<SerialKeypad>,
+        areas: Areas,
+        alarm: AlarmHandle,
+        users: Arc<UserStore>,
+    ) -> KeypadManager {
+        let (event_ch, _) = broadcast::channel(16);
+
         KeypadManager {
             keypad,
+            prox: None,
+            zone_events: None,
+            areas,
+            alarm,
+            users,
+            options: Options::default(),
+            menu: Arc::new(Menu::galaxy()),
+            services: Services::default(),
             state: Arc::new(Mutex::new(DisplayMode::Idle)),
             accumulator: Arc::new(Mutex::new(None)),
+            fob: Mutex::new(None),
+            failed_attempts: Mutex::new(0),
+            navigator: Mutex::new(None),
+            sounding: None,
+            event_ch,
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> KeypadManager {
+        self.options = options;
+        self
+    }
+
+    /// Accepts fobs presented at the keypad's prox reader in place of, or along with, codes.
+    pub fn with_prox(mut self, prox: Arc<SerialProx>) -> KeypadManager {
+        self.prox = Some(prox);
+        self
+    }
+
+    /// Passes zone openings to the menu, so that they can be proved by the walk test.
+    pub fn with_zone_events(mut self, events: broadcast::Receiver<zones::Event>) -> KeypadManager {
+        self.zone_events = Some(events);
+        self
+    }
+
+    pub fn with_menu(mut self, menu: Arc<Menu>) -> KeypadManager {
+        self.menu = menu;
+        self
+    }
+
+    /// Makes zones, the event log and PSUs available to menu options.
+    pub fn with_services(mut self, services: Services) -> KeypadManager {
+        self.services = services;
+        self
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<super::Event> {
+        self.event_ch.subscribe()
+    }
+
+    fn locked_until(&self) -> Option<Instant> {
+        match *self.state.lock().unwrap() {
+            DisplayMode::LockedOut { until } => Some(until),
+            _ => None,
         }
     }
 
@@ -38,16 +209,23 @@ impl KeypadManager {
         use backlight_responder::BacklightResponder;
 
         let mut event_ch = self.keypad.subscribe_events();
+        let mut prox_event_ch = self.prox.as_ref().map(|prox| prox.subscribe_events());
+        let mut zone_event_ch = self.zone_events.take();
+        let mut alarm_event_ch = self.alarm.subscribe_events();
         let mut time_updater_interval = interval_at_next_minute();
+        let mut menu_refresh_interval = time::interval(MENU_REFRESH_INTERVAL);
 
         // TODO stop the responder when it's time to shut down
         let (_backlight_responder_token, backlight_state_tx) = {
             let (mut backlight_responder, state_tx) = {
                 let backlight_keypad = self.keypad.clone();
 
-                BacklightResponder::new(Box::new(move |backlight_state| {
-                    backlight_keypad.mutate_state(|state| state.backlight = backlight_state)
-                }))
+                BacklightResponder::new(
+                    Box::new(move |backlight_state| {
+                        backlight_keypad.mutate_state(|state| state.backlight = backlight_state)
+                    }),
+                    self.options.backlight_timeout,
+                )
             };
 
             (
@@ -60,10 +238,43 @@ impl KeypadManager {
         self.update_keypad_state();
 
         loop {
+            let locked_until = self.locked_until();
+            let in_menu = *self.state.lock().unwrap() == DisplayMode::Menu;
+            let sounding_until = self.sounding.map(|(_, until)| until);
+
             tokio::select! {
                 _ = time_updater_interval.tick() => {
                     self.update_keypad_state();
                 }
+                _ = menu_refresh_interval.tick(), if in_menu => {
+                    self.update_keypad_state();
+                }
+                _ = time::sleep_until(sounding_until.unwrap_or_else(Instant::now)),
+                    if sounding_until.is_some() =>
+                {
+                    self.sounding = None;
+                    self.update_keypad_state();
+                }
+                _ = time::sleep_until(locked_until.unwrap_or_else(Instant::now)),
+                    if locked_until.is_some() =>
+                {
+                    *self.state.lock().unwrap() = DisplayMode::Idle;
+                    backlight_state_tx.send(DisplayMode::Idle)?;
+                    self.update_keypad_state();
+                }
+                msg = alarm_event_ch.recv() => match msg {
+                    Ok(alarm::Event(alarm::EventType::StateChanged { area, .. }))
+                        if self.areas.contains(area) =>
+                    {
+                        self.update_keypad_state();
+                    }
+                    Ok(alarm::Event(
+                        alarm::EventType::FaultRaised { .. }
+                        | alarm::EventType::FaultRestored { .. }
+                        | alarm::EventType::FaultsAcknowledged { .. },
+                    )) => self.update_keypad_state(),
+                    _ => {}
+                },
                 msg = event_ch.recv() => {
                     debug!("Received keypad event: {:?}", msg);
 
@@ -80,25 +291,83 @@ impl KeypadManager {
                         }
                     }
                 }
+                msg = recv_event(&mut zone_event_ch) => match msg {
+                    Ok(zones::Event(zones::EventType::StateChanged {
+                        zone,
+                        state: ZoneState::Open,
+                        ..
+                    })) => self.zone_opened(zone),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("KeypadManager lagged {} zone events", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => zone_event_ch = None,
+                },
+                msg = recv_event(&mut prox_event_ch) => {
+                    debug!("Received prox event: {:?}", msg);
+
+                    if let Ok(prox::Event(prox::EventType::CardRead(id))) = msg {
+                        let new_state = self.present_fob(id);
+                        backlight_state_tx.send(new_state)?;
+                        self.update_keypad_state();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Summarises the state of the areas served by this keypad for the idle display.
+    fn status_line(&self) -> String {
+        let states = self.alarm.states();
+        let mut area_states = self.areas.iter().map(|area| states.get(area));
+        let first = area_states.next().unwrap_or(SystemState::Unset);
+
+        if area_states.all(|state| state == first) {
+            let faults = self.alarm.faults();
+
+            match first {
+                // Latched faults are shown in place of the time until acknowledged.
+                SystemState::Unset if !faults.is_empty() => {
+                    let fault = &faults[0];
+                    format!("FAULT {} {:02X}", fault.kind, fault.address)
+                }
+                SystemState::Unset => chrono::Local::now()
+                    .format("%a %_d %b %H:%M")
+                    .to_string()
+                    .to_uppercase(),
+                state => state.to_string(),
+            }
+        } else {
+            let alarmed = states.filter(self.areas, |state| state == SystemState::Alarm);
+
+            if alarmed.is_empty() {
+                format!(
+                    "SET {}",
+                    states.filter(self.areas, |state| state != SystemState::Unset)
+                )
+            } else {
+                format!("ALARM {}", alarmed)
             }
         }
     }
 
     fn update_keypad_state(&mut self) {
         let state = self.state.lock().unwrap();
-        let banner = format!("{:<16}", SYSTEM_OWNER);
+        let banner = format!("{:<16}", self.options.banner);
+
+        let beeper = match self.sounding {
+            Some((beeper, _)) => beeper,
+            None => self.alarm.states().beeper(self.areas),
+        };
+        self.keypad.mutate_state(|state| state.beeper = beeper);
 
         match *state {
             DisplayMode::Idle => {
+                let status = self.status_line();
+
                 self.keypad.mutate_state(|state| {
                     state.blink = false;
-                    state.screen.lines = [
-                        banner,
-                        chrono::Local::now()
-                            .format("%a %_d %b %H:%M")
-                            .to_string()
-                            .to_uppercase(),
-                    ]
+                    state.screen.lines = [banner, status]
                 });
             }
             DisplayMode::CodeEntry => {
@@ -114,20 +383,282 @@ impl KeypadManager {
                 } else {
                     "".to_string()
                 };
+                let line2 = if self.fob.lock().unwrap().is_some() {
+                    "FOB PRESENTED".to_string()
+                } else {
+                    "".to_string()
+                };
+
+                self.keypad.mutate_state(|state| {
+                    state.blink = false;
+                    state.screen.lines = [line1, line2];
+                });
+            }
+            DisplayMode::AreaSelection {
+                action,
+                permitted,
+                selected,
+                ..
+            } => {
+                // Selected areas show their letter and deselected ones a dash, while areas the
+                // user may not act on are left blank.
+                let groups: String = selected
+                    .to_keypad_string('-')
+                    .chars()
+                    .zip(permitted.to_keypad_string(' ').chars())
+                    .map(|(selected, permitted)| if permitted == ' ' { ' ' } else { selected })
+                    .collect();
 
                 self.keypad.mutate_state(|state| {
+                    state.backlight = Backlight::On;
                     state.blink = false;
-                    state.screen.lines = [line1, "".to_string()];
+                    state.screen.lines = [action.title().to_string(), groups];
                 });
             }
             DisplayMode::Menu => {
+                let lines = self
+                    .with_navigator(|navigator, ctx| navigator.screen(ctx))
+                    .unwrap_or_default();
+
                 self.keypad.mutate_state(|state| {
                     state.backlight = Backlight::On;
                     state.blink = true;
-                    state.screen.lines =
-                        ["10 = SETTING".to_string(), "[ent] to select".to_string()];
+                    state.screen.lines = lines;
+                });
+            }
+            DisplayMode::LockedOut { .. } => {
+                self.keypad.mutate_state(|state| {
+                    state.backlight = Backlight::On;
+                    state.blink = false;
+                    state.screen.lines = ["KEYPAD LOCKED".to_string(), "".to_string()];
+                });
+            }
+        }
+    }
+
+    /// Determines the action to take for a code terminated with `key`, and the areas it applies
+    /// to. A terminates a full set, B a part set, and ent an unset, or a reset following an
+    /// alarm. None is returned if ent should instead enter the menu.
+    fn code_action(&self, key: char, permitted: Areas) -> Option<(Action, Areas)> {
+        let states = self.alarm.states();
+
+        match key {
+            'A' | 'B' => Some((
+                Action::Set(if key == 'A' {
+                    SetMode::Full
+                } else {
+                    SetMode::Part
+                }),
+                states.filter(permitted, |state| state == SystemState::Unset),
+            )),
+            _ => {
+                let reset =
+                    states.filter(permitted, |state| state == SystemState::AlarmResetRequired);
+                let unset = states.filter(permitted, |state| {
+                    state.is_set() || state == SystemState::Alarm
                 });
+
+                if !unset.is_empty() {
+                    Some((Action::Unset, unset))
+                } else if !reset.is_empty() {
+                    Some((Action::Reset, reset))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Authenticates a code and any fob presented with it, terminated with `key`, and carries out
+    /// the requested action, returning the display mode to move to.
+    fn enter_code(&self, code: &str, fob: Option<u32>, key: char) -> DisplayMode {
+        let now = chrono::Local::now().naive_local();
+        // PINs are slow to check by design, and a wrong one is checked against every user's.
+        let user = blocking(|| match fob {
+            Some(fob) => {
+                self.users
+                    .authenticate_fob(fob, Some(code).filter(|code| !code.is_empty()), now)
+            }
+            None => self.users.authenticate(code, now),
+        });
+        let Some(user) = user else {
+            return self.code_rejected();
+        };
+
+        *self.failed_attempts.lock().unwrap() = 0;
+        let _ = self
+            .event_ch
+            .send(super::Event(super::EventType::CodeAccepted {
+                user: user.number,
+            }));
+
+        let permitted = user.areas.intersection(self.areas);
+
+        match self.code_action(key, permitted) {
+            // Faults are acknowledged in preference to entering the menu, once they've restored.
+            None if user.level.can_acknowledge_faults()
+                && self.alarm.faults().iter().any(|fault| fault.restored) =>
+            {
+                self.alarm
+                    .send_as(user.number, alarm::Command::AcknowledgeFaults);
+                DisplayMode::Idle
+            }
+            None if user.level.can_enter_menu() => {
+                match Navigator::new(self.menu.clone(), user.level) {
+                    Some(navigator) => {
+                        *self.navigator.lock().unwrap() = Some((user.number, navigator));
+                        DisplayMode::Menu
+                    }
+                    None => DisplayMode::Idle,
+                }
+            }
+            None => DisplayMode::Idle,
+            Some((action, areas)) => self.perform(user, action, areas),
+        }
+    }
+
+    /// Carries out an action for the user on the given areas, asking the user to choose between
+    /// them if there are several.
+    fn perform(&self, user: &User, action: Action, areas: Areas) -> DisplayMode {
+        match (action, areas) {
+            (action, _) if !action.permitted(user.level) => DisplayMode::Idle,
+            (_, areas) if areas.is_empty() => DisplayMode::Idle,
+            (action, areas) if areas.len() == 1 => {
+                self.alarm.send_as(user.number, action.command(areas));
+                DisplayMode::Idle
+            }
+            (action, areas) => DisplayMode::AreaSelection {
+                user: user.number,
+                action,
+                permitted: areas,
+                selected: areas,
+            },
+        }
+    }
+
+    /// Calls `f` with the navigator of the user in the menu, if any, and their context.
+    fn with_navigator<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Navigator, &Context) -> R,
+    {
+        let mut navigator = self.navigator.lock().unwrap();
+        let (number, navigator) = navigator.as_mut()?;
+        let user = self.users.get(*number)?;
+
+        let ctx = Context {
+            user,
+            areas: user.areas.intersection(self.areas),
+            alarm: &self.alarm,
+            users: &self.users,
+            services: &self.services,
+        };
+
+        Some(f(navigator, &ctx))
+    }
+
+    /// Passes a zone opening to the option in use in the menu, chirping if it asks, or otherwise
+    /// sounds the chime if the zone is due one.
+    fn zone_opened(&mut self, zone: u16) {
+        let in_menu = *self.state.lock().unwrap() == DisplayMode::Menu;
+
+        let (beeper, duration) = if in_menu
+            && self
+                .with_navigator(|navigator, ctx| navigator.zone_opened(ctx, zone))
+                .unwrap_or(false)
+        {
+            (Beeper::On, CHIRP_DURATION)
+        } else if self.chimes(zone) {
+            (
+                Beeper::new_intermittent(Duration::from_millis(200), Duration::from_millis(200)),
+                CHIME_DURATION,
+            )
+        } else {
+            return;
+        };
+
+        self.sounding = Some((beeper, Instant::now() + duration));
+        self.update_keypad_state();
+    }
+
+    /// Returns whether a zone opening sounds the chime at this keypad: the zone must be a chime
+    /// zone whose areas are all unset, with chime switched on in one of them served here. The
+    /// chime never sounds over the tones of other areas of the keypad, e.g. while exit timing.
+    fn chimes(&self, number: u16) -> bool {
+        let Some(zone) = self
+            .services
+            .zones
+            .iter()
+            .find(|zone| zone.number == number)
+        else {
+            return false;
+        };
+
+        let states = self.alarm.states();
+
+        zone.chime
+            && states.beeper(self.areas) == Beeper::Off
+            && !zone
+                .areas
+                .intersection(self.areas)
+                .intersection(self.alarm.chime())
+                .is_empty()
+            && states
+                .filter(zone.areas, |state| state != SystemState::Unset)
+                .is_empty()
+    }
+
+    /// Passes a key press to the menu, returning the display mode to move to.
+    fn menu_key(&self, key: char) -> DisplayMode {
+        let outcome = self
+            .with_navigator(|navigator, ctx| navigator.key(ctx, key))
+            .unwrap_or(Outcome::Back);
+
+        if outcome == Outcome::Continue {
+            return DisplayMode::Menu;
+        }
+
+        let Some((number, _)) = self.navigator.lock().unwrap().take() else {
+            return DisplayMode::Idle;
+        };
+
+        match (outcome, self.users.get(number)) {
+            (Outcome::Set(mode), Some(user)) => {
+                let areas = self
+                    .alarm
+                    .states()
+                    .filter(user.areas.intersection(self.areas), |state| {
+                        state == SystemState::Unset
+                    });
+                self.perform(user, Action::Set(mode), areas)
             }
+            _ => DisplayMode::Idle,
+        }
+    }
+
+    fn code_rejected(&self) -> DisplayMode {
+        let mut failed_attempts = self.failed_attempts.lock().unwrap();
+        *failed_attempts += 1;
+
+        warn!("Invalid code entered ({} consecutive)", *failed_attempts);
+        let _ = self
+            .event_ch
+            .send(super::Event(super::EventType::CodeRejected {
+                attempts: *failed_attempts,
+            }));
+
+        if *failed_attempts < MAX_CODE_ATTEMPTS {
+            return DisplayMode::Idle;
+        }
+
+        *failed_attempts = 0;
+        let _ = self
+            .event_ch
+            .send(super::Event(super::EventType::LockedOut));
+        self.alarm
+            .send(alarm::Command::Tamper { areas: self.areas });
+
+        DisplayMode::LockedOut {
+            until: Instant::now() + LOCKOUT_DURATION,
         }
     }
 
@@ -135,9 +666,19 @@ impl KeypadManager {
         let mut state = self.state.lock().unwrap();
         let mut acc = self.accumulator.lock().unwrap();
 
+        if let DisplayMode::LockedOut { until } = *state {
+            if Instant::now() < until {
+                return *state;
+            }
+
+            *state = DisplayMode::Idle;
+        }
+
         match event.0 {
             EventType::KeyPress(key) => {
-                if key == 'X' {
+                if *state == DisplayMode::Menu {
+                    *state = self.menu_key(key);
+                } else if key == 'X' {
                     *state = DisplayMode::Idle;
                 } else if *state == DisplayMode::Idle && key != 'X' {
                     let mut s = String::with_capacity(16);
@@ -145,11 +686,38 @@ impl KeypadManager {
 
                     *state = DisplayMode::CodeEntry;
                     *acc = Some(s);
+                    *self.fob.lock().unwrap() = None;
                 } else if *state == DisplayMode::CodeEntry {
-                    acc.as_mut().unwrap().push(key);
-
-                    if acc.as_ref().unwrap() == "1234E" {
-                        *state = DisplayMode::Menu;
+                    match key {
+                        'A' | 'B' | 'E' => {
+                            let code = acc.take().unwrap();
+                            let fob = self.fob.lock().unwrap().take();
+                            *state = self.enter_code(&code, fob, key);
+                        }
+                        _ => acc.as_mut().unwrap().push(key),
+                    }
+                } else if let DisplayMode::AreaSelection {
+                    user,
+                    action,
+                    permitted,
+                    ref mut selected,
+                } = *state
+                {
+                    match key {
+                        // Number keys toggle the corresponding area, 1 being A.
+                        '1'..='8' => {
+                            let area = Area::from_index(key as usize - '1' as usize).unwrap();
+                            if permitted.contains(area) {
+                                selected.toggle(area);
+                            }
+                        }
+                        'E' => {
+                            if !selected.is_empty() {
+                                self.alarm.send_as(user, action.command(*selected));
+                            }
+                            *state = DisplayMode::Idle;
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -157,6 +725,42 @@ impl KeypadManager {
 
         *state
     }
+
+    /// Takes a fob presented at the prox reader as the start of code entry, or alongside a code
+    /// being entered, so that the user then chooses the action as they would for a code.
+    fn present_fob(&mut self, id: u32) -> DisplayMode {
+        let mut state = self.state.lock().unwrap();
+        let mut acc = self.accumulator.lock().unwrap();
+
+        if let DisplayMode::LockedOut { until } = *state {
+            if Instant::now() < until {
+                return *state;
+            }
+
+            *state = DisplayMode::Idle;
+        }
+
+        match *state {
+            DisplayMode::Idle => {
+                *state = DisplayMode::CodeEntry;
+                *acc = Some(String::with_capacity(16));
+                *self.fob.lock().unwrap() = Some(id);
+            }
+            DisplayMode::CodeEntry => *self.fob.lock().unwrap() = Some(id),
+            _ => {}
+        }
+
+        *state
+    }
+}
+
+/// Runs `f`, which may block for some time, handing the other tasks on this worker to the rest of
+/// the runtime meanwhile if it has other workers to take them.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    match runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(runtime::RuntimeFlavor::MultiThread) => task::block_in_place(f),
+        _ => f(),
+    }
 }
 
 mod backlight_responder {
@@ -181,6 +785,7 @@ mod backlight_responder {
     impl BacklightResponder {
         pub fn new(
             controller: Box<dyn Fn(Backlight) + Send + Sync>,
+            timeout: Duration,
         ) -> (BacklightResponder, mpsc::UnboundedSender<DisplayMode>) {
             let (tx, rx) = mpsc::unbounded_channel();
 
@@ -189,7 +794,7 @@ mod backlight_responder {
                     rx,
                     last_state: None,
                     backlight_control: Arc::new(controller),
-                    timeout: Duration::from_secs(5),
+                    timeout,
                 },
                 tx,
             )
@@ -198,52 +803,45 @@ mod backlight_responder {
         pub async fn run(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
             let mut cancel_token: Option<oneshot::Sender<()>> = None;
 
-            loop {
-                match self.rx.recv().await {
-                    Some(display_mode) => {
-                        if self
-                            .last_state
-                            .is_some_and(|last_state| last_state == display_mode)
-                        {
-                            continue;
-                        }
+            while let Some(display_mode) = self.rx.recv().await {
+                if self
+                    .last_state
+                    .is_some_and(|last_state| last_state == display_mode)
+                {
+                    continue;
+                }
 
-                        self.last_state = Some(display_mode);
+                self.last_state = Some(display_mode);
 
-                        if let Some(cancel_token) = cancel_token.take() {
-                            debug!(
-                                "BacklightResponder: cancelling last task as entering new state"
-                            );
-                            let _ = cancel_token.send(());
-                        }
+                if let Some(cancel_token) = cancel_token.take() {
+                    debug!("BacklightResponder: cancelling last task as entering new state");
+                    let _ = cancel_token.send(());
+                }
 
-                        match display_mode {
-                            DisplayMode::Idle => {
-                                // Start a timer to switch off the backlight after a period of time.
-                                let (cancel_tx, cancel_rx) = oneshot::channel();
-                                cancel_token = Some(cancel_tx);
-
-                                let backlight_control = self.backlight_control.clone();
-                                debug!("BacklightResponder: spawning task to toggle backlight state after quiescent period");
-
-                                let timeout_duration = self.timeout.clone();
-
-                                tokio::spawn(async move {
-                                    tokio::select! {
-                                        _ = time::sleep(timeout_duration) => {
-                                            debug!("BacklightResponder: toggling backlight as timer fired");
-                                            backlight_control(Backlight::Off);
-                                        }
-                                        _ = cancel_rx => {},
-                                    }
-                                });
+                // In any state change away from Idle, the cancel_token was already cancelled
+                // above.
+                if display_mode == DisplayMode::Idle {
+                    // Start a timer to switch off the backlight after a period of time.
+                    let (cancel_tx, cancel_rx) = oneshot::channel();
+                    cancel_token = Some(cancel_tx);
+
+                    let backlight_control = self.backlight_control.clone();
+                    debug!(
+                        "BacklightResponder: spawning task to toggle backlight state after \
+                         quiescent period"
+                    );
+
+                    let timeout_duration = self.timeout;
+
+                    tokio::spawn(async move {
+                        tokio::select! {
+                            _ = time::sleep(timeout_duration) => {
+                                debug!("BacklightResponder: toggling backlight as timer fired");
+                                backlight_control(Backlight::Off);
                             }
-                            // In any state change away from Idle, the cancel_token was already
-                            // cancelled earlier.
-                            _ => {}
+                            _ = cancel_rx => {},
                         }
-                    }
-                    None => break,
+                    });
                 }
             }
 
@@ -252,6 +850,11 @@ mod backlight_responder {
     }
 
     #[cfg(test)]
+    #[allow(
+        clippy::bool_assert_comparison,
+        clippy::type_complexity,
+        unused_variables
+    )]
     mod tests {
         use std::sync::{Arc, Mutex};
         use tokio::{sync::Notify, time};
@@ -270,10 +873,13 @@ mod backlight_responder {
                 let state = tx_backlight_state.clone();
                 let notify = notify.clone();
 
-                BacklightResponder::new(Box::new(move |new_state| {
-                    *state.lock().unwrap() = Some(new_state);
-                    notify.notify_waiters();
-                }))
+                BacklightResponder::new(
+                    Box::new(move |new_state| {
+                        *state.lock().unwrap() = Some(new_state);
+                        notify.notify_waiters();
+                    }),
+                    Duration::from_secs(5),
+                )
             };
 
             (tx_backlight_state, notify, backlight_responder, tx)
@@ -334,7 +940,8 @@ mod backlight_responder {
 
         #[tokio::test]
         async fn test_receiver_closes_loop_breaks() {
-            let (mut backlight_responder, tx) = BacklightResponder::new(Box::new(|_| {}));
+            let (mut backlight_responder, tx) =
+                BacklightResponder::new(Box::new(|_| {}), Duration::from_secs(5));
 
             drop(tx); // simulate receiver closing
 
@@ -366,3 +973,338 @@ fn interval_at_next_minute() -> Interval {
 
     interval_at(start_instant, Duration::from_secs(60))
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        alarm::{manager::AlarmManager, FaultKind, Module, Timers},
+        keypad,
+        serial::manager::{self as serial, DeviceStatus},
+        users::{PinHash, User, Validity},
+        zones::{EolScheme, Zone, ZoneInput, ZoneType},
+    };
+
+    fn user(number: u16, pin: &str, level: AccessLevel) -> User {
+        User {
+            number,
+            name: format!("USER {}", number),
+            pin: PinHash::with_rounds(pin, 1_000),
+            fob: None,
+            fob_with_pin: false,
+            level,
+            areas: Areas::ALL,
+            validity: Validity::default(),
+        }
+    }
+
+    fn keypad_manager() -> (KeypadManager, AlarmHandle) {
+        let (manager, alarm, _) = supervising_keypad_manager(vec![]);
+        (manager, alarm)
+    }
+
+    /// Returns a keypad manager whose alarm supervises the given modules, with the sender through
+    /// which their status changes are fed in.
+    fn supervising_keypad_manager(
+        modules: Vec<Module>,
+    ) -> (KeypadManager, AlarmHandle, broadcast::Sender<serial::Event>) {
+        let (zone_tx, zone_rx) = broadcast::channel(1);
+        let (device_tx, device_rx) = broadcast::channel(10);
+        let (mut alarm_manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        alarm_manager.supervise(modules, device_rx);
+        tokio::spawn(async move {
+            // The manager stops if its zone event source goes away.
+            let _zone_tx = zone_tx;
+            alarm_manager.run().await
+        });
+
+        let mut fob_user = user(1, "1234", AccessLevel::USER);
+        fob_user.fob = Some(1001);
+        let mut fob_and_pin_user = user(4, "4444", AccessLevel::USER);
+        fob_and_pin_user.fob = Some(4004);
+        fob_and_pin_user.fob_with_pin = true;
+
+        let users = UserStore::new(vec![
+            fob_user,
+            user(2, "5555", AccessLevel::SET_ONLY),
+            user(3, "7777", AccessLevel::MANAGER),
+            fob_and_pin_user,
+        ])
+        .unwrap();
+
+        (
+            KeypadManager::new(
+                Arc::new(SerialKeypad::new()),
+                Areas::single(Area::A),
+                alarm.clone(),
+                Arc::new(users),
+            ),
+            alarm,
+            device_tx,
+        )
+    }
+
+    fn enter(manager: &mut KeypadManager, keys: &str) -> DisplayMode {
+        keys.chars()
+            .map(|key| manager.process_event(Event(EventType::KeyPress(key))))
+            .last()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_access_level_gates_unset() {
+        time::pause();
+
+        let (mut manager, alarm) = keypad_manager();
+        let mut state_rx = alarm.subscribe_state();
+
+        assert!(enter(&mut manager, "5555A") == DisplayMode::Idle);
+        state_rx.changed().await.unwrap();
+        assert!(alarm.state(Area::A).is_set());
+
+        // A set-only user can't unset.
+        enter(&mut manager, "5555E");
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(alarm.state(Area::A).is_set());
+
+        enter(&mut manager, "1234E");
+        state_rx.changed().await.unwrap();
+        assert_eq!(alarm.state(Area::A), SystemState::Unset);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_code_checked_on_multi_threaded_runtime() {
+        let (mut manager, alarm) = keypad_manager();
+        let mut state_rx = alarm.subscribe_state();
+
+        assert!(enter(&mut manager, "1234A") == DisplayMode::Idle);
+        state_rx.changed().await.unwrap();
+        assert!(alarm.state(Area::A).is_set());
+    }
+
+    #[tokio::test]
+    async fn test_fob_in_place_of_or_with_code() {
+        time::pause();
+
+        let (mut manager, alarm) = keypad_manager();
+        let mut state_rx = alarm.subscribe_state();
+
+        assert!(manager.present_fob(1001) == DisplayMode::CodeEntry);
+        assert!(enter(&mut manager, "A") == DisplayMode::Idle);
+        state_rx.changed().await.unwrap();
+        assert!(alarm.state(Area::A).is_set());
+
+        // This user's fob needs their code too, before or after presenting it.
+        manager.present_fob(4004);
+        enter(&mut manager, "E");
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(alarm.state(Area::A).is_set());
+        assert!(enter(&mut manager, "4444E") == DisplayMode::Idle);
+        assert!(alarm.state(Area::A).is_set());
+
+        enter(&mut manager, "4444");
+        manager.present_fob(4004);
+        enter(&mut manager, "E");
+        state_rx.changed().await.unwrap();
+        assert_eq!(alarm.state(Area::A), SystemState::Unset);
+
+        // A code given with a fob must be the fob holder's own.
+        manager.present_fob(1001);
+        enter(&mut manager, "5555A");
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(alarm.state(Area::A), SystemState::Unset);
+        assert_eq!(*manager.failed_attempts.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_part_set_from_menu() {
+        time::pause();
+
+        let (mut manager, alarm) = keypad_manager();
+        let mut state_rx = alarm.subscribe_state();
+
+        // A set-only user can't enter the menu.
+        assert!(enter(&mut manager, "5555E") == DisplayMode::Idle);
+
+        assert!(enter(&mut manager, "1234E") == DisplayMode::Menu);
+        assert!(enter(&mut manager, "EX") == DisplayMode::Menu);
+        assert!(enter(&mut manager, "13E") == DisplayMode::Idle);
+
+        state_rx.changed().await.unwrap();
+        assert_eq!(alarm.state(Area::A), SystemState::ExitTiming(SetMode::Part));
+    }
+
+    #[tokio::test]
+    async fn test_walk_test_chirps_zones_opened() {
+        time::pause();
+
+        let (manager, _alarm) = keypad_manager();
+        let zone = Zone {
+            number: 1001,
+            name: "FRONT DOOR".to_string(),
+            zone_type: ZoneType::Final,
+            scheme: EolScheme::OneKOneK,
+            input: ZoneInput {
+                device: 0x20,
+                input: 1,
+            },
+            part_set: true,
+            chime: false,
+            areas: Areas::single(Area::A),
+        };
+        let mut manager = manager.with_services(Services {
+            zones: Arc::new(vec![zone]),
+            ..Default::default()
+        });
+
+        // Openings are ignored outside the walk test.
+        manager.zone_opened(1001);
+        assert_eq!(manager.sounding, None);
+
+        enter(&mut manager, "7777E");
+        assert!(enter(&mut manager, "24E") == DisplayMode::Menu);
+        manager.zone_opened(1002);
+        assert_eq!(manager.sounding, None);
+        manager.zone_opened(1001);
+        assert_eq!(
+            manager.sounding,
+            Some((Beeper::On, Instant::now() + CHIRP_DURATION))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chime_zones_chime_while_unset() {
+        time::pause();
+
+        let (manager, alarm) = keypad_manager();
+        let zone = |number, chime| Zone {
+            number,
+            name: format!("ZONE {}", number),
+            zone_type: ZoneType::Intruder,
+            scheme: EolScheme::OneKOneK,
+            input: ZoneInput {
+                device: 0x20,
+                input: (number % 10) as u8,
+            },
+            part_set: true,
+            chime,
+            areas: Areas::single(Area::A),
+        };
+        let mut manager = manager.with_services(Services {
+            zones: Arc::new(vec![zone(1001, true), zone(1002, false)]),
+            ..Default::default()
+        });
+
+        // Chime is off until switched on.
+        manager.zone_opened(1001);
+        assert_eq!(manager.sounding, None);
+
+        alarm.send(alarm::Command::Chime {
+            areas: Areas::single(Area::A),
+            chime: true,
+        });
+        time::sleep(Duration::from_millis(10)).await;
+        manager.zone_opened(1002);
+        assert_eq!(manager.sounding, None);
+        manager.zone_opened(1001);
+        assert_eq!(
+            manager.sounding,
+            Some((
+                Beeper::new_intermittent(Duration::from_millis(200), Duration::from_millis(200)),
+                Instant::now() + CHIME_DURATION
+            ))
+        );
+
+        // Nothing chimes once set.
+        manager.sounding = None;
+        let mut state_rx = alarm.subscribe_state();
+        enter(&mut manager, "5555A");
+        state_rx.changed().await.unwrap();
+        manager.zone_opened(1001);
+        assert_eq!(manager.sounding, None);
+    }
+
+    #[tokio::test]
+    async fn test_manager_acknowledges_restored_fault() {
+        time::pause();
+
+        let (mut manager, alarm, device_tx) = supervising_keypad_manager(vec![Module {
+            address: 0x20,
+            areas: Areas::single(Area::A),
+            tamper: false,
+        }]);
+        let mut faults_rx = alarm.subscribe_faults();
+
+        for status in [DeviceStatus::Offline, DeviceStatus::OnlineOK] {
+            device_tx
+                .send(serial::Event(serial::EventType::StatusChanged {
+                    address: 0x20,
+                    status,
+                    previous: DeviceStatus::Unknown,
+                }))
+                .unwrap();
+        }
+        faults_rx
+            .wait_for(|faults| faults.first().is_some_and(|fault| fault.restored))
+            .await
+            .unwrap();
+        assert_eq!(alarm.faults()[0].kind, FaultKind::Missing);
+
+        // A user without manager access goes to the menu as usual.
+        assert!(enter(&mut manager, "1234E") == DisplayMode::Menu);
+        assert!(enter(&mut manager, "X") == DisplayMode::Idle);
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(!alarm.faults().is_empty());
+
+        assert!(enter(&mut manager, "7777E") == DisplayMode::Idle);
+        faults_rx
+            .wait_for(|faults| faults.is_empty())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lockout_after_invalid_codes() {
+        time::pause();
+
+        let (mut manager, alarm) = keypad_manager();
+        let mut event_rx = manager.subscribe_events();
+
+        for attempt in 1..MAX_CODE_ATTEMPTS {
+            assert!(enter(&mut manager, "9999E") == DisplayMode::Idle);
+            assert_eq!(
+                event_rx.recv().await.unwrap().0,
+                keypad::EventType::CodeRejected { attempts: attempt }
+            );
+        }
+
+        assert!(matches!(
+            enter(&mut manager, "9999E"),
+            DisplayMode::LockedOut { .. }
+        ));
+        event_rx.recv().await.unwrap();
+        assert_eq!(
+            event_rx.recv().await.unwrap().0,
+            keypad::EventType::LockedOut
+        );
+
+        let mut state_rx = alarm.subscribe_state();
+        state_rx.changed().await.unwrap();
+        assert_eq!(alarm.state(Area::A), SystemState::Alarm);
+
+        // Even a valid code is ignored until the lockout expires.
+        assert!(matches!(
+            enter(&mut manager, "1234E"),
+            DisplayMode::LockedOut { .. }
+        ));
+
+        time::advance(LOCKOUT_DURATION).await;
+        enter(&mut manager, "1234E");
+        assert_eq!(
+            event_rx.recv().await.unwrap().0,
+            keypad::EventType::CodeAccepted { user: 1 }
+        );
+    }
+}
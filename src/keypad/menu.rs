@@ -0,0 +1,1054 @@
+use chrono::Local;
+use log::error;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    alarm::{AlarmHandle, Command, SetMode, SystemState},
+    areas::Areas,
+    eventlog::{EventKind, EventLog, EventLogError, Filter},
+    serial::devices::psu::{Condition, SerialPsu},
+    users::{AccessLevel, User, UserStore},
+    zones::Zone,
+};
+
+// Width of a line of the keypad display.
+const LINE_WIDTH: usize = 16;
+
+fn fit(line: String) -> String {
+    line.chars().take(LINE_WIDTH).collect()
+}
+
+/// Services are the parts of the system made available to menu options, beyond the alarm and
+/// the user store.
+#[derive(Clone, Default)]
+pub struct Services {
+    pub zones: Arc<Vec<Zone>>,
+    pub event_log: Option<Arc<Mutex<EventLog>>>,
+    // Smart PSUs on the bus, by address.
+    pub psus: BTreeMap<u8, Arc<SerialPsu>>,
+}
+
+/// Context gives menu options access to the system on behalf of the user in the menu.
+pub struct Context<'a> {
+    pub user: &'a User,
+    // Areas the user may act on from this keypad.
+    pub areas: Areas,
+    pub alarm: &'a AlarmHandle,
+    pub users: &'a UserStore,
+    pub services: &'a Services,
+}
+
+/// Outcome is the result of a key press within the menu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // Stay where we are.
+    Continue,
+    // Leave the current option, or the menu when at the top level.
+    Back,
+    // Leave the menu and set the user's areas.
+    Set(SetMode),
+}
+
+/// Session is an option in use, which receives key presses until it returns Back.
+pub trait Session: Send {
+    fn screen(&self, ctx: &Context) -> [String; 2];
+    fn key(&mut self, ctx: &Context, key: char) -> Outcome;
+
+    /// Notifies the session of a zone opening, returning whether the keypad should chirp.
+    fn zone_opened(&mut self, _ctx: &Context, _zone: u16) -> bool {
+        false
+    }
+}
+
+pub enum Opened {
+    Session(Box<dyn Session>),
+    // The option acts immediately on selection, without a session.
+    Done(Outcome),
+}
+
+/// MenuOption is the behaviour behind a selectable menu entry.
+pub trait MenuOption: Send + Sync {
+    fn open(&self, ctx: &Context) -> Opened;
+}
+
+pub enum Node {
+    Group(Vec<MenuItem>),
+    Option(Box<dyn MenuOption>),
+}
+
+/// MenuItem is an entry in the menu tree, numbered following Galaxy conventions so that it can
+/// be reached directly by keying its number.
+pub struct MenuItem {
+    pub number: u8,
+    pub title: &'static str,
+    // Minimum access level of a user to see and select the item.
+    pub level: AccessLevel,
+    pub node: Node,
+}
+
+pub fn group(
+    number: u8,
+    title: &'static str,
+    level: AccessLevel,
+    items: Vec<MenuItem>,
+) -> MenuItem {
+    MenuItem {
+        number,
+        title,
+        level,
+        node: Node::Group(items),
+    }
+}
+
+pub fn option(
+    number: u8,
+    title: &'static str,
+    level: AccessLevel,
+    option: impl MenuOption + 'static,
+) -> MenuItem {
+    MenuItem {
+        number,
+        title,
+        level,
+        node: Node::Option(Box::new(option)),
+    }
+}
+
+pub struct Menu {
+    items: Vec<MenuItem>,
+}
+
+impl Menu {
+    pub fn new(items: Vec<MenuItem>) -> Menu {
+        Menu { items }
+    }
+
+    /// Returns the standard menu tree.
+    pub fn galaxy() -> Menu {
+        Menu::new(vec![
+            group(
+                10,
+                "SETTING",
+                AccessLevel::USER,
+                vec![
+                    option(11, "OMIT ZONES", AccessLevel::USER, OmitZones),
+                    option(13, "PART SET", AccessLevel::USER, SetOption(SetMode::Part)),
+                    option(15, "CHIME", AccessLevel::USER, Chime),
+                ],
+            ),
+            group(
+                20,
+                "VIEW",
+                AccessLevel::USER,
+                vec![
+                    option(21, "VIEW LOG", AccessLevel::USER, ViewLog),
+                    option(24, "WALK TEST", AccessLevel::MANAGER, WalkTest),
+                ],
+            ),
+            group(
+                40,
+                "MODIFY",
+                AccessLevel::MANAGER,
+                vec![option(42, "CODES", AccessLevel::MANAGER, ViewCodes)],
+            ),
+            group(
+                50,
+                "ENGINEER",
+                AccessLevel::ENGINEER,
+                vec![option(
+                    52,
+                    "PROGRAM ZONES",
+                    AccessLevel::ENGINEER,
+                    ViewZones,
+                )],
+            ),
+            group(
+                60,
+                "ENGINEER 2",
+                AccessLevel::ENGINEER,
+                vec![option(
+                    61,
+                    "DIAGNOSTICS",
+                    AccessLevel::ENGINEER,
+                    Diagnostics,
+                )],
+            ),
+        ])
+    }
+
+    /// Returns the path of indices to the item with the given number, if the user can reach it.
+    fn find(&self, number: u8, level: AccessLevel) -> Option<Vec<usize>> {
+        fn search(items: &[MenuItem], number: u8, level: AccessLevel) -> Option<Vec<usize>> {
+            for (i, item) in items.iter().enumerate() {
+                if item.level > level {
+                    continue;
+                }
+
+                if item.number == number {
+                    return Some(vec![i]);
+                }
+
+                if let Node::Group(children) = &item.node {
+                    if let Some(mut path) = search(children, number, level) {
+                        path.insert(0, i);
+                        return Some(path);
+                    }
+                }
+            }
+
+            None
+        }
+
+        search(&self.items, number, level)
+    }
+}
+
+/// Navigator is a user's position within the menu. A scrolls forward and B back through the
+/// items at the current level, ent selects, esc goes back up a level, and two digits jump
+/// straight to the item with that number.
+pub struct Navigator {
+    menu: Arc<Menu>,
+    // Index of the item selected at each level, from the top.
+    path: Vec<usize>,
+    // First digit of a shortcut being entered.
+    digit: Option<char>,
+    session: Option<Box<dyn Session>>,
+}
+
+fn first_accessible(items: &[MenuItem], level: AccessLevel) -> Option<usize> {
+    items.iter().position(|item| item.level <= level)
+}
+
+impl Navigator {
+    /// Enters the menu, or returns None if the user can't access any of it.
+    pub fn new(menu: Arc<Menu>, level: AccessLevel) -> Option<Navigator> {
+        let first = first_accessible(&menu.items, level)?;
+
+        Some(Navigator {
+            menu,
+            path: vec![first],
+            digit: None,
+            session: None,
+        })
+    }
+
+    // Returns the items at the current level.
+    fn items(&self) -> &[MenuItem] {
+        let mut items = &self.menu.items[..];
+        for &i in &self.path[..self.path.len() - 1] {
+            match &items[i].node {
+                Node::Group(children) => items = children,
+                Node::Option(_) => unreachable!("menu path passes through an option"),
+            }
+        }
+
+        items
+    }
+
+    fn current(&self) -> &MenuItem {
+        &self.items()[*self.path.last().unwrap()]
+    }
+
+    fn scroll(&mut self, forward: bool, level: AccessLevel) {
+        let len = self.items().len();
+        let mut i = *self.path.last().unwrap();
+
+        for _ in 0..len {
+            i = if forward {
+                (i + 1) % len
+            } else {
+                (i + len - 1) % len
+            };
+            if self.items()[i].level <= level {
+                break;
+            }
+        }
+
+        *self.path.last_mut().unwrap() = i;
+    }
+
+    pub fn screen(&self, ctx: &Context) -> [String; 2] {
+        if let Some(session) = &self.session {
+            return session.screen(ctx);
+        }
+
+        let item = self.current();
+
+        [
+            fit(format!("{:02}={}", item.number, item.title)),
+            match self.digit {
+                Some(digit) => format!("{}_", digit),
+                None => "[ent] to select".to_string(),
+            },
+        ]
+    }
+
+    pub fn key(&mut self, ctx: &Context, key: char) -> Outcome {
+        let level = ctx.user.level;
+
+        if let Some(session) = &mut self.session {
+            return match session.key(ctx, key) {
+                Outcome::Back => {
+                    self.session = None;
+                    Outcome::Continue
+                }
+                outcome => outcome,
+            };
+        }
+
+        match key {
+            'A' => self.scroll(true, level),
+            'B' => self.scroll(false, level),
+            'E' => match &self.current().node {
+                Node::Group(children) => {
+                    if let Some(first) = first_accessible(children, level) {
+                        self.path.push(first);
+                    }
+                }
+                Node::Option(option) => match option.open(ctx) {
+                    Opened::Session(session) => self.session = Some(session),
+                    Opened::Done(outcome) => return outcome,
+                },
+            },
+            'X' => {
+                self.digit = None;
+
+                if self.path.len() == 1 {
+                    return Outcome::Back;
+                }
+                self.path.pop();
+            }
+            '0'..='9' => match self.digit.take() {
+                None => self.digit = Some(key),
+                Some(first) => {
+                    let number = format!("{}{}", first, key).parse().unwrap();
+                    if let Some(path) = self.menu.find(number, level) {
+                        self.path = path;
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    /// Passes a zone opening to the option in use, returning whether the keypad should chirp.
+    pub fn zone_opened(&mut self, ctx: &Context, zone: u16) -> bool {
+        self.session
+            .as_mut()
+            .is_some_and(|session| session.zone_opened(ctx, zone))
+    }
+}
+
+/// ListSession scrolls through a list of items with A and B.
+struct ListSession<T> {
+    items: Vec<T>,
+    index: usize,
+    empty: &'static str,
+    render: fn(&T) -> [String; 2],
+}
+
+impl<T: Send> Session for ListSession<T> {
+    fn screen(&self, _: &Context) -> [String; 2] {
+        match self.items.get(self.index) {
+            Some(item) => (self.render)(item).map(fit),
+            None => [self.empty.to_string(), "".to_string()],
+        }
+    }
+
+    fn key(&mut self, _: &Context, key: char) -> Outcome {
+        match key {
+            'A' if self.index + 1 < self.items.len() => self.index += 1,
+            'B' => self.index = self.index.saturating_sub(1),
+            'X' => return Outcome::Back,
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+}
+
+struct SetOption(SetMode);
+
+impl MenuOption for SetOption {
+    fn open(&self, _: &Context) -> Opened {
+        Opened::Done(Outcome::Set(self.0))
+    }
+}
+
+/// Shows whether chime zones sound in the user's areas while unset, switching chime on or off
+/// with ent.
+struct Chime;
+
+struct ChimeSession {
+    on: bool,
+}
+
+impl MenuOption for Chime {
+    fn open(&self, ctx: &Context) -> Opened {
+        Opened::Session(Box::new(ChimeSession {
+            on: !ctx.areas.is_empty() && ctx.alarm.chime().intersection(ctx.areas) == ctx.areas,
+        }))
+    }
+}
+
+impl Session for ChimeSession {
+    fn screen(&self, _: &Context) -> [String; 2] {
+        [
+            "CHIME".to_string(),
+            if self.on { "ON" } else { "OFF" }.to_string(),
+        ]
+    }
+
+    fn key(&mut self, ctx: &Context, key: char) -> Outcome {
+        match key {
+            'E' => {
+                self.on = !self.on;
+                ctx.alarm.send_as(
+                    ctx.user.number,
+                    Command::Chime {
+                        areas: ctx.areas,
+                        chime: self.on,
+                    },
+                );
+            }
+            'X' => return Outcome::Back,
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+}
+
+/// Shows the most recent events first, scrolling back in time with A.
+struct ViewLog;
+
+impl MenuOption for ViewLog {
+    fn open(&self, ctx: &Context) -> Opened {
+        let (mut entries, empty) = match &ctx.services.event_log {
+            Some(log) => match log.lock().unwrap().query(&Filter::default()) {
+                Ok(entries) => (entries, "LOG EMPTY"),
+                // Nothing is shown from a log that can't be trusted.
+                Err(e @ EventLogError::Tampered { .. }) => {
+                    error!("Unable to view event log: {}", e);
+                    (vec![], "LOG TAMPERED")
+                }
+                Err(e) => {
+                    error!("Unable to view event log: {}", e);
+                    (vec![], "LOG ERROR")
+                }
+            },
+            None => (vec![], "LOG EMPTY"),
+        };
+        entries.reverse();
+
+        Opened::Session(Box::new(ListSession {
+            items: entries,
+            index: 0,
+            empty,
+            render: |entry| {
+                [
+                    entry.event.to_string(),
+                    format!(
+                        "{} {}",
+                        entry.time.with_timezone(&Local).format("%d/%m %H:%M"),
+                        entry.event.attribution().unwrap_or_default()
+                    ),
+                ]
+            },
+        }))
+    }
+}
+
+// Users are defined in the configuration file, so are only viewed from the keypad.
+struct ViewCodes;
+
+impl MenuOption for ViewCodes {
+    fn open(&self, ctx: &Context) -> Opened {
+        Opened::Session(Box::new(ListSession {
+            items: ctx.users.users().to_vec(),
+            index: 0,
+            empty: "NO CODES",
+            render: |user| {
+                [
+                    format!("{:03} {}", user.number, user.name),
+                    format!("LEVEL {} {}", user.level, user.areas),
+                ]
+            },
+        }))
+    }
+}
+
+// Zones are defined in the configuration file, so are only viewed from the keypad.
+struct ViewZones;
+
+impl MenuOption for ViewZones {
+    fn open(&self, ctx: &Context) -> Opened {
+        Opened::Session(Box::new(ListSession {
+            items: ctx.services.zones.to_vec(),
+            index: 0,
+            empty: "NO ZONES",
+            render: |zone| {
+                [
+                    format!("{} {}", zone.number, zone.zone_type),
+                    zone.name.clone(),
+                ]
+            },
+        }))
+    }
+}
+
+/// Shows the readings of each smart PSU, as they change.
+struct Diagnostics;
+
+impl MenuOption for Diagnostics {
+    fn open(&self, ctx: &Context) -> Opened {
+        Opened::Session(Box::new(ListSession {
+            items: ctx
+                .services
+                .psus
+                .iter()
+                .map(|(&address, psu)| (address, psu.clone()))
+                .collect(),
+            index: 0,
+            empty: "NO PSUS",
+            render: |(address, psu)| match psu.status() {
+                Some(status) => [
+                    format!("PSU {:02X} BAT {:.1}V", address, volts(status.battery_mv)),
+                    format!(
+                        "OUT {:.1}V AC {}",
+                        volts(status.output_mv),
+                        if status.is_active(Condition::MainsFail) {
+                            "OFF"
+                        } else {
+                            "ON"
+                        }
+                    ),
+                ],
+                None => [format!("PSU {:02X}", address), "NOT RESPONDING".to_string()],
+            },
+        }))
+    }
+}
+
+fn volts(mv: u16) -> f32 {
+    mv as f32 / 1000.0
+}
+
+/// Lists the zones the user may omit, toggling the omit of the zone shown with ent.
+struct OmitZones;
+
+struct OmitZonesSession {
+    zones: Vec<Zone>,
+    index: usize,
+    omitted: BTreeSet<u16>,
+}
+
+impl MenuOption for OmitZones {
+    fn open(&self, ctx: &Context) -> Opened {
+        let zones = ctx
+            .services
+            .zones
+            .iter()
+            .filter(|zone| {
+                zone.zone_type.can_omit()
+                    && !zone.areas.is_empty()
+                    && zone.areas.intersection(ctx.areas) == zone.areas
+            })
+            .cloned()
+            .collect();
+
+        Opened::Session(Box::new(OmitZonesSession {
+            zones,
+            index: 0,
+            omitted: ctx.alarm.omitted(),
+        }))
+    }
+}
+
+impl Session for OmitZonesSession {
+    fn screen(&self, _: &Context) -> [String; 2] {
+        match self.zones.get(self.index) {
+            Some(zone) => [
+                fit(format!("{} {}", zone.number, zone.name)),
+                if self.omitted.contains(&zone.number) {
+                    "OMITTED"
+                } else {
+                    "NOT OMITTED"
+                }
+                .to_string(),
+            ],
+            None => ["NO ZONES".to_string(), "".to_string()],
+        }
+    }
+
+    fn key(&mut self, ctx: &Context, key: char) -> Outcome {
+        match key {
+            'A' if self.index + 1 < self.zones.len() => self.index += 1,
+            'B' => self.index = self.index.saturating_sub(1),
+            'E' => {
+                let Some(zone) = self.zones.get(self.index) else {
+                    return Outcome::Continue;
+                };
+
+                // The alarm only accepts omits while the zone's areas are unset.
+                let states = ctx.alarm.states();
+                if !states
+                    .filter(zone.areas, |state| state != SystemState::Unset)
+                    .is_empty()
+                {
+                    return Outcome::Continue;
+                }
+
+                let omit = !self.omitted.contains(&zone.number);
+                ctx.alarm.send_as(
+                    ctx.user.number,
+                    Command::Omit {
+                        zone: zone.number,
+                        omit,
+                    },
+                );
+
+                if omit {
+                    self.omitted.insert(zone.number);
+                } else {
+                    self.omitted.remove(&zone.number);
+                }
+            }
+            'X' => return Outcome::Back,
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+}
+
+/// Proves the detectors in the user's areas. Each zone opened is announced and chirped, and
+/// recorded as tested. A and B scroll through the zones yet to be tested, and esc ends the test,
+/// recording the result in the event log.
+struct WalkTest;
+
+struct WalkTestSession {
+    zones: Vec<Zone>,
+    tested: BTreeSet<u16>,
+    // Zone last opened, announced until the untested zones are scrolled through.
+    last: Option<usize>,
+    // Position within the untested zones, while scrolling through them.
+    browsing: Option<usize>,
+}
+
+impl MenuOption for WalkTest {
+    fn open(&self, ctx: &Context) -> Opened {
+        let zones = ctx
+            .services
+            .zones
+            .iter()
+            .filter(|zone| !zone.areas.intersection(ctx.areas).is_empty())
+            .cloned()
+            .collect();
+
+        Opened::Session(Box::new(WalkTestSession {
+            zones,
+            tested: BTreeSet::new(),
+            last: None,
+            browsing: None,
+        }))
+    }
+}
+
+impl WalkTestSession {
+    fn untested(&self) -> Vec<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| !self.tested.contains(&zone.number))
+            .collect()
+    }
+
+    fn progress(&self) -> String {
+        format!("TESTED {}/{}", self.tested.len(), self.zones.len())
+    }
+
+    fn finish(&self, ctx: &Context) {
+        let Some(log) = &ctx.services.event_log else {
+            return;
+        };
+
+        let event = EventKind::WalkTest {
+            user: ctx.user.number,
+            tested: self.tested.iter().copied().collect(),
+            untested: self.untested().iter().map(|zone| zone.number).collect(),
+        };
+        if let Err(e) = log.lock().unwrap().append(event) {
+            error!("Unable to record walk test: {}", e);
+        }
+    }
+}
+
+impl Session for WalkTestSession {
+    fn screen(&self, _: &Context) -> [String; 2] {
+        if let Some(index) = self.browsing {
+            let untested = self.untested();
+
+            return match untested.get(index) {
+                Some(zone) => [
+                    fit(format!("{} {}", zone.number, zone.name)),
+                    format!("UNTESTED {}/{}", index + 1, untested.len()),
+                ],
+                None => ["ALL TESTED".to_string(), self.progress()],
+            };
+        }
+
+        match self.last.map(|index| &self.zones[index]) {
+            Some(zone) => [
+                fit(format!("{} {}", zone.number, zone.name)),
+                self.progress(),
+            ],
+            None => ["WALK TEST".to_string(), self.progress()],
+        }
+    }
+
+    fn key(&mut self, ctx: &Context, key: char) -> Outcome {
+        match (key, self.browsing) {
+            ('A', None) => self.browsing = Some(0),
+            ('A', Some(index)) if index + 1 < self.untested().len() => {
+                self.browsing = Some(index + 1)
+            }
+            ('B', Some(index)) => self.browsing = Some(index.saturating_sub(1)),
+            ('X', Some(_)) => self.browsing = None,
+            ('X', None) => {
+                self.finish(ctx);
+                return Outcome::Back;
+            }
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    fn zone_opened(&mut self, _: &Context, zone: u16) -> bool {
+        let Some(index) = self.zones.iter().position(|z| z.number == zone) else {
+            return false;
+        };
+
+        self.tested.insert(zone);
+        self.last = Some(index);
+        self.browsing = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{
+        alarm::{manager::AlarmManager, Timers},
+        serial::{devices::psu, SerialDevice, SerialMessage},
+        users::{PinHash, Validity},
+        zones::{EolScheme, ZoneInput, ZoneType},
+    };
+
+    struct Probe;
+
+    impl MenuOption for Probe {
+        fn open(&self, _: &Context) -> Opened {
+            Opened::Done(Outcome::Set(SetMode::Full))
+        }
+    }
+
+    fn user(level: AccessLevel) -> User {
+        User {
+            number: 1,
+            name: "USER".to_string(),
+            pin: PinHash::with_rounds("1234", 1_000),
+            fob: None,
+            fob_with_pin: false,
+            level,
+            areas: Areas::ALL,
+            validity: Validity::default(),
+        }
+    }
+
+    fn menu() -> Arc<Menu> {
+        Arc::new(Menu::new(vec![
+            group(
+                10,
+                "SETTING",
+                AccessLevel::USER,
+                vec![
+                    option(11, "FIRST", AccessLevel::USER, Probe),
+                    option(12, "MANAGER ONLY", AccessLevel::MANAGER, Probe),
+                    option(13, "THIRD", AccessLevel::USER, Probe),
+                ],
+            ),
+            group(
+                50,
+                "ENGINEER",
+                AccessLevel::ENGINEER,
+                vec![option(51, "PARAMETERS", AccessLevel::ENGINEER, Probe)],
+            ),
+        ]))
+    }
+
+    fn navigate(level: AccessLevel, keys: &str) -> (Option<Outcome>, [String; 2]) {
+        let (_, zone_rx) = broadcast::channel(1);
+        let (_manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        let user = user(level);
+        let users = UserStore::default();
+        let services = Services::default();
+        let ctx = Context {
+            user: &user,
+            areas: Areas::ALL,
+            alarm: &alarm,
+            users: &users,
+            services: &services,
+        };
+
+        let mut navigator = Navigator::new(menu(), level).unwrap();
+        let outcome = keys
+            .chars()
+            .map(|key| navigator.key(&ctx, key))
+            .find(|&outcome| outcome != Outcome::Continue);
+
+        (outcome, navigator.screen(&ctx))
+    }
+
+    #[test]
+    fn test_scroll_skips_inaccessible_items() {
+        assert_eq!(navigate(AccessLevel::USER, "").1[0], "10=SETTING");
+        assert_eq!(navigate(AccessLevel::USER, "EA").1[0], "13=THIRD");
+        assert_eq!(navigate(AccessLevel::MANAGER, "EA").1[0], "12=MANAGER ONLY");
+        assert_eq!(navigate(AccessLevel::USER, "EB").1[0], "13=THIRD");
+
+        // The engineer group is hidden from users, so scrolling wraps straight round.
+        assert_eq!(navigate(AccessLevel::USER, "A").1[0], "10=SETTING");
+        assert_eq!(navigate(AccessLevel::ENGINEER, "A").1[0], "50=ENGINEER");
+    }
+
+    #[test]
+    fn test_shortcuts_and_escape() {
+        assert_eq!(navigate(AccessLevel::USER, "13").1[0], "13=THIRD");
+        assert_eq!(navigate(AccessLevel::USER, "1").1[1], "1_");
+        assert_eq!(navigate(AccessLevel::USER, "51").1[0], "10=SETTING");
+        assert_eq!(navigate(AccessLevel::ENGINEER, "51").1[0], "51=PARAMETERS");
+
+        assert_eq!(navigate(AccessLevel::USER, "13X").1[0], "10=SETTING");
+        assert_eq!(navigate(AccessLevel::USER, "13XX").0, Some(Outcome::Back));
+        assert_eq!(
+            navigate(AccessLevel::USER, "11E").0,
+            Some(Outcome::Set(SetMode::Full))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chime_switches_users_areas() {
+        let (_zone_tx, zone_rx) = broadcast::channel(1);
+        let (mut manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        manager.set_chime("B".parse().unwrap());
+        tokio::spawn(async move { manager.run().await });
+
+        let user = user(AccessLevel::USER);
+        let users = UserStore::default();
+        let services = Services::default();
+        let ctx = Context {
+            user: &user,
+            areas: "AB".parse().unwrap(),
+            alarm: &alarm,
+            users: &users,
+            services: &services,
+        };
+
+        // Chime is only shown on when it's on in all of the user's areas.
+        let Opened::Session(mut session) = Chime.open(&ctx) else {
+            panic!("chime opened no session");
+        };
+        assert_eq!(session.screen(&ctx), ["CHIME", "OFF"]);
+
+        let mut chime_rx = alarm.subscribe_chime();
+        chime_rx.borrow_and_update();
+        session.key(&ctx, 'E');
+        assert_eq!(session.screen(&ctx), ["CHIME", "ON"]);
+        chime_rx.changed().await.unwrap();
+        assert_eq!(alarm.chime(), "AB".parse().unwrap());
+
+        session.key(&ctx, 'E');
+        chime_rx.changed().await.unwrap();
+        assert_eq!(alarm.chime(), Areas::NONE);
+        assert_eq!(session.key(&ctx, 'X'), Outcome::Back);
+    }
+
+    #[test]
+    fn test_diagnostics_shows_psu_readings() {
+        let (_, zone_rx) = broadcast::channel(1);
+        let (_manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        let user = user(AccessLevel::ENGINEER);
+        let users = UserStore::default();
+
+        let psu = Arc::new(SerialPsu::new());
+        let services = Services {
+            psus: [(0x70, psu.clone())].into(),
+            ..Default::default()
+        };
+        let ctx = Context {
+            user: &user,
+            areas: Areas::ALL,
+            alarm: &alarm,
+            users: &users,
+            services: &services,
+        };
+
+        let Opened::Session(session) = Diagnostics.open(&ctx) else {
+            panic!("diagnostics opened no session");
+        };
+        assert_eq!(session.screen(&ctx), ["PSU 70", "NOT RESPONDING"]);
+
+        for (command, data) in [
+            (psu::ReplyCommand::Initialised, vec![0x0A, 0x01, 0x05]),
+            (
+                psu::ReplyCommand::Status,
+                vec![0x01, 0x04, 0x7E, 0x05, 0x5B],
+            ),
+        ] {
+            psu.receive_update(Ok(SerialMessage {
+                recipient_address: 0x11,
+                command: command.into(),
+                additional_data: Some(data),
+            }));
+        }
+        assert_eq!(
+            session.screen(&ctx),
+            ["PSU 70 BAT 11.5V", "OUT 13.7V AC OFF"]
+        );
+    }
+
+    #[test]
+    fn test_view_log_shows_tampering() {
+        let (_, zone_rx) = broadcast::channel(1);
+        let (_manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        let user = user(AccessLevel::USER);
+        let users = UserStore::default();
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(Mutex::new(
+            EventLog::open(dir.path(), 100, &[0; 32]).unwrap(),
+        ));
+        log.lock()
+            .unwrap()
+            .append(EventKind::CodeRejected {
+                keypad: 0x10,
+                attempts: 1,
+            })
+            .unwrap();
+        let services = Services {
+            event_log: Some(log),
+            ..Default::default()
+        };
+        let ctx = Context {
+            user: &user,
+            areas: Areas::ALL,
+            alarm: &alarm,
+            users: &users,
+            services: &services,
+        };
+
+        let Opened::Session(session) = ViewLog.open(&ctx) else {
+            panic!("view log opened no session");
+        };
+        assert_ne!(session.screen(&ctx)[0], "LOG EMPTY");
+
+        let segment = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .unwrap();
+        let contents = std::fs::read_to_string(&segment).unwrap();
+        std::fs::write(
+            &segment,
+            contents.replace("\"attempts\":1", "\"attempts\":2"),
+        )
+        .unwrap();
+
+        let Opened::Session(session) = ViewLog.open(&ctx) else {
+            panic!("view log opened no session");
+        };
+        assert_eq!(session.screen(&ctx), ["LOG TAMPERED", ""]);
+    }
+
+    #[test]
+    fn test_walk_test_records_zones_opened() {
+        let (_, zone_rx) = broadcast::channel(1);
+        let (_manager, alarm) = AlarmManager::new(Timers::default(), vec![], zone_rx);
+        let user = user(AccessLevel::MANAGER);
+        let users = UserStore::default();
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(Mutex::new(
+            EventLog::open(dir.path(), 100, &[0; 32]).unwrap(),
+        ));
+        let zone = |number, name: &str, areas: &str| Zone {
+            number,
+            name: name.to_string(),
+            zone_type: ZoneType::Intruder,
+            scheme: EolScheme::OneKOneK,
+            input: ZoneInput {
+                device: 0x20,
+                input: (number % 10) as u8,
+            },
+            part_set: true,
+            chime: false,
+            areas: areas.parse().unwrap(),
+        };
+        let services = Services {
+            zones: Arc::new(vec![
+                zone(1001, "FRONT DOOR", "A"),
+                zone(1002, "LOUNGE", "A"),
+                zone(1003, "BEDROOM", "A"),
+                zone(1004, "GARAGE", "B"),
+            ]),
+            event_log: Some(log.clone()),
+            ..Default::default()
+        };
+        let ctx = Context {
+            user: &user,
+            areas: "A".parse().unwrap(),
+            alarm: &alarm,
+            users: &users,
+            services: &services,
+        };
+
+        let Opened::Session(mut session) = WalkTest.open(&ctx) else {
+            panic!("walk test opened no session");
+        };
+        assert_eq!(session.screen(&ctx), ["WALK TEST", "TESTED 0/3"]);
+
+        // Zones outside the user's areas aren't part of the test.
+        assert!(!session.zone_opened(&ctx, 1004));
+        assert!(session.zone_opened(&ctx, 1002));
+        assert_eq!(session.screen(&ctx), ["1002 LOUNGE", "TESTED 1/3"]);
+
+        session.key(&ctx, 'A');
+        assert_eq!(session.screen(&ctx), ["1001 FRONT DOOR", "UNTESTED 1/2"]);
+        session.key(&ctx, 'A');
+        session.key(&ctx, 'A');
+        assert_eq!(session.screen(&ctx), ["1003 BEDROOM", "UNTESTED 2/2"]);
+        session.key(&ctx, 'X');
+        assert_eq!(session.screen(&ctx), ["1002 LOUNGE", "TESTED 1/3"]);
+
+        assert_eq!(session.key(&ctx, 'X'), Outcome::Back);
+        let entries = log.lock().unwrap().query(&Filter::default()).unwrap();
+        assert_eq!(
+            entries[0].event,
+            EventKind::WalkTest {
+                user: 1,
+                tested: vec![1002],
+                untested: vec![1001, 1003],
+            }
+        );
+        assert_eq!(entries[0].event.to_string(), "WALK TEST 1/3");
+    }
+}
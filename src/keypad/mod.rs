@@ -1 +1,15 @@
 pub mod manager;
+pub mod menu;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventType {
+    // A valid code was entered by the given user number.
+    CodeAccepted { user: u16 },
+    // An invalid code was entered, being the given number of consecutive failures.
+    CodeRejected { attempts: u32 },
+    // The keypad stopped accepting codes after too many consecutive failures.
+    LockedOut,
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
@@ -1,2 +1,12 @@
+pub mod access;
+pub mod alarm;
+pub mod areas;
+mod channel;
+pub mod config;
+pub mod eventlog;
+pub mod exporter;
 pub mod keypad;
+pub mod reporting;
 pub mod serial;
+pub mod users;
+pub mod zones;
@@ -1,7 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env,
     error::Error,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -9,11 +10,47 @@ use std::{
     time::Duration,
 };
 
-use ::galaxy::serial::{galaxy::Bus, manager::SerialManager, SerialDevice};
-use galaxy::{keypad::manager::KeypadManager, serial::devices::keypad::SerialKeypad};
-use log::debug;
-use tokio::runtime;
-use tokio_serial::{self, SerialStream};
+use ::galaxy::serial::{
+    galaxy::{Bus, Exchange},
+    manager::SerialManager,
+    SerialDevice,
+};
+use galaxy::{
+    access::manager::AccessManager,
+    alarm::{manager::AlarmManager, Module},
+    config::{Config, DeviceType, SerialConfig, DEFAULT_BAUD_RATE},
+    eventlog::{
+        load_key, recorder::Recorder, EventClass, EventKind, EventLog, EventLogError, Filter,
+    },
+    exporter,
+    keypad::{manager::KeypadManager, menu::Services},
+    reporting::{ip::Dc09Transport, manager::ReportingManager},
+    serial::{
+        self,
+        capture::{BusRecorder, CaptureWriter, Record},
+        devices::{
+            keypad::{self, SerialKeypad},
+            max::SerialMax,
+            prox::SerialProx,
+            psu::SerialPsu,
+            rio::SerialRio,
+            DeviceKind,
+        },
+        galaxy::Timing,
+        simulator::{SimulatedKeypad, Simulator},
+        sniffer::{Decoder, Sniffer},
+    },
+    users::PinHash,
+    zones::manager::ZoneManager,
+};
+use log::{debug, error};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpListener,
+    runtime,
+    task::JoinHandle,
+};
+use tokio_serial::{self, SerialPortBuilder, SerialStream};
 
 fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     env_logger::Builder::new()
@@ -22,11 +59,30 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} SERIAL_ADAPTER", args[0]);
-        return Err("Missing mandatory serial path argument".into());
+    match args.get(1).map(String::as_str) {
+        Some("hash-pin") if args.len() == 3 => {
+            // Produces a value for the pin field of a user in the configuration file.
+            println!("{}", PinHash::new(&args[2]));
+            return Ok(());
+        }
+        Some("sniff") if args.len() == 4 => {
+            return sniff(&args[2], &args[3]);
+        }
+        Some("emulate-keypad") if args.len() == 4 => {
+            return emulate_keypad(&args[2], &args[3]);
+        }
+        Some(_) if args.len() == 2 => {}
+        _ => {
+            eprintln!("Usage: {} CONFIG_FILE", args[0]);
+            eprintln!("       {} hash-pin PIN", args[0]);
+            eprintln!("       {} sniff SERIAL_PORT CAPTURE_FILE", args[0]);
+            eprintln!("       {} emulate-keypad SERIAL_PORT ADDRESS", args[0]);
+            return Err("Missing mandatory configuration file argument".into());
+        }
     }
 
+    let config = Config::load(&args[1]).map_err(|e| format!("{}: {}", args[1], e))?;
+
     let rt = runtime::Builder::new_multi_thread()
         .enable_io()
         .enable_time()
@@ -38,46 +94,400 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         .build()
         .expect("unable to build tokio runtime");
 
-    let keypad = Arc::new(SerialKeypad::new());
+    let key = load_key(&config.event_log.key_file)?;
+    let mut event_log =
+        EventLog::open(&config.event_log.directory, config.event_log.capacity, &key)?;
+    record_config_change(&mut event_log, &config.digest)?;
+    let mut recorder = Recorder::new(event_log);
+
+    // Smart PSUs by address, created ahead of the keypads so that their readings can be shown
+    // in the menu.
+    let psus: BTreeMap<u8, Arc<SerialPsu>> = config
+        .devices
+        .iter()
+        .filter(|device| device.device_type == DeviceType::Psu)
+        .map(|device| (device.address, Arc::new(SerialPsu::new())))
+        .collect();
+
+    let services = Services {
+        zones: Arc::new(config.zones.clone()),
+        event_log: Some(recorder.log()),
+        psus: psus.clone(),
+    };
+
+    let mut zone_manager = ZoneManager::new(config.zones.clone());
+    // Modules supervised by the alarm core, where a RIO serves the areas of its zones.
+    let modules: Vec<Module> = config
+        .devices
+        .iter()
+        .map(|device| Module {
+            address: device.address,
+            areas: match device.device_type {
+                DeviceType::Keypad | DeviceType::Prox | DeviceType::Max | DeviceType::Psu => {
+                    device.areas
+                }
+                DeviceType::Rio => config
+                    .zones
+                    .iter()
+                    .filter(|zone| zone.input.device == device.address)
+                    .flat_map(|zone| zone.areas.iter())
+                    .collect(),
+            },
+            tamper: device.tamper_on_fault,
+        })
+        .collect();
+
+    let zones = config.zones;
+    let (mut alarm_manager, alarm) = AlarmManager::new(
+        config.timers,
+        zones.clone(),
+        zone_manager.subscribe_events(),
+    );
+    alarm_manager.set_chime(config.chime);
+    let users = Arc::new(config.users);
+
+    let reporting_manager = config.reporting.map(|reporting| {
+        let mut transport =
+            Dc09Transport::new(reporting.protocol, reporting.receiver, reporting.account)
+                .with_prefixes(reporting.receiver_number, reporting.line_number);
+        if let Some(key) = reporting.key {
+            transport = transport.with_key(key);
+        }
+
+        let reporting_manager = ReportingManager::new(
+            reporting.account,
+            transport,
+            &zones,
+            alarm.subscribe_events(),
+        )
+        .with_delivery(reporting.delivery);
+        match reporting.heartbeat {
+            Some(interval) => reporting_manager.with_heartbeat(interval),
+            None => reporting_manager,
+        }
+    });
+
+    let mut access_manager = (!config.doors.is_empty())
+        .then(|| AccessManager::new(config.doors, config.permissions, users.clone()));
+
+    {
+        let _guard = rt.enter();
+        recorder.attach_alarm(&alarm);
+        recorder.attach_zones(&zone_manager);
+        if let Some(access_manager) = &access_manager {
+            recorder.attach_access(access_manager);
+        }
+    }
+
+    let mut devices: HashMap<u8, Arc<dyn SerialDevice>> = HashMap::new();
+    let mut keypad_managers = Vec::new();
+    // Prox readers by the address of the keypad they're part of.
+    let proxes: HashMap<u8, Arc<SerialProx>> = config
+        .devices
+        .iter()
+        .filter_map(|device| device.keypad)
+        .map(|keypad| (keypad, Arc::new(SerialProx::new())))
+        .collect();
+
+    for device in config.devices {
+        match device.device_type {
+            DeviceType::Keypad => {
+                let keypad = Arc::new(SerialKeypad::new());
+                devices.insert(device.address, keypad.clone());
+
+                let mut keypad_manager =
+                    KeypadManager::new(keypad, device.areas, alarm.clone(), users.clone())
+                        .with_options(config.keypad.clone())
+                        .with_services(services.clone())
+                        .with_zone_events(zone_manager.subscribe_events());
+                if let Some(prox) = proxes.get(&device.address) {
+                    keypad_manager = keypad_manager.with_prox(prox.clone());
+                }
 
-    let serial_manager = rt.spawn(run_serial_manager(
-        args[1].clone(),
-        HashMap::from([(0x10u8, keypad.clone() as Arc<dyn SerialDevice>)]),
-    ));
-    let keypad_worker = rt.spawn(async move { KeypadManager::new(keypad.clone()).run().await });
+                let _guard = rt.enter();
+                recorder.attach_keypad(device.address, &keypad_manager);
+                keypad_managers.push(keypad_manager);
+            }
+            DeviceType::Rio => {
+                let rio = Arc::new(SerialRio::new());
+                devices.insert(device.address, rio.clone());
 
-    rt.block_on(serial_manager)??;
-    rt.block_on(keypad_worker)??;
+                let _guard = rt.enter();
+                zone_manager.attach_rio(device.address, rio.clone());
+                alarm_manager.attach_rio(device.address, &rio);
+            }
+            DeviceType::Max => {
+                let max = Arc::new(SerialMax::new());
+                devices.insert(device.address, max.clone());
+
+                {
+                    let _guard = rt.enter();
+                    alarm_manager.attach_max(device.address, &max);
+                }
+
+                if let Some(access_manager) = &mut access_manager {
+                    let _guard = rt.enter();
+                    access_manager.attach_reader(device.address, max);
+                }
+            }
+            DeviceType::Psu => {
+                let psu = psus[&device.address].clone();
+                devices.insert(device.address, psu.clone());
+
+                let _guard = rt.enter();
+                alarm_manager.attach_psu(device.address, &psu);
+            }
+            DeviceType::Prox => {
+                // Validated to name a configured keypad.
+                let prox = proxes[&device.keypad.unwrap()].clone();
+                devices.insert(device.address, prox);
+            }
+        }
+    }
+
+    let bus = rt.block_on(open_bus(&config.serial, config.bus))?;
+    let options = SerialOptions {
+        poll_interval: config.poll_interval,
+        discovery_interval: config.discovery_interval,
+        metrics: config.metrics,
+    };
+    let serial_manager = match &config.serial.capture {
+        Some(path) => start_serial_manager(
+            &rt,
+            BusRecorder::new(bus, CaptureWriter::create(path)?),
+            &options,
+            devices,
+            modules,
+            &recorder,
+            &mut alarm_manager,
+        )?,
+        None => start_serial_manager(
+            &rt,
+            bus,
+            &options,
+            devices,
+            modules,
+            &recorder,
+            &mut alarm_manager,
+        )?,
+    };
+
+    rt.spawn(async move { recorder.run().await });
+    rt.spawn(async move { zone_manager.run().await });
+    rt.spawn(async move { alarm_manager.run().await });
+    if let Some(mut reporting_manager) = reporting_manager {
+        rt.spawn(async move { reporting_manager.run().await });
+    }
+    if let Some(mut access_manager) = access_manager {
+        rt.spawn(async move { access_manager.run().await });
+    }
+
+    let keypad_workers: Vec<_> = keypad_managers
+        .into_iter()
+        .map(|mut keypad_manager| rt.spawn(async move { keypad_manager.run().await }))
+        .collect();
+
+    rt.block_on(serial_manager)?;
+    for keypad_worker in keypad_workers {
+        rt.block_on(keypad_worker)??;
+    }
 
     Ok(())
 }
 
-async fn run_serial_manager(
-    serial_device: String,
-    devices: HashMap<u8, Arc<dyn SerialDevice>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut serial_stream = SerialStream::open(
-        &tokio_serial::new(serial_device, 9600)
-            .data_bits(tokio_serial::DataBits::Eight)
-            .stop_bits(tokio_serial::StopBits::One)
-            .parity(tokio_serial::Parity::None)
-            .flow_control(tokio_serial::FlowControl::None)
-            .timeout(Duration::from_millis(100)),
-    )?;
+/// Records the configuration in the event log if it differs from that last recorded.
+fn record_config_change(log: &mut EventLog, digest: &str) -> Result<(), EventLogError> {
+    let last = log
+        .query(&Filter {
+            classes: vec![EventClass::Config],
+            ..Default::default()
+        })?
+        .pop();
+    let event = EventKind::ConfigChanged {
+        digest: digest.to_string(),
+    };
+
+    if last.map(|entry| entry.event) != Some(event.clone()) {
+        log.append(event)?;
+    }
+
+    Ok(())
+}
+
+fn serial_port(port: &str, baud_rate: u32) -> SerialPortBuilder {
+    tokio_serial::new(port, baud_rate)
+        .data_bits(tokio_serial::DataBits::Eight)
+        .stop_bits(tokio_serial::StopBits::One)
+        .parity(tokio_serial::Parity::None)
+        .flow_control(tokio_serial::FlowControl::None)
+        .timeout(Duration::from_millis(100))
+}
+
+/// Listens to a bus driven by another panel, printing the frames seen and writing them to a
+/// capture file.
+fn sniff(port: &str, capture: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("unable to build tokio runtime");
+
+    rt.block_on(async {
+        let mut sniffer = Sniffer::new(SerialStream::open(&serial_port(port, DEFAULT_BAUD_RATE))?);
+        let mut writer = CaptureWriter::create(capture)?;
+        let mut decoder = Decoder::new();
+
+        while let Some(entries) = sniffer.next().await? {
+            for entry in entries {
+                writer.write(&entry)?;
+
+                let Record::Frame { data } = &entry.record else {
+                    continue;
+                };
+                println!(
+                    "{:>10.3} {}",
+                    entry.at.as_secs_f64(),
+                    decoder.describe(data)
+                );
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Emulates a keypad on the bus of a real panel, printing the display as it changes and pressing
+/// the keys typed on each line of input.
+fn emulate_keypad(port: &str, address: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let address = u8::from_str_radix(address.trim_start_matches("0x"), 16)
+        .ok()
+        .filter(|address| DeviceKind::Keypad.addresses().contains(address))
+        .ok_or_else(|| format!("Invalid keypad address {}, expected 10 to 1F", address))?;
+
+    let rt = runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("unable to build tokio runtime");
+
+    rt.block_on(async {
+        let keypad = SimulatedKeypad::new();
+        let mut simulator =
+            Simulator::new(SerialStream::open(&serial_port(port, DEFAULT_BAUD_RATE))?);
+        simulator.add_device(address, Box::new(keypad.clone()));
+        let mut simulator = tokio::spawn(async move { simulator.run().await });
+
+        let mut input = BufReader::new(tokio::io::stdin()).lines();
+        let mut refresh = tokio::time::interval(Duration::from_millis(100));
+        let mut shown = None;
+
+        loop {
+            tokio::select! {
+                result = &mut simulator => return Ok(result??),
+                line = input.next_line() => {
+                    let Some(line) = line? else {
+                        return Ok(());
+                    };
+
+                    let keys = line.trim().to_uppercase();
+                    match keys.chars().find(|&key| !keypad::is_key(key)) {
+                        Some(key) => eprintln!("No such key: {}", key),
+                        None => keypad.press(&keys),
+                    }
+                }
+                _ = refresh.tick() => {
+                    let screen = keypad.screen();
+                    if shown.as_ref() != Some(&screen) {
+                        println!("|{:<16}|\n|{:<16}|", screen[0], screen[1]);
+                        shown = Some(screen);
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn open_bus(
+    serial: &SerialConfig,
+    timing: Timing,
+) -> Result<Bus, Box<dyn Error + Send + Sync>> {
+    let mut serial_stream = SerialStream::open(&serial_port(&serial.port, serial.baud_rate))?;
     if !serial_stream.exclusive() {
         serial_stream
             .set_exclusive(true)
             .map_err(|e| format!("Unable to exclusively acquire serial port: {}", e))?;
     }
 
-    let mut serial_manager = SerialManager::new(Bus::new(serial_stream));
+    Ok(Bus::new(serial_stream)
+        .with_timing(timing)
+        .with_half_duplex(serial.half_duplex)
+        .map_err(|e| format!("Unable to configure RS485 half-duplex: {}", e))?)
+}
+
+/// SerialOptions are the settings of the serial manager and the services around it.
+struct SerialOptions {
+    poll_interval: Duration,
+    // How often to scan for unconfigured modules, if at all.
+    discovery_interval: Option<Duration>,
+    // Address on which to serve bus metrics, if at all.
+    metrics: Option<SocketAddr>,
+}
+
+/// Starts polling the devices on the bus, which may be wrapped e.g. to capture its traffic, along
+/// with discovery scans and the metrics endpoint if configured. The alarm manager is attached to
+/// supervise `modules`.
+fn start_serial_manager<B: Exchange + 'static>(
+    rt: &runtime::Runtime,
+    bus: B,
+    options: &SerialOptions,
+    devices: HashMap<u8, Arc<dyn SerialDevice>>,
+    modules: Vec<Module>,
+    recorder: &Recorder,
+    alarm_manager: &mut AlarmManager,
+) -> Result<JoinHandle<()>, Box<dyn Error + Send + Sync>> {
+    let mut serial_manager = SerialManager::new(bus).with_poll_interval(options.poll_interval);
     for (address, device) in devices {
-        serial_manager.register_device(address, device);
+        serial_manager.register_device(address, device)?;
     }
 
-    debug!("Starting serial manager");
+    let listener = match options.metrics {
+        Some(addr) => Some((
+            rt.block_on(TcpListener::bind(addr))
+                .map_err(|e| format!("Unable to serve metrics on {}: {}", addr, e))?,
+            addr,
+        )),
+        None => None,
+    };
 
-    serial_manager.run().await;
+    let _guard = rt.enter();
+    recorder.attach_serial(&serial_manager);
+    alarm_manager.supervise(modules, serial_manager.subscribe_events());
 
-    Ok(())
+    if let Some(discovery_interval) = options.discovery_interval {
+        let serial = serial_manager.handle();
+        rt.spawn(async move {
+            let mut scans = tokio::time::interval(discovery_interval);
+            loop {
+                scans.tick().await;
+                serial.scan();
+            }
+        });
+    }
+
+    if let Some((listener, addr)) = listener {
+        let metrics = serial_manager.subscribe_metrics();
+
+        rt.spawn(async move {
+            let result = exporter::serve_metrics(listener, move || {
+                serial::metrics::render(&metrics.borrow())
+            })
+            .await;
+            if let Err(e) = result {
+                error!("Metrics endpoint on {} failed: {}", addr, e);
+            }
+        });
+    }
+
+    debug!("Starting serial manager");
+    Ok(rt.spawn(async move { serial_manager.run().await }))
 }
@@ -0,0 +1,217 @@
+use derive_more::Display;
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// MESSAGE_TYPE identifies a Contact ID message to the receiver, as opposed to other formats sent
+/// over the same line.
+const MESSAGE_TYPE: &str = "18";
+
+/// MESSAGE_LENGTH is the number of digits in a message, including its checksum.
+const MESSAGE_LENGTH: usize = 16;
+
+// Event codes reported, from the Contact ID event code table.
+pub const FIRE: u16 = 110;
+pub const PANIC: u16 = 120;
+pub const BURGLARY: u16 = 130;
+pub const TWENTY_FOUR_HOUR: u16 = 133;
+pub const ENTRY_EXIT: u16 = 134;
+pub const SENSOR_TAMPER: u16 = 144;
+pub const MODULE_TAMPER: u16 = 145;
+pub const AC_LOSS: u16 = 301;
+pub const LOW_BATTERY: u16 = 302;
+pub const POWER_OVERCURRENT: u16 = 312;
+pub const MODULE_FAILURE: u16 = 333;
+pub const OPEN_CLOSE: u16 = 401;
+pub const PART_SET: u16 = 441;
+pub const FAILED_TO_SET: u16 = 454;
+pub const WRONG_CODE: u16 = 461;
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ContactIdError {
+    #[error("invalid account {0:?}, expected 4 digits 0-9 or B-F")]
+    InvalidAccount(String),
+    #[error("malformed message {0:?}")]
+    Malformed(String),
+    #[error("checksum mismatch in {0:?}")]
+    Checksum(String),
+}
+
+/// Account identifies the premises to the monitoring station. Its digits may include B to F, but
+/// not A, which stands in for 0 on some receivers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Account([u8; 4]);
+
+impl FromStr for Account {
+    type Err = ContactIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.as_bytes();
+        if digits.len() != 4 || !digits.iter().all(|&d| is_digit(d)) {
+            return Err(ContactIdError::InvalidAccount(s.to_string()));
+        }
+
+        Ok(Account([digits[0], digits[1], digits[2], digits[3]]))
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only ASCII digits are accepted on parsing.
+        f.write_str(std::str::from_utf8(&self.0).unwrap())
+    }
+}
+
+/// Qualifier distinguishes an event occurring from it restoring.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum Qualifier {
+    // A new event, or an opening (unset).
+    #[display(fmt = "1")]
+    New,
+    // A restore, or a closing (set).
+    #[display(fmt = "3")]
+    Restore,
+    // A previously reported condition which is still present.
+    #[display(fmt = "6")]
+    Previous,
+}
+
+/// ContactId is an Ademco Contact ID message, written as ACCT 18 Q XYZ GG CCC S without spaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContactId {
+    pub account: Account,
+    pub qualifier: Qualifier,
+    // Event code, e.g. 130 for a burglary alarm.
+    pub code: u16,
+    // Group (area) in which the event occurred, numbered from 1, or 0 for the whole system.
+    pub group: u8,
+    // Zone or user number, depending on the event, or 0 if neither applies.
+    pub number: u16,
+}
+
+impl ContactId {
+    /// Returns the digits of the message, without the checksum.
+    fn digits(&self) -> String {
+        format!(
+            "{}{}{}{:03}{:02}{:03}",
+            self.account, MESSAGE_TYPE, self.qualifier, self.code, self.group, self.number
+        )
+    }
+}
+
+impl fmt::Display for ContactId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.digits();
+        write!(f, "{}{}", digits, checksum(&digits))
+    }
+}
+
+impl FromStr for ContactId {
+    type Err = ContactIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || ContactIdError::Malformed(s.to_string());
+
+        if s.len() != MESSAGE_LENGTH || !s.bytes().all(is_digit) {
+            return Err(malformed());
+        }
+        if checksum(&s[..MESSAGE_LENGTH - 1]) != s.as_bytes()[MESSAGE_LENGTH - 1] as char {
+            return Err(ContactIdError::Checksum(s.to_string()));
+        }
+        if &s[4..6] != MESSAGE_TYPE {
+            return Err(malformed());
+        }
+
+        Ok(ContactId {
+            account: s[..4].parse()?,
+            qualifier: match &s[6..7] {
+                "1" => Qualifier::New,
+                "3" => Qualifier::Restore,
+                "6" => Qualifier::Previous,
+                _ => return Err(malformed()),
+            },
+            code: s[7..10].parse().map_err(|_| malformed())?,
+            group: s[10..12].parse().map_err(|_| malformed())?,
+            number: s[12..15].parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+fn is_digit(digit: u8) -> bool {
+    matches!(digit, b'0'..=b'9' | b'B'..=b'F')
+}
+
+/// Returns the value of a digit in the checksum, where 0 counts as 10.
+fn digit_value(digit: char) -> u32 {
+    match digit {
+        '0' => 10,
+        _ => digit.to_digit(16).unwrap_or(0),
+    }
+}
+
+/// Returns the checksum digit, which brings the sum of all the digits of the message to a
+/// multiple of 15.
+fn checksum(digits: &str) -> char {
+    let sum: u32 = digits.chars().map(digit_value).sum();
+
+    match 15 - sum % 15 {
+        10 => '0',
+        check => char::from_digit(check, 16).unwrap().to_ascii_uppercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(qualifier: Qualifier, code: u16, group: u8, number: u16) -> ContactId {
+        ContactId {
+            account: "1234".parse().unwrap(),
+            qualifier,
+            code,
+            group,
+            number,
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            message(Qualifier::New, 131, 1, 15).to_string(),
+            "1234181131010158"
+        );
+
+        // A checksum of 15 is written as F, and of 10 as 0.
+        assert_eq!(
+            message(Qualifier::Restore, OPEN_CLOSE, 1, 7).to_string(),
+            "123418340101007F"
+        );
+        assert_eq!(
+            message(Qualifier::Restore, OPEN_CLOSE, 1, 16).to_string(),
+            "1234183401010160"
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        for encoded in ["1234181131010158", "123418340101007F", "1234183401010160"] {
+            let decoded: ContactId = encoded.parse().unwrap();
+            assert_eq!(decoded.to_string(), encoded);
+        }
+
+        assert_eq!(
+            "1234181131010159".parse::<ContactId>(),
+            Err(ContactIdError::Checksum("1234181131010159".to_string()))
+        );
+        assert!(matches!(
+            "12341811310101".parse::<ContactId>(),
+            Err(ContactIdError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_account_digits() {
+        assert_eq!("B0F1".parse::<Account>().unwrap().to_string(), "B0F1");
+        assert!("12A4".parse::<Account>().is_err());
+        assert!("123".parse::<Account>().is_err());
+    }
+}
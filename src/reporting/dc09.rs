@@ -0,0 +1,473 @@
+use aes::{Aes128, Aes192, Aes256};
+use cbc::cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use chrono::NaiveDateTime;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+use super::contact_id::{ContactId, Qualifier};
+
+// Message types, which are quoted in the message.
+pub const CONTACT_ID: &str = "ADM-CID";
+pub const HEARTBEAT: &str = "NULL";
+pub const ACK: &str = "ACK";
+pub const NAK: &str = "NAK";
+pub const DUH: &str = "DUH";
+
+const LF: u8 = b'\n';
+const CR: u8 = b'\r';
+
+/// BLOCK_SIZE is that of AES, to which the encrypted part of a message is padded.
+const BLOCK_SIZE: usize = 16;
+
+const TIMESTAMP_FORMAT: &str = "_%H:%M:%S,%m-%d-%Y";
+
+/// MAX_SEQUENCE is the highest sequence number, after which they wrap around to 1. Zero is
+/// reserved for NAK replies.
+pub const MAX_SEQUENCE: u16 = 9999;
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum Dc09Error {
+    #[error("malformed message: {0}")]
+    Malformed(&'static str),
+    #[error("CRC mismatch, calculated {calculated:04X} but received {received:04X}")]
+    Crc { calculated: u16, received: u16 },
+    #[error("length mismatch, {actual} bytes but {declared} declared")]
+    Length { actual: usize, declared: usize },
+    #[error("encrypted message, but no key configured")]
+    NoKey,
+    #[error("unable to decrypt message")]
+    Decryption,
+    #[error("invalid key, expected 32, 48 or 64 hex digits for AES-128, 192 or 256")]
+    InvalidKey,
+}
+
+/// Key is the AES key shared with the receiver, whose length selects AES-128, 192 or 256.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key(Vec<u8>);
+
+impl FromStr for Key {
+    type Err = Dc09Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !matches!(s.len(), 32 | 48 | 64) {
+            return Err(Dc09Error::InvalidKey);
+        }
+
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(Key)
+            .ok_or(Dc09Error::InvalidKey)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the key itself out of logs.
+        write!(f, "Key(AES-{})", self.0.len() * 8)
+    }
+}
+
+// The IV is all zeroes, with the random pad at the start of each message standing in for it.
+const IV: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+impl Key {
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        match self.0.len() {
+            16 => cbc::Encryptor::<Aes128>::new_from_slices(&self.0, &IV)
+                .unwrap()
+                .encrypt_padded_vec_mut::<NoPadding>(plaintext),
+            24 => cbc::Encryptor::<Aes192>::new_from_slices(&self.0, &IV)
+                .unwrap()
+                .encrypt_padded_vec_mut::<NoPadding>(plaintext),
+            _ => cbc::Encryptor::<Aes256>::new_from_slices(&self.0, &IV)
+                .unwrap()
+                .encrypt_padded_vec_mut::<NoPadding>(plaintext),
+        }
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Dc09Error> {
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Dc09Error::Decryption);
+        }
+
+        match self.0.len() {
+            16 => cbc::Decryptor::<Aes128>::new_from_slices(&self.0, &IV)
+                .unwrap()
+                .decrypt_padded_vec_mut::<NoPadding>(ciphertext),
+            24 => cbc::Decryptor::<Aes192>::new_from_slices(&self.0, &IV)
+                .unwrap()
+                .decrypt_padded_vec_mut::<NoPadding>(ciphertext),
+            _ => cbc::Decryptor::<Aes256>::new_from_slices(&self.0, &IV)
+                .unwrap()
+                .decrypt_padded_vec_mut::<NoPadding>(ciphertext),
+        }
+        .map_err(|_| Dc09Error::Decryption)
+    }
+}
+
+/// Frame is a SIA DC-09 message, written as
+/// `<LF><CRC><0LLL>"<type>"<seq>R<receiver>L<line>#<account>[<data>]<timestamp><CR>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    // Message type, e.g. ADM-CID, without the quotes or the asterisk marking it encrypted.
+    pub message_type: String,
+    pub sequence: u16,
+    // Receiver and line numbers at the monitoring station, in hex, where the receiver is
+    // optional.
+    pub receiver: Option<String>,
+    pub line: String,
+    pub account: Option<String>,
+    // Contents of the data brackets, e.g. #1234|1131 01 015 for Contact ID.
+    pub data: String,
+    // Time the message was sent in UTC, which is required when encrypted.
+    pub timestamp: Option<NaiveDateTime>,
+}
+
+impl Frame {
+    /// Returns a frame carrying a Contact ID message.
+    pub fn contact_id(message: &ContactId, sequence: u16) -> Frame {
+        Frame {
+            message_type: CONTACT_ID.to_string(),
+            sequence,
+            receiver: None,
+            line: "0".to_string(),
+            account: Some(message.account.to_string()),
+            data: format!(
+                "#{}|{}{:03} {:02} {:03}",
+                message.account, message.qualifier, message.code, message.group, message.number
+            ),
+            timestamp: None,
+        }
+    }
+
+    /// Returns the Contact ID message carried, if any.
+    pub fn to_contact_id(&self) -> Option<ContactId> {
+        if self.message_type != CONTACT_ID {
+            return None;
+        }
+
+        let (account, event) = self.data.strip_prefix('#')?.split_once('|')?;
+        let mut fields = event.split(' ');
+        let (event, group, number) = (fields.next()?, fields.next()?, fields.next()?);
+
+        Some(ContactId {
+            account: account.parse().ok()?,
+            qualifier: match event.get(..1)? {
+                "1" => Qualifier::New,
+                "3" => Qualifier::Restore,
+                "6" => Qualifier::Previous,
+                _ => return None,
+            },
+            code: event.get(1..)?.parse().ok()?,
+            group: group.parse().ok()?,
+            number: number.parse().ok()?,
+        })
+    }
+
+    /// Returns the reply to this frame, with the same sequence number and prefixes.
+    pub fn reply(&self, message_type: &str) -> Frame {
+        Frame {
+            message_type: message_type.to_string(),
+            sequence: self.sequence,
+            receiver: self.receiver.clone(),
+            line: self.line.clone(),
+            account: self.account.clone(),
+            data: String::new(),
+            timestamp: None,
+        }
+    }
+
+    /// Returns a NAK, which carries the receiver's time for the sender to correct its clock by.
+    pub fn nak(timestamp: NaiveDateTime) -> Frame {
+        Frame {
+            message_type: NAK.to_string(),
+            sequence: 0,
+            receiver: Some("0".to_string()),
+            line: "0".to_string(),
+            account: None,
+            data: String::new(),
+            timestamp: Some(timestamp),
+        }
+    }
+
+    /// Encodes the frame, encrypting its data and timestamp if given a key.
+    pub fn encode(&self, key: Option<&Key>) -> Vec<u8> {
+        let mut body = format!(
+            "\"{}{}\"{:04}",
+            if key.is_some() { "*" } else { "" },
+            self.message_type,
+            self.sequence
+        );
+        if let Some(receiver) = &self.receiver {
+            body += &format!("R{}", receiver);
+        }
+        body += &format!("L{}", self.line);
+        if let Some(account) = &self.account {
+            body += &format!("#{}", account);
+        }
+        body.push('[');
+
+        let content = format!(
+            "{}]{}",
+            self.data,
+            self.timestamp
+                .map(|timestamp| timestamp.format(TIMESTAMP_FORMAT).to_string())
+                .unwrap_or_default()
+        );
+        match key {
+            Some(key) => {
+                // The pad fills the encrypted part out to a whole number of blocks, and can't
+                // contain the separator following it.
+                let pad_length = BLOCK_SIZE - (content.len() + 1) % BLOCK_SIZE;
+                let pad: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(pad_length)
+                    .map(char::from)
+                    .collect();
+
+                for byte in key.encrypt(format!("{}|{}", pad, content).as_bytes()) {
+                    body += &format!("{:02X}", byte);
+                }
+            }
+            None => body += &content,
+        }
+
+        let mut frame = vec![LF];
+        frame.extend(format!("{:04X}0{:03X}", crc16(body.as_bytes()), body.len()).bytes());
+        frame.extend(body.bytes());
+        frame.push(CR);
+        frame
+    }
+
+    /// Decodes a frame, decrypting it with the key if it's encrypted.
+    pub fn decode(frame: &[u8], key: Option<&Key>) -> Result<Frame, Dc09Error> {
+        let frame = frame
+            .strip_prefix(&[LF])
+            .and_then(|frame| frame.strip_suffix(&[CR]))
+            .ok_or(Dc09Error::Malformed("missing LF or CR"))?;
+        let frame = std::str::from_utf8(frame).map_err(|_| Dc09Error::Malformed("not ASCII"))?;
+
+        let (crc, length, body) = match (frame.get(..4), frame.get(4..8), frame.get(8..)) {
+            (Some(crc), Some(length), Some(body)) if length.starts_with('0') => (
+                u16::from_str_radix(crc, 16).map_err(|_| Dc09Error::Malformed("CRC"))?,
+                usize::from_str_radix(&length[1..], 16)
+                    .map_err(|_| Dc09Error::Malformed("length"))?,
+                body,
+            ),
+            _ => return Err(Dc09Error::Malformed("header")),
+        };
+        if body.len() != length {
+            return Err(Dc09Error::Length {
+                actual: body.len(),
+                declared: length,
+            });
+        }
+        if crc16(body.as_bytes()) != crc {
+            return Err(Dc09Error::Crc {
+                calculated: crc16(body.as_bytes()),
+                received: crc,
+            });
+        }
+
+        let (message_type, rest) = body
+            .strip_prefix('"')
+            .and_then(|body| body.split_once('"'))
+            .ok_or(Dc09Error::Malformed("message type"))?;
+        let (encrypted, message_type) = match message_type.strip_prefix('*') {
+            Some(message_type) => (true, message_type),
+            None => (false, message_type),
+        };
+
+        let sequence = rest
+            .get(..4)
+            .and_then(|sequence| sequence.parse().ok())
+            .ok_or(Dc09Error::Malformed("sequence number"))?;
+        let (prefixes, content) = rest[4..]
+            .split_once('[')
+            .ok_or(Dc09Error::Malformed("missing data"))?;
+
+        let content = if encrypted {
+            let key = key.ok_or(Dc09Error::NoKey)?;
+            let ciphertext = (0..content.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(content.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+                .ok_or(Dc09Error::Decryption)?;
+            let plaintext =
+                String::from_utf8(key.decrypt(&ciphertext)?).map_err(|_| Dc09Error::Decryption)?;
+
+            match plaintext.split_once('|') {
+                Some((_pad, content)) => content.to_string(),
+                None => return Err(Dc09Error::Decryption),
+            }
+        } else {
+            content.to_string()
+        };
+
+        let (data, timestamp) = content
+            .rsplit_once(']')
+            .ok_or(Dc09Error::Malformed("unterminated data"))?;
+        let timestamp = match timestamp {
+            "" => None,
+            timestamp => Some(
+                NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+                    .map_err(|_| Dc09Error::Malformed("timestamp"))?,
+            ),
+        };
+
+        let (receiver, line, account) = parse_prefixes(prefixes)?;
+
+        Ok(Frame {
+            message_type: message_type.to_string(),
+            sequence,
+            receiver,
+            line,
+            account,
+            data: data.to_string(),
+            timestamp,
+        })
+    }
+}
+
+/// Returns whether an encoded frame is encrypted, so that a reply can be encrypted likewise.
+pub fn is_encrypted(frame: &[u8]) -> bool {
+    // The asterisk follows the LF, CRC, length and opening quote.
+    frame.get(10) == Some(&b'*')
+}
+
+/// Parses the receiver, line and account prefixes, each a letter followed by hex digits.
+fn parse_prefixes(prefixes: &str) -> Result<(Option<String>, String, Option<String>), Dc09Error> {
+    let (mut receiver, mut line, mut account) = (None, None, None);
+    let mut rest = prefixes;
+
+    while let Some(prefix) = rest.chars().next() {
+        let field = match prefix {
+            'R' => &mut receiver,
+            'L' => &mut line,
+            '#' => &mut account,
+            _ => return Err(Dc09Error::Malformed("prefix")),
+        };
+        let digits = rest[1..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .map_or(&rest[1..], |end| &rest[1..end + 1]);
+        *field = Some(digits.to_string());
+        rest = &rest[1 + digits.len()..];
+    }
+
+    Ok((
+        receiver,
+        line.ok_or(Dc09Error::Malformed("missing line prefix"))?,
+        account,
+    ))
+}
+
+/// Returns the CRC-16/ARC of the data, as used by DC-09.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn contact_id() -> ContactId {
+        "1234181131010158".parse().unwrap()
+    }
+
+    fn timestamp() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, 9)
+            .unwrap()
+            .and_hms_opt(14, 5, 30)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0xBB3D);
+    }
+
+    #[test]
+    fn test_encode_plain() {
+        let mut frame = Frame::contact_id(&contact_id(), 2);
+        frame.receiver = Some("12".to_string());
+        frame.line = "3".to_string();
+        frame.timestamp = Some(timestamp());
+
+        let body = "\"ADM-CID\"0002R12L3#1234[#1234|1131 01 015]_14:05:30,03-09-2024";
+        let expected = format!(
+            "\n{:04X}0{:03X}{}\r",
+            crc16(body.as_bytes()),
+            body.len(),
+            body
+        );
+        assert_eq!(frame.encode(None), expected.as_bytes());
+
+        let decoded = Frame::decode(expected.as_bytes(), None).unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(decoded.to_contact_id(), Some(contact_id()));
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        for key in [
+            "000102030405060708090A0B0C0D0E0F",
+            "000102030405060708090A0B0C0D0E0F1011121314151617",
+            "000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F",
+        ] {
+            let key: Key = key.parse().unwrap();
+            let mut frame = Frame::contact_id(&contact_id(), 9999);
+            frame.timestamp = Some(timestamp());
+
+            let encoded = frame.encode(Some(&key));
+            let text = String::from_utf8(encoded.clone()).unwrap();
+            assert!(text.contains("\"*ADM-CID\"9999L0#1234["), "{}", text);
+            assert!(!text.contains("1131"), "{}", text);
+
+            assert_eq!(Frame::decode(&encoded, Some(&key)).unwrap(), frame);
+            assert_eq!(Frame::decode(&encoded, None), Err(Dc09Error::NoKey));
+
+            let other: Key = "FF".repeat(16).parse().unwrap();
+            assert!(Frame::decode(&encoded, Some(&other)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut encoded = Frame::contact_id(&contact_id(), 1).encode(None);
+        encoded[20] ^= 0x01;
+        assert!(matches!(
+            Frame::decode(&encoded, None),
+            Err(Dc09Error::Crc { .. })
+        ));
+
+        assert_eq!(
+            Frame::decode(b"no frame", None),
+            Err(Dc09Error::Malformed("missing LF or CR"))
+        );
+        assert_eq!("0123".parse::<Key>(), Err(Dc09Error::InvalidKey));
+    }
+
+    #[test]
+    fn test_nak() {
+        let encoded = Frame::nak(timestamp()).encode(None);
+        let text = String::from_utf8_lossy(&encoded);
+        assert!(text.contains("\"NAK\"0000R0L0[]_14:05:30,03-09-2024"));
+
+        let decoded = Frame::decode(&encoded, None).unwrap();
+        assert_eq!(decoded.message_type, NAK);
+        assert_eq!(decoded.timestamp, Some(timestamp()));
+    }
+}
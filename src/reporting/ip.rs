@@ -0,0 +1,223 @@
+use chrono::Utc;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{io, net::SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use super::{
+    contact_id::{Account, ContactId},
+    dc09::{self, Frame, Key, MAX_SEQUENCE},
+    Transport, TransportError,
+};
+
+/// MAX_FRAME_LENGTH bounds a reply from the receiver, which is only ever an acknowledgement.
+const MAX_FRAME_LENGTH: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+enum Connection {
+    // With any data read beyond the end of the last reply.
+    Tcp(TcpStream, Vec<u8>),
+    Udp(UdpSocket),
+}
+
+/// Dc09Transport reports to a receiver by SIA DC-09 over TCP or UDP, optionally encrypted.
+pub struct Dc09Transport {
+    protocol: Protocol,
+    address: SocketAddr,
+    account: Account,
+    key: Option<Key>,
+    // Receiver and line numbers at the monitoring station, prefixed to every message.
+    receiver: Option<String>,
+    line: String,
+    connection: Option<Connection>,
+    sequence: u16,
+    // The message last sent without being acknowledged, which keeps its sequence number when
+    // sent again.
+    unacknowledged: Option<ContactId>,
+    // Correction to the local clock, from the receiver's time given with its last NAK.
+    clock_offset: chrono::Duration,
+}
+
+impl Dc09Transport {
+    pub fn new(protocol: Protocol, address: SocketAddr, account: Account) -> Dc09Transport {
+        Dc09Transport {
+            protocol,
+            address,
+            account,
+            key: None,
+            receiver: None,
+            line: "0".to_string(),
+            connection: None,
+            sequence: 0,
+            unacknowledged: None,
+            clock_offset: chrono::Duration::zero(),
+        }
+    }
+
+    /// Encrypts messages with the given key, which the receiver must share.
+    pub fn with_key(mut self, key: Key) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_prefixes(mut self, receiver: Option<String>, line: String) -> Self {
+        self.receiver = receiver;
+        self.line = line;
+        self
+    }
+
+    fn next_sequence(&mut self) -> u16 {
+        self.sequence = self.sequence % MAX_SEQUENCE + 1;
+        self.sequence
+    }
+
+    /// Sends a frame and awaits the receiver's reply to it, dropping the connection on failure
+    /// so that the next attempt starts afresh.
+    async fn exchange(&mut self, mut frame: Frame) -> Result<(), TransportError> {
+        frame.receiver = self.receiver.clone();
+        frame.line = self.line.clone();
+        frame.timestamp = Some(Utc::now().naive_utc() + self.clock_offset);
+
+        let result = self.transfer(&frame).await;
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    async fn transfer(&mut self, frame: &Frame) -> Result<(), TransportError> {
+        if self.connection.is_none() {
+            debug!("Connecting to receiver at {}", self.address);
+            self.connection = Some(self.connect().await?);
+        }
+        let Some(connection) = &mut self.connection else {
+            unreachable!();
+        };
+
+        let encoded = frame.encode(self.key.as_ref());
+        match connection {
+            Connection::Tcp(stream, _) => stream.write_all(&encoded).await?,
+            Connection::Udp(socket) => {
+                socket.send(&encoded).await?;
+            }
+        }
+
+        loop {
+            let reply = match connection {
+                Connection::Tcp(stream, buffer) => read_frame(stream, buffer).await?,
+                Connection::Udp(socket) => {
+                    let mut buffer = vec![0; MAX_FRAME_LENGTH];
+                    let n = socket.recv(&mut buffer).await?;
+                    buffer.truncate(n);
+                    buffer
+                }
+            };
+
+            let reply = Frame::decode(&reply, self.key.as_ref())
+                .map_err(|e| TransportError::Malformed(e.to_string()))?;
+            match reply.message_type.as_str() {
+                dc09::ACK if reply.sequence == frame.sequence => return Ok(()),
+                // A late acknowledgement of an earlier attempt.
+                dc09::ACK => continue,
+                dc09::NAK => {
+                    // The receiver refuses messages whose time is too far from its own, so take
+                    // its time for the next attempt.
+                    if let Some(timestamp) = reply.timestamp {
+                        self.clock_offset = timestamp - Utc::now().naive_utc();
+                        warn!(
+                            "Receiver refused message, correcting clock by {}s",
+                            self.clock_offset.num_seconds()
+                        );
+                    }
+                    return Err(TransportError::Rejected);
+                }
+                dc09::DUH => return Err(TransportError::Unsupported),
+                other => {
+                    return Err(TransportError::Malformed(format!(
+                        "unexpected reply {:?}",
+                        other
+                    )))
+                }
+            }
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Connection> {
+        match self.protocol {
+            Protocol::Tcp => Ok(Connection::Tcp(
+                TcpStream::connect(self.address).await?,
+                Vec::new(),
+            )),
+            Protocol::Udp => {
+                let local: SocketAddr = match self.address {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(self.address).await?;
+                Ok(Connection::Udp(socket))
+            }
+        }
+    }
+}
+
+impl Transport for Dc09Transport {
+    async fn send(&mut self, message: &ContactId) -> Result<(), TransportError> {
+        if self.unacknowledged != Some(*message) {
+            self.next_sequence();
+            self.unacknowledged = Some(*message);
+        }
+
+        self.exchange(Frame::contact_id(message, self.sequence))
+            .await?;
+        self.unacknowledged = None;
+        Ok(())
+    }
+
+    async fn heartbeat(&mut self) -> Result<(), TransportError> {
+        self.unacknowledged = None;
+        let frame = Frame {
+            message_type: dc09::HEARTBEAT.to_string(),
+            sequence: self.next_sequence(),
+            receiver: None,
+            line: String::new(),
+            account: Some(self.account.to_string()),
+            data: String::new(),
+            timestamp: None,
+        };
+
+        self.exchange(frame).await
+    }
+}
+
+/// Reads up to the CR ending a frame, keeping anything after it for the next read.
+pub(crate) async fn read_frame(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    loop {
+        if let Some(end) = buffer.iter().position(|&byte| byte == b'\r') {
+            return Ok(buffer.drain(..=end).collect());
+        }
+        if buffer.len() > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+        }
+
+        let mut chunk = [0u8; 256];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
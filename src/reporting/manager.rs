@@ -0,0 +1,556 @@
+use log::{debug, error, warn};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast,
+    time::{self, Instant},
+};
+
+use super::{
+    contact_id::{self, Account, ContactId, Qualifier},
+    Delivery, Event, EventType, Transport, TransportError,
+};
+use crate::{
+    alarm::{self, Cause, FaultKind, SystemState},
+    areas::Area,
+    channel::recv_event,
+    zones::{Zone, ZoneType},
+};
+
+/// ReportingManager reports alarm events to a monitoring station as Contact ID messages, queueing
+/// them for delivery one at a time over its transport.
+pub struct ReportingManager<T> {
+    transport: T,
+    account: Account,
+    delivery: Delivery,
+    // Time without traffic after which a heartbeat is sent, if at all.
+    heartbeat: Option<Duration>,
+    last_sent: Instant,
+    // Whether the last exchange with the receiver succeeded.
+    link_up: bool,
+    zone_types: HashMap<u16, ZoneType>,
+    alarm_events: Option<broadcast::Receiver<alarm::Event>>,
+    queue: VecDeque<ContactId>,
+    // Users who began setting each area, to whom the closing is reported once it sets.
+    setting_users: BTreeMap<Area, u16>,
+    event_ch: broadcast::Sender<Event>,
+}
+
+impl<T: Transport> ReportingManager<T> {
+    pub fn new(
+        account: Account,
+        transport: T,
+        zones: &[Zone],
+        alarm_events: broadcast::Receiver<alarm::Event>,
+    ) -> ReportingManager<T> {
+        ReportingManager {
+            transport,
+            account,
+            delivery: Delivery::default(),
+            heartbeat: None,
+            last_sent: Instant::now(),
+            link_up: true,
+            zone_types: zones
+                .iter()
+                .map(|zone| (zone.number, zone.zone_type))
+                .collect(),
+            alarm_events: Some(alarm_events),
+            queue: VecDeque::new(),
+            setting_users: BTreeMap::new(),
+            event_ch: broadcast::Sender::new(32),
+        }
+    }
+
+    pub fn with_delivery(mut self, delivery: Delivery) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Sends a heartbeat through the transport after the given time without other traffic.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_ch.subscribe()
+    }
+
+    /// Runs until the alarm manager goes away and every queued message has been dealt with.
+    pub async fn run(&mut self) {
+        loop {
+            if let Some(message) = self.queue.pop_front() {
+                self.deliver(message).await;
+                continue;
+            }
+
+            if self.alarm_events.is_none() {
+                break;
+            }
+
+            let heartbeat_due = self.heartbeat.map(|interval| self.last_sent + interval);
+            tokio::select! {
+                event = recv_event(&mut self.alarm_events) => self.receive(event),
+                _ = time::sleep_until(heartbeat_due.unwrap_or_else(Instant::now)),
+                    if heartbeat_due.is_some() =>
+                {
+                    self.send_heartbeat().await;
+                }
+            }
+        }
+    }
+
+    async fn send_heartbeat(&mut self) {
+        let result = self.exchange(None).await;
+        if let Err(e) = &result {
+            warn!("Heartbeat failed: {}", e);
+        }
+        self.update_link(result.is_ok());
+    }
+
+    /// Sends a message, or a heartbeat without one, which fails if not acknowledged in time. Alarm
+    /// events are still taken while waiting so that a burst of them can't overrun the channel.
+    async fn exchange(&mut self, message: Option<&ContactId>) -> Result<(), TransportError> {
+        self.last_sent = Instant::now();
+        let mut events = Vec::new();
+
+        let result = {
+            let transport = &mut self.transport;
+            let exchange = async move {
+                match message {
+                    Some(message) => transport.send(message).await,
+                    None => transport.heartbeat().await,
+                }
+            };
+            let deadline = time::sleep(self.delivery.ack_timeout);
+            tokio::pin!(exchange, deadline);
+
+            loop {
+                tokio::select! {
+                    result = &mut exchange => break result,
+                    _ = &mut deadline => break Err(TransportError::Timeout),
+                    event = recv_event(&mut self.alarm_events) => {
+                        // The transport is still in use, so the events are queued afterwards.
+                        if let Err(broadcast::error::RecvError::Closed) = event {
+                            self.alarm_events = None;
+                        }
+                        events.push(event);
+                    }
+                }
+            }
+        };
+
+        for event in events {
+            self.receive(event);
+        }
+        result
+    }
+
+    fn update_link(&mut self, up: bool) {
+        if up != self.link_up {
+            self.link_up = up;
+            let _ = self.event_ch.send(Event(if up {
+                EventType::LinkRestored
+            } else {
+                EventType::LinkFailed
+            }));
+        }
+    }
+
+    fn receive(&mut self, event: Result<alarm::Event, broadcast::error::RecvError>) {
+        match event {
+            Ok(event) => {
+                if let Some(message) = self.message(event) {
+                    debug!("Queueing report {}", message);
+                    self.queue.push_back(message);
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                error!(
+                    "Reporting lagged {} alarm events, which are not reported",
+                    n
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => self.alarm_events = None,
+        }
+    }
+
+    /// Sends a message until the receiver acknowledges it or the attempts run out, queueing any
+    /// alarm events that arrive meanwhile.
+    async fn deliver(&mut self, message: ContactId) {
+        let mut attempts = 0;
+
+        while attempts < self.delivery.attempts {
+            attempts += 1;
+
+            match self.exchange(Some(&message)).await {
+                Ok(()) => {
+                    self.update_link(true);
+                    let _ = self.event_ch.send(Event(EventType::Delivered { message }));
+                    return;
+                }
+                // Sending it again would get the same answer.
+                Err(TransportError::Unsupported) => {
+                    warn!("Receiver doesn't support report {}", message);
+                    break;
+                }
+                Err(e) => warn!(
+                    "Attempt {} to deliver report {} failed: {}",
+                    attempts, message, e
+                ),
+            }
+
+            if attempts < self.delivery.attempts {
+                self.wait(self.delivery.retry_delay).await;
+            } else {
+                self.update_link(false);
+            }
+        }
+
+        error!(
+            "Giving up on report {} after {} attempts",
+            message, attempts
+        );
+        let _ = self
+            .event_ch
+            .send(Event(EventType::Failed { message, attempts }));
+    }
+
+    async fn wait(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(deadline) => break,
+                event = recv_event(&mut self.alarm_events) => self.receive(event),
+            }
+        }
+    }
+
+    /// Returns the message reporting an alarm event, if it's reported at all.
+    fn message(&mut self, event: alarm::Event) -> Option<ContactId> {
+        let report = |qualifier, code, group, number| {
+            Some(ContactId {
+                account: self.account,
+                qualifier,
+                code,
+                group,
+                number,
+            })
+        };
+
+        match event.0 {
+            alarm::EventType::StateChanged {
+                area,
+                state,
+                previous,
+                cause,
+            } => {
+                let group = area.index() as u8 + 1;
+                let user = match cause {
+                    Cause::User(user) => Some(user),
+                    _ => None,
+                };
+
+                match (state, previous) {
+                    (SystemState::ExitTiming(_), _) => {
+                        match user {
+                            Some(user) => self.setting_users.insert(area, user),
+                            None => self.setting_users.remove(&area),
+                        };
+                        None
+                    }
+                    (SystemState::Set | SystemState::PartSet, SystemState::ExitTiming(_)) => {
+                        let code = match state {
+                            SystemState::PartSet => contact_id::PART_SET,
+                            _ => contact_id::OPEN_CLOSE,
+                        };
+                        let user = self.setting_users.remove(&area).unwrap_or(0);
+                        report(Qualifier::Restore, code, group, user)
+                    }
+                    (SystemState::Alarm, _) => {
+                        let (code, number) = match cause {
+                            Cause::Zone(zone) => (self.alarm_code(zone), zone_number(zone)),
+                            // A cut or shorted cable, whatever the type of the zone.
+                            Cause::ZoneTamper(zone) => {
+                                (contact_id::SENSOR_TAMPER, zone_number(zone))
+                            }
+                            Cause::Module(address) => (contact_id::MODULE_TAMPER, address as u16),
+                            // A keypad locked out after repeated invalid codes.
+                            Cause::Tamper => (contact_id::WRONG_CODE, 0),
+                            // The entry timer expired.
+                            _ => (contact_id::ENTRY_EXIT, 0),
+                        };
+                        report(Qualifier::New, code, group, number)
+                    }
+                    (
+                        SystemState::Unset | SystemState::AlarmResetRequired,
+                        SystemState::Set
+                        | SystemState::PartSet
+                        | SystemState::EntryTiming(_)
+                        | SystemState::Alarm,
+                    ) => report(
+                        Qualifier::New,
+                        contact_id::OPEN_CLOSE,
+                        group,
+                        user.unwrap_or(0),
+                    ),
+                    _ => None,
+                }
+            }
+            alarm::EventType::SetFailed { area, .. } => report(
+                Qualifier::New,
+                contact_id::FAILED_TO_SET,
+                area.index() as u8 + 1,
+                0,
+            ),
+            alarm::EventType::FaultRaised { address, kind } => {
+                report(Qualifier::New, fault_code(kind), 0, address as u16)
+            }
+            alarm::EventType::FaultRestored { address, kind } => {
+                report(Qualifier::Restore, fault_code(kind), 0, address as u16)
+            }
+            alarm::EventType::FaultsAcknowledged { .. } => None,
+        }
+    }
+
+    fn alarm_code(&self, zone: u16) -> u16 {
+        match self.zone_types.get(&zone) {
+            Some(ZoneType::Fire) => contact_id::FIRE,
+            Some(ZoneType::Pa) => contact_id::PANIC,
+            Some(ZoneType::TwentyFourHour) => contact_id::TWENTY_FOUR_HOUR,
+            Some(ZoneType::Tamper) => contact_id::SENSOR_TAMPER,
+            _ => contact_id::BURGLARY,
+        }
+    }
+}
+
+/// Returns the event code reporting a module fault.
+fn fault_code(kind: FaultKind) -> u16 {
+    match kind {
+        FaultKind::Missing | FaultKind::Comms => contact_id::MODULE_FAILURE,
+        FaultKind::MainsFail => contact_id::AC_LOSS,
+        FaultKind::BatteryLow => contact_id::LOW_BATTERY,
+        // A blown output fuse follows an overload of the PSU's output.
+        FaultKind::FuseBlown => contact_id::POWER_OVERCURRENT,
+        FaultKind::Tamper => contact_id::MODULE_TAMPER,
+    }
+}
+
+/// Returns the three digit zone number reported for a zone, which drops the leading 1 of the
+/// Galaxy zone number, so that zone 1001 is reported as 001.
+fn zone_number(zone: u16) -> u16 {
+    zone % 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alarm::SetMode,
+        areas::Areas,
+        reporting::simulator::SimulatedReceiver,
+        zones::{EolScheme, ZoneInput},
+    };
+
+    fn zone(number: u16, zone_type: ZoneType) -> Zone {
+        Zone {
+            number,
+            name: format!("ZONE {}", number),
+            zone_type,
+            scheme: EolScheme::default(),
+            input: ZoneInput {
+                device: 0x20,
+                input: (number % 10) as u8,
+            },
+            part_set: true,
+            chime: false,
+            areas: Areas::single(Area::A),
+        }
+    }
+
+    fn reporting_manager() -> (
+        ReportingManager<SimulatedReceiver>,
+        SimulatedReceiver,
+        broadcast::Sender<alarm::Event>,
+    ) {
+        let (alarm_tx, alarm_rx) = broadcast::channel(10);
+        let receiver = SimulatedReceiver::new();
+        let manager = ReportingManager::new(
+            "1234".parse().unwrap(),
+            receiver.clone(),
+            &[zone(1001, ZoneType::Final), zone(1002, ZoneType::Fire)],
+            alarm_rx,
+        );
+
+        (manager, receiver, alarm_tx)
+    }
+
+    fn state_changed(state: SystemState, previous: SystemState, cause: Cause) -> alarm::Event {
+        alarm::Event(alarm::EventType::StateChanged {
+            area: Area::A,
+            state,
+            previous,
+            cause,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reports_alarm_events() {
+        time::pause();
+
+        let (mut manager, receiver, alarm_tx) = reporting_manager();
+
+        for event in [
+            state_changed(
+                SystemState::ExitTiming(SetMode::Full),
+                SystemState::Unset,
+                Cause::User(7),
+            ),
+            state_changed(
+                SystemState::Set,
+                SystemState::ExitTiming(SetMode::Full),
+                Cause::Timer,
+            ),
+            state_changed(SystemState::Alarm, SystemState::Set, Cause::Zone(1002)),
+            state_changed(
+                SystemState::Alarm,
+                SystemState::Alarm,
+                Cause::ZoneTamper(1001),
+            ),
+            state_changed(
+                SystemState::AlarmResetRequired,
+                SystemState::Alarm,
+                Cause::User(3),
+            ),
+            alarm::Event(alarm::EventType::FaultRaised {
+                address: 0x20,
+                kind: FaultKind::Missing,
+            }),
+        ] {
+            alarm_tx.send(event).unwrap();
+        }
+        drop(alarm_tx);
+        manager.run().await;
+
+        assert_eq!(
+            receiver
+                .received()
+                .iter()
+                .map(ContactId::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "123418340101007F",
+                "1234181110010020",
+                "123418114401001E",
+                "1234181401010036",
+                "123418133300032B",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_acknowledged() {
+        time::pause();
+
+        let (manager, receiver, alarm_tx) = reporting_manager();
+        let mut manager = manager.with_delivery(Delivery {
+            attempts: 3,
+            retry_delay: Duration::from_secs(5),
+            ack_timeout: Duration::from_secs(10),
+        });
+        let mut events = manager.subscribe_events();
+
+        receiver.reject_next(1);
+        receiver.ignore_next(1);
+        alarm_tx
+            .send(state_changed(
+                SystemState::Alarm,
+                SystemState::Set,
+                Cause::Zone(1001),
+            ))
+            .unwrap();
+        drop(alarm_tx);
+
+        let start = Instant::now();
+        manager.run().await;
+
+        // Rejected, retried after 5s, timed out after 10s and delivered after another 5s.
+        assert_eq!(start.elapsed().as_secs(), 20);
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::Delivered { message } if message.code == contact_id::BURGLARY
+        ));
+        assert_eq!(receiver.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_takes_alarm_events_during_exchange() {
+        time::pause();
+
+        let (mut manager, receiver, alarm_tx) = reporting_manager();
+        let fault = |address| {
+            alarm::Event(alarm::EventType::FaultRaised {
+                address,
+                kind: FaultKind::Missing,
+            })
+        };
+
+        // The first report waits out the acknowledgement timeout while more events arrive than
+        // the channel holds.
+        receiver.ignore_next(1);
+        alarm_tx.send(fault(0x20)).unwrap();
+        let run = tokio::spawn(async move { manager.run().await });
+        time::sleep(Duration::from_secs(1)).await;
+        for address in 0x21..0x35 {
+            alarm_tx.send(fault(address)).unwrap();
+            tokio::task::yield_now().await;
+        }
+        drop(alarm_tx);
+        run.await.unwrap();
+
+        assert_eq!(receiver.received().len(), 21);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_attempts() {
+        time::pause();
+
+        let (manager, receiver, alarm_tx) = reporting_manager();
+        let mut manager = manager.with_delivery(Delivery {
+            attempts: 2,
+            ..Default::default()
+        });
+        let mut events = manager.subscribe_events();
+
+        receiver.reject_next(2);
+        alarm_tx
+            .send(alarm::Event(alarm::EventType::SetFailed {
+                area: Area::A,
+                zones: vec![1001],
+            }))
+            .unwrap();
+        alarm_tx
+            .send(alarm::Event(alarm::EventType::FaultRestored {
+                address: 0x20,
+                kind: FaultKind::Missing,
+            }))
+            .unwrap();
+        drop(alarm_tx);
+        manager.run().await;
+
+        assert_eq!(events.recv().await.unwrap().0, EventType::LinkFailed);
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::Failed { message, attempts: 2 } if message.code == contact_id::FAILED_TO_SET
+        ));
+        // The next message is delivered regardless.
+        assert_eq!(events.recv().await.unwrap().0, EventType::LinkRestored);
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::Delivered { message } if message.code == contact_id::MODULE_FAILURE
+        ));
+    }
+}
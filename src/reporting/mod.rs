@@ -0,0 +1,76 @@
+use std::{future::Future, io, time::Duration};
+use thiserror::Error;
+
+use contact_id::ContactId;
+
+pub mod contact_id;
+pub mod dc09;
+pub mod ip;
+pub mod manager;
+pub mod simulator;
+
+/// Transport carries reports to the alarm receiver at a monitoring station.
+pub trait Transport: Send {
+    /// Sends a message, resolving once the receiver has acknowledged it.
+    fn send(
+        &mut self,
+        message: &ContactId,
+    ) -> impl Future<Output = Result<(), TransportError>> + Send;
+
+    /// Sends a supervision message while there's nothing to report, so that the monitoring
+    /// station knows the link is still working. Transports without supervision do nothing.
+    fn heartbeat(&mut self) -> impl Future<Output = Result<(), TransportError>> + Send {
+        async { Ok(()) }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    // The receiver replied, but refused the message, e.g. as it arrived corrupted.
+    #[error("Rejected by receiver")]
+    Rejected,
+    // The receiver doesn't support the message, so there's no use sending it again.
+    #[error("Not supported by receiver")]
+    Unsupported,
+    #[error("Malformed reply: {0}")]
+    Malformed(String),
+    #[error("Timed out awaiting acknowledgement")]
+    Timeout,
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Delivery governs how hard a message is pushed to the receiver before giving up on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    // Number of times a message is sent before giving up on it.
+    pub attempts: u32,
+    // Time to wait between attempts.
+    pub retry_delay: Duration,
+    // Time allowed for the receiver to acknowledge a message.
+    pub ack_timeout: Duration,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery {
+            attempts: 5,
+            retry_delay: Duration::from_secs(5),
+            ack_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventType {
+    // The receiver acknowledged the message.
+    Delivered { message: ContactId },
+    // The message was dropped after every attempt to deliver it failed.
+    Failed { message: ContactId, attempts: u32 },
+    // A heartbeat or report went unacknowledged, so the monitoring station can't be reached.
+    LinkFailed,
+    LinkRestored,
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
@@ -0,0 +1,208 @@
+use chrono::Utc;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, UdpSocket},
+};
+
+use super::{
+    contact_id::ContactId,
+    dc09::{self, Frame, Key},
+    ip::read_frame,
+    Transport, TransportError,
+};
+
+/// SimulatedReceiver stands in for the alarm receiver at a monitoring station, for testing
+/// reporting without one. Clones share the same receiver, so that one can be given to the
+/// ReportingManager as its transport and the other used to inspect what it received.
+#[derive(Clone, Default)]
+pub struct SimulatedReceiver {
+    state: Arc<Mutex<ReceiverState>>,
+}
+
+#[derive(Default)]
+struct ReceiverState {
+    received: Vec<ContactId>,
+    // Number of the next messages to refuse, and to ignore without acknowledging.
+    reject: usize,
+    ignore: usize,
+}
+
+impl SimulatedReceiver {
+    pub fn new() -> SimulatedReceiver {
+        Self::default()
+    }
+
+    /// Returns the messages received and acknowledged, in order.
+    pub fn received(&self) -> Vec<ContactId> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Refuses the next `count` messages, as if they arrived corrupted.
+    pub fn reject_next(&self, count: usize) {
+        self.state.lock().unwrap().reject = count;
+    }
+
+    /// Never acknowledges the next `count` messages, as if they were lost on the way.
+    pub fn ignore_next(&self, count: usize) {
+        self.state.lock().unwrap().ignore = count;
+    }
+
+    fn receive(&self, text: &str) -> Option<Result<(), TransportError>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.reject > 0 {
+            state.reject -= 1;
+            return Some(Err(TransportError::Rejected));
+        }
+        if state.ignore > 0 {
+            state.ignore -= 1;
+            return None;
+        }
+
+        // The message is checked as a real receiver would, from its digits.
+        match text.parse() {
+            Ok(message) => {
+                state.received.push(message);
+                Some(Ok(()))
+            }
+            Err(_) => Some(Err(TransportError::Rejected)),
+        }
+    }
+}
+
+impl Transport for SimulatedReceiver {
+    async fn send(&mut self, message: &ContactId) -> Result<(), TransportError> {
+        match self.receive(&message.to_string()) {
+            Some(result) => result,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// MAX_CLOCK_AHEAD and MAX_CLOCK_BEHIND bound how far the time of an encrypted message may be
+/// from the receiver's own before it's refused, guarding against replayed messages.
+const MAX_CLOCK_AHEAD: i64 = 20;
+const MAX_CLOCK_BEHIND: i64 = 40;
+
+/// Dc09Receiver is a SIA DC-09 receiver serving on local sockets, for testing reporting over IP
+/// without a monitoring station. Clones share the same receiver.
+#[derive(Clone)]
+pub struct Dc09Receiver {
+    key: Option<Key>,
+    // Difference between the receiver's clock and the local clock.
+    clock_offset: chrono::Duration,
+    state: Arc<Mutex<Dc09State>>,
+}
+
+#[derive(Default)]
+struct Dc09State {
+    received: Vec<Frame>,
+    // Number of the next messages to NAK, and to answer with DUH as if not understood.
+    nak: usize,
+    duh: usize,
+}
+
+impl Dc09Receiver {
+    pub fn new(key: Option<Key>) -> Dc09Receiver {
+        Dc09Receiver {
+            key,
+            clock_offset: chrono::Duration::zero(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Runs the receiver's clock ahead of the local clock by the given offset.
+    pub fn with_clock_offset(mut self, offset: chrono::Duration) -> Self {
+        self.clock_offset = offset;
+        self
+    }
+
+    /// Returns the messages received and acknowledged, including heartbeats, in order.
+    pub fn received(&self) -> Vec<Frame> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    pub fn nak_next(&self, count: usize) {
+        self.state.lock().unwrap().nak = count;
+    }
+
+    pub fn duh_next(&self, count: usize) {
+        self.state.lock().unwrap().duh = count;
+    }
+
+    /// Accepts connections, answering each message sent on them until they close.
+    pub async fn serve_tcp(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let receiver = self.clone();
+
+            tokio::spawn(async move {
+                let mut buffer = Vec::new();
+                while let Ok(frame) = read_frame(&mut stream, &mut buffer).await {
+                    if stream.write_all(&receiver.reply(&frame)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Answers each datagram received.
+    pub async fn serve_udp(self, socket: UdpSocket) -> io::Result<()> {
+        let mut buffer = [0u8; 1024];
+
+        loop {
+            let (n, peer) = socket.recv_from(&mut buffer).await?;
+            socket.send_to(&self.reply(&buffer[..n]), peer).await?;
+        }
+    }
+
+    fn reply(&self, encoded: &[u8]) -> Vec<u8> {
+        let now = Utc::now().naive_utc() + self.clock_offset;
+        let encrypted = dc09::is_encrypted(encoded);
+
+        let frame = match Frame::decode(encoded, self.key.as_ref()) {
+            Ok(frame) => frame,
+            Err(_) => return Frame::nak(now).encode(None),
+        };
+        let skew = frame
+            .timestamp
+            .map(|timestamp| (timestamp - now).num_seconds());
+        if encrypted
+            && !skew.is_some_and(|skew| (-MAX_CLOCK_BEHIND..=MAX_CLOCK_AHEAD).contains(&skew))
+        {
+            return Frame::nak(now).encode(None);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.nak > 0 {
+            state.nak -= 1;
+            return Frame::nak(now).encode(None);
+        }
+
+        let message_type = match frame.message_type.as_str() {
+            _ if state.duh > 0 => {
+                state.duh -= 1;
+                dc09::DUH
+            }
+            dc09::CONTACT_ID | dc09::HEARTBEAT => {
+                state.received.push(frame.clone());
+                dc09::ACK
+            }
+            _ => dc09::DUH,
+        };
+
+        // Replies to encrypted messages are encrypted in turn, and so need the time.
+        let mut reply = frame.reply(message_type);
+        if encrypted {
+            reply.timestamp = Some(now);
+            reply.encode(self.key.as_ref())
+        } else {
+            reply.encode(None)
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::time::Instant;
+
+use super::galaxy::{bus::ReadError, Exchange, GalaxyCRC};
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("capture I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("unable to encode capture entry: {0}")]
+    Encode(#[from] serde_json::Error),
+    #[error("capture line {line}: {source}")]
+    Format {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+/// Record is something observed on the bus.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    // Bytes seen on the line and split into a frame by the sniffer, including the CRC, which may
+    // not be valid.
+    Frame {
+        #[serde(with = "hex")]
+        data: Vec<u8>,
+    },
+    // A message sent by the panel, including the CRC.
+    Outbound {
+        #[serde(with = "hex")]
+        data: Vec<u8>,
+    },
+    // The reply to the last outbound message, including the CRC.
+    Reply {
+        #[serde(with = "hex")]
+        data: Vec<u8>,
+    },
+    // The last outbound message went without a usable reply.
+    Error {
+        error: BusError,
+    },
+}
+
+/// BusError records why an exchange on the bus failed, mirroring `ReadError`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BusError {
+    Timeout,
+    NoData,
+    Truncated(usize),
+    TrailingGarbage(usize),
+    Overflow(usize),
+    CrcCheckFailed,
+    EchoMismatch,
+    InvalidReplyRecipient(u8),
+    // Only the description of an I/O error is kept.
+    Io(String),
+}
+
+impl From<&ReadError> for BusError {
+    fn from(value: &ReadError) -> Self {
+        match value {
+            ReadError::Timeout => BusError::Timeout,
+            ReadError::NoData => BusError::NoData,
+            ReadError::Truncated(length) => BusError::Truncated(*length),
+            ReadError::TrailingGarbage(length) => BusError::TrailingGarbage(*length),
+            ReadError::Overflow(length) => BusError::Overflow(*length),
+            ReadError::CrcCheckFailed => BusError::CrcCheckFailed,
+            ReadError::EchoMismatch => BusError::EchoMismatch,
+            ReadError::InvalidReplyRecipient(address) => BusError::InvalidReplyRecipient(*address),
+            ReadError::IoError(e) => BusError::Io(e.to_string()),
+        }
+    }
+}
+
+impl From<BusError> for ReadError {
+    fn from(value: BusError) -> Self {
+        match value {
+            BusError::Timeout => ReadError::Timeout,
+            BusError::NoData => ReadError::NoData,
+            BusError::Truncated(length) => ReadError::Truncated(length),
+            BusError::TrailingGarbage(length) => ReadError::TrailingGarbage(length),
+            BusError::Overflow(length) => ReadError::Overflow(length),
+            BusError::CrcCheckFailed => ReadError::CrcCheckFailed,
+            BusError::EchoMismatch => ReadError::EchoMismatch,
+            BusError::InvalidReplyRecipient(address) => ReadError::InvalidReplyRecipient(address),
+            BusError::Io(message) => ReadError::from(io::Error::other(message)),
+        }
+    }
+}
+
+/// Entry is a record timestamped relative to the start of the capture.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    #[serde(rename = "at_us", with = "micros")]
+    pub at: Duration,
+    #[serde(flatten)]
+    pub record: Record,
+}
+
+/// CaptureWriter writes a capture file, holding one JSON encoded entry per line.
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl CaptureWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        Ok(CaptureWriter::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(writer: W) -> CaptureWriter<W> {
+        CaptureWriter { writer }
+    }
+
+    /// Writes an entry, flushing it so that the capture survives the process being killed.
+    pub fn write(&mut self, entry: &Entry) -> Result<(), CaptureError> {
+        writeln!(self.writer, "{}", serde_json::to_string(entry)?)?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// BusRecorder wraps the bus, writing every exchange to a capture as it happens so that it can be
+/// replayed later.
+pub struct BusRecorder<B, W: Write = BufWriter<File>> {
+    bus: B,
+    writer: CaptureWriter<W>,
+    started: Instant,
+}
+
+impl<B: Exchange, W: Write + Send> BusRecorder<B, W> {
+    pub fn new(bus: B, writer: CaptureWriter<W>) -> BusRecorder<B, W> {
+        BusRecorder {
+            bus,
+            writer,
+            started: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, record: Record) {
+        let entry = Entry {
+            at: self.started.elapsed(),
+            record,
+        };
+
+        // Failing to capture shouldn't take down the bus.
+        if let Err(e) = self.writer.write(&entry) {
+            error!("Unable to write bus capture: {}", e);
+        }
+    }
+}
+
+impl<B: Exchange, W: Write + Send> Exchange for BusRecorder<B, W> {
+    async fn send_receive_buffered(
+        &mut self,
+        data: &[u8],
+        reply: &mut [u8],
+    ) -> Result<usize, ReadError> {
+        self.record(Record::Outbound {
+            data: [data, &[data.galaxy_crc()]].concat(),
+        });
+
+        let result = self.bus.send_receive_buffered(data, reply).await;
+
+        self.record(match &result {
+            // The CRC follows the reply in the buffer.
+            Ok(bytes_read) => Record::Reply {
+                data: reply[..bytes_read + 1].to_vec(),
+            },
+            Err(e) => Record::Error { error: e.into() },
+        });
+
+        result
+    }
+}
+
+/// Reads every entry from a capture file.
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Entry>, CaptureError> {
+    let mut entries = Vec::new();
+
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        entries.push(
+            serde_json::from_str(&line).map_err(|source| CaptureError::Format {
+                line: i + 1,
+                source,
+            })?,
+        );
+    }
+
+    Ok(entries)
+}
+
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let s: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+        serializer.serialize_str(&s.join(" "))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        String::deserialize(deserializer)?
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+mod micros {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(at: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(at.as_micros() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_micros(u64::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bus.capture");
+
+        let entries = vec![
+            Entry {
+                at: Duration::from_micros(1500),
+                record: Record::Frame {
+                    data: vec![0x10, 0x06, 0x01],
+                },
+            },
+            Entry {
+                at: Duration::from_millis(12),
+                record: Record::Frame {
+                    data: vec![0x11, 0xFE, 0xBA],
+                },
+            },
+            Entry {
+                at: Duration::from_millis(20),
+                record: Record::Error {
+                    error: BusError::InvalidReplyRecipient(0x10),
+                },
+            },
+        ];
+
+        let mut writer = CaptureWriter::create(&path).unwrap();
+        for entry in &entries {
+            writer.write(entry).unwrap();
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().next(),
+            Some(r#"{"at_us":1500,"type":"frame","data":"10 06 01"}"#)
+        );
+        assert_eq!(read(&path).unwrap(), entries);
+    }
+}
@@ -1,18 +1,24 @@
-use log::{debug, error, info, trace};
-use std::sync::{Mutex, RwLock};
+use log::{error, info, trace};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::Notify;
 
-use crate::serial::{DeliveryError, SerialDevice, SerialMessage};
+use crate::serial::{manager::Priority, DeliveryError, SerialDevice, SerialMessage};
 
 // KEYS represents the individual keys on the keypad, with the indices representing the code used
 // to convey key meaning from the device.
-const KEYS: &str = "0123456789BAEX*#";
+pub(crate) const KEYS: &str = "0123456789BAEX*#";
+
+/// Returns whether a character is one of the keys on the keypad.
+pub fn is_key(key: char) -> bool {
+    KEYS.contains(key)
+}
 
 fn key_to_char(idx: u8) -> char {
     KEYS.chars()
         .nth(idx as usize)
-        .expect(format!("key index out of bounds: {:02X}", idx).as_str())
+        .unwrap_or_else(|| panic!("key index out of bounds: {:02X}", idx))
 }
 
 #[derive(Clone, Debug)]
@@ -71,6 +77,10 @@ struct KeypadUpdates {
     send_key_clicks: bool,
     send_screen: bool,
 
+    // Set when the beeper was the last update sent, so that a beeper changing in quick succession,
+    // as when chiming, can't hold back updates to the screen.
+    beeper_sent_last: bool,
+
     // Flags used when conveying acknowledgements or update events to the keypad that require an
     // indication of freshness, rather than a repeat of a previous event. Toggled each time they
     // are sent.
@@ -87,6 +97,8 @@ impl Default for KeypadUpdates {
             send_key_clicks: false,
             send_screen: false,
 
+            beeper_sent_last: false,
+
             screen_update_flag: UpdateFlag(true),
             key_update_flag: UpdateFlag(true),
         }
@@ -95,8 +107,8 @@ impl Default for KeypadUpdates {
 
 /// SerialKeypad handles the serial interface and state management for interacting with a CP-037 or
 /// CP-038 keypad on the Galaxy bus. In the case of CP-038, this specifically focuses on the keypad
-/// itself; the integrated Prox reader, operating on a distinct serial bus address, is not taken
-/// into account in this model.
+/// itself; the integrated Prox reader operates on a distinct serial bus address, and is modelled
+/// by SerialProx.
 pub struct SerialKeypad {
     state: RwLock<State>,
     // The keypad is online if last_state is Some.
@@ -105,6 +117,8 @@ pub struct SerialKeypad {
     // The serial update logic typically works out the correct message to send, but in some cases
     // it is necessary to force sending.
     updates: Mutex<KeypadUpdates>,
+    // Notified when the state is changed, so that the update is sent promptly.
+    wake: Mutex<Option<Arc<Notify>>>,
 
     event_ch: Mutex<tokio::sync::broadcast::Sender<Event>>,
 }
@@ -116,6 +130,7 @@ impl Default for SerialKeypad {
             last_state: RwLock::new(None),
             tamper: Mutex::new(false),
             updates: Mutex::new(KeypadUpdates::default()),
+            wake: Mutex::new(None),
 
             event_ch: Mutex::new(tokio::sync::broadcast::Sender::new(10)),
         }
@@ -153,6 +168,11 @@ impl SerialKeypad {
             .write()
             .expect("unable to lock keypad state for writing");
         f(&mut state);
+        drop(state);
+
+        if let Some(wake) = self.wake.lock().unwrap().as_ref() {
+            wake.notify_one();
+        }
     }
 
     pub fn is_tamper(&self) -> bool {
@@ -163,6 +183,27 @@ impl SerialKeypad {
         self.event_ch.lock().unwrap().subscribe()
     }
 
+    /// Returns whether anything other than a ping is waiting to be sent to the keypad.
+    fn has_updates(&self) -> bool {
+        let current_state = self.state.read().unwrap();
+        let last_state_lock = self.last_state.read().unwrap();
+        let Some(last_state) = last_state_lock.as_ref() else {
+            return false;
+        };
+        let updates = self.updates.lock().unwrap();
+
+        updates.send_key_ack
+            || updates.send_backlight
+            || updates.send_beeper
+            || updates.send_key_clicks
+            || updates.send_screen
+            || current_state.backlight != last_state.backlight
+            || current_state.beeper != last_state.beeper
+            || current_state.key_clicks != last_state.key_clicks
+            || current_state.screen != last_state.screen
+            || current_state.blink != last_state.blink
+    }
+
     fn next_command(&self) -> (Command, Option<Vec<u8>>) {
         let current_state = self.state.read().unwrap();
         let mut last_state_lock = self
@@ -179,6 +220,12 @@ impl SerialKeypad {
         // If unsatisfactory responses are received, the entire state is reset, at which point the
         // partial optimistic updates here are immaterial.
 
+        let screen_pending = updates.send_screen
+            || current_state.screen != last_state.screen
+            // Blink is not captured on screen because it complicates the diff algorithm.
+            || current_state.blink != last_state.blink;
+        let beeper_sent_last = std::mem::take(&mut updates.beeper_sent_last);
+
         if updates.send_backlight || current_state.backlight != last_state.backlight {
             updates.send_backlight = false;
             last_state.backlight = current_state.backlight;
@@ -187,8 +234,11 @@ impl SerialKeypad {
                 Command::Backlight,
                 Some(vec![current_state.backlight.into()]),
             )
-        } else if updates.send_beeper || current_state.beeper != last_state.beeper {
+        } else if (updates.send_beeper || current_state.beeper != last_state.beeper)
+            && !(beeper_sent_last && screen_pending)
+        {
             updates.send_beeper = false;
+            updates.beeper_sent_last = true;
             last_state.beeper = current_state.beeper;
 
             (Command::Beeper, Some(current_state.beeper.into()))
@@ -200,11 +250,7 @@ impl SerialKeypad {
                 Command::KeyClicks,
                 Some(vec![current_state.key_clicks.into()]),
             )
-        } else if updates.send_screen
-            || current_state.screen != last_state.screen
-            // Blink is not captured on screen because it complicates the diff algorithm.
-            || current_state.blink != last_state.blink
-        {
+        } else if screen_pending {
             let screen = &current_state.screen;
 
             // TODO investigate what 0x1F command does in screen updates
@@ -263,6 +309,18 @@ impl SerialDevice for SerialKeypad {
         (command.into(), data)
     }
 
+    fn priority(&self) -> Priority {
+        if self.has_updates() {
+            Priority::Urgent
+        } else {
+            Priority::Idle
+        }
+    }
+
+    fn set_wake(&self, wake: Arc<Notify>) {
+        *self.wake.lock().unwrap() = Some(wake);
+    }
+
     fn receive_update(&self, msg: Result<SerialMessage, DeliveryError>) {
         let ev_ch = self.event_ch.lock().unwrap().clone();
 
@@ -404,6 +462,27 @@ pub enum Command {
     KeyClicks,
 }
 
+#[derive(Clone, Debug, Error)]
+#[error("invalid keypad command op code {0}")]
+pub struct InvalidCommandByteError(pub u8);
+
+impl TryFrom<u8> for Command {
+    type Error = InvalidCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Initialise),
+            0x06 => Ok(Self::Ping),
+            0x07 => Ok(Self::Screen),
+            0x0B => Ok(Self::ButtonAck),
+            0x0C => Ok(Self::Beeper),
+            0x0D => Ok(Self::Backlight),
+            0x19 => Ok(Self::KeyClicks),
+            x => Err(InvalidCommandByteError(x)),
+        }
+    }
+}
+
 impl From<Command> for u8 {
     fn from(value: Command) -> Self {
         match value {
@@ -527,8 +606,8 @@ impl From<Beeper> for Vec<u8> {
     }
 }
 
-mod display {
-    use log::{debug, trace};
+pub mod display {
+    use log::trace;
 
     #[derive(Clone, Copy, Debug, PartialEq)]
     pub enum CursorStyle {
@@ -628,7 +707,7 @@ mod display {
                 };
 
             if let Some(offset) = self.cursor_position {
-                if cursor_position.map_or(true, |cur_pos| cur_pos as u8 != offset) {
+                if cursor_position.is_none_or(|cur_pos| cur_pos as u8 != offset) {
                     update.extend([ScreenOpCodes::CURSOR_SEEK_BYTE, offset]);
                 }
             }
@@ -637,7 +716,9 @@ mod display {
         }
 
         pub(super) fn full_update(&self) -> (Vec<u8>, Option<usize>) {
-            let mut data = vec![ScreenOpCodes::DISPLAY_RESET];
+            // The cursor is hidden while the lines are written out, so that it does not visibly
+            // trail the output.
+            let mut data = vec![ScreenOpCodes::DISPLAY_RESET, ScreenOpCodes::CURSOR_HIDDEN];
 
             // Full update does not require line padding of output lines to display width with
             // whitespace as the display was reset to blank.
@@ -653,7 +734,7 @@ mod display {
                 .zip(self.lines.iter())
                 .enumerate()
                 {
-                    if line.len() > 0 {
+                    if !line.is_empty() {
                         data.push(op);
                         data.extend(line.chars().map(|x| x as u8));
 
@@ -675,8 +756,8 @@ mod display {
             let mut data = vec![];
 
             let cursor_final_position = {
-                let from = from.lines.iter().map(|line| pad_string_iterator(16, &line));
-                let to = self.lines.iter().map(|line| pad_string_iterator(16, &line));
+                let from = from.lines.iter().map(|line| pad_string_iterator(16, line));
+                let to = self.lines.iter().map(|line| pad_string_iterator(16, line));
 
                 let mut cursor_position = None;
 
@@ -703,10 +784,10 @@ mod display {
 
                             // if cursor_diff is 0, it is already in the correct place, so no
                             // action is required.
-                            if cursor_diff == 1 && skipped_char.is_some() {
+                            if let (1, Some(skipped_char)) = (cursor_diff, skipped_char) {
                                 // The changed blocks can be 'fused' by pushing the skipped
                                 // character, saving a byte relative to seeking.
-                                data.push(skipped_char.unwrap() as u8);
+                                data.push(skipped_char as u8);
                             } else if cursor_diff >= 2 {
                                 if j == 0 {
                                     // Start of block can use the special start of block op code,
@@ -763,9 +844,11 @@ mod display {
                 2 * discrete_blocks + chars_diff
             };
 
-            let cursor_score = (from.cursor_style != self.cursor_style)
-                .then_some(1)
-                .unwrap_or(0);
+            let cursor_score = if from.cursor_style != self.cursor_style {
+                1
+            } else {
+                0
+            };
 
             lines_score + cursor_score
         }
@@ -784,6 +867,97 @@ mod display {
         }
     }
 
+    // Width of a line of the display.
+    const WIDTH: usize = 16;
+    // Cursor offset of the start of the second line.
+    const SECOND_LINE_OFFSET: usize = 0x40;
+
+    /// VirtualDisplay renders screen updates into the text they show, as a keypad would.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct VirtualDisplay {
+        lines: [[u8; WIDTH]; 2],
+        cursor: usize,
+    }
+
+    impl Default for VirtualDisplay {
+        fn default() -> Self {
+            VirtualDisplay {
+                lines: [[b' '; WIDTH]; 2],
+                cursor: 0,
+            }
+        }
+    }
+
+    impl VirtualDisplay {
+        pub fn new() -> VirtualDisplay {
+            Default::default()
+        }
+
+        fn put(&mut self, c: u8) {
+            let (line, column) = (
+                self.cursor / SECOND_LINE_OFFSET,
+                self.cursor % SECOND_LINE_OFFSET,
+            );
+            if line < self.lines.len() && column < WIDTH {
+                self.lines[line][column] = c;
+            }
+            self.cursor += 1;
+        }
+
+        /// Applies the op codes of a screen update, following the display flags byte, and returns
+        /// any op codes that were not understood.
+        pub fn apply(&mut self, ops: &[u8]) -> Vec<u8> {
+            let mut unknown = Vec::new();
+            let mut ops = ops.iter();
+
+            while let Some(&op) = ops.next() {
+                match op {
+                    ScreenOpCodes::DISPLAY_RESET => *self = VirtualDisplay::new(),
+                    ScreenOpCodes::CURSOR_FIRST_LINE => self.cursor = 0,
+                    ScreenOpCodes::CURSOR_SECOND_LINE => self.cursor = SECOND_LINE_OFFSET,
+                    ScreenOpCodes::CURSOR_SEEK_BYTE => match ops.next() {
+                        Some(&offset) => self.cursor = offset as usize,
+                        None => unknown.push(op),
+                    },
+                    ScreenOpCodes::CURSOR_LEFT_NO_ERASE => {
+                        self.cursor = self.cursor.saturating_sub(1);
+                    }
+                    ScreenOpCodes::CURSOR_RIGHT_NO_ERASE => self.cursor += 1,
+                    ScreenOpCodes::BACKSPACE => {
+                        self.cursor = self.cursor.saturating_sub(1);
+                        self.put(b' ');
+                        self.cursor -= 1;
+                    }
+                    // Cursor style, scrolling and flashing don't change the text shown.
+                    ScreenOpCodes::CURSOR_BLOCK_STYLE
+                    | ScreenOpCodes::CURSOR_HIDDEN
+                    | ScreenOpCodes::CURSOR_UNDERLINE_STYLE
+                    | ScreenOpCodes::SCROLL_LEFT
+                    | ScreenOpCodes::SCROLL_RIGHT
+                    | ScreenOpCodes::FLASH_DISPLAY
+                    | ScreenOpCodes::STOP_FLASHING => {}
+                    // 0x08 and 0x09 print glyphs of their own.
+                    0x08 | 0x09 | 0x20.. => self.put(op),
+                    _ => unknown.push(op),
+                }
+            }
+
+            unknown
+        }
+
+        /// Returns the text of each line, without trailing whitespace. Characters outside of
+        /// printable ASCII are shown as their Latin-1 equivalents.
+        pub fn lines(&self) -> [String; 2] {
+            self.lines.map(|line| {
+                line.iter()
+                    .map(|&c| c as char)
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+        }
+    }
+
     pub struct ScreenOpCodes;
 
     impl ScreenOpCodes {
@@ -845,6 +1019,7 @@ mod display {
     }
 
     #[cfg(test)]
+    #[allow(clippy::char_lit_as_u8)]
     mod tests {
         use super::*;
 
@@ -924,6 +1099,36 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_beeper_does_not_starve_screen() {
+        let keypad = SerialKeypad::new();
+        keypad.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: ReplyCommand::Initialised.into(),
+            additional_data: Some(vec![0x08, 0x00, 0x64]),
+        }));
+        while keypad.next_message().0 != u8::from(Command::Ping) {}
+
+        // The beeper and screen both change before every poll, as when chiming over a clock.
+        let mut commands = vec![];
+        for i in 0..6 {
+            keypad.mutate_state(|state| {
+                state.beeper = if i % 2 == 0 { Beeper::On } else { Beeper::Off };
+                state.screen.lines[0] = format!("{:02}:00", i);
+            });
+            commands.push(Command::try_from(keypad.next_message().0).unwrap());
+        }
+
+        // The screen is sent at least every other poll.
+        assert!(
+            commands
+                .windows(2)
+                .all(|pair| pair.contains(&Command::Screen)),
+            "{:?}",
+            commands
+        );
+    }
+
     #[test]
     fn test_update_flag() {
         let mut flag: UpdateFlag<0xFF> = UpdateFlag(false);
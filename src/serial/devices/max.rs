@@ -0,0 +1,538 @@
+use log::{error, info, trace};
+use std::sync::{Arc, Mutex, RwLock};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use super::prox::ID_LEN;
+use crate::serial::{manager::Priority, DeliveryError, SerialDevice, SerialMessage};
+
+// Flags of the status reply conveying the state of the reader's inputs, as assumed below.
+pub(crate) const DOOR_OPEN_FLAG: u8 = 0x01;
+pub(crate) const TAMPER_FLAG: u8 = 0x40;
+
+/// Led is the colour shown by the reader's LED.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Led {
+    #[default]
+    Off,
+    Red,
+    Green,
+}
+
+impl From<Led> for u8 {
+    fn from(value: Led) -> Self {
+        match value {
+            Led::Off => 0x00,
+            Led::Red => 0x01,
+            Led::Green => 0x02,
+        }
+    }
+}
+
+/// Sounder is the behaviour of the reader's internal sounder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sounder {
+    #[default]
+    Off,
+    On,
+    Intermittent,
+}
+
+impl From<Sounder> for u8 {
+    fn from(value: Sounder) -> Self {
+        match value {
+            Sounder::Off => 0x00,
+            Sounder::On => 0x01,
+            Sounder::Intermittent => 0x03,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct State {
+    pub led: Led,
+    pub sounder: Sounder,
+    pub relay: bool, // true = door lock released
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventType {
+    // A card or fob was presented to the reader, giving its ID.
+    CardRead(u32),
+    // The door contact wired to the reader opened or closed.
+    Door { open: bool },
+    Tamper(bool),
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+#[derive(Default)]
+struct MaxUpdates {
+    request_status: bool,
+    send_outputs: bool,
+    send_read_ack: bool,
+    // Toggled each time a read is acknowledged, so the reader can tell a fresh acknowledgement
+    // from a repeat.
+    read_ack_flag: bool,
+}
+
+/// SerialMax handles the serial interface and state management for a standalone Galaxy MAX or
+/// MAX3 proximity reader on the Galaxy bus. Besides reading cards and fobs, the reader drives an
+/// LED, a sounder and the door lock relay, and monitors the door contact.
+pub struct SerialMax {
+    state: RwLock<State>,
+    // The reader is online if last_state is Some.
+    last_state: RwLock<Option<State>>,
+    // Input flags last reported by the reader; None until the first status is received after
+    // initialisation.
+    inputs: Mutex<Option<u8>>,
+    updates: Mutex<MaxUpdates>,
+    // Notified when the state is changed, so that the update is sent promptly.
+    wake: Mutex<Option<Arc<Notify>>>,
+
+    event_ch: Mutex<tokio::sync::broadcast::Sender<Event>>,
+}
+
+impl Default for SerialMax {
+    fn default() -> Self {
+        Self {
+            state: RwLock::new(State::default()),
+            last_state: RwLock::new(None),
+            inputs: Mutex::new(None),
+            updates: Mutex::new(MaxUpdates::default()),
+            wake: Mutex::new(None),
+
+            event_ch: Mutex::new(tokio::sync::broadcast::Sender::new(16)),
+        }
+    }
+}
+
+impl SerialMax {
+    pub fn new() -> SerialMax {
+        Default::default()
+    }
+
+    pub fn mutate_state<F>(&self, f: F)
+    where
+        F: FnOnce(&mut State),
+    {
+        let mut state = self
+            .state
+            .write()
+            .expect("unable to lock MAX state for writing");
+        f(&mut state);
+        drop(state);
+
+        self.wake();
+    }
+
+    pub fn state(&self) -> State {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Returns whether the door is open, or None if the reader has not yet reported.
+    pub fn is_door_open(&self) -> Option<bool> {
+        self.inputs
+            .lock()
+            .unwrap()
+            .map(|inputs| inputs & DOOR_OPEN_FLAG != 0)
+    }
+
+    pub fn is_tamper(&self) -> bool {
+        self.inputs
+            .lock()
+            .unwrap()
+            .is_some_and(|inputs| inputs & TAMPER_FLAG != 0)
+    }
+
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.event_ch.lock().unwrap().subscribe()
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = self.wake.lock().unwrap().as_ref() {
+            wake.notify_one();
+        }
+    }
+
+    /// Returns whether anything other than a ping is waiting to be sent to the reader.
+    fn has_updates(&self) -> bool {
+        let current_state = self.state.read().unwrap();
+        let last_state_lock = self.last_state.read().unwrap();
+        let Some(last_state) = last_state_lock.as_ref() else {
+            return false;
+        };
+        let updates = self.updates.lock().unwrap();
+
+        updates.request_status
+            || updates.send_outputs
+            || updates.send_read_ack
+            || *current_state != *last_state
+    }
+
+    fn next_command(&self) -> (Command, Option<Vec<u8>>) {
+        let current_state = self.state.read().unwrap();
+        let mut last_state_lock = self
+            .last_state
+            .write()
+            .expect("unable to lock MAX last state for writing");
+
+        // unwrap guaranteed to succeed, as the device is guaranteed online
+        let last_state = last_state_lock.as_mut().unwrap();
+        let mut updates = self.updates.lock().unwrap();
+
+        if updates.request_status {
+            updates.request_status = false;
+
+            (Command::RequestStatus, None)
+        } else if updates.send_read_ack {
+            updates.send_read_ack = false;
+            updates.read_ack_flag ^= true;
+
+            (
+                Command::ReadAck,
+                Some(vec![if updates.read_ack_flag { 0x02 } else { 0x00 }]),
+            )
+        } else if updates.send_outputs || *current_state != *last_state {
+            updates.send_outputs = false;
+            *last_state = current_state.clone();
+
+            (
+                Command::Outputs,
+                Some(vec![
+                    current_state.led.into(),
+                    current_state.sounder.into(),
+                    current_state.relay as u8,
+                ]),
+            )
+        } else {
+            (Command::Ping, None)
+        }
+    }
+
+    fn process_status(&self, flags: u8, ev_ch: &tokio::sync::broadcast::Sender<Event>) {
+        let mut inputs = self.inputs.lock().unwrap();
+
+        // On the first report after initialisation, every input is published so subscribers
+        // learn the full picture.
+        let changed = inputs.map_or(0xFF, |inputs| inputs ^ flags);
+
+        if changed & DOOR_OPEN_FLAG != 0 {
+            let open = flags & DOOR_OPEN_FLAG != 0;
+            trace!("MAX door open {}", open);
+            let _ = ev_ch.send(Event(EventType::Door { open }));
+        }
+        if changed & TAMPER_FLAG != 0 {
+            let tamper = flags & TAMPER_FLAG != 0;
+            info!("MAX tamper state changed to {}", tamper);
+            let _ = ev_ch.send(Event(EventType::Tamper(tamper)));
+        }
+
+        *inputs = Some(flags);
+    }
+}
+
+impl SerialDevice for SerialMax {
+    fn next_message(&self) -> (u8, Option<Vec<u8>>) {
+        let (command, data) = if self
+            .last_state
+            .read()
+            .expect("unable to read last_state")
+            .is_none()
+        {
+            (Command::Initialise, Some(vec![0x0E]))
+        } else {
+            self.next_command()
+        };
+
+        (command.into(), data)
+    }
+
+    fn priority(&self) -> Priority {
+        if self.has_updates() {
+            Priority::Urgent
+        } else {
+            Priority::Idle
+        }
+    }
+
+    fn set_wake(&self, wake: Arc<Notify>) {
+        *self.wake.lock().unwrap() = Some(wake);
+    }
+
+    fn receive_update(&self, msg: Result<SerialMessage, DeliveryError>) {
+        let ev_ch = self.event_ch.lock().unwrap().clone();
+
+        trace!("got update: {:?}", msg);
+
+        let mut last_state = self
+            .last_state
+            .write()
+            .expect("unable to lock last_state for writing");
+
+        match msg {
+            Ok(reply) => match ReplyCommand::try_from(reply.command) {
+                Ok(ReplyCommand::Initialised) => {
+                    if last_state.is_some() {
+                        error!("Received initialise response for an already initialised MAX");
+                    } else if reply.additional_data.as_ref().map_or(0, |d| d.len()) != 3 {
+                        error!("Received invalid initialisation data from MAX");
+                    } else {
+                        info!(
+                            "MAX initialised, identity {:02X?}",
+                            reply.additional_data.unwrap()
+                        );
+                        *last_state = Some(self.state.read().unwrap().clone());
+
+                        let mut updates = self.updates.lock().unwrap();
+                        updates.request_status = true;
+                        updates.send_outputs = true;
+                        updates.send_read_ack = false;
+                    }
+                }
+                Ok(ReplyCommand::Ack) => {}
+                Ok(ReplyCommand::Status) => match reply.additional_data {
+                    Some(ref data) if data.len() == 1 => self.process_status(data[0], &ev_ch),
+                    _ => {
+                        error!("Received status with invalid data length from MAX");
+                        self.updates.lock().unwrap().request_status = true;
+                    }
+                },
+                Ok(ReplyCommand::CardRead) => match reply.additional_data {
+                    Some(ref data) if data.len() == ID_LEN => {
+                        let mut updates = self.updates.lock().unwrap();
+
+                        // The reader repeats the read until it's acknowledged, so a read while an
+                        // acknowledgement is pending is the same card.
+                        if !updates.send_read_ack {
+                            let id = u32::from_be_bytes(data[..].try_into().unwrap());
+                            info!("MAX read card {}", id);
+
+                            updates.send_read_ack = true;
+                            let _ = ev_ch.send(Event(EventType::CardRead(id)));
+                            drop(updates);
+                            self.wake();
+                        }
+                    }
+                    _ => error!("Received card read with invalid data length from MAX"),
+                },
+                Ok(ReplyCommand::BadChecksum) => {
+                    error!("Got BadChecksum from device in response to last update");
+                    // Device marked as offline.
+                    *last_state = None;
+                }
+                Err(_) => {
+                    error!("Received unknown reply command {}", reply.command);
+                }
+            },
+            Err(_) => {
+                // On error, the device is marked as offline and needs to be reinitialised. The
+                // inputs are forgotten so the full status is republished once it returns.
+                *last_state = None;
+                *self.inputs.lock().unwrap() = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    // Initialises the reader from its initial startup state, or if it dropped off the bus for a
+    // period of time. Data byte meaning is unknown.
+    //
+    // 60 00 0E 19
+    Initialise,
+    // Requests the state of the inputs regardless of whether anything changed since the last
+    // report.
+    //
+    // 60 02 0D
+    RequestStatus,
+    // General poll of the reader. It replies with the card last read if not yet acknowledged,
+    // then with a status if the inputs changed since the last report, or an Ack otherwise.
+    //
+    // 60 06 11
+    Ping,
+    // Acknowledges the card last read, so that the reader stops reporting it.
+    //
+    // 60 0B 02 18. Byte 3 toggles between 0x00 and 0x02 to guard against replays.
+    ReadAck,
+    // Sets the LED, sounder and door relay.
+    //
+    // 60 0C 02 00 01 1A.
+    //
+    // Byte 3: LED, 0x00 = off, 0x01 = red, 0x02 = green
+    // Byte 4: sounder, 0x00 = off, 0x01 = on, 0x03 = intermittent
+    // Byte 5: relay, 0x01 = door released
+    Outputs,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid MAX command op code {0}")]
+pub struct InvalidCommandByteError(pub u8);
+
+impl TryFrom<u8> for Command {
+    type Error = InvalidCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Initialise),
+            0x02 => Ok(Self::RequestStatus),
+            0x06 => Ok(Self::Ping),
+            0x0B => Ok(Self::ReadAck),
+            0x0C => Ok(Self::Outputs),
+            x => Err(InvalidCommandByteError(x)),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Initialise => 0x00,
+            Command::RequestStatus => 0x02,
+            Command::Ping => 0x06,
+            Command::ReadAck => 0x0B,
+            Command::Outputs => 0x0C,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyCommand {
+    // Returned after the reader is initialised, identifying the module. 11 FF 09 03 12 D9
+    Initialised,
+    // Acknowledge last message when nothing has changed. 11 FE BA
+    Ack,
+    // Conveys the state of the reader's inputs.
+    //
+    // 11 F1 01 AE. Byte 3: 0x01 - door open, 0x40 - enclosure tamper.
+    Status,
+    // Conveys the ID of a card or fob presented to the reader.
+    //
+    // 11 F5 00 12 D6 87 22. Bytes 3-6: card ID, most significant byte first.
+    CardRead,
+    // Indicates the reader could not process the last message.
+    BadChecksum,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid MAX reply command op code {0}")]
+pub struct InvalidReplyCommandByteError(pub u8);
+
+impl TryFrom<u8> for ReplyCommand {
+    type Error = InvalidReplyCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0xF1 => Ok(Self::Status),
+            0xF2 => Ok(Self::BadChecksum),
+            0xF5 => Ok(Self::CardRead),
+            0xFE => Ok(Self::Ack),
+            0xFF => Ok(Self::Initialised),
+            x => Err(InvalidReplyCommandByteError(x)),
+        }
+    }
+}
+
+impl From<ReplyCommand> for u8 {
+    fn from(value: ReplyCommand) -> Self {
+        match value {
+            ReplyCommand::Initialised => 0xFF,
+            ReplyCommand::Ack => 0xFE,
+            ReplyCommand::Status => 0xF1,
+            ReplyCommand::CardRead => 0xF5,
+            ReplyCommand::BadChecksum => 0xF2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(command: ReplyCommand, data: Option<Vec<u8>>) -> Result<SerialMessage, DeliveryError> {
+        Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: command.into(),
+            additional_data: data,
+        })
+    }
+
+    fn initialised_max() -> SerialMax {
+        let max = SerialMax::new();
+        max.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x09, 0x03, 0x12]),
+        ));
+
+        max
+    }
+
+    #[test]
+    fn test_initialisation_sequence() {
+        let max = SerialMax::new();
+        assert_eq!(max.next_message(), (0x00, Some(vec![0x0E])));
+
+        max.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x09, 0x03, 0x12]),
+        ));
+
+        assert_eq!(max.next_message(), (0x02, None));
+        assert_eq!(max.next_message(), (0x0C, Some(vec![0x00, 0x00, 0x00])));
+        assert_eq!(max.next_message(), (0x06, None));
+    }
+
+    #[test]
+    fn test_outputs() {
+        let max = initialised_max();
+        max.next_message();
+        max.next_message();
+
+        max.mutate_state(|state| {
+            state.led = Led::Green;
+            state.relay = true;
+        });
+        assert_eq!(max.priority(), Priority::Urgent);
+        assert_eq!(max.next_message(), (0x0C, Some(vec![0x02, 0x00, 0x01])));
+        assert_eq!(max.next_message(), (0x06, None));
+    }
+
+    #[test]
+    fn test_inputs_and_card_reads_broadcast() {
+        let max = initialised_max();
+        let mut events = max.subscribe_events();
+        max.next_message();
+        max.next_message();
+
+        max.receive_update(reply(ReplyCommand::Status, Some(vec![0x00])));
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::Door { open: false }
+        );
+        assert_eq!(events.try_recv().unwrap().0, EventType::Tamper(false));
+        assert_eq!(max.is_door_open(), Some(false));
+
+        max.receive_update(reply(ReplyCommand::Status, Some(vec![DOOR_OPEN_FLAG])));
+        assert_eq!(events.try_recv().unwrap().0, EventType::Door { open: true });
+        assert!(events.try_recv().is_err());
+
+        let read = Some(vec![0x00, 0x12, 0xD6, 0x87]);
+        max.receive_update(reply(ReplyCommand::CardRead, read.clone()));
+        max.receive_update(reply(ReplyCommand::CardRead, read));
+        assert_eq!(events.try_recv().unwrap().0, EventType::CardRead(1234567));
+        assert!(events.try_recv().is_err());
+        assert_eq!(max.next_message(), (0x0B, Some(vec![0x02])));
+    }
+
+    #[test]
+    fn test_delivery_failure_reinitialises() {
+        let max = initialised_max();
+        max.receive_update(reply(ReplyCommand::Status, Some(vec![DOOR_OPEN_FLAG])));
+
+        max.receive_update(Err(DeliveryError::Timeout));
+        assert_eq!(max.next_message(), (0x00, Some(vec![0x0E])));
+        assert_eq!(max.is_door_open(), None);
+    }
+}
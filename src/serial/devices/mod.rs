@@ -1 +1,87 @@
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use super::SerialMessage;
+
+// Only the keypad's protocol was worked out from its traffic on a real bus. The other drivers
+// assume theirs follow the keypad's, down to the data byte sent on initialisation, so their op
+// codes, data layouts and example frames are unverified until confirmed on hardware.
 pub mod keypad;
+pub mod max;
+pub mod prox;
+pub mod psu;
+pub mod rio;
+
+/// DeviceKind is the type of a device on the bus, which is implied by the range its address falls
+/// in.
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    #[display(fmt = "keypad")]
+    Keypad,
+    #[display(fmt = "RIO")]
+    Rio,
+    #[display(fmt = "prox reader")]
+    Prox,
+    #[display(fmt = "MAX reader")]
+    Max,
+    #[display(fmt = "PSU")]
+    Psu,
+}
+
+impl DeviceKind {
+    pub const ALL: [DeviceKind; 5] = [
+        DeviceKind::Keypad,
+        DeviceKind::Rio,
+        DeviceKind::Prox,
+        DeviceKind::Max,
+        DeviceKind::Psu,
+    ];
+
+    /// Infers the kind of device from the range its address falls in.
+    pub fn from_address(address: u8) -> Option<DeviceKind> {
+        DeviceKind::ALL
+            .into_iter()
+            .find(|kind| kind.addresses().contains(&address))
+    }
+
+    /// Returns the addresses a device of this kind may take.
+    pub fn addresses(&self) -> std::ops::RangeInclusive<u8> {
+        match self {
+            DeviceKind::Keypad => 0x10..=0x1F,
+            DeviceKind::Rio => 0x20..=0x2F,
+            // The prox reader of a CP-038 is given an address of its own from the keypad.
+            DeviceKind::Prox => 0x50..=0x5F,
+            DeviceKind::Max => 0x60..=0x6F,
+            DeviceKind::Psu => 0x70..=0x7F,
+        }
+    }
+
+    /// Returns the message initialising a device of this kind, as when it first joins the bus.
+    pub(crate) fn initialise(&self, address: u8) -> SerialMessage {
+        let command = match self {
+            DeviceKind::Keypad => keypad::Command::Initialise.into(),
+            DeviceKind::Rio => rio::Command::Initialise.into(),
+            DeviceKind::Prox => prox::Command::Initialise.into(),
+            DeviceKind::Max => max::Command::Initialise.into(),
+            DeviceKind::Psu => psu::Command::Initialise.into(),
+        };
+
+        SerialMessage {
+            recipient_address: address,
+            command,
+            additional_data: Some(vec![0x0E]),
+        }
+    }
+
+    /// Returns the command with which a device of this kind replies to initialisation.
+    pub(crate) fn initialised_reply(&self) -> u8 {
+        match self {
+            DeviceKind::Keypad => keypad::ReplyCommand::Initialised.into(),
+            DeviceKind::Rio => rio::ReplyCommand::Initialised.into(),
+            DeviceKind::Prox => prox::ReplyCommand::Initialised.into(),
+            DeviceKind::Max => max::ReplyCommand::Initialised.into(),
+            DeviceKind::Psu => psu::ReplyCommand::Initialised.into(),
+        }
+    }
+}
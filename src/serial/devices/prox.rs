@@ -0,0 +1,302 @@
+use log::{error, info, trace};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::serial::{manager::Priority, DeliveryError, SerialDevice, SerialMessage};
+
+/// ID_LEN is the number of bytes in which the ID of a card or fob is conveyed.
+pub(crate) const ID_LEN: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventType {
+    // A card or fob was presented to the reader, giving its ID.
+    CardRead(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+#[derive(Default)]
+struct ProxUpdates {
+    send_read_ack: bool,
+    // Toggled each time a read is acknowledged, so the reader can tell a fresh acknowledgement
+    // from a repeat.
+    read_ack_flag: bool,
+}
+
+/// SerialProx handles the serial interface for the prox reader integrated in a CP-038 keypad.
+/// The reader answers on a bus address of its own, separate from the keypad, and reports the ID
+/// of any card or fob presented to it until the read is acknowledged.
+pub struct SerialProx {
+    // The reader is online once it has replied to initialisation.
+    online: Mutex<bool>,
+    updates: Mutex<ProxUpdates>,
+    wake: Mutex<Option<Arc<Notify>>>,
+
+    event_ch: Mutex<tokio::sync::broadcast::Sender<Event>>,
+}
+
+impl Default for SerialProx {
+    fn default() -> Self {
+        Self {
+            online: Mutex::new(false),
+            updates: Mutex::new(ProxUpdates::default()),
+            wake: Mutex::new(None),
+
+            event_ch: Mutex::new(tokio::sync::broadcast::Sender::new(10)),
+        }
+    }
+}
+
+impl SerialProx {
+    pub fn new() -> SerialProx {
+        Default::default()
+    }
+
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.event_ch.lock().unwrap().subscribe()
+    }
+
+    fn next_command(&self) -> (Command, Option<Vec<u8>>) {
+        let mut updates = self.updates.lock().unwrap();
+
+        if updates.send_read_ack {
+            updates.send_read_ack = false;
+            updates.read_ack_flag ^= true;
+
+            (
+                Command::ReadAck,
+                Some(vec![if updates.read_ack_flag { 0x02 } else { 0x00 }]),
+            )
+        } else {
+            (Command::Ping, None)
+        }
+    }
+}
+
+impl SerialDevice for SerialProx {
+    fn next_message(&self) -> (u8, Option<Vec<u8>>) {
+        let (command, data) = if !*self.online.lock().unwrap() {
+            (Command::Initialise, Some(vec![0x0E]))
+        } else {
+            self.next_command()
+        };
+
+        (command.into(), data)
+    }
+
+    fn priority(&self) -> Priority {
+        if *self.online.lock().unwrap() && self.updates.lock().unwrap().send_read_ack {
+            Priority::Urgent
+        } else {
+            Priority::Idle
+        }
+    }
+
+    fn set_wake(&self, wake: Arc<Notify>) {
+        *self.wake.lock().unwrap() = Some(wake);
+    }
+
+    fn receive_update(&self, msg: Result<SerialMessage, DeliveryError>) {
+        let ev_ch = self.event_ch.lock().unwrap().clone();
+
+        trace!("got update: {:?}", msg);
+
+        let mut online = self.online.lock().unwrap();
+        let mut updates = self.updates.lock().unwrap();
+
+        match msg {
+            Ok(reply) => match ReplyCommand::try_from(reply.command) {
+                Ok(ReplyCommand::Initialised) => {
+                    if *online {
+                        error!("Received initialise response for an already initialised prox");
+                    } else if reply.additional_data.as_ref().map_or(0, |d| d.len()) != 3 {
+                        error!("Received invalid initialisation data from prox");
+                    } else {
+                        info!(
+                            "Prox initialised, identity {:02X?}",
+                            reply.additional_data.unwrap()
+                        );
+                        *online = true;
+                        updates.send_read_ack = false;
+                    }
+                }
+                Ok(ReplyCommand::Ack) => {}
+                Ok(ReplyCommand::CardRead) => match reply.additional_data {
+                    Some(ref data) if data.len() == ID_LEN => {
+                        // The reader repeats the read until it's acknowledged, so a read while an
+                        // acknowledgement is pending is the same card.
+                        if !updates.send_read_ack {
+                            let id = u32::from_be_bytes(data[..].try_into().unwrap());
+                            info!("Prox read card {}", id);
+
+                            updates.send_read_ack = true;
+                            let _ = ev_ch.send(Event(EventType::CardRead(id)));
+
+                            if let Some(wake) = self.wake.lock().unwrap().as_ref() {
+                                wake.notify_one();
+                            }
+                        }
+                    }
+                    _ => error!("Received card read with invalid data length from prox"),
+                },
+                Ok(ReplyCommand::BadChecksum) => {
+                    error!("Got BadChecksum from device in response to last update");
+                    // Device marked as offline.
+                    *online = false;
+                }
+                Err(_) => {
+                    error!("Received unknown reply command {}", reply.command);
+                }
+            },
+            Err(_) => {
+                // On error, the device is marked as offline and needs to be reinitialised.
+                *online = false;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    // Initialises the reader from its initial startup state, or if it dropped off the bus for a
+    // period of time. Data byte meaning is unknown.
+    //
+    // 50 00 0E 09
+    Initialise,
+    // General poll of the reader. It replies with the card last read if not yet acknowledged, or
+    // an Ack otherwise.
+    //
+    // 50 06 01
+    Ping,
+    // Acknowledges the card last read, so that the reader stops reporting it.
+    //
+    // 50 0B 02 08. Byte 3 toggles between 0x00 and 0x02 to guard against replays.
+    ReadAck,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid prox command op code {0}")]
+pub struct InvalidCommandByteError(pub u8);
+
+impl TryFrom<u8> for Command {
+    type Error = InvalidCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Initialise),
+            0x06 => Ok(Self::Ping),
+            0x0B => Ok(Self::ReadAck),
+            x => Err(InvalidCommandByteError(x)),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Initialise => 0x00,
+            Command::Ping => 0x06,
+            Command::ReadAck => 0x0B,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyCommand {
+    // Returned after the reader is initialised, identifying the module. 11 FF 08 02 10 D5
+    Initialised,
+    // Acknowledge last message when no card has been read. 11 FE BA
+    Ack,
+    // Conveys the ID of a card or fob presented to the reader.
+    //
+    // 11 F5 00 12 D6 87 22. Bytes 3-6: card ID, most significant byte first.
+    CardRead,
+    // Indicates the reader could not process the last message.
+    BadChecksum,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid prox reply command op code {0}")]
+pub struct InvalidReplyCommandByteError(pub u8);
+
+impl TryFrom<u8> for ReplyCommand {
+    type Error = InvalidReplyCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0xF2 => Ok(Self::BadChecksum),
+            0xF5 => Ok(Self::CardRead),
+            0xFE => Ok(Self::Ack),
+            0xFF => Ok(Self::Initialised),
+            x => Err(InvalidReplyCommandByteError(x)),
+        }
+    }
+}
+
+impl From<ReplyCommand> for u8 {
+    fn from(value: ReplyCommand) -> Self {
+        match value {
+            ReplyCommand::Initialised => 0xFF,
+            ReplyCommand::Ack => 0xFE,
+            ReplyCommand::CardRead => 0xF5,
+            ReplyCommand::BadChecksum => 0xF2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(command: ReplyCommand, data: Option<Vec<u8>>) -> Result<SerialMessage, DeliveryError> {
+        Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: command.into(),
+            additional_data: data,
+        })
+    }
+
+    fn initialised_prox() -> SerialProx {
+        let prox = SerialProx::new();
+        assert_eq!(prox.next_message(), (0x00, Some(vec![0x0E])));
+        prox.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x08, 0x02, 0x10]),
+        ));
+
+        prox
+    }
+
+    #[test]
+    fn test_card_read_acknowledged_once() {
+        let prox = initialised_prox();
+        let mut events = prox.subscribe_events();
+        assert_eq!(prox.next_message(), (0x06, None));
+
+        let read = vec![0x00, 0x12, 0xD6, 0x87];
+        prox.receive_update(reply(ReplyCommand::CardRead, Some(read.clone())));
+        assert_eq!(events.try_recv().unwrap().0, EventType::CardRead(1234567));
+        assert_eq!(prox.priority(), Priority::Urgent);
+
+        // Repeated before the acknowledgement goes out.
+        prox.receive_update(reply(ReplyCommand::CardRead, Some(read.clone())));
+        assert!(events.try_recv().is_err());
+
+        assert_eq!(prox.next_message(), (0x0B, Some(vec![0x02])));
+        assert_eq!(prox.next_message(), (0x06, None));
+
+        prox.receive_update(reply(ReplyCommand::CardRead, Some(read)));
+        assert_eq!(events.try_recv().unwrap().0, EventType::CardRead(1234567));
+        assert_eq!(prox.next_message(), (0x0B, Some(vec![0x00])));
+    }
+
+    #[test]
+    fn test_delivery_failure_reinitialises() {
+        let prox = initialised_prox();
+
+        prox.receive_update(Err(DeliveryError::Timeout));
+        assert_eq!(prox.next_message(), (0x00, Some(vec![0x0E])));
+    }
+}
@@ -0,0 +1,349 @@
+use derive_more::Display;
+use log::{error, info, trace};
+use std::sync::Mutex;
+use thiserror::Error;
+
+use crate::serial::{DeliveryError, SerialDevice, SerialMessage};
+
+/// Condition is a problem with the power supply reported by a Smart PSU.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum Condition {
+    #[display(fmt = "mains failed")]
+    MainsFail,
+    #[display(fmt = "battery low")]
+    BatteryLow,
+    #[display(fmt = "output fuse blown")]
+    FuseBlown,
+    #[display(fmt = "tamper")]
+    Tamper,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 4] = [
+        Condition::MainsFail,
+        Condition::BatteryLow,
+        Condition::FuseBlown,
+        Condition::Tamper,
+    ];
+
+    /// Returns the flag of the status reply assumed to convey the condition.
+    pub(crate) fn flag(&self) -> u8 {
+        match self {
+            Condition::MainsFail => 0x01,
+            Condition::BatteryLow => 0x02,
+            Condition::FuseBlown => 0x04,
+            Condition::Tamper => 0x40,
+        }
+    }
+}
+
+/// Status is the state of the PSU as last reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    flags: u8,
+    pub battery_mv: u16,
+    pub output_mv: u16,
+}
+
+impl Status {
+    /// Reads the status from the data of a status reply.
+    pub(crate) fn parse(data: &[u8]) -> Option<Status> {
+        let &[flags, battery_hi, battery_lo, output_hi, output_lo] = data else {
+            return None;
+        };
+
+        Some(Status {
+            flags,
+            battery_mv: u16::from_be_bytes([battery_hi, battery_lo]).saturating_mul(10),
+            output_mv: u16::from_be_bytes([output_hi, output_lo]).saturating_mul(10),
+        })
+    }
+
+    pub fn is_active(&self, condition: Condition) -> bool {
+        self.flags & condition.flag() != 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventType {
+    // A condition was raised or cleared by the PSU. Every condition is published on the first
+    // status after initialisation.
+    Condition { condition: Condition, active: bool },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+/// SerialPsu handles the serial interface for a Galaxy Smart PSU on the bus. The PSU has nothing
+/// to be set by the panel, but replies to every poll with the state of its mains supply, battery,
+/// output fuse and tamper, along with its battery and output voltages.
+pub struct SerialPsu {
+    // The PSU is online once it has replied to initialisation.
+    online: Mutex<bool>,
+    // None until the first status is received after initialisation.
+    status: Mutex<Option<Status>>,
+
+    event_ch: Mutex<tokio::sync::broadcast::Sender<Event>>,
+}
+
+impl Default for SerialPsu {
+    fn default() -> Self {
+        Self {
+            online: Mutex::new(false),
+            status: Mutex::new(None),
+
+            event_ch: Mutex::new(tokio::sync::broadcast::Sender::new(16)),
+        }
+    }
+}
+
+impl SerialPsu {
+    pub fn new() -> SerialPsu {
+        Default::default()
+    }
+
+    /// Returns the status last reported, or None if the PSU hasn't reported since it was last
+    /// initialised.
+    pub fn status(&self) -> Option<Status> {
+        *self.status.lock().unwrap()
+    }
+
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.event_ch.lock().unwrap().subscribe()
+    }
+
+    fn process_status(&self, status: Status, ev_ch: &tokio::sync::broadcast::Sender<Event>) {
+        let mut last = self.status.lock().unwrap();
+
+        for condition in Condition::ALL {
+            let active = status.is_active(condition);
+
+            if last.is_none_or(|last| last.is_active(condition) != active) {
+                info!(
+                    "PSU {} {}",
+                    condition,
+                    if active { "raised" } else { "cleared" }
+                );
+                let _ = ev_ch.send(Event(EventType::Condition { condition, active }));
+            }
+        }
+
+        *last = Some(status);
+    }
+}
+
+impl SerialDevice for SerialPsu {
+    fn next_message(&self) -> (u8, Option<Vec<u8>>) {
+        let (command, data) = if !*self.online.lock().unwrap() {
+            (Command::Initialise, Some(vec![0x0E]))
+        } else {
+            (Command::Ping, None)
+        };
+
+        (command.into(), data)
+    }
+
+    fn receive_update(&self, msg: Result<SerialMessage, DeliveryError>) {
+        let ev_ch = self.event_ch.lock().unwrap().clone();
+
+        trace!("got update: {:?}", msg);
+
+        let mut online = self.online.lock().unwrap();
+
+        match msg {
+            Ok(reply) => match ReplyCommand::try_from(reply.command) {
+                Ok(ReplyCommand::Initialised) => {
+                    if *online {
+                        error!("Received initialise response for an already initialised PSU");
+                    } else if reply.additional_data.as_ref().map_or(0, |d| d.len()) != 3 {
+                        error!("Received invalid initialisation data from PSU");
+                    } else {
+                        info!(
+                            "PSU initialised, identity {:02X?}",
+                            reply.additional_data.unwrap()
+                        );
+                        *online = true;
+                    }
+                }
+                Ok(ReplyCommand::Status) => {
+                    match reply.additional_data.as_deref().and_then(Status::parse) {
+                        Some(status) => self.process_status(status, &ev_ch),
+                        None => error!("Received status with invalid data length from PSU"),
+                    }
+                }
+                Ok(ReplyCommand::BadChecksum) => {
+                    error!("Got BadChecksum from device in response to last update");
+                    // Device marked as offline.
+                    *online = false;
+                }
+                Err(_) => {
+                    error!("Received unknown reply command {}", reply.command);
+                }
+            },
+            Err(_) => {
+                // On error, the device is marked as offline and needs to be reinitialised. The
+                // status is forgotten so every condition is republished once it returns.
+                *online = false;
+                *self.status.lock().unwrap() = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    // Initialises the PSU from its initial startup state, or if it dropped off the bus for a
+    // period of time. Data byte meaning is unknown.
+    //
+    // 70 00 0E 29
+    Initialise,
+    // General poll of the PSU, to which it replies with its status.
+    //
+    // 70 06 21
+    Ping,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid PSU command op code {0}")]
+pub struct InvalidCommandByteError(pub u8);
+
+impl TryFrom<u8> for Command {
+    type Error = InvalidCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Initialise),
+            0x06 => Ok(Self::Ping),
+            x => Err(InvalidCommandByteError(x)),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Initialise => 0x00,
+            Command::Ping => 0x06,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyCommand {
+    // Returned after the PSU is initialised, identifying the module. 11 FF 0A 01 05 CB
+    Initialised,
+    // Conveys the state of the PSU, in reply to every poll.
+    //
+    // 11 F1 01 04 7E 05 5B 91.
+    //
+    // Byte 3: 0x01 - mains failed, 0x02 - battery low, 0x04 - output fuse blown, 0x40 - tamper
+    // Bytes 4-5: battery voltage in units of 10mV, most significant byte first
+    // Bytes 6-7: output voltage, likewise
+    Status,
+    // Indicates the PSU could not process the last message. 11 F2 AE
+    BadChecksum,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid PSU reply command op code {0}")]
+pub struct InvalidReplyCommandByteError(pub u8);
+
+impl TryFrom<u8> for ReplyCommand {
+    type Error = InvalidReplyCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0xF1 => Ok(Self::Status),
+            0xF2 => Ok(Self::BadChecksum),
+            0xFF => Ok(Self::Initialised),
+            x => Err(InvalidReplyCommandByteError(x)),
+        }
+    }
+}
+
+impl From<ReplyCommand> for u8 {
+    fn from(value: ReplyCommand) -> Self {
+        match value {
+            ReplyCommand::Initialised => 0xFF,
+            ReplyCommand::Status => 0xF1,
+            ReplyCommand::BadChecksum => 0xF2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(command: ReplyCommand, data: Option<Vec<u8>>) -> Result<SerialMessage, DeliveryError> {
+        Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: command.into(),
+            additional_data: data,
+        })
+    }
+
+    #[test]
+    fn test_initialisation_and_polling() {
+        let psu = SerialPsu::new();
+        assert_eq!(psu.next_message(), (0x00, Some(vec![0x0E])));
+
+        psu.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x0A, 0x01, 0x05]),
+        ));
+        assert_eq!(psu.next_message(), (0x06, None));
+        assert_eq!(psu.status(), None);
+
+        psu.receive_update(Err(DeliveryError::Timeout));
+        assert_eq!(psu.next_message(), (0x00, Some(vec![0x0E])));
+    }
+
+    #[test]
+    fn test_conditions_broadcast_on_change() {
+        let psu = SerialPsu::new();
+        let mut events = psu.subscribe_events();
+        psu.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x0A, 0x01, 0x05]),
+        ));
+
+        psu.receive_update(reply(
+            ReplyCommand::Status,
+            Some(vec![0x00, 0x05, 0x52, 0x05, 0x5B]),
+        ));
+        for condition in Condition::ALL {
+            assert_eq!(
+                events.try_recv().unwrap().0,
+                EventType::Condition {
+                    condition,
+                    active: false
+                }
+            );
+        }
+        let status = psu.status().unwrap();
+        assert_eq!((status.battery_mv, status.output_mv), (13620, 13710));
+
+        // Voltages alone changing publishes nothing.
+        psu.receive_update(reply(
+            ReplyCommand::Status,
+            Some(vec![0x00, 0x05, 0x50, 0x05, 0x5B]),
+        ));
+        assert!(events.try_recv().is_err());
+
+        psu.receive_update(reply(
+            ReplyCommand::Status,
+            Some(vec![0x01, 0x04, 0x7E, 0x05, 0x5B]),
+        ));
+        assert_eq!(
+            events.try_recv().unwrap().0,
+            EventType::Condition {
+                condition: Condition::MainsFail,
+                active: true
+            }
+        );
+        assert!(events.try_recv().is_err());
+        assert!(psu.status().unwrap().is_active(Condition::MainsFail));
+        assert_eq!(psu.status().unwrap().battery_mv, 11500);
+    }
+}
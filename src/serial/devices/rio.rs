@@ -0,0 +1,507 @@
+use log::{error, info, trace};
+use std::sync::{Arc, Mutex, RwLock};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::serial::{manager::Priority, DeliveryError, SerialDevice, SerialMessage};
+
+/// ZONE_COUNT is the number of zone inputs on a RIO.
+pub const ZONE_COUNT: usize = 8;
+
+/// OUTPUT_COUNT is the number of outputs driven by a RIO.
+pub const OUTPUT_COUNT: usize = 4;
+
+/// TAMPER_FLAG is assumed to be set in the trailing flags byte of a zone status reply when the
+/// RIO enclosure tamper switch is open.
+pub(crate) const TAMPER_FLAG: u8 = 0x40;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub outputs: [bool; OUTPUT_COUNT], // true = output active
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            outputs: [false; OUTPUT_COUNT],
+        }
+    }
+}
+
+impl State {
+    fn output_mask(&self) -> u8 {
+        self.outputs
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (i, &on)| if on { mask | 1 << i } else { mask })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EventType {
+    // Zones are numbered from 1 to ZONE_COUNT, matching the terminal labels on the board. The
+    // reading is as reported by the RIO, and is classified by the zone it is wired to.
+    ZoneChanged { zone: u8, reading: u8 },
+    Tamper(bool),
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+#[derive(Default)]
+struct RioUpdates {
+    request_zones: bool,
+    send_outputs: bool,
+}
+
+/// SerialRio handles the serial interface and state management for a Galaxy RIO (remote
+/// input/output expander) on the Galaxy bus. The RIO provides eight end-of-line monitored zone
+/// inputs and four outputs.
+pub struct SerialRio {
+    state: RwLock<State>,
+    // The RIO is online if last_state is Some.
+    last_state: RwLock<Option<State>>,
+    // Last zone readings reported by the RIO; None until the first zone status is received after
+    // initialisation.
+    zones: Mutex<Option<[u8; ZONE_COUNT]>>,
+    tamper: Mutex<bool>,
+    updates: Mutex<RioUpdates>,
+    // Notified when the state is changed, so that the update is sent promptly.
+    wake: Mutex<Option<Arc<Notify>>>,
+
+    event_ch: Mutex<tokio::sync::broadcast::Sender<Event>>,
+}
+
+impl Default for SerialRio {
+    fn default() -> Self {
+        Self {
+            state: RwLock::new(State::default()),
+            last_state: RwLock::new(None),
+            zones: Mutex::new(None),
+            tamper: Mutex::new(false),
+            updates: Mutex::new(RioUpdates::default()),
+            wake: Mutex::new(None),
+
+            event_ch: Mutex::new(tokio::sync::broadcast::Sender::new(32)),
+        }
+    }
+}
+
+impl SerialRio {
+    pub fn new() -> SerialRio {
+        Default::default()
+    }
+
+    pub fn mutate_state<F>(&self, f: F)
+    where
+        F: FnOnce(&mut State),
+    {
+        let mut state = self
+            .state
+            .write()
+            .expect("unable to lock RIO state for writing");
+        f(&mut state);
+        drop(state);
+
+        if let Some(wake) = self.wake.lock().unwrap().as_ref() {
+            wake.notify_one();
+        }
+    }
+
+    /// Sets output `output`, numbered from 1 to OUTPUT_COUNT, to the given state.
+    pub fn set_output(&self, output: u8, on: bool) {
+        assert!(
+            (1..=OUTPUT_COUNT as u8).contains(&output),
+            "RIO output out of range: {}",
+            output
+        );
+
+        self.mutate_state(|state| state.outputs[output as usize - 1] = on);
+    }
+
+    pub fn is_tamper(&self) -> bool {
+        *self.tamper.lock().unwrap()
+    }
+
+    /// Returns the last known readings of each zone, or None if the RIO has not yet reported.
+    pub fn zone_readings(&self) -> Option<[u8; ZONE_COUNT]> {
+        *self.zones.lock().unwrap()
+    }
+
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.event_ch.lock().unwrap().subscribe()
+    }
+
+    /// Returns whether anything other than a ping is waiting to be sent to the RIO.
+    fn has_updates(&self) -> bool {
+        let current_state = self.state.read().unwrap();
+        let last_state_lock = self.last_state.read().unwrap();
+        let Some(last_state) = last_state_lock.as_ref() else {
+            return false;
+        };
+        let updates = self.updates.lock().unwrap();
+
+        updates.request_zones || updates.send_outputs || current_state.outputs != last_state.outputs
+    }
+
+    fn next_command(&self) -> (Command, Option<Vec<u8>>) {
+        let current_state = self.state.read().unwrap();
+        let mut last_state_lock = self
+            .last_state
+            .write()
+            .expect("unable to lock RIO last state for writing");
+
+        // unwrap guaranteed to succeed, as the device is guaranteed online
+        let last_state = last_state_lock.as_mut().unwrap();
+        let mut updates = self.updates.lock().unwrap();
+
+        if updates.request_zones {
+            updates.request_zones = false;
+
+            (Command::RequestZones, None)
+        } else if updates.send_outputs || current_state.outputs != last_state.outputs {
+            updates.send_outputs = false;
+            last_state.outputs = current_state.outputs;
+
+            (Command::Outputs, Some(vec![current_state.output_mask()]))
+        } else {
+            (Command::Ping, None)
+        }
+    }
+
+    fn process_zone_status(&self, data: &[u8], ev_ch: &tokio::sync::broadcast::Sender<Event>) {
+        let mut zones = self.zones.lock().unwrap();
+        let mut tamper = self.tamper.lock().unwrap();
+
+        let readings: [u8; ZONE_COUNT] = data[0..ZONE_COUNT].try_into().unwrap();
+        let new_tamper = data[ZONE_COUNT] & TAMPER_FLAG == TAMPER_FLAG;
+
+        for (i, &reading) in readings.iter().enumerate() {
+            // On the first report after initialisation, every zone is published so subscribers
+            // learn the full picture.
+            if zones.is_some_and(|zones| zones[i] == reading) {
+                continue;
+            }
+
+            trace!("RIO zone {} reading {:02X}", i + 1, reading);
+
+            let _ = ev_ch.send(Event(EventType::ZoneChanged {
+                zone: i as u8 + 1,
+                reading,
+            }));
+        }
+
+        if new_tamper != *tamper {
+            info!("RIO tamper state changed to {}", new_tamper);
+            let _ = ev_ch.send(Event(EventType::Tamper(new_tamper)));
+        }
+
+        *zones = Some(readings);
+        *tamper = new_tamper;
+    }
+}
+
+impl SerialDevice for SerialRio {
+    fn next_message(&self) -> (u8, Option<Vec<u8>>) {
+        let (command, data) = if self
+            .last_state
+            .read()
+            .expect("unable to read last_state")
+            .is_none()
+        {
+            (Command::Initialise, Some(vec![0x0E]))
+        } else {
+            self.next_command()
+        };
+
+        (command.into(), data)
+    }
+
+    fn priority(&self) -> Priority {
+        if self.has_updates() {
+            Priority::Urgent
+        } else {
+            Priority::Idle
+        }
+    }
+
+    fn set_wake(&self, wake: Arc<Notify>) {
+        *self.wake.lock().unwrap() = Some(wake);
+    }
+
+    fn receive_update(&self, msg: Result<SerialMessage, DeliveryError>) {
+        let ev_ch = self.event_ch.lock().unwrap().clone();
+
+        trace!("got update: {:?}", msg);
+
+        let mut last_state = self
+            .last_state
+            .write()
+            .expect("unable to lock last_state for writing");
+
+        match msg {
+            Ok(reply) => match ReplyCommand::try_from(reply.command) {
+                Ok(ReplyCommand::Initialised) => {
+                    if last_state.is_some() {
+                        error!("Received initialise response for an already initialised RIO");
+                    } else if reply.additional_data.as_ref().map_or(0, |d| d.len()) != 3 {
+                        error!("Received invalid initialisation data from RIO");
+                    } else {
+                        info!(
+                            "RIO initialised, identity {:02X?}",
+                            reply.additional_data.unwrap()
+                        );
+                        *last_state = Some(self.state.read().unwrap().clone());
+
+                        let mut updates = self.updates.lock().unwrap();
+                        updates.request_zones = true;
+                        updates.send_outputs = true;
+                    }
+                }
+                Ok(ReplyCommand::Ack) => {}
+                Ok(ReplyCommand::ZoneStatus) => match reply.additional_data {
+                    Some(ref data) if data.len() == ZONE_COUNT + 1 => {
+                        self.process_zone_status(data, &ev_ch);
+                    }
+                    _ => {
+                        error!("Received zone status with invalid data length from RIO");
+                        // Ask again for the full picture, as a change may have been lost.
+                        self.updates.lock().unwrap().request_zones = true;
+                    }
+                },
+                Ok(ReplyCommand::BadChecksum) => {
+                    error!("Got BadChecksum from device in response to last update");
+                    // Device marked as offline, and its zones republished once it returns.
+                    *last_state = None;
+                    *self.zones.lock().unwrap() = None;
+                }
+                Err(_) => {
+                    error!("Received unknown reply command {}", reply.command);
+                }
+            },
+            Err(_) => {
+                // On error, the device is marked as offline and needs to be reinitialised. The
+                // zone readings are forgotten so the full status is republished once it returns.
+                *last_state = None;
+                *self.zones.lock().unwrap() = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    // Initialises the RIO from its initial startup state, or if it dropped off the bus for a period
+    // of time. Data byte meaning is unknown.
+    //
+    // 20 00 0E D8
+    Initialise,
+    // Requests the full zone status regardless of whether anything changed since the last report.
+    //
+    // 20 02 CC
+    RequestZones,
+    // General poll of the device state. The RIO replies with a zone status if any zone reading
+    // changed since the last report, or an Ack otherwise.
+    //
+    // 20 06 D0
+    Ping,
+    // Sets the state of the four outputs.
+    //
+    // 20 0C 05 DB. Byte 3 is a bit mask of active outputs, bit 0 being output 1.
+    Outputs,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid RIO command op code {0}")]
+pub struct InvalidCommandByteError(pub u8);
+
+impl TryFrom<u8> for Command {
+    type Error = InvalidCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Initialise),
+            0x02 => Ok(Self::RequestZones),
+            0x06 => Ok(Self::Ping),
+            0x0C => Ok(Self::Outputs),
+            x => Err(InvalidCommandByteError(x)),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Initialise => 0x00,
+            Command::RequestZones => 0x02,
+            Command::Ping => 0x06,
+            Command::Outputs => 0x0C,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyCommand {
+    // Returned after the RIO is initialised. Byte 3 identifies the module type, the remainder
+    // appear to be the firmware revision. 11 FF 01 01 05 C2
+    Initialised,
+    // Acknowledge last message when no zone readings have changed. 11 FE BA
+    Ack,
+    // Conveys the reading of each zone, followed by a flags byte.
+    //
+    // Bytes 3-10: loop resistance of zones 1-8 in units of 100R, saturating at 0xFF.
+    // Byte 11:    0x40 - enclosure tamper.
+    ZoneStatus,
+    // Indicates the RIO could not process the last message.
+    BadChecksum,
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid RIO reply command op code {0}")]
+pub struct InvalidReplyCommandByteError(pub u8);
+
+impl TryFrom<u8> for ReplyCommand {
+    type Error = InvalidReplyCommandByteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0xF1 => Ok(Self::ZoneStatus),
+            0xF2 => Ok(Self::BadChecksum),
+            0xFE => Ok(Self::Ack),
+            0xFF => Ok(Self::Initialised),
+            x => Err(InvalidReplyCommandByteError(x)),
+        }
+    }
+}
+
+impl From<ReplyCommand> for u8 {
+    fn from(value: ReplyCommand) -> Self {
+        match value {
+            ReplyCommand::Initialised => 0xFF,
+            ReplyCommand::Ack => 0xFE,
+            ReplyCommand::ZoneStatus => 0xF1,
+            ReplyCommand::BadChecksum => 0xF2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(command: ReplyCommand, data: Option<Vec<u8>>) -> Result<SerialMessage, DeliveryError> {
+        Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: command.into(),
+            additional_data: data,
+        })
+    }
+
+    fn initialised_rio() -> SerialRio {
+        let rio = SerialRio::new();
+        rio.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x01, 0x01, 0x05]),
+        ));
+
+        rio
+    }
+
+    #[test]
+    fn test_initialisation_sequence() {
+        let rio = SerialRio::new();
+        assert_eq!(rio.next_message(), (0x00, Some(vec![0x0E])));
+
+        rio.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x01, 0x01, 0x05]),
+        ));
+
+        assert_eq!(rio.next_message(), (0x02, None));
+        assert_eq!(rio.next_message(), (0x0C, Some(vec![0x00])));
+        assert_eq!(rio.next_message(), (0x06, None));
+    }
+
+    #[test]
+    fn test_outputs() {
+        let rio = initialised_rio();
+        rio.next_message();
+        rio.next_message();
+
+        rio.set_output(1, true);
+        rio.set_output(4, true);
+        assert_eq!(rio.next_message(), (0x0C, Some(vec![0x09])));
+        assert_eq!(rio.next_message(), (0x06, None));
+    }
+
+    #[test]
+    fn test_zone_changes_broadcast() {
+        let rio = initialised_rio();
+        let mut events = rio.subscribe_events();
+
+        let mut data = vec![10; ZONE_COUNT];
+        data.push(0x00);
+        rio.receive_update(reply(ReplyCommand::ZoneStatus, Some(data.clone())));
+
+        for zone in 1..=ZONE_COUNT as u8 {
+            assert!(matches!(
+                events.try_recv().unwrap().0,
+                EventType::ZoneChanged { zone: z, reading: 10 } if z == zone
+            ));
+        }
+        assert!(events.try_recv().is_err());
+
+        data[2] = 20;
+        data[ZONE_COUNT] = TAMPER_FLAG;
+        rio.receive_update(reply(ReplyCommand::ZoneStatus, Some(data)));
+
+        assert!(matches!(
+            events.try_recv().unwrap().0,
+            EventType::ZoneChanged {
+                zone: 3,
+                reading: 20
+            }
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap().0,
+            EventType::Tamper(true)
+        ));
+        assert!(events.try_recv().is_err());
+        assert!(rio.is_tamper());
+    }
+
+    #[test]
+    fn test_delivery_failure_reinitialises() {
+        let rio = initialised_rio();
+
+        rio.receive_update(Err(DeliveryError::Timeout));
+
+        assert_eq!(rio.next_message(), (0x00, Some(vec![0x0E])));
+        assert_eq!(rio.zone_readings(), None);
+    }
+
+    #[test]
+    fn test_bad_checksum_republishes_zones() {
+        let rio = initialised_rio();
+        let mut data = vec![10; ZONE_COUNT];
+        data.push(0x00);
+        rio.receive_update(reply(ReplyCommand::ZoneStatus, Some(data.clone())));
+
+        rio.receive_update(reply(ReplyCommand::BadChecksum, None));
+        assert_eq!(rio.next_message(), (0x00, Some(vec![0x0E])));
+        assert_eq!(rio.zone_readings(), None);
+
+        let mut events = rio.subscribe_events();
+        rio.receive_update(reply(
+            ReplyCommand::Initialised,
+            Some(vec![0x01, 0x01, 0x05]),
+        ));
+        rio.receive_update(reply(ReplyCommand::ZoneStatus, Some(data)));
+        for zone in 1..=ZONE_COUNT as u8 {
+            assert!(matches!(
+                events.try_recv().unwrap().0,
+                EventType::ZoneChanged { zone: z, reading: 10 } if z == zone
+            ));
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use log::trace;
-use std::{io, time::Duration};
+use std::{future::Future, io, time::Duration};
 use thiserror::Error;
 
-use super::crc::GalaxyCRC;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_serial::SerialStream;
+use super::{crc::GalaxyCRC, frame::receive_frame};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio_serial::{SerialPort, SerialStream};
 
 /// INTERPACKET_GAP is the duration that must be allowed between transmissions on the bus to allow
 /// for signal propagation.
@@ -12,13 +13,191 @@ const INTERPACKET_GAP: Duration = Duration::from_millis(10);
 
 /// PANEL_ADDRESS is the identifier of the panel on the bus, used as the recipient for all response
 /// messages.
-const PANEL_ADDRESS: u8 = 0x11;
+pub const PANEL_ADDRESS: u8 = 0x11;
 
 /// BUS_TIMEOUT is the time after which a read operation for replies from devices gives up.
 const BUS_TIMEOUT: Duration = Duration::from_millis(50);
 
-pub struct Bus {
-    serial_port: SerialStream,
+/// INTERCHARACTER_TIMEOUT is the time the line must be idle after receiving part of a reply for it
+/// to be considered complete. USB serial adapters can deliver a reply in pieces some milliseconds
+/// apart.
+const INTERCHARACTER_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// Timing holds the configurable bus timing parameters, defaulting to INTERPACKET_GAP,
+/// BUS_TIMEOUT and INTERCHARACTER_TIMEOUT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub interpacket_gap: Duration,
+    pub bus_timeout: Duration,
+    pub intercharacter_timeout: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            interpacket_gap: INTERPACKET_GAP,
+            bus_timeout: BUS_TIMEOUT,
+            intercharacter_timeout: INTERCHARACTER_TIMEOUT,
+        }
+    }
+}
+
+/// ControlLine is a modem control line of a serial port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlLine {
+    Rts,
+    Dtr,
+}
+
+/// Direction is how the RS485 transceiver is switched between transmitting and receiving.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // The adapter switches by itself, as most USB adapters do.
+    #[default]
+    Automatic,
+    // The transmitter is enabled by asserting RTS or DTR while sending.
+    Rts,
+    Dtr,
+    // The kernel driver switches the transceiver using RTS.
+    Kernel,
+}
+
+impl Direction {
+    fn control_line(&self) -> Option<ControlLine> {
+        match self {
+            Direction::Rts => Some(ControlLine::Rts),
+            Direction::Dtr => Some(ControlLine::Dtr),
+            Direction::Automatic | Direction::Kernel => None,
+        }
+    }
+}
+
+/// HalfDuplex configures the handling of the half-duplex RS485 bus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HalfDuplex {
+    pub direction: Direction,
+    // Delays between enabling the transmitter and sending, and between the end of sending and
+    // disabling the transmitter.
+    pub pre_transmit_delay: Duration,
+    pub post_transmit_delay: Duration,
+    // Whether the transceiver echoes our own transmission back on the receive line.
+    pub echo: bool,
+}
+
+/// Transport is the byte stream carrying the bus, normally a serial port but e.g. an in-memory
+/// stream to a simulator in tests.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Sets the level of a modem control line, where the transport has them.
+    fn set_control_line(&mut self, _line: ControlLine, _level: bool) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "transport has no control lines",
+        ))
+    }
+
+    /// Has the kernel driver switch the direction of the RS485 transceiver.
+    fn enable_kernel_rs485(&mut self, _half_duplex: &HalfDuplex) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "transport has no kernel RS485 support",
+        ))
+    }
+}
+
+impl Transport for DuplexStream {}
+
+impl Transport for SerialStream {
+    fn set_control_line(&mut self, line: ControlLine, level: bool) -> io::Result<()> {
+        match line {
+            ControlLine::Rts => self.write_request_to_send(level),
+            ControlLine::Dtr => self.write_data_terminal_ready(level),
+        }
+        .map_err(io::Error::from)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn enable_kernel_rs485(&mut self, half_duplex: &HalfDuplex) -> io::Result<()> {
+        rs485::enable(self, half_duplex)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod rs485 {
+    use std::{io, os::unix::io::AsRawFd};
+
+    use super::HalfDuplex;
+
+    // From linux/serial.h.
+    const SER_RS485_ENABLED: u32 = 1 << 0;
+    const SER_RS485_RTS_ON_SEND: u32 = 1 << 1;
+
+    #[repr(C)]
+    struct SerialRs485 {
+        flags: u32,
+        delay_rts_before_send: u32,
+        delay_rts_after_send: u32,
+        padding: [u32; 5],
+    }
+
+    pub fn enable(port: &impl AsRawFd, half_duplex: &HalfDuplex) -> io::Result<()> {
+        let config = SerialRs485 {
+            flags: SER_RS485_ENABLED | SER_RS485_RTS_ON_SEND,
+            // The kernel takes the delays in whole milliseconds.
+            delay_rts_before_send: half_duplex.pre_transmit_delay.as_millis() as u32,
+            delay_rts_after_send: half_duplex.post_transmit_delay.as_millis() as u32,
+            padding: [0; 5],
+        };
+
+        // SAFETY: TIOCSRS485 only reads the configuration, which outlives the call.
+        match unsafe { libc::ioctl(port.as_raw_fd(), libc::TIOCSRS485, &config) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads a burst of data from the line, ending once no more arrives within `quiet`, much as
+/// devices on the bus detect the end of a message. Returns an empty burst once the line is closed.
+pub async fn read_burst<R>(reader: &mut R, quiet: Duration) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 64];
+
+    let n = reader.read(&mut chunk).await?;
+    let mut burst = chunk[..n].to_vec();
+    if n == 0 {
+        return Ok(burst);
+    }
+
+    loop {
+        match tokio::time::timeout(quiet, reader.read(&mut chunk)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => burst.extend_from_slice(&chunk[..n]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+
+    Ok(burst)
+}
+
+/// Exchange sends a message to a device and waits for its reply. It's implemented by `Bus`, and by
+/// wrappers around it such as `BusRecorder`.
+pub trait Exchange: Send {
+    /// Sends a message, to which the CRC is added, and reads the reply into a buffer. Returns the
+    /// length of the reply, not counting its CRC.
+    fn send_receive_buffered(
+        &mut self,
+        data: &[u8],
+        reply: &mut [u8],
+    ) -> impl Future<Output = Result<usize, ReadError>> + Send;
+}
+
+pub struct Bus<T = SerialStream> {
+    serial_port: T,
+    timing: Timing,
+    half_duplex: HalfDuplex,
 }
 
 #[derive(Clone, Debug, Error)]
@@ -27,10 +206,18 @@ pub enum ReadError {
     Timeout,
     #[error("No data available")]
     NoData,
-    #[error("Insufficient data")]
-    InsufficientData,
+    #[error("Truncated frame of {0} bytes")]
+    Truncated(usize),
+    #[error("{0} bytes of trailing garbage after frame")]
+    TrailingGarbage(usize),
+    #[error("Frame longer than the {0} byte reply buffer")]
+    Overflow(usize),
     #[error("CRC check failed")]
     CrcCheckFailed,
+    #[error("Echo of transmission not received intact")]
+    /// The transceiver echoes our transmission, but it wasn't read back as sent. Another device
+    /// may have been transmitting at the same time.
+    EchoMismatch,
     #[error("Reply not addressed to panel: {0}")]
     /// The reply was not found to be addressed to the panel address, but to some other address.
     /// This is likely indicative of a tamper condition.
@@ -45,9 +232,29 @@ impl From<io::Error> for ReadError {
     }
 }
 
-impl Bus {
-    pub fn new(serial_port: SerialStream) -> Bus {
-        Bus { serial_port }
+impl<T: Transport> Bus<T> {
+    pub fn new(serial_port: T) -> Bus<T> {
+        Bus {
+            serial_port,
+            timing: Timing::default(),
+            half_duplex: HalfDuplex::default(),
+        }
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> Bus<T> {
+        self.timing = timing;
+        self
+    }
+
+    /// Configures half-duplex handling, setting up the kernel driver straight away if it's to
+    /// switch the transceiver.
+    pub fn with_half_duplex(mut self, half_duplex: HalfDuplex) -> io::Result<Bus<T>> {
+        if half_duplex.direction == Direction::Kernel {
+            self.serial_port.enable_kernel_rs485(&half_duplex)?;
+        }
+
+        self.half_duplex = half_duplex;
+        Ok(self)
     }
 
     pub async fn send_receive_buffered(
@@ -60,65 +267,152 @@ impl Bus {
             "insufficient data provided to send to Galaxy bus"
         );
         let crc = data.galaxy_crc();
+        let frame = [data, &[crc]].concat();
 
         trace!("output data {:02X?} crc {:02X}", data, crc);
 
-        AsyncWriteExt::write_all(&mut self.serial_port, &data)
-            .await
-            .map_err(ReadError::from)?;
-        AsyncWriteExt::write_u8(&mut self.serial_port, crc)
-            .await
-            .map_err(ReadError::from)?;
-
-        tokio::time::sleep(
-            INTERPACKET_GAP
-                + Duration::from_millis(
-                    // 1 stop bit
-                    1u64 + 10
-                        * (
-                            // CRC byte plus data
-                            1 + data.len() as u64
+        match self.half_duplex.direction.control_line() {
+            Some(line) => {
+                self.serial_port.set_control_line(line, true)?;
+                tokio::time::sleep(self.half_duplex.pre_transmit_delay).await;
+
+                let port = &mut self.serial_port;
+                let sent = async {
+                    AsyncWriteExt::write_all(port, &frame).await?;
+                    // Waits until the frame has left the port, before the device starts to reply.
+                    AsyncWriteExt::flush(port).await
+                }
+                .await;
+
+                tokio::time::sleep(self.half_duplex.post_transmit_delay).await;
+                // Released even if sending failed, so as not to jam the bus.
+                self.serial_port.set_control_line(line, false)?;
+                sent?;
+
+                tokio::time::sleep(self.timing.interpacket_gap).await;
+            }
+            None => {
+                AsyncWriteExt::write_all(&mut self.serial_port, &frame).await?;
+
+                tokio::time::sleep(
+                    self.timing.interpacket_gap
+                        + Duration::from_millis(
+                            // 1 stop bit
+                            1u64 + 10
+                                * (
+                                    // CRC byte plus data
+                                    1 + data.len() as u64
+                                ),
                         ),
-                ),
+                )
+                .await;
+            }
+        }
+
+        if self.half_duplex.echo {
+            self.strip_echo(&frame).await?;
+        }
+
+        let result = receive_frame(
+            &mut self.serial_port,
+            reply,
+            self.timing.bus_timeout,
+            self.timing.intercharacter_timeout,
         )
         .await;
+        match &result {
+            Ok(n) => trace!("response {:02X?}", &reply[..=*n]),
+            Err(e) => trace!("response error: {}", e),
+        }
+
+        result
+    }
+}
+
+impl<T: Transport> Bus<T> {
+    /// Reads back our own transmission echoed by the transceiver, leaving the reply to be read.
+    async fn strip_echo(&mut self, frame: &[u8]) -> Result<(), ReadError> {
+        let mut echo = vec![0u8; frame.len()];
 
-        tokio::time::timeout(
-            BUS_TIMEOUT,
-            AsyncReadExt::read(&mut self.serial_port, reply),
+        match tokio::time::timeout(
+            self.timing.bus_timeout,
+            AsyncReadExt::read_exact(&mut self.serial_port, &mut echo),
         )
         .await
-        .map_err(|_| ReadError::Timeout)
-        .and_then(|result| match result {
-            Ok(bytes_read) => {
-                trace!(
-                    "response {} bytes: {:02X?}",
-                    bytes_read,
-                    &reply[0..bytes_read]
-                );
-
-                if bytes_read == 0 {
-                    return Err(ReadError::NoData);
-                } else if bytes_read < 3 {
-                    return Err(ReadError::InsufficientData);
-                }
+        {
+            Ok(Ok(_)) if echo == frame => Ok(()),
+            Ok(Err(e)) if e.kind() != io::ErrorKind::UnexpectedEof => Err(e.into()),
+            _ => {
+                trace!("echo {:02X?} of {:02X?}", echo, frame);
+                Err(ReadError::EchoMismatch)
+            }
+        }
+    }
+}
 
-                // Check the reply was directed to the panel.
-                match reply[0] {
-                    PANEL_ADDRESS => (),
-                    n => return Err(ReadError::InvalidReplyRecipient(n)),
-                };
+impl<T: Transport> Exchange for Bus<T> {
+    async fn send_receive_buffered(
+        &mut self,
+        data: &[u8],
+        reply: &mut [u8],
+    ) -> Result<usize, ReadError> {
+        Bus::send_receive_buffered(self, data, reply).await
+    }
+}
 
-                let crc = reply[bytes_read - 1];
-                if crc != reply[0..bytes_read - 1].galaxy_crc() {
-                    return Err(ReadError::CrcCheckFailed);
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
 
-                // Don't tell the caller about the CRC; the bus handles checking it and returns
-                // a better error in case it's missing or invalid.
-                Ok(bytes_read - 1)
-            }
-            Err(e) => Err(ReadError::from(e)),
-        })
+    // Keypad ping and its Ack reply, including the CRCs.
+    const PING: [u8; 4] = [0x10, 0x06, 0x01, 0xC1];
+    const ACK: [u8; 3] = [0x11, 0xFE, 0xBA];
+
+    /// Runs a device that replies to one message, echoing it first as an adapter would.
+    fn echoing_device(echo: Vec<u8>) -> Bus<DuplexStream> {
+        let (panel, mut device) = tokio::io::duplex(64);
+
+        tokio::spawn(async move {
+            let mut message = [0u8; PING.len()];
+            device.read_exact(&mut message).await.unwrap();
+            device.write_all(&echo).await.unwrap();
+            time::sleep(Duration::from_millis(5)).await;
+            device.write_all(&ACK).await.unwrap();
+            // Hold the line open, as a serial port would.
+            time::sleep(Duration::from_secs(1)).await;
+        });
+
+        Bus::new(panel)
+            .with_half_duplex(HalfDuplex {
+                echo: true,
+                ..Default::default()
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_strips_echo() {
+        time::pause();
+
+        let mut bus = echoing_device(PING.to_vec());
+        let mut reply = [0u8; 16];
+        let n = bus
+            .send_receive_buffered(&PING[..3], &mut reply)
+            .await
+            .unwrap();
+        assert_eq!(reply[..n], ACK[..2]);
+    }
+
+    #[tokio::test]
+    async fn test_echo_mismatch() {
+        time::pause();
+
+        let mut bus = echoing_device(vec![0x10, 0x16, 0x01, 0xC1]);
+        let mut reply = [0u8; 16];
+        assert!(matches!(
+            bus.send_receive_buffered(&PING[..3], &mut reply).await,
+            Err(ReadError::EchoMismatch)
+        ));
     }
 }
@@ -16,7 +16,7 @@ pub trait CheckGalaxyCRC {
 
 impl GalaxyCRC for Vec<u8> {
     fn galaxy_crc(&self) -> u8 {
-        (&self[..]).galaxy_crc()
+        self[..].galaxy_crc()
     }
 }
 
@@ -34,7 +34,7 @@ impl GalaxyCRC for [u8] {
 
 impl CheckGalaxyCRC for [u8] {
     fn check_galaxy_crc(&self) -> GalaxyCRCCheckResult {
-        assert!(self.len() >= 1, "message has no embedded CRC");
+        assert!(!self.is_empty(), "message has no embedded CRC");
 
         let msg_crc: u8 = self[self.len() - 1];
         let expect_crc = galaxy_crc(&self[0..self.len() - 1]);
@@ -0,0 +1,179 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::bus::{ReadError, PANEL_ADDRESS};
+
+/// MAX_FRAME_LENGTH is comfortably longer than any known reply, for sizing reply buffers.
+pub const MAX_FRAME_LENGTH: usize = 64;
+
+/// FrameCheck validates a frame incrementally as its bytes arrive, tracking the longest prefix
+/// that is a complete frame with a valid CRC.
+pub(crate) struct FrameCheck {
+    len: usize,
+    // CRC of the bytes so far, kept as the running sum from which it is folded.
+    crc: u16,
+    complete: Option<usize>,
+}
+
+impl FrameCheck {
+    pub fn new() -> FrameCheck {
+        FrameCheck {
+            len: 0,
+            crc: 0xAA,
+            complete: None,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        // A frame is at least an address, a command and the CRC.
+        if self.len >= 2 && self.crc == byte as u16 {
+            self.complete = Some(self.len + 1);
+        }
+
+        self.len += 1;
+        self.crc += byte as u16;
+        while self.crc > 0xFF {
+            self.crc = (self.crc & 0xFF) + (self.crc >> 8);
+        }
+    }
+
+    /// Classifies the frame pushed, returning its length without the CRC if it's a valid reply to
+    /// the panel.
+    pub fn result(&self, frame: &[u8]) -> Result<usize, ReadError> {
+        match frame.first() {
+            None => return Err(ReadError::NoData),
+            Some(&PANEL_ADDRESS) => (),
+            Some(&n) => return Err(ReadError::InvalidReplyRecipient(n)),
+        }
+
+        match self.complete {
+            // Don't tell the caller about the CRC; the bus handles checking it and returns a better
+            // error in case it's missing or invalid.
+            Some(n) if n == self.len => Ok(n - 1),
+            Some(n) => Err(ReadError::TrailingGarbage(self.len - n)),
+            None if self.len < 3 => Err(ReadError::Truncated(self.len)),
+            None => Err(ReadError::CrcCheckFailed),
+        }
+    }
+}
+
+/// Checks a reply read from the bus, including its CRC, returning the length of the reply without
+/// the CRC.
+pub(crate) fn check_reply(reply: &[u8]) -> Result<usize, ReadError> {
+    let mut check = FrameCheck::new();
+    for &byte in reply {
+        check.push(byte);
+    }
+
+    check.result(reply)
+}
+
+/// Receives a frame into a buffer, waiting up to `timeout` for it to begin and then accumulating
+/// bytes until none arrive within `intercharacter_timeout`, however the transport splits them into
+/// reads. Returns the length of the frame without its CRC.
+pub async fn receive_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+    timeout: Duration,
+    intercharacter_timeout: Duration,
+) -> Result<usize, ReadError> {
+    let mut check = FrameCheck::new();
+
+    let mut len = tokio::time::timeout(timeout, reader.read(buf))
+        .await
+        .map_err(|_| ReadError::Timeout)??;
+    buf[..len].iter().for_each(|&byte| check.push(byte));
+
+    while len > 0 {
+        if len == buf.len() {
+            // Wait out the rest of the frame, so that it doesn't run into the next reply.
+            let mut discard = [0u8; MAX_FRAME_LENGTH];
+            while matches!(
+                tokio::time::timeout(intercharacter_timeout, reader.read(&mut discard)).await,
+                Ok(Ok(1..))
+            ) {}
+
+            return Err(ReadError::Overflow(buf.len()));
+        }
+
+        match tokio::time::timeout(intercharacter_timeout, reader.read(&mut buf[len..])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => {
+                buf[len..len + n].iter().for_each(|&byte| check.push(byte));
+                len += n;
+            }
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    check.result(&buf[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, time};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+    const INTERCHARACTER_TIMEOUT: Duration = Duration::from_millis(5);
+
+    // Keypad AckWithKey reply, including the CRC.
+    const REPLY: [u8; 4] = [0x11, 0xF4, 0x01, 0xB1];
+
+    #[test]
+    fn test_check_reply() {
+        assert_eq!(check_reply(&REPLY).unwrap(), 3);
+        assert!(matches!(check_reply(&[]), Err(ReadError::NoData)));
+        assert!(matches!(
+            check_reply(&REPLY[..2]),
+            Err(ReadError::Truncated(2))
+        ));
+        assert!(matches!(
+            check_reply(&REPLY[..3]),
+            Err(ReadError::CrcCheckFailed)
+        ));
+        assert!(matches!(
+            check_reply(&[&REPLY[..], &[0x00, 0x42]].concat()),
+            Err(ReadError::TrailingGarbage(2))
+        ));
+        assert!(matches!(
+            check_reply(&[0x10, 0xF4, 0x01, 0xAF]),
+            Err(ReadError::InvalidReplyRecipient(0x10))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_receives_split_reads() {
+        time::pause();
+
+        let (mut device, mut panel) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for byte in REPLY {
+                device.write_u8(byte).await.unwrap();
+                time::sleep(Duration::from_millis(2)).await;
+            }
+            // Hold the line open, as a serial port would.
+            time::sleep(Duration::from_secs(1)).await;
+        });
+
+        let mut buf = [0u8; MAX_FRAME_LENGTH];
+        let n = receive_frame(&mut panel, &mut buf, TIMEOUT, INTERCHARACTER_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(buf[..n], REPLY[..3]);
+    }
+
+    #[tokio::test]
+    async fn test_receive_overflow() {
+        time::pause();
+
+        let (mut device, mut panel) = tokio::io::duplex(64);
+        device.write_all(&[0x11; 12]).await.unwrap();
+
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            receive_frame(&mut panel, &mut buf, TIMEOUT, INTERCHARACTER_TIMEOUT).await,
+            Err(ReadError::Overflow(8))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 pub mod bus;
 pub mod crc;
+pub mod frame;
 
-pub use bus::Bus;
+pub use bus::{Bus, ControlLine, Direction, Exchange, HalfDuplex, Timing, Transport};
 pub use crc::{CheckGalaxyCRC, GalaxyCRC};
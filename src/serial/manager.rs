@@ -1,24 +1,120 @@
 use derive_more::Display;
-use log::{debug, error, trace, warn};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use log::{debug, error, info, trace, warn};
+use priority_queue::PriorityQueue;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch, Notify},
+    time::Instant,
+};
+use tokio_serial::SerialStream;
 
 use self::queue::BackoffState;
 
 use super::{
-    galaxy::{self, bus::ReadError},
-    DeliveryError, SerialMessage,
+    devices::DeviceKind,
+    galaxy::{
+        self,
+        bus::{ReadError, PANEL_ADDRESS},
+        frame::MAX_FRAME_LENGTH,
+        Exchange,
+    },
+    metrics::DeviceMetrics,
+    DeliveryError, SerialMessage, SerialResponseResult,
 };
 
 /// LAST_MESSAGE_BAD_CHECKSUM_REPLY_COMMAND is the command returned from a device when the last
 /// message was corrupted or not understood by the device.
 const LAST_MESSAGE_BAD_CHECKSUM_REPLY_COMMAND: u8 = 0xF2;
 
+/// DELIVERY_ATTEMPTS is the number of times a message is sent to a device before giving up on a
+/// reply.
+pub(crate) const DELIVERY_ATTEMPTS: usize = 3;
+
+/// Interprets the outcome of an exchange on the bus as delivered to a device, given the reply
+/// buffer it was read into.
+pub(crate) fn delivery_result(
+    result: Result<usize, ReadError>,
+    reply: &[u8],
+) -> SerialResponseResult {
+    result
+        .map_err(|e| match e {
+            ReadError::NoData | ReadError::Timeout => DeliveryError::Timeout,
+            ReadError::CrcCheckFailed => DeliveryError::CrcFailed,
+            e @ (ReadError::Truncated(_)
+            | ReadError::TrailingGarbage(_)
+            | ReadError::Overflow(_)) => DeliveryError::Malformed(e),
+            e => DeliveryError::BusError(e),
+        })
+        .and_then(|bytes_read| {
+            // The data was already CRCed when it came off the bus.
+            SerialMessage::deserialise_unchecked(&reply[0..bytes_read])
+                .map_err(DeliveryError::DeserialisationError)
+        })
+}
+
+/// POLL_INTERVAL is the default longest time between polls of each device.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Priority is how urgently a device needs to be polled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    // Nothing to send, so polled at the minimum rate.
+    #[default]
+    Idle,
+    // Updates are waiting to be sent, e.g. a key acknowledgement or screen change.
+    Urgent,
+}
+
 pub trait SerialDevice: Send + Sync {
     fn next_message(&self) -> (u8, Option<Vec<u8>>);
     fn receive_update(&self, _: Result<SerialMessage, DeliveryError>);
+
+    /// Returns how urgently the device needs to be polled.
+    fn priority(&self) -> Priority {
+        Priority::Idle
+    }
+
+    /// Hands the device a notifier to trigger when it becomes urgent, so that the manager polls it
+    /// without waiting for its next scheduled poll.
+    fn set_wake(&self, _wake: Arc<Notify>) {}
 }
 
-#[derive(Clone, Copy, Debug, Display, PartialEq)]
+/// Latency summarises the time devices wait for the bus, from becoming due a poll, or having urgent
+/// updates, to the reply to the poll being received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub polls: u64,
+    pub last: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl Latency {
+    pub fn mean(&self) -> Duration {
+        if self.polls == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.polls as u32
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        self.polls += 1;
+        self.last = latency;
+        self.max = self.max.max(latency);
+        self.total += latency;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum DeviceStatus {
     Offline,
     OnlineOK,
@@ -26,10 +122,106 @@ pub enum DeviceStatus {
     Unknown,
 }
 
+#[derive(Clone, Debug)]
+pub enum EventType {
+    StatusChanged {
+        address: u8,
+        status: DeviceStatus,
+        previous: DeviceStatus,
+    },
+    // A device answered a discovery probe of an address with no device registered. Reported once,
+    // until the device stops answering.
+    Discovered {
+        address: u8,
+        kind: DeviceKind,
+        // Data of the reply to initialisation, which identifies the module and its firmware.
+        firmware: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum RegistrationError {
+    #[error("device {0:#04x} is already registered")]
+    Duplicate(u8),
+    #[error("device {0:#04x} is not registered")]
+    NotRegistered(u8),
+    #[error("serial manager has stopped")]
+    Stopped,
+}
+
+enum Command {
+    Add {
+        address: u8,
+        device: Arc<dyn SerialDevice>,
+        reply: oneshot::Sender<Result<(), RegistrationError>>,
+    },
+    Remove {
+        address: u8,
+        reply: oneshot::Sender<Result<(), RegistrationError>>,
+    },
+    Scan,
+}
+
+/// SerialHandle is used to add and remove devices while a SerialManager runs, and to have it scan
+/// the bus for devices that aren't registered.
+#[derive(Clone)]
+pub struct SerialHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    event_ch: broadcast::Sender<Event>,
+}
+
+impl SerialHandle {
+    pub async fn add_device(
+        &self,
+        address: u8,
+        device: Arc<dyn SerialDevice>,
+    ) -> Result<(), RegistrationError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command::Add {
+                address,
+                device,
+                reply,
+            })
+            .map_err(|_| RegistrationError::Stopped)?;
+
+        result.await.map_err(|_| RegistrationError::Stopped)?
+    }
+
+    pub async fn remove_device(&self, address: u8) -> Result<(), RegistrationError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(Command::Remove { address, reply })
+            .map_err(|_| RegistrationError::Stopped)?;
+
+        result.await.map_err(|_| RegistrationError::Stopped)?
+    }
+
+    /// Probes every address with no device registered, in between polls, reporting devices that
+    /// answer as Discovered events.
+    pub fn scan(&self) {
+        // The manager only goes away on shutdown, at which point a scan is moot.
+        let _ = self.commands.send(Command::Scan);
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_ch.subscribe()
+    }
+}
+
 struct DeviceState {
     device: Arc<dyn SerialDevice>,
     status: DeviceStatus,
     failures: u16,
+    // None until the device is first polled.
+    last_poll: Option<Instant>,
+    // When the device was first seen to be urgent, if it still is.
+    urgent_since: Option<Instant>,
+    // Since when backoff has been accounted for in the metrics, if the device is in backoff.
+    backoff_since: Option<Instant>,
 }
 
 impl DeviceState {
@@ -38,6 +230,9 @@ impl DeviceState {
             device,
             status: DeviceStatus::Unknown,
             failures: 0,
+            last_poll: None,
+            urgent_since: None,
+            backoff_since: None,
         }
     }
 }
@@ -59,6 +254,11 @@ mod queue {
             Default::default()
         }
 
+        /// Drops any backoff of a device, e.g. once it's removed.
+        pub fn forget_device(&mut self, id: u8) {
+            self.backoff_devices.remove(&id);
+        }
+
         pub fn mark_device_backoff(&mut self, id: u8) {
             self.backoff_devices
                 .entry(id)
@@ -168,90 +368,327 @@ mod queue {
     }
 }
 
-pub struct SerialManager {
-    pub bus: galaxy::Bus,
+pub struct SerialManager<B = galaxy::Bus<SerialStream>> {
+    pub bus: B,
     devices: HashMap<u8, DeviceState>,
     backoff: BackoffState,
+    // Devices keyed by when they are next due to be polled, the earliest first.
+    queue: PriorityQueue<u8, Reverse<Instant>>,
+    poll_interval: Duration,
+    // Notified by devices when they become urgent.
+    wake: Arc<Notify>,
+    event_ch: broadcast::Sender<Event>,
+    latency: watch::Sender<BTreeMap<u8, Latency>>,
+    metrics: watch::Sender<BTreeMap<u8, DeviceMetrics>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    // Kept to hand out to handles.
+    commands_tx: mpsc::UnboundedSender<Command>,
+    // Addresses waiting to be probed by a discovery scan.
+    probes: VecDeque<u8>,
+    // Unregistered addresses at which a device has answered, so it's only reported once.
+    discovered: HashSet<u8>,
 }
 
-impl SerialManager {
-    pub fn new(bus: galaxy::Bus) -> SerialManager {
+impl<B: Exchange> SerialManager<B> {
+    pub fn new(bus: B) -> SerialManager<B> {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+
         SerialManager {
             bus,
             devices: HashMap::new(),
             backoff: BackoffState::new(),
+            queue: PriorityQueue::new(),
+            poll_interval: POLL_INTERVAL,
+            wake: Arc::new(Notify::new()),
+            event_ch: broadcast::Sender::new(32),
+            latency: watch::channel(BTreeMap::new()).0,
+            metrics: watch::channel(BTreeMap::new()).0,
+            commands,
+            commands_tx,
+            probes: VecDeque::new(),
+            discovered: HashSet::new(),
+        }
+    }
+
+    /// Sets the longest time between polls of each device, which sets the minimum rate at which
+    /// even idle devices are polled.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> SerialManager<B> {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_ch.subscribe()
+    }
+
+    /// Subscribes to the latency of polls of each device, updated after every poll.
+    pub fn subscribe_latency(&self) -> watch::Receiver<BTreeMap<u8, Latency>> {
+        self.latency.subscribe()
+    }
+
+    /// Subscribes to the health metrics of the link to each device, updated after every exchange.
+    pub fn subscribe_metrics(&self) -> watch::Receiver<BTreeMap<u8, DeviceMetrics>> {
+        self.metrics.subscribe()
+    }
+
+    /// Returns a handle through which devices can be added and removed while the manager runs.
+    pub fn handle(&self) -> SerialHandle {
+        SerialHandle {
+            commands: self.commands_tx.clone(),
+            event_ch: self.event_ch.clone(),
         }
     }
 
-    pub fn register_device(&mut self, id: u8, device: Arc<dyn SerialDevice>) {
+    pub fn register_device(
+        &mut self,
+        id: u8,
+        device: Arc<dyn SerialDevice>,
+    ) -> Result<(), RegistrationError> {
         if self.devices.contains_key(&id) {
-            panic!("attempting to register duplicate serial device {}", id);
+            return Err(RegistrationError::Duplicate(id));
         }
 
+        device.set_wake(self.wake.clone());
         self.devices.insert(id, DeviceState::new(device));
+        self.discovered.remove(&id);
+        self.metrics.send_modify(|metrics| {
+            metrics.insert(id, DeviceMetrics::default());
+        });
+
+        Ok(())
+    }
+
+    /// Stops polling a device, forgetting everything about it.
+    pub fn deregister_device(&mut self, id: u8) -> Result<(), RegistrationError> {
+        self.devices
+            .remove(&id)
+            .ok_or(RegistrationError::NotRegistered(id))?;
+        self.queue.remove(&id);
+        self.backoff.forget_device(id);
+        self.latency.send_modify(|latencies| {
+            latencies.remove(&id);
+        });
+        self.metrics.send_modify(|metrics| {
+            metrics.remove(&id);
+        });
+
+        Ok(())
     }
 
     // TODO return error?
     pub async fn run(&mut self) {
-        let mut reply_buf = vec![0u8; 8];
-        let device_ids: Vec<u8> = self.devices.keys().cloned().collect();
+        let mut reply_buf = [0u8; MAX_FRAME_LENGTH];
 
         loop {
-            for id in &device_ids {
-                if let Some(_) = self.backoff.visit_device(*id) {
-                    // Device is in backoff.
+            while let Ok(command) = self.commands.try_recv() {
+                self.process_command(command);
+            }
+
+            let now = Instant::now();
+            self.schedule(now);
+
+            let (id, due) = match self.queue.peek() {
+                Some((&id, &Reverse(due))) if due <= now => (id, due),
+                next => {
+                    let due = next.map(|(_, &Reverse(due))| due);
+
+                    // Discovery only uses the bus while no device is due a poll.
+                    if let Some(address) = self.probes.pop_front() {
+                        self.probe(address, &mut reply_buf[..]).await;
+                        continue;
+                    }
+
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due.unwrap_or(now)), if due.is_some() => {}
+                        // A device became urgent, so the schedule is out of date.
+                        _ = self.wake.notified() => {}
+                        Some(command) = self.commands.recv() => self.process_command(command),
+                    }
                     continue;
                 }
+            };
 
-                trace!("Polling device {}", id);
-
-                let result = self.poll_device(*id, &mut reply_buf[..]).await;
-
-                {
-                    let state = self.devices.get_mut(id).unwrap();
-                    let old_status = state.status;
-
-                    (state.failures, state.status) = match result {
-                        Ok(_) => (0, DeviceStatus::OnlineOK),
-                        Err(e) => (
-                            state.failures + 1,
-                            match e {
-                                DeliveryError::Timeout | DeliveryError::BusError(_) => {
-                                    // If it's a bus error, we can't tell if it's this specific device that's in fault
-                                    // condition. It may or may not be online, but there's probably a far bigger issue
-                                    // for which the device state tracking is the least of our concerns.
-                                    //
-                                    // It's marked as Offline for now, but this could be revisited.
-                                    DeviceStatus::Offline
-                                }
-                                DeliveryError::CrcFailed
-                                | DeliveryError::DeserialisationError(_) => {
-                                    DeviceStatus::OnlineCorruptReplies
-                                }
-                            },
-                        ),
-                    };
-
-                    if state.failures == 3 || (state.failures > 0 && state.failures % 10 == 0) {
-                        warn!(
-                            "Device {} has exhibited {} communications failures",
-                            id, state.failures
-                        );
-                    } else if state.status != old_status {
-                        debug!(
-                            "Device {} status has changed from {} to {}",
-                            id, old_status, state.status
-                        );
-                    }
+            let state = self.devices.get_mut(&id).unwrap();
+            state.last_poll = Some(now);
+            state.urgent_since = None;
+
+            let in_backoff = self.backoff.visit_device(id).is_some();
+            if let Some(since) = state.backoff_since {
+                state.backoff_since = in_backoff.then_some(now);
+                self.metrics.send_modify(|metrics| {
+                    metrics.entry(id).or_default().backoff += now - since;
+                });
+            }
+
+            if in_backoff {
+                continue;
+            }
+
+            trace!("Polling device {}", id);
+
+            let result = self.poll_device(id, &mut reply_buf[..]).await;
+            self.update_status(id, result);
 
-                    if state.status == DeviceStatus::Offline {
-                        self.backoff.mark_device_backoff(*id);
+            let latency = Instant::now() - due;
+            self.latency.send_modify(|latencies| {
+                latencies.entry(id).or_default().record(latency);
+            });
+        }
+    }
+
+    /// Works out when each device is next due to be polled: straight away if it has urgent
+    /// updates and is online, otherwise once the poll interval has passed since it was last polled.
+    fn schedule(&mut self, now: Instant) {
+        for (&id, state) in self.devices.iter_mut() {
+            let due = match state.device.priority() {
+                // An offline device is left to its backoff, however urgent.
+                Priority::Urgent if state.status != DeviceStatus::Offline => {
+                    *state.urgent_since.get_or_insert(now)
+                }
+                _ => {
+                    state.urgent_since = None;
+                    state
+                        .last_poll
+                        .map_or(now, |last_poll| last_poll + self.poll_interval)
+                }
+            };
+
+            self.queue.push(id, Reverse(due));
+        }
+    }
+
+    fn process_command(&mut self, command: Command) {
+        match command {
+            Command::Add {
+                address,
+                device,
+                reply,
+            } => {
+                let result = self.register_device(address, device);
+                if result.is_ok() {
+                    info!("Device {} added", address);
+                }
+                let _ = reply.send(result);
+            }
+            Command::Remove { address, reply } => {
+                let result = self.deregister_device(address);
+                if result.is_ok() {
+                    info!("Device {} removed", address);
+                }
+                let _ = reply.send(result);
+            }
+            Command::Scan => {
+                for address in DeviceKind::ALL.iter().flat_map(DeviceKind::addresses) {
+                    if address != PANEL_ADDRESS
+                        && !self.devices.contains_key(&address)
+                        && !self.probes.contains(&address)
+                    {
+                        self.probes.push_back(address);
                     }
                 }
             }
+        }
+    }
+
+    /// Initialises whatever device may be at an unregistered address, reporting it if it answers.
+    /// Probes aren't retried, since most addresses are expected to be unused.
+    async fn probe(&mut self, address: u8, reply_buf: &mut [u8]) {
+        let Some(kind) = DeviceKind::from_address(address) else {
+            return;
+        };
+        if self.devices.contains_key(&address) {
+            return;
+        }
+
+        trace!("Probing address {}", address);
+
+        let data = kind.initialise(address).serialise_without_crc();
+        let result = self.bus.send_receive_buffered(&data, reply_buf).await;
+
+        match delivery_result(result, reply_buf) {
+            Ok(reply) if reply.command == kind.initialised_reply() => {
+                if self.discovered.insert(address) {
+                    let firmware = reply.additional_data.unwrap_or_default();
+                    info!(
+                        "Discovered {} at address {}, identity {:02X?}",
+                        kind, address, firmware
+                    );
+
+                    let _ = self.event_ch.send(Event(EventType::Discovered {
+                        address,
+                        kind,
+                        firmware,
+                    }));
+                }
+            }
+            Ok(reply) => debug!(
+                "Address {} answered probe with unexpected command {:02X}",
+                address, reply.command
+            ),
+            Err(DeliveryError::Timeout) => {
+                self.discovered.remove(&address);
+            }
+            Err(e) => debug!("Probe of address {} failed: {}", address, e),
+        }
+    }
+
+    fn update_status(&mut self, id: u8, result: Result<(), DeliveryError>) {
+        let state = self.devices.get_mut(&id).unwrap();
+        let old_status = state.status;
+
+        (state.failures, state.status) = match result {
+            Ok(_) => (0, DeviceStatus::OnlineOK),
+            Err(e) => (
+                state.failures + 1,
+                match e {
+                    DeliveryError::Timeout | DeliveryError::BusError(_) => {
+                        // If it's a bus error, we can't tell if it's this specific device that's in
+                        // fault condition. It may or may not be online, but there's probably a far
+                        // bigger issue for which the device state tracking is the least of our
+                        // concerns.
+                        //
+                        // It's marked as Offline for now, but this could be revisited.
+                        DeviceStatus::Offline
+                    }
+                    DeliveryError::CrcFailed
+                    | DeliveryError::Malformed(_)
+                    | DeliveryError::DeserialisationError(_) => DeviceStatus::OnlineCorruptReplies,
+                },
+            ),
+        };
+
+        if state.failures == 3 || (state.failures > 0 && state.failures.is_multiple_of(10)) {
+            warn!(
+                "Device {} has exhibited {} communications failures",
+                id, state.failures
+            );
+        } else if state.status != old_status {
+            debug!(
+                "Device {} status has changed from {} to {}",
+                id, old_status, state.status
+            );
+        }
+
+        if state.status != old_status {
+            let _ = self.event_ch.send(Event(EventType::StatusChanged {
+                address: id,
+                status: state.status,
+                previous: old_status,
+            }));
+        }
 
-            tokio::time::sleep(Duration::from_millis(100)).await;
+        if state.status == DeviceStatus::Offline {
+            self.backoff.mark_device_backoff(id);
+            state.backoff_since = Some(Instant::now());
         }
+
+        let status = state.status;
+        self.metrics.send_if_modified(|metrics| {
+            let device = metrics.entry(id).or_default();
+            let modified = device.status != status;
+            device.status = status;
+            modified
+        });
     }
 
     async fn poll_device(&mut self, id: u8, reply_buf: &mut [u8]) -> Result<(), DeliveryError> {
@@ -267,30 +704,50 @@ impl SerialManager {
 
         trace!("Device {}: outbound data {:02X?}", id, data.as_slice());
 
-        let mut retries_left = 3;
+        let mut retries_left = DELIVERY_ATTEMPTS;
         let mut reply_status = Err(DeliveryError::Timeout);
 
         while retries_left > 0 && reply_status.is_err() {
-            reply_status = self
+            let sent = Instant::now();
+            let result = self
                 .bus
                 .send_receive_buffered(data.as_slice(), reply_buf)
-                .await
-                .map_err(|e| match e {
-                    ReadError::NoData => DeliveryError::Timeout,
-                    ReadError::CrcCheckFailed => DeliveryError::CrcFailed,
-                    ReadError::InsufficientData => DeliveryError::CrcFailed,
-                    e => DeliveryError::BusError(e),
-                })
-                .and_then(|bytes_read| {
-                    // The data was already CRCed when it came off the bus.
-                    SerialMessage::deserialise_unchecked(&reply_buf[0..bytes_read])
-                        .map_err(DeliveryError::DeserialisationError)
-                });
+                .await;
+            let round_trip = sent.elapsed();
+            reply_status = delivery_result(result, reply_buf);
+
+            self.metrics.send_modify(|metrics| {
+                let device = metrics.entry(id).or_default();
+                device.frames_sent += 1;
+                if retries_left < DELIVERY_ATTEMPTS {
+                    device.retries += 1;
+                }
+
+                match reply_status.as_ref() {
+                    Ok(reply) if reply.command == LAST_MESSAGE_BAD_CHECKSUM_REPLY_COMMAND => {
+                        device.bad_checksum_replies += 1;
+                    }
+                    Err(DeliveryError::Timeout) => device.timeouts += 1,
+                    Err(DeliveryError::CrcFailed) => device.crc_failures += 1,
+                    Err(DeliveryError::Malformed(_)) => device.malformed_replies += 1,
+                    _ => (),
+                }
 
+                // Only exchanges that got some sort of reply say anything about the round trip.
+                if !matches!(
+                    reply_status,
+                    Err(DeliveryError::Timeout | DeliveryError::BusError(_))
+                ) {
+                    device.round_trip.observe(round_trip);
+                }
+            });
+
+            // A device that rejects a message is reinitialised on being passed the rejection,
+            // which sends its whole state again, so the message itself isn't retried.
             let should_retry = match reply_status.as_ref() {
                 Ok(reply) if reply.command == LAST_MESSAGE_BAD_CHECKSUM_REPLY_COMMAND => {
                     error!("Device {} last outbound message failed checksum", id);
-                    true
+                    false
                 }
                 Err(e) => {
                     error!("Device {} failed message delivery: {}", id, e);
@@ -91,7 +91,7 @@ impl SerialMessage {
         Ok(SerialMessage {
             recipient_address,
             command,
-            additional_data: if additional_data.len() > 0 {
+            additional_data: if !additional_data.is_empty() {
                 Some(additional_data)
             } else {
                 None
@@ -106,6 +106,8 @@ pub enum DeliveryError {
     Timeout,
     #[error("CRC check failed")]
     CrcFailed,
+    #[error("Malformed reply: {0}")]
+    Malformed(galaxy::bus::ReadError),
     #[error("Deserialisation error: {0}")]
     DeserialisationError(#[from] DeserialisationError),
     #[error("Bus error: {0}")]
@@ -0,0 +1,264 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use super::manager::DeviceStatus;
+
+/// LATENCY_BUCKETS are the upper bounds of the round trip latency histogram, in seconds. A reply
+/// normally arrives within a few milliseconds; the upper buckets catch replies nearing the bus
+/// timeout.
+const LATENCY_BUCKETS: [f64; 8] = [0.005, 0.01, 0.02, 0.035, 0.05, 0.075, 0.1, 0.25];
+
+/// Histogram counts observations into cumulative buckets, as Prometheus expects.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    // Observations no greater than each of LATENCY_BUCKETS.
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: Duration) {
+        for (bucket, &bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value.as_secs_f64() <= bound {
+                *bucket += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// DeviceMetrics describes the health of the link to a device on the bus.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceMetrics {
+    // Messages sent, including retries.
+    pub frames_sent: u64,
+    pub timeouts: u64,
+    pub crc_failures: u64,
+    // Replies cut short, running on or overflowing the reply buffer.
+    pub malformed_replies: u64,
+    // F2 replies, sent by a device that couldn't make sense of the last message.
+    pub bad_checksum_replies: u64,
+    // Messages sent again after a failed delivery.
+    pub retries: u64,
+    // Time from sending a message to receiving a reply, for exchanges that got one.
+    pub round_trip: Histogram,
+    // Time the device has spent in backoff, not being polled.
+    pub backoff: Duration,
+    pub status: DeviceStatus,
+}
+
+impl Default for DeviceMetrics {
+    fn default() -> Self {
+        DeviceMetrics {
+            frames_sent: 0,
+            timeouts: 0,
+            crc_failures: 0,
+            malformed_replies: 0,
+            bad_checksum_replies: 0,
+            retries: 0,
+            round_trip: Histogram::default(),
+            backoff: Duration::ZERO,
+            status: DeviceStatus::Unknown,
+        }
+    }
+}
+
+fn status_label(status: DeviceStatus) -> &'static str {
+    match status {
+        DeviceStatus::Offline => "offline",
+        DeviceStatus::OnlineOK => "online_ok",
+        DeviceStatus::OnlineCorruptReplies => "online_corrupt_replies",
+        DeviceStatus::Unknown => "unknown",
+    }
+}
+
+const STATUSES: [DeviceStatus; 4] = [
+    DeviceStatus::Offline,
+    DeviceStatus::OnlineOK,
+    DeviceStatus::OnlineCorruptReplies,
+    DeviceStatus::Unknown,
+];
+
+struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&DeviceMetrics) -> u64,
+}
+
+/// COUNTERS are the counters of each device, with their names and help text.
+const COUNTERS: [Counter; 6] = [
+    Counter {
+        name: "frames_sent",
+        help: "Messages sent to the device, including retries.",
+        value: |m| m.frames_sent,
+    },
+    Counter {
+        name: "timeouts",
+        help: "Messages to which the device did not reply in time.",
+        value: |m| m.timeouts,
+    },
+    Counter {
+        name: "crc_failures",
+        help: "Replies from the device that failed the CRC.",
+        value: |m| m.crc_failures,
+    },
+    Counter {
+        name: "malformed_replies",
+        help: "Replies from the device that were truncated or overran.",
+        value: |m| m.malformed_replies,
+    },
+    Counter {
+        name: "bad_checksum_replies",
+        help: "F2 replies, from the device failing to understand a message.",
+        value: |m| m.bad_checksum_replies,
+    },
+    Counter {
+        name: "retries",
+        help: "Messages sent again after a failed delivery.",
+        value: |m| m.retries,
+    },
+];
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders the metrics of each device in the Prometheus text exposition format.
+pub fn render(metrics: &BTreeMap<u8, DeviceMetrics>) -> String {
+    let mut out = String::new();
+
+    for counter in COUNTERS {
+        header(
+            &mut out,
+            &format!("galaxy_bus_{}_total", counter.name),
+            "counter",
+            counter.help,
+        );
+        for (address, device) in metrics {
+            let _ = writeln!(
+                out,
+                "galaxy_bus_{}_total{{address=\"{:02X}\"}} {}",
+                counter.name,
+                address,
+                (counter.value)(device)
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "galaxy_bus_round_trip_seconds",
+        "histogram",
+        "Time from sending a message to receiving the reply.",
+    );
+    for (address, device) in metrics {
+        let histogram = &device.round_trip;
+        for (&bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "galaxy_bus_round_trip_seconds_bucket{{address=\"{:02X}\",le=\"{}\"}} {}",
+                address, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "galaxy_bus_round_trip_seconds_bucket{{address=\"{:02X}\",le=\"+Inf\"}} {}",
+            address, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "galaxy_bus_round_trip_seconds_sum{{address=\"{:02X}\"}} {}",
+            address,
+            histogram.sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "galaxy_bus_round_trip_seconds_count{{address=\"{:02X}\"}} {}",
+            address, histogram.count
+        );
+    }
+
+    header(
+        &mut out,
+        "galaxy_bus_backoff_seconds_total",
+        "counter",
+        "Time the device has spent in backoff after going offline.",
+    );
+    for (address, device) in metrics {
+        let _ = writeln!(
+            out,
+            "galaxy_bus_backoff_seconds_total{{address=\"{:02X}\"}} {}",
+            address,
+            device.backoff.as_secs_f64()
+        );
+    }
+
+    header(
+        &mut out,
+        "galaxy_bus_device_status",
+        "gauge",
+        "Current status of the device, 1 for the status it is in.",
+    );
+    for (address, device) in metrics {
+        for status in STATUSES {
+            let _ = writeln!(
+                out,
+                "galaxy_bus_device_status{{address=\"{:02X}\",status=\"{}\"}} {}",
+                address,
+                status_label(status),
+                u8::from(device.status == status)
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(1));
+
+        assert_eq!(histogram.buckets, [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.sum, Duration::from_millis(1043));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut device = DeviceMetrics {
+            frames_sent: 12,
+            bad_checksum_replies: 1,
+            retries: 1,
+            backoff: Duration::from_millis(1500),
+            status: DeviceStatus::OnlineOK,
+            ..Default::default()
+        };
+        device.round_trip.observe(Duration::from_millis(8));
+        let text = render(&BTreeMap::from([(0x10, device)]));
+
+        for line in [
+            "# TYPE galaxy_bus_frames_sent_total counter",
+            "galaxy_bus_frames_sent_total{address=\"10\"} 12",
+            "galaxy_bus_bad_checksum_replies_total{address=\"10\"} 1",
+            "galaxy_bus_round_trip_seconds_bucket{address=\"10\",le=\"0.005\"} 0",
+            "galaxy_bus_round_trip_seconds_bucket{address=\"10\",le=\"0.01\"} 1",
+            "galaxy_bus_round_trip_seconds_bucket{address=\"10\",le=\"+Inf\"} 1",
+            "galaxy_bus_round_trip_seconds_count{address=\"10\"} 1",
+            "galaxy_bus_backoff_seconds_total{address=\"10\"} 1.5",
+            "galaxy_bus_device_status{address=\"10\",status=\"online_ok\"} 1",
+            "galaxy_bus_device_status{address=\"10\",status=\"offline\"} 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+}
@@ -1,8 +1,13 @@
 // Galaxy encapsulates all logic for interfacing with the Galaxy serial bus and devices.
+pub mod capture;
 pub mod devices;
 pub mod galaxy;
 pub mod manager;
 mod message;
+pub mod metrics;
+pub mod replay;
+pub mod simulator;
+pub mod sniffer;
 
 pub use manager::SerialDevice;
 pub use message::{DeliveryError, SerialMessage, SerialResponseResult};
@@ -0,0 +1,206 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use super::{
+    capture::{Entry, Record},
+    galaxy::{
+        bus::{ReadError, PANEL_ADDRESS},
+        frame::check_reply,
+    },
+    manager::{delivery_result, DELIVERY_ATTEMPTS},
+    SerialDevice, SerialMessage, SerialResponseResult,
+};
+
+/// Divergence is a point in a capture where a device driver asked to send something other than the
+/// message captured, meaning the replay no longer reflects what happened on the bus.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub at: Duration,
+    pub address: u8,
+    // Messages including the CRC.
+    pub captured: Vec<u8>,
+    pub replayed: Vec<u8>,
+}
+
+// A message sent by the panel and the reply it got, including the CRC.
+struct Transaction {
+    at: Duration,
+    message: Vec<u8>,
+    reply: Result<Vec<u8>, ReadError>,
+}
+
+/// Pairs each outbound message in a capture with its reply. Frames seen by the sniffer are taken
+/// as replies when addressed to the panel, and a message with no reply as having timed out.
+fn transactions(entries: &[Entry]) -> Vec<Transaction> {
+    let mut transactions = Vec::new();
+    let mut entries = entries.iter().peekable();
+
+    while let Some(entry) = entries.next() {
+        let message = match &entry.record {
+            Record::Outbound { data } => data,
+            Record::Frame { data } if data.first() != Some(&PANEL_ADDRESS) => data,
+            // A reply to nothing, e.g. at the start of a sniffed capture.
+            _ => continue,
+        };
+
+        let reply = match entries.peek().map(|entry| &entry.record) {
+            Some(Record::Reply { data }) => Ok(data.clone()),
+            Some(Record::Frame { data }) if data.first() == Some(&PANEL_ADDRESS) => {
+                Ok(data.clone())
+            }
+            Some(Record::Error { error }) => Err(error.clone().into()),
+            _ => {
+                transactions.push(Transaction {
+                    at: entry.at,
+                    message: message.clone(),
+                    reply: Err(ReadError::Timeout),
+                });
+                continue;
+            }
+        };
+        entries.next();
+
+        transactions.push(Transaction {
+            at: entry.at,
+            message: message.clone(),
+            reply,
+        });
+    }
+
+    transactions
+}
+
+/// Replayer feeds the replies in a capture to device drivers offline, as `SerialManager` would
+/// have delivered them, so that a fault seen in the field can be reproduced.
+#[derive(Default)]
+pub struct Replayer {
+    devices: HashMap<u8, Arc<dyn SerialDevice>>,
+}
+
+impl Replayer {
+    pub fn new() -> Replayer {
+        Default::default()
+    }
+
+    pub fn register_device(&mut self, address: u8, device: Arc<dyn SerialDevice>) {
+        if self.devices.contains_key(&address) {
+            panic!("attempting to register duplicate replay device {}", address);
+        }
+
+        self.devices.insert(address, device);
+    }
+
+    /// Replays a capture into the registered devices, returning the points at which they asked to
+    /// send something other than was captured. Traffic for other devices is skipped.
+    pub fn replay(&self, entries: &[Entry]) -> Vec<Divergence> {
+        let transactions = transactions(entries);
+        let mut divergences = Vec::new();
+        let mut i = 0;
+
+        while i < transactions.len() {
+            let transaction = &transactions[i];
+            i += 1;
+
+            let address = transaction.message[0];
+            let Some(device) = self.devices.get(&address) else {
+                continue;
+            };
+
+            let (command, additional_data) = device.next_message();
+            let replayed = SerialMessage {
+                recipient_address: address,
+                command,
+                additional_data,
+            }
+            .serialise();
+            if replayed != transaction.message {
+                divergences.push(Divergence {
+                    at: transaction.at,
+                    address,
+                    captured: transaction.message.clone(),
+                    replayed,
+                });
+            }
+
+            // The manager resends a message while delivery fails, passing on only the last result.
+            let mut status = deliver(transaction);
+            let mut attempts = 1;
+            while status.is_err()
+                && attempts < DELIVERY_ATTEMPTS
+                && transactions
+                    .get(i)
+                    .is_some_and(|retry| retry.message == transaction.message)
+            {
+                status = deliver(&transactions[i]);
+                attempts += 1;
+                i += 1;
+            }
+
+            device.receive_update(status);
+        }
+
+        divergences
+    }
+}
+
+fn deliver(transaction: &Transaction) -> SerialResponseResult {
+    match &transaction.reply {
+        Ok(reply) => delivery_result(check_reply(reply), reply),
+        Err(e) => delivery_result(Err(e.clone()), &[]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::devices::keypad::{self, SerialKeypad};
+
+    fn frame(at_ms: u64, recipient_address: u8, command: u8, data: &[u8]) -> Entry {
+        Entry {
+            at: Duration::from_millis(at_ms),
+            record: Record::Frame {
+                data: SerialMessage {
+                    recipient_address,
+                    command,
+                    additional_data: (!data.is_empty()).then(|| data.to_vec()),
+                }
+                .serialise(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_replays_sniffed_frames() {
+        let keypad = Arc::new(SerialKeypad::new());
+        let mut replayer = Replayer::new();
+        replayer.register_device(0x10, keypad.clone());
+
+        let entries = vec![
+            // The keypad doesn't answer at first, so the panel retries.
+            frame(0, 0x10, 0x00, &[0x0E]),
+            frame(60, 0x10, 0x00, &[0x0E]),
+            frame(120, 0x10, 0x00, &[0x0E]),
+            frame(125, PANEL_ADDRESS, 0xFF, &[0x08, 0x00, 0x64]),
+            // Traffic for other devices is skipped.
+            frame(200, 0x20, 0x00, &[]),
+            frame(205, PANEL_ADDRESS, 0xFF, &[0x01, 0x01, 0x05]),
+        ];
+
+        assert_eq!(replayer.replay(&entries), []);
+        assert_ne!(
+            keypad.next_message().0,
+            u8::from(keypad::Command::Initialise)
+        );
+    }
+
+    #[test]
+    fn test_reports_divergence() {
+        let mut replayer = Replayer::new();
+        replayer.register_device(0x10, Arc::new(SerialKeypad::new()));
+
+        // Unlike the panel captured, the driver would have initialised the keypad first.
+        let divergences = replayer.replay(&[frame(300, 0x10, 0x06, &[0x01])]);
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].at, Duration::from_millis(300));
+        assert_eq!(divergences[0].replayed, [0x10, 0x00, 0x0E, 0xC8]);
+    }
+}
@@ -0,0 +1,200 @@
+use log::warn;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use super::SimulatedDevice;
+use crate::serial::{
+    devices::keypad::{display::VirtualDisplay, Beeper, Command, ReplyCommand, KEYS},
+    SerialMessage,
+};
+
+// Returned in the initialisation reply of a CP-037.
+const IDENTITY: [u8; 3] = [0x08, 0x00, 0x64];
+
+// Key data bytes of an AckWithKey reply.
+const TAMPER_ONLY: u8 = 0x7F;
+const TAMPER_FLAG: u8 = 0x40;
+
+// Display flags of a screen update.
+const BLINK_FLAG: u8 = 0x08;
+const KEY_ACK_FLAG: u8 = 0x10;
+const KEY_ACK_TOGGLE: u8 = 0x02;
+
+struct Keypad {
+    initialised: bool,
+    initialisations: usize,
+    display: VirtualDisplay,
+    backlight: bool,
+    beeper: Beeper,
+    blink: bool,
+    // Pressed keys not yet acknowledged by the panel, the first being reported in each reply.
+    keys: VecDeque<char>,
+    // Toggle of the last key acknowledgement, to ignore repeats.
+    last_key_ack: Option<u8>,
+    tamper: bool,
+    reject_next: bool,
+}
+
+impl Keypad {
+    fn new() -> Keypad {
+        Keypad {
+            initialised: false,
+            initialisations: 0,
+            display: VirtualDisplay::new(),
+            backlight: false,
+            beeper: Beeper::Off,
+            blink: false,
+            keys: VecDeque::new(),
+            last_key_ack: None,
+            tamper: false,
+            reject_next: false,
+        }
+    }
+
+    fn ack_key(&mut self, toggle: u8) {
+        if self.last_key_ack != Some(toggle) {
+            self.last_key_ack = Some(toggle);
+            self.keys.pop_front();
+        }
+    }
+
+    fn screen(&mut self, data: &[u8]) {
+        let Some((&flags, ops)) = data.split_first() else {
+            return;
+        };
+
+        if flags & KEY_ACK_FLAG != 0 {
+            self.ack_key(flags & KEY_ACK_TOGGLE);
+        }
+        self.blink = flags & BLINK_FLAG != 0;
+
+        let unknown = self.display.apply(ops);
+        if !unknown.is_empty() {
+            warn!("Keypad screen update has unknown op codes {:02X?}", unknown);
+        }
+    }
+
+    fn status(&self) -> (u8, Option<Vec<u8>>) {
+        let tamper = if self.tamper { TAMPER_FLAG } else { 0 };
+
+        match self.keys.front() {
+            Some(&key) => (
+                ReplyCommand::AckWithKey.into(),
+                Some(vec![KEYS.find(key).unwrap() as u8 | tamper]),
+            ),
+            None if self.tamper => (ReplyCommand::AckWithKey.into(), Some(vec![TAMPER_ONLY])),
+            None => (ReplyCommand::Ack.into(), None),
+        }
+    }
+}
+
+/// SimulatedKeypad emulates a CP-037 keypad. Clones share the same keypad, so one can be added to
+/// a simulator while another is used to press keys and inspect the display.
+#[derive(Clone)]
+pub struct SimulatedKeypad(Arc<Mutex<Keypad>>);
+
+impl Default for SimulatedKeypad {
+    fn default() -> Self {
+        SimulatedKeypad(Arc::new(Mutex::new(Keypad::new())))
+    }
+}
+
+impl SimulatedKeypad {
+    pub fn new() -> SimulatedKeypad {
+        Default::default()
+    }
+
+    /// Presses each key in turn, using the characters of the keypad event model.
+    pub fn press(&self, keys: &str) {
+        let mut keypad = self.0.lock().unwrap();
+
+        for key in keys.chars() {
+            assert!(KEYS.contains(key), "no such key on keypad: {}", key);
+            keypad.keys.push_back(key);
+        }
+    }
+
+    /// Returns the number of pressed keys yet to be acknowledged by the panel.
+    pub fn pending_keys(&self) -> usize {
+        self.0.lock().unwrap().keys.len()
+    }
+
+    /// Returns the text on each line of the display, without trailing whitespace.
+    pub fn screen(&self) -> [String; 2] {
+        self.0.lock().unwrap().display.lines()
+    }
+
+    pub fn backlight(&self) -> bool {
+        self.0.lock().unwrap().backlight
+    }
+
+    pub fn beeper(&self) -> Beeper {
+        self.0.lock().unwrap().beeper
+    }
+
+    pub fn blink(&self) -> bool {
+        self.0.lock().unwrap().blink
+    }
+
+    pub fn set_tamper(&self, tamper: bool) {
+        self.0.lock().unwrap().tamper = tamper;
+    }
+
+    /// Returns the number of times the keypad has been initialised by the panel.
+    pub fn initialisations(&self) -> usize {
+        self.0.lock().unwrap().initialisations
+    }
+
+    /// Replies to the next message with a bad checksum, as though it was corrupted on the line.
+    pub fn reject_next(&self) {
+        self.0.lock().unwrap().reject_next = true;
+    }
+}
+
+impl SimulatedDevice for SimulatedKeypad {
+    fn reply(&self, msg: &SerialMessage) -> Option<(u8, Option<Vec<u8>>)> {
+        let mut keypad = self.0.lock().unwrap();
+
+        if keypad.reject_next {
+            keypad.reject_next = false;
+            return Some((ReplyCommand::BadChecksum.into(), None));
+        }
+
+        let data = msg.additional_data.as_deref().unwrap_or_default();
+
+        match Command::try_from(msg.command) {
+            Ok(Command::Initialise) => {
+                keypad.initialised = true;
+                keypad.initialisations += 1;
+                keypad.last_key_ack = None;
+
+                return Some((ReplyCommand::Initialised.into(), Some(IDENTITY.to_vec())));
+            }
+            // The keypad ignores the panel until it has been initialised.
+            _ if !keypad.initialised => return None,
+            Ok(Command::Ping) | Ok(Command::KeyClicks) => {}
+            Ok(Command::Screen) => keypad.screen(data),
+            Ok(Command::ButtonAck) => {
+                if let Some(&toggle) = data.first() {
+                    keypad.ack_key(toggle);
+                }
+            }
+            Ok(Command::Beeper) => {
+                keypad.beeper = match *data {
+                    [0x01, ..] => Beeper::On,
+                    [0x03, on_time, off_time] => Beeper::Intermittent { on_time, off_time },
+                    _ => Beeper::Off,
+                };
+            }
+            Ok(Command::Backlight) => keypad.backlight = data.first() == Some(&0x01),
+            Err(_) => {
+                warn!("Keypad received unknown command {:02X}", msg.command);
+                return Some((ReplyCommand::BadChecksum.into(), None));
+            }
+        }
+
+        Some(keypad.status())
+    }
+}
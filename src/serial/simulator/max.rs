@@ -0,0 +1,113 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use super::SimulatedDevice;
+use crate::serial::{
+    devices::max::{Command, ReplyCommand, DOOR_OPEN_FLAG, TAMPER_FLAG},
+    SerialMessage,
+};
+
+// Returned in the initialisation reply of a MAX.
+const IDENTITY: [u8; 3] = [0x09, 0x03, 0x12];
+
+#[derive(Default)]
+struct Max {
+    initialised: bool,
+    // Cards presented and not yet acknowledged by the panel, the first being reported.
+    reads: VecDeque<u32>,
+    door_open: bool,
+    tamper: bool,
+    // The input flags last reported to the panel.
+    reported: Option<u8>,
+    // LED, sounder and relay as last set by the panel.
+    outputs: [u8; 3],
+}
+
+impl Max {
+    fn flags(&self) -> u8 {
+        (if self.door_open { DOOR_OPEN_FLAG } else { 0 })
+            | if self.tamper { TAMPER_FLAG } else { 0 }
+    }
+
+    fn status(&mut self) -> (u8, Option<Vec<u8>>) {
+        let flags = self.flags();
+        self.reported = Some(flags);
+
+        (ReplyCommand::Status.into(), Some(vec![flags]))
+    }
+}
+
+/// SimulatedMax emulates a MAX reader with its door closed until opened. Clones share the same
+/// reader.
+#[derive(Clone, Default)]
+pub struct SimulatedMax(Arc<Mutex<Max>>);
+
+impl SimulatedMax {
+    pub fn new() -> SimulatedMax {
+        Default::default()
+    }
+
+    /// Presents a card or fob with the given ID to the reader.
+    pub fn present(&self, id: u32) {
+        self.0.lock().unwrap().reads.push_back(id);
+    }
+
+    pub fn set_door_open(&self, open: bool) {
+        self.0.lock().unwrap().door_open = open;
+    }
+
+    pub fn set_tamper(&self, tamper: bool) {
+        self.0.lock().unwrap().tamper = tamper;
+    }
+
+    /// Returns whether the panel has released the door lock.
+    pub fn relay(&self) -> bool {
+        self.0.lock().unwrap().outputs[2] != 0
+    }
+
+    /// Returns the LED and sounder bytes as last set by the panel.
+    pub fn indication(&self) -> (u8, u8) {
+        let outputs = self.0.lock().unwrap().outputs;
+        (outputs[0], outputs[1])
+    }
+}
+
+impl SimulatedDevice for SimulatedMax {
+    fn reply(&self, msg: &SerialMessage) -> Option<(u8, Option<Vec<u8>>)> {
+        let mut max = self.0.lock().unwrap();
+
+        match Command::try_from(msg.command) {
+            Ok(Command::Initialise) => {
+                max.initialised = true;
+                max.reported = None;
+
+                return Some((ReplyCommand::Initialised.into(), Some(IDENTITY.to_vec())));
+            }
+            _ if !max.initialised => return None,
+            Ok(Command::RequestStatus) => return Some(max.status()),
+            Ok(Command::ReadAck) => {
+                max.reads.pop_front();
+            }
+            Ok(Command::Outputs) => match msg.additional_data.as_deref() {
+                Some(&[led, sounder, relay]) => max.outputs = [led, sounder, relay],
+                _ => return Some((ReplyCommand::BadChecksum.into(), None)),
+            },
+            Ok(Command::Ping) => {}
+            Err(_) => return Some((ReplyCommand::BadChecksum.into(), None)),
+        }
+
+        // A card is reported until acknowledged, ahead of any change to the inputs.
+        if let Some(id) = max.reads.front() {
+            Some((
+                ReplyCommand::CardRead.into(),
+                Some(id.to_be_bytes().to_vec()),
+            ))
+        } else if max.reported != Some(max.flags()) {
+            Some(max.status())
+        } else {
+            Some((ReplyCommand::Ack.into(), None))
+        }
+    }
+}
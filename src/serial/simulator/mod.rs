@@ -0,0 +1,105 @@
+use log::trace;
+use std::{collections::HashMap, io, time::Duration};
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    galaxy::{
+        bus::{read_burst, PANEL_ADDRESS},
+        Transport,
+    },
+    message::DeserialisationError,
+    sniffer::split_valid,
+    SerialMessage,
+};
+
+pub mod keypad;
+pub mod max;
+pub mod prox;
+pub mod psu;
+pub mod rio;
+
+pub use keypad::SimulatedKeypad;
+pub use max::SimulatedMax;
+pub use prox::SimulatedProx;
+pub use psu::SimulatedPsu;
+pub use rio::SimulatedRio;
+
+/// QUIET_PERIOD is the time for which the line must be idle after receiving data for a message to
+/// be considered complete.
+const QUIET_PERIOD: Duration = Duration::from_millis(2);
+
+/// BAD_CHECKSUM_REPLY_COMMAND is returned by a device when a message addressed to it is corrupted
+/// or not understood.
+const BAD_CHECKSUM_REPLY_COMMAND: u8 = 0xF2;
+
+/// SimulatedDevice emulates a device on the far end of the bus.
+pub trait SimulatedDevice: Send + Sync {
+    /// Returns the reply to a message addressed to the device as a command and any additional
+    /// data, or None if the device stays silent.
+    fn reply(&self, msg: &SerialMessage) -> Option<(u8, Option<Vec<u8>>)>;
+}
+
+/// Simulator answers the panel on behalf of a set of simulated devices, for testing the bus and
+/// device handling without hardware. The panel end of the transport is passed to a `Bus`.
+///
+/// Given a serial port, the simulator instead runs in slave mode on the bus of a real panel,
+/// answering only for its devices amongst any others on the bus.
+pub struct Simulator<T> {
+    transport: T,
+    devices: HashMap<u8, Box<dyn SimulatedDevice>>,
+}
+
+impl<T: Transport> Simulator<T> {
+    pub fn new(transport: T) -> Simulator<T> {
+        Simulator {
+            transport,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn add_device(&mut self, address: u8, device: Box<dyn SimulatedDevice>) {
+        if self.devices.contains_key(&address) {
+            panic!("attempting to add duplicate simulated device {}", address);
+        }
+
+        self.devices.insert(address, device);
+    }
+
+    /// Answers messages from the panel until the other end of the transport is closed.
+    pub async fn run(&mut self) -> io::Result<()> {
+        loop {
+            let burst = read_burst(&mut self.transport, QUIET_PERIOD).await?;
+            if burst.is_empty() {
+                return Ok(());
+            }
+
+            trace!("simulator received {:02X?}", burst);
+
+            // On a real bus a message can run into the reply of another device.
+            for frame in split_valid(&burst).unwrap_or_else(|| vec![burst]) {
+                if let Some(reply) = self.process(&frame) {
+                    let reply = reply.serialise();
+                    trace!("simulator replying {:02X?}", reply);
+
+                    self.transport.write_all(&reply).await?;
+                }
+            }
+        }
+    }
+
+    fn process(&self, frame: &[u8]) -> Option<SerialMessage> {
+        let device = self.devices.get(frame.first()?)?;
+
+        let (command, additional_data) = match SerialMessage::deserialise(frame) {
+            Ok(msg) => device.reply(&msg)?,
+            Err(DeserialisationError::CrcFailed) => (BAD_CHECKSUM_REPLY_COMMAND, None),
+            Err(_) => return None,
+        };
+
+        Some(SerialMessage {
+            recipient_address: PANEL_ADDRESS,
+            command,
+            additional_data,
+        })
+    }
+}
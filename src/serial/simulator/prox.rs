@@ -0,0 +1,64 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use super::SimulatedDevice;
+use crate::serial::{
+    devices::prox::{Command, ReplyCommand},
+    SerialMessage,
+};
+
+// Returned in the initialisation reply of a prox reader.
+const IDENTITY: [u8; 3] = [0x08, 0x02, 0x10];
+
+#[derive(Default)]
+struct Prox {
+    initialised: bool,
+    // Cards presented and not yet acknowledged by the panel, the first being reported.
+    reads: VecDeque<u32>,
+}
+
+/// SimulatedProx emulates the prox reader of a CP-038 keypad. Clones share the same reader.
+#[derive(Clone, Default)]
+pub struct SimulatedProx(Arc<Mutex<Prox>>);
+
+impl SimulatedProx {
+    pub fn new() -> SimulatedProx {
+        Default::default()
+    }
+
+    /// Presents a card or fob with the given ID to the reader.
+    pub fn present(&self, id: u32) {
+        self.0.lock().unwrap().reads.push_back(id);
+    }
+}
+
+impl SimulatedDevice for SimulatedProx {
+    fn reply(&self, msg: &SerialMessage) -> Option<(u8, Option<Vec<u8>>)> {
+        let mut prox = self.0.lock().unwrap();
+
+        match Command::try_from(msg.command) {
+            Ok(Command::Initialise) => {
+                prox.initialised = true;
+
+                return Some((ReplyCommand::Initialised.into(), Some(IDENTITY.to_vec())));
+            }
+            _ if !prox.initialised => return None,
+            Ok(Command::ReadAck) => {
+                prox.reads.pop_front();
+            }
+            Ok(Command::Ping) => {}
+            Err(_) => return Some((ReplyCommand::BadChecksum.into(), None)),
+        }
+
+        // The card is reported in reply to every message until acknowledged.
+        match prox.reads.front() {
+            Some(id) => Some((
+                ReplyCommand::CardRead.into(),
+                Some(id.to_be_bytes().to_vec()),
+            )),
+            None => Some((ReplyCommand::Ack.into(), None)),
+        }
+    }
+}
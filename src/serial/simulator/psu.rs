@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+
+use super::SimulatedDevice;
+use crate::serial::{
+    devices::psu::{Command, Condition, ReplyCommand},
+    SerialMessage,
+};
+
+// Returned in the initialisation reply of a Smart PSU.
+const IDENTITY: [u8; 3] = [0x0A, 0x01, 0x05];
+// Battery voltage below which the PSU reports its battery low.
+const BATTERY_LOW_MV: u16 = 11_800;
+
+struct Psu {
+    initialised: bool,
+    mains: bool,
+    fuse_blown: bool,
+    tamper: bool,
+    battery_mv: u16,
+    output_mv: u16,
+}
+
+impl Default for Psu {
+    fn default() -> Self {
+        Psu {
+            initialised: false,
+            mains: true,
+            fuse_blown: false,
+            tamper: false,
+            battery_mv: 13_620,
+            output_mv: 13_710,
+        }
+    }
+}
+
+impl Psu {
+    fn status(&self) -> Vec<u8> {
+        let active = |condition| match condition {
+            Condition::MainsFail => !self.mains,
+            Condition::BatteryLow => self.battery_mv < BATTERY_LOW_MV,
+            Condition::FuseBlown => self.fuse_blown,
+            Condition::Tamper => self.tamper,
+        };
+        let flags = Condition::ALL
+            .into_iter()
+            .filter(|&condition| active(condition))
+            .fold(0, |flags, condition| flags | condition.flag());
+
+        let mut data = vec![flags];
+        data.extend((self.battery_mv / 10).to_be_bytes());
+        data.extend((self.output_mv / 10).to_be_bytes());
+        data
+    }
+}
+
+/// SimulatedPsu emulates a healthy Smart PSU on mains until told otherwise. Clones share the same
+/// PSU.
+#[derive(Clone, Default)]
+pub struct SimulatedPsu(Arc<Mutex<Psu>>);
+
+impl SimulatedPsu {
+    pub fn new() -> SimulatedPsu {
+        Default::default()
+    }
+
+    pub fn set_mains(&self, on: bool) {
+        self.0.lock().unwrap().mains = on;
+    }
+
+    /// Sets the battery voltage, the battery being reported low below 11.8V.
+    pub fn set_battery_mv(&self, mv: u16) {
+        self.0.lock().unwrap().battery_mv = mv;
+    }
+
+    pub fn set_fuse_blown(&self, blown: bool) {
+        self.0.lock().unwrap().fuse_blown = blown;
+    }
+
+    pub fn set_tamper(&self, tamper: bool) {
+        self.0.lock().unwrap().tamper = tamper;
+    }
+}
+
+impl SimulatedDevice for SimulatedPsu {
+    fn reply(&self, msg: &SerialMessage) -> Option<(u8, Option<Vec<u8>>)> {
+        let mut psu = self.0.lock().unwrap();
+
+        match Command::try_from(msg.command) {
+            Ok(Command::Initialise) => {
+                psu.initialised = true;
+
+                Some((ReplyCommand::Initialised.into(), Some(IDENTITY.to_vec())))
+            }
+            _ if !psu.initialised => None,
+            Ok(Command::Ping) => Some((ReplyCommand::Status.into(), Some(psu.status()))),
+            Err(_) => Some((ReplyCommand::BadChecksum.into(), None)),
+        }
+    }
+}
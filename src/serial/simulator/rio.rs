@@ -0,0 +1,107 @@
+use std::sync::{Arc, Mutex};
+
+use super::SimulatedDevice;
+use crate::serial::{
+    devices::rio::{Command, ReplyCommand, OUTPUT_COUNT, TAMPER_FLAG, ZONE_COUNT},
+    SerialMessage,
+};
+
+// Returned in the initialisation reply of a RIO.
+const IDENTITY: [u8; 3] = [0x01, 0x01, 0x05];
+
+// A zone closed with the nominal 1k end-of-line resistance.
+const CLOSED_READING: u8 = 10;
+
+struct Rio {
+    initialised: bool,
+    readings: [u8; ZONE_COUNT],
+    tamper: bool,
+    // The readings and tamper last reported to the panel.
+    reported: Option<([u8; ZONE_COUNT], bool)>,
+    outputs: u8,
+}
+
+impl Rio {
+    fn zone_status(&mut self) -> (u8, Option<Vec<u8>>) {
+        self.reported = Some((self.readings, self.tamper));
+
+        let mut data = self.readings.to_vec();
+        data.push(if self.tamper { TAMPER_FLAG } else { 0 });
+
+        (ReplyCommand::ZoneStatus.into(), Some(data))
+    }
+}
+
+/// SimulatedRio emulates a RIO, with all zones closed until changed. Clones share the same RIO.
+#[derive(Clone)]
+pub struct SimulatedRio(Arc<Mutex<Rio>>);
+
+impl Default for SimulatedRio {
+    fn default() -> Self {
+        SimulatedRio(Arc::new(Mutex::new(Rio {
+            initialised: false,
+            readings: [CLOSED_READING; ZONE_COUNT],
+            tamper: false,
+            reported: None,
+            outputs: 0,
+        })))
+    }
+}
+
+impl SimulatedRio {
+    pub fn new() -> SimulatedRio {
+        Default::default()
+    }
+
+    /// Sets the reading of zone `zone`, numbered from 1 to ZONE_COUNT, in units of 100 ohms.
+    pub fn set_zone(&self, zone: u8, reading: u8) {
+        assert!(
+            (1..=ZONE_COUNT as u8).contains(&zone),
+            "RIO zone out of range: {}",
+            zone
+        );
+
+        self.0.lock().unwrap().readings[zone as usize - 1] = reading;
+    }
+
+    pub fn set_tamper(&self, tamper: bool) {
+        self.0.lock().unwrap().tamper = tamper;
+    }
+
+    /// Returns the state of each output as last set by the panel.
+    pub fn outputs(&self) -> [bool; OUTPUT_COUNT] {
+        let mask = self.0.lock().unwrap().outputs;
+        std::array::from_fn(|i| mask & 1 << i != 0)
+    }
+}
+
+impl SimulatedDevice for SimulatedRio {
+    fn reply(&self, msg: &SerialMessage) -> Option<(u8, Option<Vec<u8>>)> {
+        let mut rio = self.0.lock().unwrap();
+
+        match Command::try_from(msg.command) {
+            Ok(Command::Initialise) => {
+                rio.initialised = true;
+                rio.reported = None;
+
+                return Some((ReplyCommand::Initialised.into(), Some(IDENTITY.to_vec())));
+            }
+            _ if !rio.initialised => return None,
+            Ok(Command::RequestZones) => return Some(rio.zone_status()),
+            Ok(Command::Outputs) => {
+                if let Some(&mask) = msg.additional_data.as_ref().and_then(|data| data.first()) {
+                    rio.outputs = mask;
+                }
+            }
+            Ok(Command::Ping) => {}
+            Err(_) => return Some((ReplyCommand::BadChecksum.into(), None)),
+        }
+
+        // Changes since the last report are conveyed in reply to any message.
+        if rio.reported != Some((rio.readings, rio.tamper)) {
+            Some(rio.zone_status())
+        } else {
+            Some((ReplyCommand::Ack.into(), None))
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use std::{collections::HashMap, io, time::Duration};
+use tokio::{io::AsyncRead, time::Instant};
+
+use super::{
+    capture::{Entry, Record},
+    devices::{
+        keypad::{self, display::VirtualDisplay, KEYS},
+        max, prox, psu, rio, DeviceKind,
+    },
+    galaxy::{
+        bus::{read_burst, PANEL_ADDRESS},
+        GalaxyCRC,
+    },
+};
+
+/// QUIET_PERIOD is the idle time on the line taken to separate frames. Frames can still run
+/// together, e.g. when a device replies promptly, so bursts are also split where the CRC allows.
+const QUIET_PERIOD: Duration = Duration::from_millis(2);
+
+// Display flags of a keypad screen update that are decoded.
+const SCREEN_BLINK_FLAG: u8 = 0x08;
+const SCREEN_KEY_ACK_FLAG: u8 = 0x10;
+
+fn crc_valid(frame: &[u8]) -> bool {
+    frame.len() >= 3 && frame[..frame.len() - 1].galaxy_crc() == frame[frame.len() - 1]
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits data into frames with valid CRCs: the whole, or e.g. a message and its reply.
+pub(crate) fn split_valid(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if crc_valid(data) {
+        return Some(vec![data.to_vec()]);
+    }
+
+    (3..data.len().saturating_sub(2))
+        .filter(|&i| crc_valid(&data[..i]))
+        .find_map(|i| {
+            let mut frames = split_valid(&data[i..])?;
+            frames.insert(0, data[..i].to_vec());
+            Some(frames)
+        })
+}
+
+/// Splitter divides the bursts of data seen on the line into frames.
+#[derive(Default)]
+pub struct Splitter {
+    // A burst that didn't pass the CRC, held back in case the next burst completes it.
+    pending: Vec<u8>,
+}
+
+impl Splitter {
+    pub fn new() -> Splitter {
+        Default::default()
+    }
+
+    /// Returns the frames completed by a burst. Frames that don't pass the CRC are returned as
+    /// they were seen, once it's clear that no more data will complete them.
+    pub fn push(&mut self, burst: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            if let Some(split) = split_valid(&[&pending[..], burst].concat()) {
+                return split;
+            }
+
+            frames.push(pending);
+        }
+
+        match split_valid(burst) {
+            Some(split) => frames.extend(split),
+            None => self.pending = burst.to_vec(),
+        }
+
+        frames
+    }
+
+    /// Returns any data still held back.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+/// Sniffer passively listens to the bus, never transmitting, and captures the frames seen.
+pub struct Sniffer<R> {
+    reader: R,
+    splitter: Splitter,
+    started: Instant,
+}
+
+impl<R: AsyncRead + Unpin> Sniffer<R> {
+    pub fn new(reader: R) -> Sniffer<R> {
+        Sniffer {
+            reader,
+            splitter: Splitter::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Waits for the next frames on the line, timestamped from the start of sniffing. Returns
+    /// None once the line is closed.
+    pub async fn next(&mut self) -> io::Result<Option<Vec<Entry>>> {
+        loop {
+            let burst = read_burst(&mut self.reader, QUIET_PERIOD).await?;
+            let at = self.started.elapsed();
+
+            let frames = if burst.is_empty() {
+                match self.splitter.flush() {
+                    Some(frame) => vec![frame],
+                    None => return Ok(None),
+                }
+            } else {
+                self.splitter.push(&burst)
+            };
+
+            if !frames.is_empty() {
+                return Ok(Some(
+                    frames
+                        .into_iter()
+                        .map(|data| Entry {
+                            at,
+                            record: Record::Frame { data },
+                        })
+                        .collect(),
+                ));
+            }
+        }
+    }
+}
+
+fn with_data(name: String, data: &[u8]) -> String {
+    if data.is_empty() {
+        name
+    } else {
+        format!("{} [{}]", name, to_hex(data))
+    }
+}
+
+fn unknown(command: u8, data: &[u8]) -> String {
+    with_data(format!("?? UNKNOWN COMMAND {:02X}", command), data)
+}
+
+/// Decoder describes frames in terms of the commands and replies of the devices involved.
+/// Anything not understood is flagged with `??`.
+#[derive(Default)]
+pub struct Decoder {
+    // Recipient of the last message from the panel, to which a reply is attributed.
+    last_recipient: Option<u8>,
+    // The display of each keypad, as rendered from its screen updates.
+    displays: HashMap<u8, VirtualDisplay>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Default::default()
+    }
+
+    /// Describes a frame on one or more lines.
+    pub fn describe(&mut self, frame: &[u8]) -> String {
+        if !crc_valid(frame) {
+            self.last_recipient = None;
+            return format!("?? BAD FRAME [{}]", to_hex(frame));
+        }
+
+        let (address, command, data) = (frame[0], frame[1], &frame[2..frame.len() - 1]);
+
+        if address == PANEL_ADDRESS {
+            match self.last_recipient.take() {
+                Some(device) => format!(
+                    "<- {:02X} {}",
+                    device,
+                    describe_reply(device, command, data)
+                ),
+                None => format!("<- ?? {}", with_data(format!("{:02X}", command), data)),
+            }
+        } else {
+            self.last_recipient = Some(address);
+            format!(
+                "-> {:02X} {}",
+                address,
+                self.describe_command(address, command, data)
+            )
+        }
+    }
+
+    fn describe_command(&mut self, address: u8, command: u8, data: &[u8]) -> String {
+        match DeviceKind::from_address(address) {
+            Some(DeviceKind::Keypad) => match keypad::Command::try_from(command) {
+                Ok(keypad::Command::Screen) => self.describe_screen(address, data),
+                Ok(command) => with_data(format!("keypad {:?}", command), data),
+                Err(_) => unknown(command, data),
+            },
+            Some(DeviceKind::Rio) => match rio::Command::try_from(command) {
+                Ok(command) => with_data(format!("RIO {:?}", command), data),
+                Err(_) => unknown(command, data),
+            },
+            Some(DeviceKind::Prox) => match prox::Command::try_from(command) {
+                Ok(command) => with_data(format!("prox {:?}", command), data),
+                Err(_) => unknown(command, data),
+            },
+            Some(DeviceKind::Max) => match max::Command::try_from(command) {
+                Ok(command) => with_data(format!("MAX {:?}", command), data),
+                Err(_) => unknown(command, data),
+            },
+            Some(DeviceKind::Psu) => match psu::Command::try_from(command) {
+                Ok(command) => with_data(format!("PSU {:?}", command), data),
+                Err(_) => unknown(command, data),
+            },
+            None => unknown(command, data),
+        }
+    }
+
+    fn describe_screen(&mut self, address: u8, data: &[u8]) -> String {
+        let Some((&flags, ops)) = data.split_first() else {
+            return "keypad Screen ?? MISSING FLAGS".to_string();
+        };
+
+        let display = self.displays.entry(address).or_default();
+        let unknown = display.apply(ops);
+
+        let mut description = format!("keypad Screen flags={:02X}", flags);
+        if flags & SCREEN_KEY_ACK_FLAG != 0 {
+            description.push_str(" key-ack");
+        }
+        if flags & SCREEN_BLINK_FLAG != 0 {
+            description.push_str(" blink");
+        }
+        if !unknown.is_empty() {
+            description.push_str(&format!(" ?? UNKNOWN OP CODES [{}]", to_hex(&unknown)));
+        }
+
+        for line in display.lines() {
+            description.push_str(&format!("\n    |{:<16}|", line));
+        }
+
+        description
+    }
+}
+
+fn describe_reply(device: u8, command: u8, data: &[u8]) -> String {
+    match DeviceKind::from_address(device) {
+        Some(DeviceKind::Keypad) => match keypad::ReplyCommand::try_from(command) {
+            Ok(keypad::ReplyCommand::AckWithKey) if data.len() == 1 => match data[0] {
+                0x7F => "keypad AckWithKey tamper".to_string(),
+                key => format!(
+                    "keypad AckWithKey key={}{}",
+                    KEYS.chars().nth(key as usize & 0xF).unwrap(),
+                    if key & 0x40 != 0 { " tamper" } else { "" }
+                ),
+            },
+            Ok(reply) => with_data(format!("keypad {:?}", reply), data),
+            Err(_) => unknown(command, data),
+        },
+        Some(DeviceKind::Rio) => match rio::ReplyCommand::try_from(command) {
+            Ok(rio::ReplyCommand::ZoneStatus) if data.len() == rio::ZONE_COUNT + 1 => format!(
+                "RIO ZoneStatus zones={:?}{}",
+                &data[..rio::ZONE_COUNT],
+                if data[rio::ZONE_COUNT] & rio::TAMPER_FLAG != 0 {
+                    " tamper"
+                } else {
+                    ""
+                }
+            ),
+            Ok(reply) => with_data(format!("RIO {:?}", reply), data),
+            Err(_) => unknown(command, data),
+        },
+        Some(DeviceKind::Prox) => match prox::ReplyCommand::try_from(command) {
+            Ok(prox::ReplyCommand::CardRead) if data.len() == prox::ID_LEN => format!(
+                "prox CardRead id={}",
+                u32::from_be_bytes(data.try_into().unwrap())
+            ),
+            Ok(reply) => with_data(format!("prox {:?}", reply), data),
+            Err(_) => unknown(command, data),
+        },
+        Some(DeviceKind::Max) => match max::ReplyCommand::try_from(command) {
+            Ok(max::ReplyCommand::CardRead) if data.len() == prox::ID_LEN => format!(
+                "MAX CardRead id={}",
+                u32::from_be_bytes(data.try_into().unwrap())
+            ),
+            Ok(max::ReplyCommand::Status) if data.len() == 1 => format!(
+                "MAX Status door={}{}",
+                if data[0] & max::DOOR_OPEN_FLAG != 0 {
+                    "open"
+                } else {
+                    "closed"
+                },
+                if data[0] & max::TAMPER_FLAG != 0 {
+                    " tamper"
+                } else {
+                    ""
+                }
+            ),
+            Ok(reply) => with_data(format!("MAX {:?}", reply), data),
+            Err(_) => unknown(command, data),
+        },
+        Some(DeviceKind::Psu) => match psu::ReplyCommand::try_from(command) {
+            Ok(psu::ReplyCommand::Status) if psu::Status::parse(data).is_some() => {
+                let status = psu::Status::parse(data).unwrap();
+
+                format!(
+                    "PSU Status battery={:.2}V output={:.2}V{}",
+                    status.battery_mv as f32 / 1000.0,
+                    status.output_mv as f32 / 1000.0,
+                    psu::Condition::ALL
+                        .into_iter()
+                        .filter(|&condition| status.is_active(condition))
+                        .map(|condition| format!(" {:?}", condition))
+                        .collect::<String>()
+                )
+            }
+            Ok(reply) => with_data(format!("PSU {:?}", reply), data),
+            Err(_) => unknown(command, data),
+        },
+        None => unknown(command, data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::SerialMessage;
+
+    fn frame(recipient_address: u8, command: u8, data: &[u8]) -> Vec<u8> {
+        SerialMessage {
+            recipient_address,
+            command,
+            additional_data: (!data.is_empty()).then(|| data.to_vec()),
+        }
+        .serialise()
+    }
+
+    #[test]
+    fn test_splits_frames_run_together() {
+        let message = frame(0x10, 0x06, &[]);
+        let reply = frame(PANEL_ADDRESS, 0xF4, &[0x01]);
+        let mut splitter = Splitter::new();
+
+        assert_eq!(
+            splitter.push(&[&message[..], &reply[..]].concat()),
+            [message.clone(), reply]
+        );
+
+        // A frame spread over two bursts is put back together.
+        let screen = frame(0x10, 0x07, b"\x81\x17\x01HELLO");
+        assert!(splitter.push(&screen[..4]).is_empty());
+        assert_eq!(splitter.push(&screen[4..]), [screen]);
+
+        // Garbage is given up on when the next frame arrives.
+        assert!(splitter.push(&[0x10, 0x06, 0x00]).is_empty());
+        assert_eq!(splitter.push(&message), [vec![0x10, 0x06, 0x00], message]);
+        assert_eq!(splitter.flush(), None);
+    }
+
+    #[test]
+    fn test_describes_frames() {
+        let mut decoder = Decoder::new();
+
+        assert_eq!(
+            decoder.describe(&frame(0x10, 0x00, &[0x0E])),
+            "-> 10 keypad Initialise [0E]"
+        );
+        assert_eq!(
+            decoder.describe(&frame(PANEL_ADDRESS, 0xFF, &[0x08, 0x00, 0x64])),
+            "<- 10 keypad Initialised [08 00 64]"
+        );
+        decoder.describe(&frame(0x10, 0x06, &[]));
+        assert_eq!(
+            decoder.describe(&frame(PANEL_ADDRESS, 0xF4, &[0x4B])),
+            "<- 10 keypad AckWithKey key=A tamper"
+        );
+        assert_eq!(
+            decoder.describe(&frame(0x20, 0x1F, &[0x01])),
+            "-> 20 ?? UNKNOWN COMMAND 1F [01]"
+        );
+        decoder.describe(&frame(0x70, 0x06, &[]));
+        assert_eq!(
+            decoder.describe(&frame(PANEL_ADDRESS, 0xF1, &[0x01, 0x04, 0x7E, 0x05, 0x5B])),
+            "<- 70 PSU Status battery=11.50V output=13.71V MainsFail"
+        );
+        assert_eq!(
+            decoder.describe(&[0x10, 0x06, 0x00]),
+            "?? BAD FRAME [10 06 00]"
+        );
+    }
+
+    #[test]
+    fn test_renders_screen_updates() {
+        let mut decoder = Decoder::new();
+
+        decoder.describe(&frame(0x10, 0x07, b"\x81\x17\x07\x01TIGER\x02SECURITY"));
+        assert_eq!(
+            decoder.describe(&frame(0x10, 0x07, b"\x01\x03\x42Z\x1F")),
+            concat!(
+                "-> 10 keypad Screen flags=01 ?? UNKNOWN OP CODES [1F]\n",
+                "    |TIGER           |\n",
+                "    |SEZURITY        |",
+            )
+        );
+    }
+}
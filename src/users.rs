@@ -0,0 +1,453 @@
+use chrono::NaiveDateTime;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+use crate::areas::Areas;
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const PIN_HASH_SCHEME: &str = "pbkdf2-sha256";
+// Iterations of PBKDF2 for newly hashed PINs. Every user's hash may be checked against a code
+// entered at a keypad, so the cost is bounded by the time a keypad can be kept waiting.
+pub const PIN_HASH_ROUNDS: u32 = 100_000;
+
+/// AccessLevel determines which functions a user may perform, following the Galaxy levels 0 to
+/// 6.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "u8")]
+pub struct AccessLevel(u8);
+
+#[derive(Clone, Debug, Error, PartialEq)]
+#[error("invalid access level {0}, expected 0 to 6")]
+pub struct InvalidAccessLevelError(pub u8);
+
+impl AccessLevel {
+    // May only operate doors, and has no keypad access.
+    pub const ACCESS_ONLY: AccessLevel = AccessLevel(0);
+    // May set but not unset.
+    pub const SET_ONLY: AccessLevel = AccessLevel(1);
+    pub const USER: AccessLevel = AccessLevel(2);
+    pub const MANAGER: AccessLevel = AccessLevel(3);
+    pub const ENGINEER: AccessLevel = AccessLevel(6);
+
+    pub fn can_set(&self) -> bool {
+        *self >= AccessLevel::SET_ONLY
+    }
+
+    pub fn can_unset(&self) -> bool {
+        *self >= AccessLevel::USER
+    }
+
+    pub fn can_enter_menu(&self) -> bool {
+        *self >= AccessLevel::USER
+    }
+
+    pub fn can_acknowledge_faults(&self) -> bool {
+        *self >= AccessLevel::MANAGER
+    }
+}
+
+impl TryFrom<u8> for AccessLevel {
+    type Error = InvalidAccessLevelError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0..=6 => Ok(AccessLevel(value)),
+            _ => Err(InvalidAccessLevelError(value)),
+        }
+    }
+}
+
+impl From<AccessLevel> for u8 {
+    fn from(level: AccessLevel) -> u8 {
+        level.0
+    }
+}
+
+impl fmt::Display for AccessLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// PinHash is a salted PBKDF2-HMAC-SHA256 hash of a PIN, so that codes are never held at rest in
+/// the clear and are slow to recover from a leaked configuration. Its string form is
+/// `pbkdf2-sha256$<rounds>$<salt>$<hash>`, with the salt and hash hex encoded, so the cost of
+/// existing hashes is known when it's raised for new ones.
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct PinHash {
+    rounds: u32,
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+}
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum PinHashError {
+    #[error("expected PIN hash of the form pbkdf2-sha256$<rounds>$<salt>$<hash>")]
+    Malformed,
+    #[error("unsupported PIN hash scheme {0:?}")]
+    UnsupportedScheme(String),
+}
+
+impl PinHash {
+    /// Hashes the PIN with a freshly generated random salt, at the default cost.
+    pub fn new(pin: &str) -> PinHash {
+        Self::with_rounds(pin, PIN_HASH_ROUNDS)
+    }
+
+    /// Hashes the PIN with a freshly generated random salt, using `rounds` iterations of PBKDF2.
+    pub fn with_rounds(pin: &str, rounds: u32) -> PinHash {
+        assert!(rounds > 0, "PIN hash needs at least one round");
+
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        PinHash {
+            rounds,
+            salt,
+            hash: Self::digest(rounds, &salt, pin),
+        }
+    }
+
+    fn digest(rounds: u32, salt: &[u8; SALT_LEN], pin: &str) -> [u8; HASH_LEN] {
+        let mut hash = [0u8; HASH_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(pin.as_bytes(), salt, rounds, &mut hash);
+
+        hash
+    }
+
+    /// Returns whether the PIN matches the hash. The comparison takes the same time regardless of
+    /// where the digests differ.
+    pub fn verify(&self, pin: &str) -> bool {
+        Self::digest(self.rounds, &self.salt, pin)
+            .iter()
+            .zip(self.hash.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Debug for PinHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PinHash(..)")
+    }
+}
+
+impl fmt::Display for PinHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}${}$", PIN_HASH_SCHEME, self.rounds)?;
+        for b in self.salt {
+            write!(f, "{:02x}", b)?;
+        }
+        f.write_str("$")?;
+        for b in self.hash {
+            write!(f, "{:02x}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        return None;
+    }
+
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(out)
+}
+
+impl FromStr for PinHash {
+    type Err = PinHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('$');
+
+        let scheme = parts.next().unwrap_or_default();
+        if scheme != PIN_HASH_SCHEME {
+            return Err(PinHashError::UnsupportedScheme(scheme.to_string()));
+        }
+
+        let (Some(rounds), Some(salt), Some(hash), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(PinHashError::Malformed);
+        };
+
+        Ok(PinHash {
+            rounds: rounds
+                .parse()
+                .ok()
+                .filter(|&rounds| rounds > 0)
+                .ok_or(PinHashError::Malformed)?,
+            salt: decode_hex(salt).ok_or(PinHashError::Malformed)?,
+            hash: decode_hex(hash).ok_or(PinHashError::Malformed)?,
+        })
+    }
+}
+
+impl TryFrom<String> for PinHash {
+    type Error = PinHashError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Validity is a window of local time during which a user's code is accepted. Either end may be
+/// left open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Validity {
+    pub from: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+impl Validity {
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        self.from.is_none_or(|from| at >= from) && self.until.is_none_or(|until| at < until)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub number: u16,
+    pub name: String,
+    pub pin: PinHash,
+    // ID of a card or fob presented at a prox reader, which stands in for the PIN.
+    pub fob: Option<u32>,
+    // Whether the fob must be presented together with the PIN, rather than either alone.
+    pub fob_with_pin: bool,
+    pub level: AccessLevel,
+    // Areas the user may set and unset.
+    pub areas: Areas,
+    pub validity: Validity,
+}
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum UserStoreError {
+    #[error("user number {0} is defined more than once")]
+    DuplicateUser(u16),
+    #[error("fob {fob} is given to both user {first} and user {second}")]
+    DuplicateFob { fob: u32, first: u16, second: u16 },
+}
+
+/// UserStore holds the users permitted to operate the system.
+#[derive(Clone, Debug, Default)]
+pub struct UserStore {
+    users: Vec<User>,
+}
+
+impl UserStore {
+    pub fn new(mut users: Vec<User>) -> Result<UserStore, UserStoreError> {
+        users.sort_by_key(|user| user.number);
+
+        if let Some(pair) = users
+            .windows(2)
+            .find(|pair| pair[0].number == pair[1].number)
+        {
+            return Err(UserStoreError::DuplicateUser(pair[0].number));
+        }
+
+        for (i, user) in users.iter().enumerate() {
+            let Some(fob) = user.fob else {
+                continue;
+            };
+            if let Some(other) = users[i + 1..].iter().find(|other| other.fob == Some(fob)) {
+                return Err(UserStoreError::DuplicateFob {
+                    fob,
+                    first: user.number,
+                    second: other.number,
+                });
+            }
+        }
+
+        Ok(UserStore { users })
+    }
+
+    pub fn get(&self, number: u16) -> Option<&User> {
+        self.users.iter().find(|user| user.number == number)
+    }
+
+    pub fn users(&self) -> &[User] {
+        &self.users
+    }
+
+    /// Returns the user whose PIN matches, provided their code is valid at the given time and
+    /// they needn't also present their fob. Users sharing a PIN are each considered in turn, so an
+    /// expired code doesn't shadow another that's valid.
+    pub fn authenticate(&self, pin: &str, at: NaiveDateTime) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| !user.fob_with_pin && user.validity.contains(at) && user.pin.verify(pin))
+    }
+
+    /// Returns the user holding the fob, provided their code is valid at the given time. A PIN
+    /// given with the fob must be the user's own, and is required if the user must present both.
+    pub fn authenticate_fob(
+        &self,
+        fob: u32,
+        pin: Option<&str>,
+        at: NaiveDateTime,
+    ) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| user.fob == Some(fob))
+            .filter(|user| match pin {
+                Some(pin) => user.pin.verify(pin),
+                None => !user.fob_with_pin,
+            })
+            .filter(|user| user.validity.contains(at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn user(number: u16, pin: &str) -> User {
+        User {
+            number,
+            name: format!("USER {}", number),
+            // Cheap hashes keep the tests quick, test_pin_hash_round_trip covers the default cost.
+            pin: PinHash::with_rounds(pin, 1_000),
+            fob: None,
+            fob_with_pin: false,
+            level: AccessLevel::USER,
+            areas: Areas::ALL,
+            validity: Validity::default(),
+        }
+    }
+
+    #[test]
+    fn test_pin_hash_round_trip() {
+        let hash = PinHash::new("1234");
+        assert!(hash.verify("1234"));
+        assert!(!hash.verify("1235"));
+        assert_ne!(hash, PinHash::new("1234"), "salts should differ");
+
+        let parsed: PinHash = hash.to_string().parse().unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("1234"));
+
+        assert!(hash
+            .to_string()
+            .starts_with(&format!("pbkdf2-sha256${}$", PIN_HASH_ROUNDS)));
+
+        assert_eq!(
+            "pbkdf2-sha256$00".parse::<PinHash>(),
+            Err(PinHashError::Malformed)
+        );
+        let zero = format!("pbkdf2-sha256$0${}${}", "00".repeat(16), "00".repeat(32));
+        assert_eq!(zero.parse::<PinHash>(), Err(PinHashError::Malformed));
+        assert_eq!(
+            "sha256$00$00".parse::<PinHash>(),
+            Err(PinHashError::UnsupportedScheme("sha256".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_authenticate_respects_validity() {
+        let at = |day| {
+            NaiveDate::from_ymd_opt(2023, 9, day)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+        };
+
+        let mut temporary = user(2, "5678");
+        temporary.validity = Validity {
+            from: Some(at(10)),
+            until: Some(at(20)),
+        };
+        let store = UserStore::new(vec![user(1, "1234"), temporary]).unwrap();
+
+        assert_eq!(store.authenticate("1234", at(1)).map(|u| u.number), Some(1));
+        assert_eq!(
+            store.authenticate("5678", at(15)).map(|u| u.number),
+            Some(2)
+        );
+        assert!(store.authenticate("5678", at(9)).is_none());
+        assert!(store.authenticate("5678", at(20)).is_none());
+        assert!(store.authenticate("0000", at(15)).is_none());
+
+        // An expired code listed first doesn't lock out another user with the same PIN.
+        let mut expired = user(1, "1234");
+        expired.validity.until = Some(at(10));
+        let store = UserStore::new(vec![expired, user(2, "1234")]).unwrap();
+        assert_eq!(
+            store.authenticate("1234", at(15)).map(|u| u.number),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_authenticate_fob() {
+        let at = NaiveDate::from_ymd_opt(2023, 9, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+
+        let mut either = user(1, "1234");
+        either.fob = Some(1001);
+        let mut both = user(2, "5678");
+        both.fob = Some(1002);
+        both.fob_with_pin = true;
+        let store = UserStore::new(vec![either, both, user(3, "9999")]).unwrap();
+
+        let number = |user: Option<&User>| user.map(|u| u.number);
+        assert_eq!(number(store.authenticate_fob(1001, None, at)), Some(1));
+        assert_eq!(
+            number(store.authenticate_fob(1001, Some("1234"), at)),
+            Some(1)
+        );
+        assert_eq!(number(store.authenticate("1234", at)), Some(1));
+        // Another user's PIN doesn't go with the fob.
+        assert!(store.authenticate_fob(1001, Some("9999"), at).is_none());
+
+        assert_eq!(
+            number(store.authenticate_fob(1002, Some("5678"), at)),
+            Some(2)
+        );
+        assert!(store.authenticate_fob(1002, None, at).is_none());
+        assert!(store.authenticate("5678", at).is_none());
+
+        assert!(store.authenticate_fob(1003, None, at).is_none());
+
+        let mut copy = user(4, "4444");
+        copy.fob = Some(1001);
+        let mut users = store.users().to_vec();
+        users.push(copy);
+        assert_eq!(
+            UserStore::new(users).unwrap_err(),
+            UserStoreError::DuplicateFob {
+                fob: 1001,
+                first: 1,
+                second: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_access_levels() {
+        assert_eq!(AccessLevel::try_from(7), Err(InvalidAccessLevelError(7)));
+        assert!(AccessLevel::SET_ONLY.can_set());
+        assert!(!AccessLevel::SET_ONLY.can_unset());
+        assert!(!AccessLevel::ACCESS_ONLY.can_set());
+        assert!(AccessLevel::ENGINEER.can_enter_menu());
+
+        assert_eq!(
+            UserStore::new(vec![user(1, "1234"), user(1, "5678")]).unwrap_err(),
+            UserStoreError::DuplicateUser(1)
+        );
+    }
+}
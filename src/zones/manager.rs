@@ -0,0 +1,306 @@
+use log::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::{broadcast, mpsc};
+
+use super::{Event, EventType, Reading, Zone, ZoneInput, ZoneState};
+use crate::serial::devices::rio::{self, SerialRio};
+
+/// ZoneStates is a shared, read-only view of the latest state of every zone known to a
+/// ZoneManager.
+#[derive(Clone, Default)]
+pub struct ZoneStates(Arc<RwLock<HashMap<u16, ZoneState>>>);
+
+impl ZoneStates {
+    pub fn get(&self, zone: u16) -> Option<ZoneState> {
+        self.0.read().unwrap().get(&zone).cloned()
+    }
+
+    /// Returns the numbers of all zones not currently in the Closed state, in ascending order.
+    pub fn not_closed(&self) -> Vec<u16> {
+        let mut zones: Vec<u16> = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, &state)| state != ZoneState::Closed)
+            .map(|(&zone, _)| zone)
+            .collect();
+        zones.sort();
+
+        zones
+    }
+}
+
+/// ZoneManager receives raw input readings from bus devices, classifies them against the zone
+/// definitions, and publishes an event whenever a zone changes state.
+pub struct ZoneManager {
+    zones: HashMap<ZoneInput, Zone>,
+    states: ZoneStates,
+
+    input_tx: mpsc::UnboundedSender<(ZoneInput, Reading)>,
+    input_rx: mpsc::UnboundedReceiver<(ZoneInput, Reading)>,
+    event_ch: broadcast::Sender<Event>,
+}
+
+impl ZoneManager {
+    pub fn new(zones: Vec<Zone>) -> ZoneManager {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+
+        ZoneManager {
+            zones: zones.into_iter().map(|zone| (zone.input, zone)).collect(),
+            states: ZoneStates::default(),
+            input_tx,
+            input_rx,
+            event_ch: broadcast::Sender::new(32),
+        }
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_ch.subscribe()
+    }
+
+    pub fn states(&self) -> ZoneStates {
+        self.states.clone()
+    }
+
+    pub fn zones(&self) -> impl Iterator<Item = &Zone> {
+        self.zones.values()
+    }
+
+    /// Returns a sender through which readings can be fed for inputs not provided by a RIO, e.g.
+    /// keypad-fed inputs.
+    pub fn input_sender(&self) -> mpsc::UnboundedSender<(ZoneInput, Reading)> {
+        self.input_tx.clone()
+    }
+
+    /// Forwards zone readings from the RIO at the given bus address into this manager.
+    pub fn attach_rio(&self, address: u8, rio: Arc<SerialRio>) {
+        let mut rio_events = rio.subscribe_events();
+        let input_tx = self.input_tx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match rio_events.recv().await {
+                    Ok(rio::Event(rio::EventType::ZoneChanged { zone, reading })) => {
+                        let input = ZoneInput {
+                            device: address,
+                            input: zone,
+                        };
+
+                        if input_tx.send((input, Reading::from_rio(reading))).is_err() {
+                            break;
+                        }
+                    }
+                    // The enclosure tamper is raised by the alarm core, see
+                    // AlarmManager::attach_rio.
+                    Ok(rio::Event(rio::EventType::Tamper(_))) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("ZoneManager lagged {} events from RIO {:02X}", n, address);
+
+                        // The changes lost can't be told apart, so every zone's latest reading is
+                        // fed in again. Those that didn't change are ignored by the manager.
+                        let readings = rio.zone_readings().unwrap_or_default();
+                        for (i, &reading) in readings.iter().enumerate() {
+                            let input = ZoneInput {
+                                device: address,
+                                input: i as u8 + 1,
+                            };
+                            if input_tx.send((input, Reading::from_rio(reading))).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub async fn run(&mut self) {
+        while let Some((input, reading)) = self.input_rx.recv().await {
+            self.process_reading(input, reading);
+        }
+    }
+
+    fn process_reading(&mut self, input: ZoneInput, reading: Reading) -> Option<Event> {
+        let Some(zone) = self.zones.get(&input) else {
+            debug!(
+                "Ignoring reading {:?} for unassigned input {}",
+                reading, input
+            );
+            return None;
+        };
+
+        let state = zone.classify(reading);
+        let previous = self.states.0.write().unwrap().insert(zone.number, state);
+
+        if previous == Some(state) {
+            return None;
+        }
+
+        info!(
+            "Zone {} ({}) changed state from {:?} to {}",
+            zone.number, zone.name, previous, state
+        );
+
+        let event = Event(EventType::StateChanged {
+            zone: zone.number,
+            zone_type: zone.zone_type,
+            state,
+            previous,
+        });
+        let _ = self.event_ch.send(event.clone());
+
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        areas::{Area, Areas},
+        zones::{EolScheme, ZoneType},
+    };
+
+    const INPUT: ZoneInput = ZoneInput {
+        device: 0x21,
+        input: 1,
+    };
+
+    fn manager() -> ZoneManager {
+        ZoneManager::new(vec![Zone {
+            number: 1011,
+            name: "FRONT DOOR".to_string(),
+            zone_type: ZoneType::Final,
+            scheme: EolScheme::OneKOneK,
+            input: INPUT,
+            part_set: true,
+            chime: false,
+            areas: Areas::single(Area::A),
+        }])
+    }
+
+    #[test]
+    fn test_state_change_published_once() {
+        let mut manager = manager();
+        let mut events = manager.subscribe_events();
+
+        assert!(manager
+            .process_reading(INPUT, Reading::Resistance(1_000))
+            .is_some());
+        assert!(manager
+            .process_reading(INPUT, Reading::Resistance(1_100))
+            .is_none());
+        assert!(manager
+            .process_reading(INPUT, Reading::Resistance(2_000))
+            .is_some());
+
+        assert!(matches!(
+            events.try_recv().unwrap().0,
+            EventType::StateChanged {
+                zone: 1011,
+                state: ZoneState::Closed,
+                previous: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap().0,
+            EventType::StateChanged {
+                zone: 1011,
+                zone_type: ZoneType::Final,
+                state: ZoneState::Open,
+                previous: Some(ZoneState::Closed),
+            }
+        ));
+        assert_eq!(manager.states().not_closed(), vec![1011]);
+    }
+
+    #[test]
+    fn test_unassigned_input_ignored() {
+        let mut manager = manager();
+
+        let input = ZoneInput {
+            device: 0x21,
+            input: 2,
+        };
+        assert!(manager
+            .process_reading(input, Reading::Contact { closed: false })
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_attach_rio() {
+        use crate::serial::{SerialDevice, SerialMessage};
+
+        let mut manager = manager();
+        let mut events = manager.subscribe_events();
+
+        let rio = Arc::new(SerialRio::new());
+        manager.attach_rio(0x21, rio.clone());
+        tokio::spawn(async move { manager.run().await });
+
+        for (command, data) in [(0xFF, vec![0x01, 0x01, 0x05]), (0xF1, vec![20; 9])] {
+            rio.receive_update(Ok(SerialMessage {
+                recipient_address: 0x11,
+                command,
+                additional_data: Some(data),
+            }));
+        }
+
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                zone: 1011,
+                state: ZoneState::Open,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_attach_rio_recovers_lagged_readings() {
+        use crate::serial::{SerialDevice, SerialMessage};
+
+        let mut manager = manager();
+        let mut events = manager.subscribe_events();
+
+        let rio = Arc::new(SerialRio::new());
+        manager.attach_rio(0x21, rio.clone());
+
+        let status = |reading: u8| {
+            rio.receive_update(Ok(SerialMessage {
+                recipient_address: 0x11,
+                command: 0xF1,
+                additional_data: Some(vec![20, reading, 10, 10, 10, 10, 10, 10, 0]),
+            }));
+        };
+        rio.receive_update(Ok(SerialMessage {
+            recipient_address: 0x11,
+            command: 0xFF,
+            additional_data: Some(vec![0x01, 0x01, 0x05]),
+        }));
+
+        // The opening of zone 1 is pushed out of the RIO's channel by changes to zone 2, before
+        // the forwarding task gets to run.
+        status(10);
+        for i in 0..40 {
+            status(if i % 2 == 0 { 20 } else { 10 });
+        }
+
+        tokio::spawn(async move { manager.run().await });
+
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            EventType::StateChanged {
+                zone: 1011,
+                state: ZoneState::Open,
+                ..
+            }
+        ));
+    }
+}
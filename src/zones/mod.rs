@@ -0,0 +1,243 @@
+use derive_more::Display;
+use serde::Deserialize;
+
+use crate::areas::Areas;
+
+pub mod manager;
+
+/// MASK_RESISTANCE is added in series with the alarm resistor by anti-mask detectors to signal a
+/// masked condition.
+const MASK_RESISTANCE: u32 = 10_000;
+
+/// ZoneState is the state of a zone after classification of its input reading.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum ZoneState {
+    Closed,
+    Open,
+    Tamper,
+    Fault,
+    Masked,
+}
+
+/// EolScheme is the end-of-line resistor scheme wired to a zone, expressed as the closed
+/// resistance and the alarm resistance placed in series when the detector opens.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, PartialEq, Eq)]
+pub enum EolScheme {
+    #[default]
+    #[display(fmt = "1k/1k")]
+    #[serde(rename = "1k/1k")]
+    OneKOneK,
+    #[display(fmt = "2k2/2k2")]
+    #[serde(rename = "2k2/2k2")]
+    TwoK2TwoK2,
+    #[display(fmt = "4k7/2k2")]
+    #[serde(rename = "4k7/2k2")]
+    FourK7TwoK2,
+}
+
+impl EolScheme {
+    /// Returns the nominal (closed, open) loop resistances in ohms.
+    fn nominal(&self) -> (u32, u32) {
+        match self {
+            EolScheme::OneKOneK => (1_000, 2_000),
+            EolScheme::TwoK2TwoK2 => (2_200, 4_400),
+            EolScheme::FourK7TwoK2 => (4_700, 6_900),
+        }
+    }
+
+    /// Classifies a loop resistance in ohms. Closed and open are accepted within 20% of their
+    /// nominal value and masked within 25%. Readings below half of the closed resistance are a
+    /// short circuit tamper, and readings above the masked band an open circuit tamper. Anything
+    /// in between the bands is neither one thing nor the other, and is reported as a fault.
+    pub fn classify(&self, ohms: u32) -> ZoneState {
+        let (closed, open) = self.nominal();
+        let masked = open + MASK_RESISTANCE;
+
+        let within = |nominal: u32, percent: u32| {
+            let tolerance = nominal * percent / 100;
+            (nominal - tolerance..=nominal + tolerance).contains(&ohms)
+        };
+
+        if ohms < closed / 2 {
+            ZoneState::Tamper
+        } else if within(closed, 20) {
+            ZoneState::Closed
+        } else if within(open, 20) {
+            ZoneState::Open
+        } else if within(masked, 25) {
+            ZoneState::Masked
+        } else if ohms > masked + masked / 4 {
+            ZoneState::Tamper
+        } else {
+            ZoneState::Fault
+        }
+    }
+}
+
+/// ZoneType determines how the alarm system responds to activity on a zone.
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZoneType {
+    /// Terminates the exit procedure when closed after being opened, and starts the entry
+    /// procedure when opened while set.
+    #[display(fmt = "FINAL")]
+    Final,
+    /// Part of the exit route; ignored during exit and entry.
+    #[display(fmt = "EXIT")]
+    Exit,
+    /// Part of the entry route; ignored during exit, and starts the entry procedure when opened
+    /// while set.
+    #[display(fmt = "ENTRY")]
+    Entry,
+    /// Generates an alarm when opened while set.
+    #[display(fmt = "INTRUDER")]
+    Intruder,
+    /// Generates an alarm when opened, regardless of whether the system is set.
+    #[display(fmt = "24 HOURS")]
+    TwentyFourHour,
+    #[display(fmt = "FIRE")]
+    Fire,
+    /// Personal attack; generates an alarm when opened, regardless of whether the system is set.
+    #[display(fmt = "PA")]
+    Pa,
+    #[display(fmt = "TAMPER")]
+    Tamper,
+    /// Sets and unsets the system when toggled.
+    #[display(fmt = "KEYSWITCH")]
+    Keyswitch,
+    /// Activity is recorded but never generates an alarm.
+    #[display(fmt = "LOG")]
+    Log,
+}
+
+impl ZoneType {
+    /// Returns whether a user may omit the zone from the next set. Zones which generate an alarm
+    /// regardless of whether the system is set can't be omitted.
+    pub fn can_omit(&self) -> bool {
+        matches!(
+            self,
+            ZoneType::Final | ZoneType::Exit | ZoneType::Entry | ZoneType::Intruder
+        )
+    }
+}
+
+/// ZoneInput identifies the physical input to which a zone is wired.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+#[display(fmt = "{:02X}:{}", device, input)]
+pub struct ZoneInput {
+    // Bus address of the device providing the input.
+    pub device: u8,
+    // Input number on the device, from 1.
+    pub input: u8,
+}
+
+impl ZoneInput {
+    /// Returns the zone number a Galaxy panel would assign to this input on a RIO, e.g. 1011 for
+    /// input 1 of the RIO at address 0x21. The first digit is the bus line, followed by the RIO
+    /// number and the input number.
+    pub fn galaxy_zone_number(&self) -> u16 {
+        1000 + ((self.device & 0x0F) as u16) * 10 + self.input as u16
+    }
+}
+
+/// Reading is a raw input reading from a device, prior to classification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reading {
+    /// Loop resistance in ohms, classified according to the zone's EOL scheme.
+    Resistance(u32),
+    /// Plain volt-free contact without end-of-line resistors, as fed from keypad inputs.
+    Contact { closed: bool },
+}
+
+impl Reading {
+    /// Converts a RIO zone reading, being the loop resistance in units of 100 ohms saturating at
+    /// 0xFF for an open circuit.
+    pub fn from_rio(reading: u8) -> Reading {
+        Reading::Resistance(match reading {
+            0xFF => u32::MAX,
+            n => n as u32 * 100,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub number: u16,
+    pub name: String,
+    pub zone_type: ZoneType,
+    pub scheme: EolScheme,
+    pub input: ZoneInput,
+    // Whether the zone is armed when the system is part set.
+    pub part_set: bool,
+    // Whether the zone sounds the chime at keypads when opened while its areas are unset.
+    pub chime: bool,
+    pub areas: Areas,
+}
+
+impl Zone {
+    pub fn classify(&self, reading: Reading) -> ZoneState {
+        match reading {
+            Reading::Resistance(ohms) => self.scheme.classify(ohms),
+            Reading::Contact { closed: true } => ZoneState::Closed,
+            Reading::Contact { closed: false } => ZoneState::Open,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EventType {
+    StateChanged {
+        zone: u16,
+        zone_type: ZoneType,
+        state: ZoneState,
+        // None when the zone reports for the first time.
+        previous: Option<ZoneState>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event(pub EventType);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_1k_1k() {
+        let scheme = EolScheme::OneKOneK;
+
+        assert_eq!(scheme.classify(0), ZoneState::Tamper);
+        assert_eq!(scheme.classify(600), ZoneState::Fault);
+        assert_eq!(scheme.classify(1_000), ZoneState::Closed);
+        assert_eq!(scheme.classify(2_000), ZoneState::Open);
+        assert_eq!(scheme.classify(5_000), ZoneState::Fault);
+        assert_eq!(scheme.classify(12_000), ZoneState::Masked);
+        assert_eq!(scheme.classify(u32::MAX), ZoneState::Tamper);
+    }
+
+    #[test]
+    fn test_classify_schemes() {
+        assert_eq!(EolScheme::TwoK2TwoK2.classify(2_200), ZoneState::Closed);
+        assert_eq!(EolScheme::TwoK2TwoK2.classify(4_400), ZoneState::Open);
+        assert_eq!(EolScheme::FourK7TwoK2.classify(4_700), ZoneState::Closed);
+        assert_eq!(EolScheme::FourK7TwoK2.classify(6_900), ZoneState::Open);
+        // A 1k/1k closed loop is a short on a 4k7 zone.
+        assert_eq!(EolScheme::FourK7TwoK2.classify(1_000), ZoneState::Tamper);
+    }
+
+    #[test]
+    fn test_reading_from_rio() {
+        assert_eq!(Reading::from_rio(10), Reading::Resistance(1_000));
+        assert_eq!(Reading::from_rio(0xFF), Reading::Resistance(u32::MAX));
+    }
+
+    #[test]
+    fn test_galaxy_zone_number() {
+        let input = ZoneInput {
+            device: 0x21,
+            input: 3,
+        };
+
+        assert_eq!(input.galaxy_zone_number(), 1013);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{sync::Arc, time::Duration};
+
+use galaxy::serial::{
+    capture::{self, BusRecorder, CaptureWriter, Record},
+    devices::keypad::{self, SerialKeypad},
+    galaxy::Bus,
+    manager::SerialManager,
+    replay::Replayer,
+    simulator::{SimulatedKeypad, Simulator},
+};
+use tokio::{io, time};
+
+const KEYPAD: u8 = 0x10;
+
+fn keypad() -> Arc<SerialKeypad> {
+    let keypad = Arc::new(SerialKeypad::new());
+    keypad.mutate_state(|state| {
+        state.screen.lines = ["TIGER SECURITY".to_string(), "MON 4 SEP 12:00".to_string()]
+    });
+    keypad
+}
+
+fn initialisations(entries: &[capture::Entry]) -> usize {
+    entries
+        .iter()
+        .filter(|entry| match &entry.record {
+            Record::Outbound { data } => {
+                data[..2] == [KEYPAD, u8::from(keypad::Command::Initialise)]
+            }
+            _ => false,
+        })
+        .count()
+}
+
+#[tokio::test]
+async fn test_replays_keypad_reinitialising() {
+    time::pause();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bus.capture");
+
+    // A keypad on a noisy line, which every so often fails to make sense of a message.
+    let (panel, devices) = io::duplex(256);
+    let bus = BusRecorder::new(Bus::new(panel), CaptureWriter::create(&path).unwrap());
+    let mut manager = SerialManager::new(bus);
+    manager.register_device(KEYPAD, keypad()).unwrap();
+
+    let sim_keypad = SimulatedKeypad::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(KEYPAD, Box::new(sim_keypad.clone()));
+
+    tokio::spawn(async move { simulator.run().await });
+    let manager = tokio::spawn(async move { manager.run().await });
+
+    for _ in 0..3 {
+        time::sleep(Duration::from_secs(1)).await;
+        sim_keypad.reject_next();
+    }
+    time::sleep(Duration::from_secs(1)).await;
+    manager.abort();
+
+    let entries = capture::read(&path).unwrap();
+    assert_eq!(sim_keypad.initialisations(), 4);
+    assert_eq!(initialisations(&entries), 4);
+
+    // Replayed offline, the driver makes exactly the same requests of the keypad.
+    let mut replayer = Replayer::new();
+    replayer.register_device(KEYPAD, keypad());
+    assert_eq!(replayer.replay(&entries), []);
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use galaxy::{
+    alarm::{self, Cause, FaultKind, SystemState},
+    areas::Area,
+    reporting::{
+        contact_id::{self, Account},
+        dc09::{self, Key},
+        ip::{Dc09Transport, Protocol},
+        manager::ReportingManager,
+        simulator::Dc09Receiver,
+        Delivery, Event, EventType,
+    },
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::broadcast,
+    time,
+};
+
+const KEY: &str = "000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F";
+
+struct Rig {
+    receiver: Dc09Receiver,
+    alarm_tx: broadcast::Sender<alarm::Event>,
+    events: broadcast::Receiver<Event>,
+}
+
+/// Runs a reporting manager against a receiver on localhost.
+async fn rig(
+    protocol: Protocol,
+    receiver: Dc09Receiver,
+    key: Option<&str>,
+    heartbeat: Option<Duration>,
+) -> Rig {
+    let address = match protocol {
+        Protocol::Tcp => {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(receiver.clone().serve_tcp(listener));
+            address
+        }
+        Protocol::Udp => {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = socket.local_addr().unwrap();
+            tokio::spawn(receiver.clone().serve_udp(socket));
+            address
+        }
+    };
+
+    let account: Account = "1234".parse().unwrap();
+    let mut transport = Dc09Transport::new(protocol, address, account)
+        .with_prefixes(Some("1".to_string()), "2".to_string());
+    if let Some(key) = key {
+        transport = transport.with_key(key.parse().unwrap());
+    }
+
+    let (alarm_tx, alarm_rx) = broadcast::channel(10);
+    let mut manager =
+        ReportingManager::new(account, transport, &[], alarm_rx).with_delivery(Delivery {
+            attempts: 3,
+            retry_delay: Duration::from_millis(10),
+            ack_timeout: Duration::from_secs(1),
+        });
+    if let Some(interval) = heartbeat {
+        manager = manager.with_heartbeat(interval);
+    }
+    let events = manager.subscribe_events();
+    tokio::spawn(async move { manager.run().await });
+
+    Rig {
+        receiver,
+        alarm_tx,
+        events,
+    }
+}
+
+fn alarm(zone: u16) -> alarm::Event {
+    alarm::Event(alarm::EventType::StateChanged {
+        area: Area::A,
+        state: SystemState::Alarm,
+        previous: SystemState::Set,
+        cause: Cause::Zone(zone),
+    })
+}
+
+async fn next_event(events: &mut broadcast::Receiver<Event>) -> EventType {
+    time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("no reporting event")
+        .unwrap()
+        .0
+}
+
+#[tokio::test]
+async fn test_encrypted_report_over_tcp() {
+    let receiver = Dc09Receiver::new(Some(KEY.parse::<Key>().unwrap()));
+    let mut rig = rig(Protocol::Tcp, receiver, Some(KEY), None).await;
+
+    rig.alarm_tx.send(alarm(1003)).unwrap();
+    rig.alarm_tx
+        .send(alarm::Event(alarm::EventType::FaultRaised {
+            address: 0x21,
+            kind: FaultKind::Comms,
+        }))
+        .unwrap();
+
+    for _ in 0..2 {
+        assert!(matches!(
+            next_event(&mut rig.events).await,
+            EventType::Delivered { .. }
+        ));
+    }
+
+    let received = rig.receiver.received();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].receiver.as_deref(), Some("1"));
+    assert_eq!(received[0].line, "2");
+    assert_eq!(received[0].sequence, 1);
+    assert!(received[0].timestamp.is_some());
+
+    let message = received[0].to_contact_id().unwrap();
+    assert_eq!(message.code, contact_id::BURGLARY);
+    assert_eq!((message.group, message.number), (1, 3));
+
+    let message = received[1].to_contact_id().unwrap();
+    assert_eq!(received[1].sequence, 2);
+    assert_eq!(message.code, contact_id::MODULE_FAILURE);
+    assert_eq!(message.number, 0x21);
+}
+
+#[tokio::test]
+async fn test_nak_corrects_clock_over_udp() {
+    // The receiver's clock is well ahead, so it refuses the first encrypted message, giving its
+    // time for the retry.
+    let receiver =
+        Dc09Receiver::new(Some(KEY.parse().unwrap())).with_clock_offset(chrono::Duration::hours(1));
+    let mut rig = rig(Protocol::Udp, receiver, Some(KEY), None).await;
+
+    rig.alarm_tx.send(alarm(1001)).unwrap();
+    assert!(matches!(
+        next_event(&mut rig.events).await,
+        EventType::Delivered { .. }
+    ));
+
+    // The retry keeps its sequence number.
+    let received = rig.receiver.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].sequence, 1);
+}
+
+#[tokio::test]
+async fn test_unsupported_report_not_retried() {
+    let receiver = Dc09Receiver::new(None);
+    receiver.duh_next(1);
+    let mut rig = rig(Protocol::Tcp, receiver, None, None).await;
+
+    rig.alarm_tx.send(alarm(1001)).unwrap();
+    assert!(matches!(
+        next_event(&mut rig.events).await,
+        EventType::Failed { attempts: 1, .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_heartbeat_supervises_link() {
+    let receiver = Dc09Receiver::new(None);
+    // Refuse the first heartbeat, which is never retried.
+    receiver.nak_next(1);
+    let mut rig = rig(
+        Protocol::Tcp,
+        receiver,
+        None,
+        Some(Duration::from_millis(50)),
+    )
+    .await;
+
+    assert_eq!(next_event(&mut rig.events).await, EventType::LinkFailed);
+    assert_eq!(next_event(&mut rig.events).await, EventType::LinkRestored);
+
+    let received = rig.receiver.received();
+    assert_eq!(received[0].message_type, dc09::HEARTBEAT);
+    assert_eq!(received[0].account.as_deref(), Some("1234"));
+}
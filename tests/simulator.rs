@@ -0,0 +1,459 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use galaxy::serial::{
+    devices::{
+        keypad::{self, SerialKeypad},
+        max::SerialMax,
+        prox::{self, SerialProx},
+        psu::{self, Condition, SerialPsu},
+        rio::{self, SerialRio},
+        DeviceKind,
+    },
+    galaxy::Bus,
+    manager::{self, DeviceStatus, Latency, RegistrationError, SerialManager},
+    simulator::{
+        SimulatedKeypad, SimulatedMax, SimulatedProx, SimulatedPsu, SimulatedRio, Simulator,
+    },
+};
+use galaxy::{
+    access::{self, manager::AccessManager, Door, Permission},
+    areas::Areas,
+    serial::SerialMessage,
+    users::{AccessLevel, PinHash, User, UserStore, Validity},
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    time,
+};
+
+const KEYPAD: u8 = 0x10;
+const RIO: u8 = 0x20;
+
+struct Rig {
+    keypad: Arc<SerialKeypad>,
+    rio: Arc<SerialRio>,
+    sim_keypad: SimulatedKeypad,
+    sim_rio: SimulatedRio,
+    latency: watch::Receiver<BTreeMap<u8, Latency>>,
+}
+
+/// Runs a keypad and RIO on a bus to the simulator.
+fn rig() -> Rig {
+    rig_polling_every(manager::POLL_INTERVAL)
+}
+
+fn rig_polling_every(poll_interval: Duration) -> Rig {
+    let (panel, devices) = io::duplex(256);
+
+    let keypad = Arc::new(SerialKeypad::new());
+    let rio = Arc::new(SerialRio::new());
+    let mut manager = SerialManager::new(Bus::new(panel)).with_poll_interval(poll_interval);
+    manager.register_device(KEYPAD, keypad.clone()).unwrap();
+    manager.register_device(RIO, rio.clone()).unwrap();
+    let latency = manager.subscribe_latency();
+
+    let sim_keypad = SimulatedKeypad::new();
+    let sim_rio = SimulatedRio::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(KEYPAD, Box::new(sim_keypad.clone()));
+    simulator.add_device(RIO, Box::new(sim_rio.clone()));
+
+    tokio::spawn(async move { simulator.run().await });
+    tokio::spawn(async move { manager.run().await });
+
+    Rig {
+        keypad,
+        rio,
+        sim_keypad,
+        sim_rio,
+        latency,
+    }
+}
+
+fn show(keypad: &SerialKeypad, line1: &str, line2: &str) {
+    keypad.mutate_state(|state| state.screen.lines = [line1.to_string(), line2.to_string()]);
+}
+
+#[tokio::test]
+async fn test_keypad_displays_screen_updates() {
+    time::pause();
+
+    let rig = rig();
+    show(&rig.keypad, "TIGER SECURITY", "MON 4 SEP 12:00");
+    rig.keypad
+        .mutate_state(|state| state.backlight = keypad::Backlight::On);
+    time::sleep(Duration::from_secs(2)).await;
+
+    assert_eq!(rig.sim_keypad.initialisations(), 1);
+    assert_eq!(
+        rig.sim_keypad.screen(),
+        ["TIGER SECURITY", "MON 4 SEP 12:00"]
+    );
+    assert!(rig.sim_keypad.backlight());
+
+    // A small change is sent as a partial update.
+    show(&rig.keypad, "TIGER SECURITY", "MON 4 SEP 12:01");
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(
+        rig.sim_keypad.screen(),
+        ["TIGER SECURITY", "MON 4 SEP 12:01"]
+    );
+}
+
+#[tokio::test]
+async fn test_key_presses_delivered_once() {
+    time::pause();
+
+    let rig = rig();
+    let mut events = rig.keypad.subscribe_events();
+    time::sleep(Duration::from_secs(1)).await;
+
+    rig.sim_keypad.press("1234E");
+
+    let mut keys = String::new();
+    while keys.len() < 5 {
+        let keypad::Event(keypad::EventType::KeyPress(key)) = events.recv().await.unwrap();
+        keys.push(key);
+    }
+    assert_eq!(keys, "1234E");
+
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(rig.sim_keypad.pending_keys(), 0);
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_bad_checksum_reinitialises_keypad() {
+    time::pause();
+
+    let rig = rig();
+    show(&rig.keypad, "BEFORE", "");
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(rig.sim_keypad.initialisations(), 1);
+
+    rig.sim_keypad.reject_next();
+    show(&rig.keypad, "AFTER", "");
+    time::sleep(Duration::from_secs(2)).await;
+
+    // The whole state is sent again after the keypad is reinitialised.
+    assert_eq!(rig.sim_keypad.initialisations(), 2);
+    assert_eq!(rig.sim_keypad.screen(), ["AFTER", ""]);
+}
+
+#[tokio::test]
+async fn test_urgent_updates_sent_promptly() {
+    time::pause();
+
+    // Idle devices are polled rarely.
+    let rig = rig_polling_every(Duration::from_secs(5));
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(rig.sim_keypad.initialisations(), 1);
+
+    show(&rig.keypad, "ALARM", "");
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(rig.sim_keypad.screen(), ["ALARM", ""]);
+
+    // No poll waited anything like as long as the poll interval.
+    time::sleep(Duration::from_secs(1)).await;
+    let latency = rig.latency.borrow()[&KEYPAD];
+    assert_eq!(latency.polls, 6);
+    assert!(latency.max < Duration::from_secs(1), "{:?}", latency);
+}
+
+#[tokio::test]
+async fn test_devices_discovered_added_and_removed_while_running() {
+    time::pause();
+
+    // The RIO is wired to the bus, but isn't registered with the manager to begin with.
+    let (panel, devices) = io::duplex(256);
+    let keypad = Arc::new(SerialKeypad::new());
+    let mut manager = SerialManager::new(Bus::new(panel));
+    manager.register_device(KEYPAD, keypad.clone()).unwrap();
+    let serial = manager.handle();
+    let mut events = serial.subscribe_events();
+
+    let sim_keypad = SimulatedKeypad::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(KEYPAD, Box::new(sim_keypad.clone()));
+    simulator.add_device(RIO, Box::new(SimulatedRio::new()));
+
+    tokio::spawn(async move { simulator.run().await });
+    tokio::spawn(async move { manager.run().await });
+
+    serial.scan();
+    let discovered = loop {
+        if let manager::Event(manager::EventType::Discovered {
+            address,
+            kind,
+            firmware,
+        }) = events.recv().await.unwrap()
+        {
+            break (address, kind, firmware);
+        }
+    };
+    assert_eq!(discovered, (RIO, DeviceKind::Rio, vec![0x01, 0x01, 0x05]));
+
+    let rio = Arc::new(SerialRio::new());
+    serial.add_device(RIO, rio.clone()).await.unwrap();
+    assert_eq!(
+        serial.add_device(RIO, Arc::new(SerialRio::new())).await,
+        Err(RegistrationError::Duplicate(RIO))
+    );
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(rio.zone_readings(), Some([10; rio::ZONE_COUNT]));
+
+    // Once removed, the keypad is no longer sent updates.
+    serial.remove_device(KEYPAD).await.unwrap();
+    show(&keypad, "REMOVED", "");
+    time::sleep(Duration::from_secs(1)).await;
+    assert_ne!(sim_keypad.screen(), ["REMOVED", ""]);
+    assert_eq!(
+        serial.remove_device(KEYPAD).await,
+        Err(RegistrationError::NotRegistered(KEYPAD))
+    );
+}
+
+#[tokio::test]
+async fn test_metrics_track_link_health() {
+    time::pause();
+
+    // A RIO is configured at an address where nothing is wired.
+    let (panel, devices) = io::duplex(256);
+    let mut manager = SerialManager::new(Bus::new(panel));
+    manager
+        .register_device(KEYPAD, Arc::new(SerialKeypad::new()))
+        .unwrap();
+    manager
+        .register_device(0x21, Arc::new(SerialRio::new()))
+        .unwrap();
+    let metrics = manager.subscribe_metrics();
+
+    let sim_keypad = SimulatedKeypad::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(KEYPAD, Box::new(sim_keypad.clone()));
+
+    tokio::spawn(async move { simulator.run().await });
+    tokio::spawn(async move { manager.run().await });
+
+    time::sleep(Duration::from_secs(1)).await;
+    sim_keypad.reject_next();
+    time::sleep(Duration::from_secs(5)).await;
+
+    let metrics = metrics.borrow();
+    let keypad = &metrics[&KEYPAD];
+    assert_eq!(keypad.bad_checksum_replies, 1);
+    // The rejected frame isn't resent, as the keypad is reinitialised instead.
+    assert_eq!(keypad.retries, 0);
+    assert_eq!(keypad.timeouts, 0);
+    assert_eq!(keypad.status, DeviceStatus::OnlineOK);
+    assert_eq!(keypad.round_trip.count, keypad.frames_sent);
+
+    let missing = &metrics[&0x21];
+    assert_eq!(missing.frames_sent, missing.timeouts);
+    assert_eq!(missing.retries, missing.timeouts * 2 / 3);
+    assert_eq!(missing.round_trip.count, 0);
+    assert_eq!(missing.status, DeviceStatus::Offline);
+    assert!(missing.backoff > Duration::from_secs(1), "{:?}", missing);
+}
+
+#[tokio::test]
+async fn test_rio_zones_and_outputs() {
+    time::pause();
+
+    let rig = rig();
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(rig.rio.zone_readings(), Some([10; rio::ZONE_COUNT]));
+    let mut events = rig.rio.subscribe_events();
+
+    rig.sim_rio.set_zone(3, 20);
+    let event = loop {
+        if let rio::Event(event @ rio::EventType::ZoneChanged { zone: 3, .. }) =
+            events.recv().await.unwrap()
+        {
+            break event;
+        }
+    };
+    assert!(matches!(
+        event,
+        rio::EventType::ZoneChanged { reading: 20, .. }
+    ));
+
+    rig.rio.set_output(2, true);
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(rig.sim_rio.outputs(), [false, true, false, false]);
+}
+
+#[tokio::test]
+async fn test_prox_reads_each_fob_once() {
+    time::pause();
+
+    let (panel, devices) = io::duplex(256);
+    let prox = Arc::new(SerialProx::new());
+    let mut manager = SerialManager::new(Bus::new(panel));
+    manager.register_device(0x50, prox.clone()).unwrap();
+    let mut events = prox.subscribe_events();
+
+    let sim_prox = SimulatedProx::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(0x50, Box::new(sim_prox.clone()));
+
+    tokio::spawn(async move { simulator.run().await });
+    tokio::spawn(async move { manager.run().await });
+    time::sleep(Duration::from_secs(1)).await;
+
+    sim_prox.present(1234567);
+    sim_prox.present(7654321);
+    for id in [1234567, 7654321] {
+        assert_eq!(
+            events.recv().await.unwrap().0,
+            prox::EventType::CardRead(id)
+        );
+    }
+
+    time::sleep(Duration::from_secs(1)).await;
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_psu_reports_conditions_and_voltages() {
+    time::pause();
+
+    let (panel, devices) = io::duplex(256);
+    let psu = Arc::new(SerialPsu::new());
+    let mut manager = SerialManager::new(Bus::new(panel));
+    manager.register_device(0x70, psu.clone()).unwrap();
+    let mut events = psu.subscribe_events();
+
+    let sim_psu = SimulatedPsu::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(0x70, Box::new(sim_psu.clone()));
+
+    tokio::spawn(async move { simulator.run().await });
+    tokio::spawn(async move { manager.run().await });
+    time::sleep(Duration::from_secs(1)).await;
+
+    // Every condition is published clear on the first status.
+    for _ in Condition::ALL {
+        assert!(matches!(
+            events.recv().await.unwrap().0,
+            psu::EventType::Condition { active: false, .. }
+        ));
+    }
+    assert_eq!(psu.status().unwrap().battery_mv, 13620);
+
+    sim_psu.set_mains(false);
+    sim_psu.set_battery_mv(11500);
+    for condition in [Condition::MainsFail, Condition::BatteryLow] {
+        assert_eq!(
+            events.recv().await.unwrap().0,
+            psu::EventType::Condition {
+                condition,
+                active: true
+            }
+        );
+    }
+    assert_eq!(psu.status().unwrap().battery_mv, 11500);
+}
+
+#[tokio::test]
+async fn test_max_controls_door() {
+    time::pause();
+
+    let (panel, devices) = io::duplex(256);
+    let max = Arc::new(SerialMax::new());
+    let mut manager = SerialManager::new(Bus::new(panel));
+    manager.register_device(0x60, max.clone()).unwrap();
+
+    let sim_max = SimulatedMax::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(0x60, Box::new(sim_max.clone()));
+
+    let users = UserStore::new(vec![User {
+        number: 1,
+        name: "CLEANER".to_string(),
+        pin: PinHash::with_rounds("1234", 1_000),
+        fob: Some(1234567),
+        fob_with_pin: false,
+        level: AccessLevel::ACCESS_ONLY,
+        areas: Areas::ALL,
+        validity: Validity::default(),
+    }])
+    .unwrap();
+    let mut access_manager = AccessManager::new(
+        vec![Door {
+            number: 1,
+            name: "OFFICE".to_string(),
+            reader: 0x60,
+            release: Duration::from_secs(5),
+            held_open: Duration::from_secs(30),
+        }],
+        vec![Permission {
+            user: 1,
+            doors: vec![1],
+            schedule: None,
+        }],
+        Arc::new(users),
+    );
+    access_manager.attach_reader(0x60, max);
+    let mut events = access_manager.subscribe_events();
+
+    tokio::spawn(async move { simulator.run().await });
+    tokio::spawn(async move { manager.run().await });
+    tokio::spawn(async move { access_manager.run().await });
+    time::sleep(Duration::from_secs(1)).await;
+
+    sim_max.present(1234567);
+    assert_eq!(
+        events.recv().await.unwrap().0,
+        access::EventType::Granted { door: 1, user: 1 }
+    );
+    time::sleep(Duration::from_secs(1)).await;
+    assert!(sim_max.relay());
+    assert_eq!(sim_max.indication(), (0x02, 0x00));
+
+    time::sleep(Duration::from_secs(5)).await;
+    assert!(!sim_max.relay());
+
+    sim_max.set_door_open(true);
+    assert_eq!(
+        events.recv().await.unwrap().0,
+        access::EventType::ForcedOpen { door: 1 }
+    );
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(sim_max.indication(), (0x00, 0x03));
+}
+
+#[tokio::test]
+async fn test_answers_amongst_other_devices() {
+    time::pause();
+
+    let (mut panel, devices) = io::duplex(256);
+    let sim_keypad = SimulatedKeypad::new();
+    let mut simulator = Simulator::new(devices);
+    simulator.add_device(KEYPAD, Box::new(sim_keypad.clone()));
+    tokio::spawn(async move { simulator.run().await });
+
+    let message = |recipient_address, command, data: &[u8]| {
+        SerialMessage {
+            recipient_address,
+            command,
+            additional_data: (!data.is_empty()).then(|| data.to_vec()),
+        }
+        .serialise()
+    };
+
+    // On a real bus, the reply of another device can run into the next message from the panel.
+    let burst = [
+        message(RIO, 0x06, &[]),
+        message(0x11, 0xFE, &[]),
+        message(KEYPAD, 0x00, &[0x0E]),
+    ]
+    .concat();
+    panel.write_all(&burst).await.unwrap();
+
+    let mut reply = [0u8; 16];
+    let n = panel.read(&mut reply).await.unwrap();
+    assert_eq!(reply[..n], message(0x11, 0xFF, &[0x08, 0x00, 0x64]));
+    assert_eq!(sim_keypad.initialisations(), 1);
+}